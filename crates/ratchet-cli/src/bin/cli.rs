@@ -1,8 +1,6 @@
 use clap::{value_parser, Arg, ArgMatches, Command};
 use hf_hub::api::sync::Api;
-use ndarray::Axis;
-use ndarray_stats::QuantileExt;
-use ratchet::{shape, Device, DeviceRequest, Tensor};
+use ratchet::{shape, Device, DeviceRequest, SampleStrategy, Tensor};
 use ratchet_loader::gguf::gguf::{self, Header};
 use ratchet_models::registry::{AvailableModels, Quantization, WhisperVariants as RegistryWhisper};
 use ratchet_models::whisper::options::DecodingOptionsBuilder;
@@ -141,16 +139,14 @@ fn handle_phi2(matches: &ArgMatches, api: Api) -> anyhow::Result<()> {
     let start_time = std::time::Instant::now();
     while tokens[tokens.len() - 1] != 50256 && loop_cnt < *max_tokens {
         let input = Tensor::from_data(tokens.clone(), shape![1, tokens.len()], device.clone());
-        let result = model.schedule(input)?.full()?.resolve()?;
-        let logits = result.to(&Device::CPU)?;
+        let result = model
+            .schedule(input)?
+            .sample(SampleStrategy::Greedy, 0)?
+            .resolve()?;
+        let next_tokens = result.to(&Device::CPU)?;
         model.cache_mut().update(tokens.len());
 
-        tokens = logits
-            .to_ndarray_view::<f32>()
-            .map_axis(Axis(2), |row| row.argmax_skipnan().unwrap())
-            .iter()
-            .map(|&x| x as i32)
-            .collect::<Vec<_>>();
+        tokens = next_tokens.to_vec::<i32>()?;
         let u32_toks = tokens.iter().map(|&x| x as u32).collect::<Vec<_>>();
         print!("{}", tokenizer.decode(&u32_toks, true).unwrap());
         std::io::stdout().flush().unwrap();
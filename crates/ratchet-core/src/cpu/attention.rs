@@ -0,0 +1,58 @@
+use crate::{
+    cpu::{from_f32, read_f32, CPUOperation},
+    Attention, CPUBuffer, OperationError, Tensor,
+};
+
+impl CPUOperation for Attention {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let (q, k, v) = (
+            read_f32(&self.query)?,
+            read_f32(&self.key)?,
+            read_f32(&self.value)?,
+        );
+        let mask = self.mask.as_ref().map(read_f32).transpose()?;
+        let [B, Hq, Sq, D]: [usize; 4] = self.query.shape().try_into()?;
+        let [_, Hkv, Sk, _]: [usize; 4] = self.key.shape().try_into()?;
+        let (mask_batch_stride, mask_head_stride) = self.mask_strides();
+        let groups = Hq / Hkv;
+
+        let mut result = vec![0f32; B * Hq * Sq * D];
+        let mut scores = vec![0f32; Sk];
+        for b in 0..B {
+            for h in 0..Hq {
+                let kv_offset = (b * Hkv + h / groups) * Sk * D;
+                for i in 0..Sq {
+                    let q_offset = ((b * Hq + h) * Sq + i) * D;
+                    let query = &q[q_offset..q_offset + D];
+                    let visible = if self.causal { i + Sk - Sq + 1 } else { Sk };
+
+                    let mut max = f32::NEG_INFINITY;
+                    for (j, score) in scores.iter_mut().enumerate().take(visible) {
+                        let key = &k[kv_offset + j * D..kv_offset + (j + 1) * D];
+                        *score =
+                            query.iter().zip(key).map(|(a, b)| a * b).sum::<f32>() * self.scale;
+                        if let Some(m) = &mask {
+                            *score += m[b * mask_batch_stride + h * mask_head_stride + i * Sk + j];
+                        }
+                        max = max.max(*score);
+                    }
+                    if max == f32::NEG_INFINITY {
+                        //Fully masked rows produce zeros, as on the GPU
+                        continue;
+                    }
+
+                    let mut sum = 0.;
+                    let out = &mut result[q_offset..q_offset + D];
+                    for (j, score) in scores.iter().enumerate().take(visible) {
+                        let p = (score - max).exp();
+                        sum += p;
+                        let value = &v[kv_offset + j * D..kv_offset + (j + 1) * D];
+                        out.iter_mut().zip(value).for_each(|(o, v)| *o += p * v);
+                    }
+                    out.iter_mut().for_each(|o| *o /= sum);
+                }
+            }
+        }
+        from_f32(&result, dst.dt())
+    }
+}
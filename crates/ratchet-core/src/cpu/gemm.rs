@@ -0,0 +1,131 @@
+use crate::{
+    cpu::{from_f32, read_f32, CPUOperation},
+    CPUBuffer, Conv, Im2Col, Matmul, OperationError, Tensor,
+};
+
+impl CPUOperation for Matmul {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let spec = self.compute_spec(dst);
+        let (lhs, rhs) = (read_f32(&self.lhs)?, read_f32(&self.rhs)?);
+        let bias = self.bias.as_ref().map(read_f32).transpose()?;
+
+        let (lhs_shape, rhs_shape) = (spec.lhs_shape(), spec.rhs_shape());
+        let (M, K) = if self.trans_lhs {
+            (lhs_shape[1], lhs_shape[0])
+        } else {
+            (lhs_shape[0], lhs_shape[1])
+        };
+        let N = if self.trans_rhs {
+            rhs_shape[0]
+        } else {
+            rhs_shape[1]
+        };
+
+        let a_at = |m: usize, k: usize| {
+            if self.trans_lhs {
+                k * M + m
+            } else {
+                m * K + k
+            }
+        };
+        let b_at = |k: usize, n: usize| {
+            if self.trans_rhs {
+                n * K + k
+            } else {
+                k * N + n
+            }
+        };
+
+        let mut result = vec![0f32; spec.stacks() * M * N];
+        for stack in 0..spec.stacks() {
+            let a = &lhs[(stack % spec.lhs_stack()) * M * K..][..M * K];
+            let b = &rhs[(stack % spec.rhs_stack()) * K * N..][..K * N];
+            let c = &mut result[stack * M * N..][..M * N];
+            for m in 0..M {
+                for n in 0..N {
+                    let acc = (0..K).fold(0f32, |acc, k| a[a_at(m, k)].mul_add(b[b_at(k, n)], acc));
+                    //Bias is always applied along the last dimension of the output
+                    let (out_index, bias_index) = if self.trans_out {
+                        (n * M + m, m)
+                    } else {
+                        (m * N + n, n)
+                    };
+                    c[out_index] = acc + bias.as_ref().map_or(0., |b| b[bias_index]);
+                }
+            }
+        }
+        from_f32(&result, dst.dt())
+    }
+}
+
+impl CPUOperation for Conv {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let (input, weight) = (read_f32(&self.input)?, read_f32(&self.weight)?);
+        let bias = self.bias.as_ref().map(read_f32).transpose()?;
+
+        let [N, C_in, L_in]: [usize; 3] = self.input.shape().try_into()?;
+        let [C_out, C_g, KS]: [usize; 3] = self.weight.shape().try_into()?;
+        let L_out = dst.shape()[2];
+        let C_out_g = C_out / self.groups;
+
+        let mut result = vec![0f32; N * C_out * L_out];
+        for n in 0..N {
+            for co in 0..C_out {
+                for l in 0..L_out {
+                    let mut acc = bias.as_ref().map_or(0., |b| b[co]);
+                    for ci in 0..C_g {
+                        let c = (co / C_out_g) * C_g + ci;
+                        for k in 0..KS {
+                            let pos = (l * self.stride + k * self.dilation) as isize
+                                - self.padding as isize;
+                            if pos < 0 || pos >= L_in as isize {
+                                continue;
+                            }
+                            let x = input[(n * C_in + c) * L_in + pos as usize];
+                            acc += x * weight[(co * C_g + ci) * KS + k];
+                        }
+                    }
+                    result[(n * C_out + co) * L_out + l] = acc;
+                }
+            }
+        }
+        from_f32(&result, dst.dt())
+    }
+}
+
+impl CPUOperation for Im2Col {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let input = read_f32(&self.input)?;
+        let [N, C, H, W]: [usize; 4] = self.input.shape().try_into()?;
+        let [KH, KW] = self.kernel_size;
+        let [H_out, W_out] = self.output_size();
+        let C_g = C / self.groups;
+
+        let mut result = vec![0f32; dst.shape().numel()];
+        let mut out = result.iter_mut();
+        for ng in 0..N * self.groups {
+            for ci in 0..C_g {
+                let plane = &input[(ng * C_g + ci) * H * W..][..H * W];
+                for kh in 0..KH {
+                    for kw in 0..KW {
+                        for oh in 0..H_out {
+                            for ow in 0..W_out {
+                                let y = out.next().unwrap();
+                                let ih = (oh * self.stride[0] + kh * self.dilation[0])
+                                    .checked_sub(self.padding[0])
+                                    .filter(|&ih| ih < H);
+                                let iw = (ow * self.stride[1] + kw * self.dilation[1])
+                                    .checked_sub(self.padding[1])
+                                    .filter(|&iw| iw < W);
+                                if let (Some(ih), Some(iw)) = (ih, iw) {
+                                    *y = plane[ih * W + iw];
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        from_f32(&result, dst.dt())
+    }
+}
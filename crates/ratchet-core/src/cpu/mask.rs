@@ -0,0 +1,47 @@
+use crate::{
+    cpu::{from_f32, read_f32, CPUOperation},
+    masked_value, CPUBuffer, CausalMask, OperationError, PaddingMask, PaddingSide, Tensor,
+};
+
+impl CPUOperation for CausalMask {
+    fn apply_cpu(&self, _dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let masked = masked_value(self.dt);
+        let result = (0..self.q_len)
+            .flat_map(|i| {
+                (0..self.kv_len).map(move |j| match self.key_position(j) {
+                    Some(key) if key <= i + self.offset => 0.,
+                    _ => masked,
+                })
+            })
+            .collect::<Vec<_>>();
+        from_f32(&result, self.dt)
+    }
+}
+
+impl CPUOperation for PaddingMask {
+    fn apply_cpu(&self, _dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let masked = masked_value(self.dt);
+        let lengths = read_f32(&self.lengths)?;
+
+        let mut result = Vec::with_capacity(lengths.len() * self.q_len * self.kv_len);
+        for length in lengths {
+            let length = (length.max(0.) as usize).min(self.kv_len);
+            let row = (0..self.kv_len).map(|j| {
+                let is_padding = match self.side {
+                    PaddingSide::Left => j + length < self.kv_len,
+                    PaddingSide::Right => j >= length,
+                };
+                if is_padding {
+                    masked
+                } else {
+                    0.
+                }
+            });
+            let row = row.collect::<Vec<_>>();
+            for _ in 0..self.q_len {
+                result.extend_from_slice(&row);
+            }
+        }
+        from_f32(&result, self.dt)
+    }
+}
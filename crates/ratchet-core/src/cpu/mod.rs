@@ -0,0 +1,245 @@
+mod attention;
+mod gemm;
+mod mask;
+mod norm;
+mod reduce;
+mod reindex;
+mod sample;
+mod unary;
+
+use bytemuck::NoUninit;
+use half::{bf16, f16};
+
+use crate::{
+    CPUBuffer, DType, InvariantError, OperationError, RVec, Shape, Strides, Tensor, QK4_0, QK8_0,
+};
+
+/// # CPUOperation
+///
+/// The host equivalent of [crate::MetaOperation].
+/// Rather than building a kernel, the operation reads the CPU storage of its sources
+/// and returns the buffer for `dst` directly.
+///
+/// Floating point operations are computed in f32 and converted to the dtype of `dst`,
+/// making this backend a useful reference for the GPU kernels.
+pub trait CPUOperation {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError>;
+}
+
+/// Clones the handle to the CPU buffer backing `t`.
+pub(crate) fn cpu_buffer(t: &Tensor) -> Result<CPUBuffer, OperationError> {
+    let storage_guard = t.storage();
+    let storage = storage_guard
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Storage missing for {:?}", t.id()))?;
+    Ok(storage.try_cpu()?.clone())
+}
+
+/// Reads the contents of `t` as f32, upcasting or dequantizing as required.
+pub(crate) fn read_f32(t: &Tensor) -> Result<Vec<f32>, OperationError> {
+    let buffer = cpu_buffer(t)?;
+    let bytes = buffer.inner().as_bytes();
+    let numel = t.shape().numel();
+    let dt = t.dt();
+    let data = match dt {
+        DType::F32 => bytemuck::cast_slice::<u8, f32>(&bytes[..numel * 4]).to_vec(),
+        DType::F16 => bytemuck::cast_slice::<u8, f16>(&bytes[..numel * 2])
+            .iter()
+            .map(|x| x.to_f32())
+            .collect(),
+        DType::BF16 => bytemuck::cast_slice::<u8, bf16>(&bytes[..numel * 2])
+            .iter()
+            .map(|x| x.to_f32())
+            .collect(),
+        DType::I32 => bytemuck::cast_slice::<u8, i32>(&bytes[..numel * 4])
+            .iter()
+            .map(|&x| x as f32)
+            .collect(),
+        DType::U32 => bytemuck::cast_slice::<u8, u32>(&bytes[..numel * 4])
+            .iter()
+            .map(|&x| x as f32)
+            .collect(),
+        DType::Q8_0F(_) | DType::Q8_0H(_) => read_q8_0(bytes, dt, numel),
+        DType::Q4_0F(_) | DType::Q4_0H(_) => read_q4_0(bytes, dt, numel),
+    };
+    Ok(data)
+}
+
+/// Dequantizes a Q8_0 buffer, laid out as described by [crate::Segments].
+fn read_q8_0(bytes: &[u8], dt: DType, numel: usize) -> Vec<f32> {
+    let scales = read_scales(bytes, dt, numel, QK8_0);
+    let qs = bytemuck::cast_slice::<u8, i8>(&bytes[..numel]);
+    qs.iter()
+        .enumerate()
+        .map(|(i, &q)| q as f32 * scales[i / QK8_0])
+        .collect()
+}
+
+/// Dequantizes a Q4_0 buffer, laid out as described by [crate::Segments].
+fn read_q4_0(bytes: &[u8], dt: DType, numel: usize) -> Vec<f32> {
+    let scales = read_scales(bytes, dt, numel, QK4_0);
+    let qs = &bytes[..numel / 2];
+    (0..numel)
+        .map(|i| {
+            //Shift the nibble into the high bits, and back down to extend its sign
+            let q = ((qs[i / 2] << (4 * (1 - i % 2))) as i8) >> 4;
+            q as f32 * scales[i / QK4_0]
+        })
+        .collect()
+}
+
+/// Reads the scale of each block of `block_numel` values, from the second segment of a Q8_0 or
+/// Q4_0 buffer.
+pub fn read_scales(bytes: &[u8], dt: DType, numel: usize, block_numel: usize) -> Vec<f32> {
+    let n_blocks = numel / block_numel;
+    let d_offset = dt.segments(numel)[1].offset as usize;
+    match dt {
+        DType::Q8_0F(_) | DType::Q4_0F(_) => {
+            bytemuck::cast_slice::<u8, f32>(&bytes[d_offset..d_offset + n_blocks * 4]).to_vec()
+        }
+        DType::Q8_0H(_) | DType::Q4_0H(_) => {
+            bytemuck::cast_slice::<u8, f16>(&bytes[d_offset..d_offset + n_blocks * 2])
+                .iter()
+                .map(|d| d.to_f32())
+                .collect()
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Creates a CPU buffer of type `dt` from f32 values.
+pub(crate) fn from_f32(data: &[f32], dt: DType) -> Result<CPUBuffer, OperationError> {
+    fn to_buffer<T: NoUninit>(data: &[T]) -> CPUBuffer {
+        CPUBuffer::from_bytes(bytemuck::cast_slice(data), std::mem::align_of::<T>())
+    }
+
+    let buffer = match dt {
+        DType::F32 => to_buffer(data),
+        DType::F16 => to_buffer(&data.iter().map(|&x| f16::from_f32(x)).collect::<Vec<_>>()),
+        DType::BF16 => to_buffer(&data.iter().map(|&x| bf16::from_f32(x)).collect::<Vec<_>>()),
+        DType::I32 => to_buffer(&data.iter().map(|&x| x as i32).collect::<Vec<_>>()),
+        DType::U32 => to_buffer(&data.iter().map(|&x| x as u32).collect::<Vec<_>>()),
+        _ => return Err(InvariantError::UnsupportedDType(dt).into()),
+    };
+    Ok(buffer)
+}
+
+/// Contiguous strides of `shape`, in elements.
+pub(crate) fn contiguous_strides(shape: &Shape) -> RVec<usize> {
+    Strides::from(shape)
+        .to_vec()
+        .into_iter()
+        .map(|s| s as usize)
+        .collect()
+}
+
+/// Converts a 1D offset into an ND index.
+pub(crate) fn offset_to_index(mut offset: usize, strides: &[usize]) -> RVec<usize> {
+    let mut index = RVec::with_capacity(strides.len());
+    for &stride in strides {
+        index.push(offset / stride);
+        offset %= stride;
+    }
+    index
+}
+
+/// Converts an ND index into a 1D offset.
+pub(crate) fn index_to_offset(index: &[usize], strides: &[usize]) -> usize {
+    index.iter().zip(strides).map(|(i, s)| i * s).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{rvec, shape, Device, SampleStrategy, Tensor};
+
+    #[test]
+    fn cpu_elementwise() -> anyhow::Result<()> {
+        let a = Tensor::from_data([1f32, -2., 3., -4.], shape![2, 2], Device::CPU);
+        let b = Tensor::from_data([0.5f32, 0.5, 2., 2.], shape![2, 2], Device::CPU);
+        let result = a.mul(b)?.abs()?.resolve()?;
+        assert_eq!(result.to_vec::<f32>()?, vec![0.5, 1., 6., 8.]);
+        Ok(())
+    }
+
+    #[test]
+    fn cpu_matmul_transposed() -> anyhow::Result<()> {
+        let a = Tensor::from_data([1f32, 2., 3., 4., 5., 6.], shape![2, 3], Device::CPU);
+        let b = Tensor::from_data([1f32, 0., 1., 0., 1., 0.], shape![2, 3], Device::CPU);
+        let result = a.matmul(b, false, true)?.resolve()?;
+        assert_eq!(result.shape(), &shape![2, 2]);
+        assert_eq!(result.to_vec::<f32>()?, vec![4., 2., 10., 5.]);
+        Ok(())
+    }
+
+    #[test]
+    fn cpu_reduce() -> anyhow::Result<()> {
+        let a = Tensor::from_data([1f32, 5., 3., 5., 2., 0.], shape![2, 3], Device::CPU);
+        let sum = a.clone().sum(1, false)?.resolve()?;
+        assert_eq!(sum.to_vec::<f32>()?, vec![9., 7.]);
+        let max = a.clone().max(0, true)?.resolve()?;
+        assert_eq!(max.shape(), &shape![1, 3]);
+        assert_eq!(max.to_vec::<f32>()?, vec![5., 5., 3.]);
+        let argmax = a.argmax(1, false)?.resolve()?;
+        assert_eq!(argmax.to_vec::<i32>()?, vec![1, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn cpu_sample() -> anyhow::Result<()> {
+        let logits = Tensor::from_data([0f32, 3., 1., 2., 9., 9.], shape![2, 3], Device::CPU);
+        let greedy = logits
+            .clone()
+            .sample(SampleStrategy::Greedy, 0)?
+            .resolve()?;
+        assert_eq!(greedy.to_vec::<i32>()?, vec![1, 1]);
+        let top1 = SampleStrategy::TopK {
+            k: 1,
+            temperature: 1.,
+        };
+        let sampled = logits.sample(top1, 42)?.resolve()?;
+        assert_eq!(sampled.to_vec::<i32>()?, vec![1, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn cpu_cache_writes_through() -> anyhow::Result<()> {
+        let cache = Tensor::zeros::<f32>(&shape![1, 4, 2], &Device::CPU);
+        let source = Tensor::from_data([1f32, 2.], shape![1, 1, 2], Device::CPU);
+        let result = cache.clone().cache(source, 1, 1)?.resolve()?;
+        assert_eq!(result.shape(), &shape![1, 2, 2]);
+        assert_eq!(result.to_vec::<f32>()?, vec![0., 0., 1., 2.]);
+        assert_eq!(cache.to_vec::<f32>()?, vec![0., 0., 1., 2., 0., 0., 0., 0.]);
+
+        let permuted = result.permute(&[0, 2, 1])?.resolve()?;
+        assert_eq!(permuted.to_vec::<f32>()?, vec![0., 1., 0., 2.]);
+        let sliced = permuted.slice(&[0..1, 1..2, 0..2])?.resolve()?;
+        assert_eq!(sliced.to_vec::<f32>()?, vec![0., 2.]);
+        let cat = Tensor::cat(rvec![sliced.clone(), sliced], 2)?.resolve()?;
+        assert_eq!(cat.to_vec::<f32>()?, vec![0., 2., 0., 2.]);
+        Ok(())
+    }
+
+    #[test]
+    fn cpu_rope_row_offsets() -> anyhow::Result<()> {
+        let x = Tensor::randn::<f32>(shape![2, 2, 3, 8], Device::CPU);
+        let row_offsets = Tensor::from_data([-1i32, 0], shape![2], Device::CPU);
+        let result = x
+            .deep_clone()
+            .rope_with_offsets(8, 10000., 1, row_offsets)?
+            .resolve()?;
+        for (row, offset) in [(0, 0), (1, 1)] {
+            let expected = x
+                .deep_clone()
+                .slice(&[row..row + 1, 0..2, 0..3, 0..8])?
+                .full()?
+                .rope(8, 10000., offset)?
+                .resolve()?;
+            let actual = result
+                .clone()
+                .slice(&[row..row + 1, 0..2, 0..3, 0..8])?
+                .resolve()?;
+            actual.all_close::<f32>(&expected, 1e-5, 1e-5)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{
+    cpu::{from_f32, read_f32, CPUOperation},
+    CPUBuffer, GroupNorm, Norm, NormOp, OperationError, RoPE, Softmax, Tensor,
+};
+
+/// Normalizes each contiguous chunk of `x` of length `N` in place.
+/// If `center` is false, the mean is taken to be 0 (RMSNorm).
+fn normalize(x: &mut [f32], N: usize, eps: f32, center: bool) {
+    for chunk in x.chunks_exact_mut(N) {
+        let mu = if center {
+            chunk.iter().sum::<f32>() / N as f32
+        } else {
+            0.
+        };
+        let sigma = chunk.iter().map(|v| (v - mu) * (v - mu)).sum::<f32>() / N as f32;
+        let denom = (sigma + eps).sqrt().recip();
+        chunk.iter_mut().for_each(|v| *v = (*v - mu) * denom);
+    }
+}
+
+impl CPUOperation for NormOp {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let result = match self {
+            NormOp::LayerNorm(Norm {
+                input,
+                scale,
+                bias,
+                eps,
+            })
+            | NormOp::RMSNorm(Norm {
+                input,
+                scale,
+                bias,
+                eps,
+            }) => {
+                let N = input.shape()[input.rank() - 1];
+                let is_layernorm = matches!(self, NormOp::LayerNorm(_));
+                let mut x = read_f32(input)?;
+                normalize(&mut x, N, *eps, is_layernorm);
+
+                let scale = read_f32(scale)?;
+                //RMSNorm never applies a bias
+                let bias = match bias {
+                    Some(b) if is_layernorm => Some(read_f32(b)?),
+                    _ => None,
+                };
+                for row in x.chunks_exact_mut(N) {
+                    for (i, v) in row.iter_mut().enumerate() {
+                        *v = *v * scale[i] + bias.as_ref().map_or(0., |b| b[i]);
+                    }
+                }
+                x
+            }
+            NormOp::GroupNorm(GroupNorm {
+                norm:
+                    Norm {
+                        input,
+                        scale,
+                        bias,
+                        eps,
+                    },
+                num_groups,
+            }) => {
+                let channels = input.shape()[1];
+                let spatial = input.shape().numel() / (input.shape()[0] * channels);
+                let N = (channels / num_groups) * spatial;
+                let mut x = read_f32(input)?;
+                normalize(&mut x, N, *eps, true);
+
+                let scale = read_f32(scale)?;
+                let bias = bias.as_ref().map(read_f32).transpose()?;
+                for (i, v) in x.iter_mut().enumerate() {
+                    let c = (i / spatial) % channels;
+                    *v = *v * scale[c] + bias.as_ref().map_or(0., |b| b[c]);
+                }
+                x
+            }
+        };
+        from_f32(&result, dst.dt())
+    }
+}
+
+impl CPUOperation for Softmax {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let shape = self.input.shape();
+        let N = shape[self.dim];
+        let inner = shape[self.dim + 1..].iter().product::<usize>();
+        let outer = shape.numel() / (N * inner);
+
+        let mut x = read_f32(&self.input)?;
+        for o in 0..outer {
+            for i in 0..inner {
+                let at = |n: usize| o * N * inner + n * inner + i;
+                let max = (0..N).fold(f32::NEG_INFINITY, |acc, n| acc.max(x[at(n)]));
+                let mut sum = 0.;
+                for n in 0..N {
+                    x[at(n)] = (x[at(n)] - max).exp();
+                    sum += x[at(n)];
+                }
+                for n in 0..N {
+                    x[at(n)] /= sum;
+                }
+            }
+        }
+        from_f32(&x, dst.dt())
+    }
+}
+
+impl CPUOperation for RoPE {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let [B, NH, SL, HD]: [usize; 4] = self.input.shape().try_into()?;
+        let half_dim = self.dim / 2;
+
+        let row_offsets = match &self.row_offsets {
+            Some(row_offsets) => read_f32(row_offsets)?,
+            None => vec![0.; B],
+        };
+        let mut x = read_f32(&self.input)?;
+        for head in 0..B * NH {
+            for pos in 0..SL {
+                let row = (head * SL + pos) * HD;
+                let L = (pos + self.offset) as f32 + row_offsets[head / NH];
+                for i in 0..half_dim {
+                    //self.base is log2(base)
+                    let d = i as f32 / half_dim as f32;
+                    let theta = L * (-d * self.base).exp2();
+                    let (sin, cos) = theta.sin_cos();
+
+                    let (x1, x2) = (x[row + i], x[row + i + half_dim]);
+                    x[row + i] = x1 * cos - x2 * sin;
+                    x[row + i + half_dim] = x1 * sin + x2 * cos;
+                }
+            }
+        }
+        from_f32(&x, dst.dt())
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{
+    cpu::{from_f32, read_f32, CPUOperation},
+    CPUBuffer, OperationError, Reduce, ReduceOp, Tensor,
+};
+
+impl CPUOperation for Reduce {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let (outer, N, inner) = self.reduction_dims();
+        let x = read_f32(&self.input)?;
+
+        //Ties in arg reductions resolve to the lowest index, matching the GPU kernel.
+        let arg_select = |o: usize, i: usize, better: fn(f32, f32) -> bool| {
+            let at = |n: usize| x[o * N * inner + n * inner + i];
+            (1..N).fold(0, |best, n| if better(at(n), at(best)) { n } else { best }) as f32
+        };
+
+        let mut result = Vec::with_capacity(outer * inner);
+        for o in 0..outer {
+            for i in 0..inner {
+                let row = (0..N).map(|n| x[o * N * inner + n * inner + i]);
+                let value = match self.op {
+                    ReduceOp::Sum => row.sum(),
+                    ReduceOp::Mean => row.sum::<f32>() / N as f32,
+                    ReduceOp::Max => row.fold(f32::NEG_INFINITY, f32::max),
+                    ReduceOp::Min => row.fold(f32::INFINITY, f32::min),
+                    ReduceOp::ArgMax => arg_select(o, i, |a, b| a > b),
+                    ReduceOp::ArgMin => arg_select(o, i, |a, b| a < b),
+                };
+                result.push(value);
+            }
+        }
+        from_f32(&result, dst.dt())
+    }
+}
@@ -0,0 +1,294 @@
+use half::f16;
+
+use crate::{
+    cpu::{
+        contiguous_strides, cpu_buffer, from_f32, index_to_offset, offset_to_index, read_f32,
+        CPUOperation,
+    },
+    CPUBuffer, Cache, Concat, DType, IndexSelect, IndexWrite, InvariantError, OperationError,
+    PagedCache, Reindex, Storage, Tensor, QK8_0,
+};
+
+/// Element size in bytes, for operations which only move data around.
+fn element_size(t: &Tensor) -> Result<usize, OperationError> {
+    if t.dt().is_quantized() {
+        return Err(InvariantError::UnsupportedDType(t.dt()).into());
+    }
+    Ok(t.dt().size_of())
+}
+
+/// Builds `numel` elements, where element `i` is copied from `src` at element offset `src_offset(i)`.
+fn gather(src: &[u8], elem: usize, numel: usize, src_offset: impl Fn(usize) -> usize) -> CPUBuffer {
+    let mut dst = vec![0u8; numel * elem];
+    for (i, chunk) in dst.chunks_exact_mut(elem).enumerate() {
+        let offset = src_offset(i) * elem;
+        chunk.copy_from_slice(&src[offset..offset + elem]);
+    }
+    CPUBuffer::from_bytes(&dst, elem)
+}
+
+/// Copies the contents of `t`, to be modified and swapped back in with [write_through].
+fn read_for_write(t: &Tensor) -> Result<(Vec<u8>, usize), OperationError> {
+    let buffer = cpu_buffer(t)?;
+    let (_, layout) = buffer.inner().into_raw_parts();
+    Ok((buffer.inner().as_bytes().to_vec(), layout.align()))
+}
+
+/// Replaces the storage of `t`, a source written through by e.g [Cache], with `bytes`.
+///
+/// The buffer of `t` may be shared with other tensors which read it, so it is never modified in
+/// place. Holders of the old buffer keep a consistent, if stale, copy.
+fn write_through(t: &Tensor, bytes: &[u8], alignment: usize) {
+    t.update_storage(Storage::CPU(CPUBuffer::from_bytes(bytes, alignment)));
+}
+
+impl CPUOperation for Reindex {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let src = match self {
+            Reindex::Permute(p) => &p.src,
+            Reindex::Slice(s) => &s.src,
+            Reindex::Broadcast(b) => &b.src,
+        };
+        let elem = element_size(src)?;
+        let src_buffer = cpu_buffer(src)?;
+        let src_bytes = src_buffer.inner().as_bytes();
+
+        let src_strides = contiguous_strides(src.shape());
+        let dst_strides = contiguous_strides(dst.shape());
+        let dst_numel = dst.shape().numel();
+
+        let buffer = match self {
+            Reindex::Permute(p) => gather(src_bytes, elem, dst_numel, |i| {
+                let dst_index = offset_to_index(i, &dst_strides);
+                let mut src_index = dst_index.clone();
+                for (d, &axis) in p.dims.iter().enumerate() {
+                    src_index[axis] = dst_index[d];
+                }
+                index_to_offset(&src_index, &src_strides)
+            }),
+            Reindex::Slice(s) => gather(src_bytes, elem, dst_numel, |i| {
+                let mut index = offset_to_index(i, &dst_strides);
+                for (d, range) in s.indices().iter().enumerate() {
+                    index[d] += range.start;
+                }
+                index_to_offset(&index, &src_strides)
+            }),
+            Reindex::Broadcast(b) => {
+                let mut src_shape = src.shape().clone();
+                src_shape.left_pad_to(1, b.to().rank());
+                let src_strides = contiguous_strides(&src_shape);
+                gather(src_bytes, elem, dst_numel, |i| {
+                    let mut index = offset_to_index(i, &dst_strides);
+                    for (d, &size) in src_shape.iter().enumerate() {
+                        if size == 1 {
+                            index[d] = 0;
+                        }
+                    }
+                    index_to_offset(&index, &src_strides)
+                })
+            }
+        };
+        Ok(buffer)
+    }
+}
+
+impl CPUOperation for Concat {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let elem = element_size(dst)?;
+        let shape = dst.shape();
+        let outer = shape[..self.dim].iter().product::<usize>();
+        let inner = shape[self.dim + 1..].iter().product::<usize>();
+
+        let buffers = self
+            .inputs
+            .iter()
+            .map(cpu_buffer)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut result = Vec::with_capacity(shape.numel() * elem);
+        for o in 0..outer {
+            for (input, buffer) in self.inputs.iter().zip(buffers.iter()) {
+                let chunk = input.shape()[self.dim] * inner * elem;
+                result.extend_from_slice(&buffer.inner().as_bytes()[o * chunk..][..chunk]);
+            }
+        }
+        Ok(CPUBuffer::from_bytes(&result, elem))
+    }
+}
+
+impl CPUOperation for IndexSelect {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let indices_buffer = cpu_buffer(&self.indices)?;
+        let indices = bytemuck::cast_slice::<u8, i32>(
+            &indices_buffer.inner().as_bytes()[..self.indices.shape().numel() * 4],
+        );
+
+        //Quantized embeddings are dequantized up front, then gathered like any other tensor.
+        let src_buffer = if self.src.dt().is_quantized() {
+            from_f32(&read_f32(&self.src)?, dst.dt())?
+        } else {
+            cpu_buffer(&self.src)?
+        };
+        let elem = dst.dt().size_of();
+
+        let cols = self.src.shape()[1];
+        let n_indices = indices.len();
+        Ok(gather(
+            src_buffer.inner().as_bytes(),
+            elem,
+            dst.shape().numel(),
+            |i| match self.dim {
+                0 => indices[i / cols] as usize * cols + i % cols,
+                _ => (i / n_indices) * cols + indices[i % n_indices] as usize,
+            },
+        ))
+    }
+}
+
+impl CPUOperation for IndexWrite {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let elem = element_size(dst)?;
+        let mut result = cpu_buffer(&self.dst)?.inner().as_bytes().to_vec();
+        let src_buffer = cpu_buffer(&self.src)?;
+        let src_bytes = &src_buffer.inner().as_bytes()[..self.src.shape().numel() * elem];
+
+        let dst_strides = contiguous_strides(self.dst.shape());
+        let start = index_to_offset(&self.write_start, &dst_strides) * elem;
+        result[start..start + src_bytes.len()].copy_from_slice(src_bytes);
+        Ok(CPUBuffer::from_bytes(&result, elem))
+    }
+}
+
+impl CPUOperation for Cache {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        if dst.dt().is_quantized() {
+            return quantized_cache(self, dst);
+        }
+        let elem = element_size(dst)?;
+        let source_buffer = cpu_buffer(&self.source)?;
+        let source = source_buffer.inner().as_bytes();
+
+        let cache_strides = contiguous_strides(self.cache.shape());
+        let source_strides = contiguous_strides(self.source.shape());
+        let dst_strides = contiguous_strides(dst.shape());
+
+        //Like the GPU kernel, the source is also written into the cache itself,
+        //so subsequent steps can read it without it being passed again.
+        let (mut cache, alignment) = read_for_write(&self.cache)?;
+
+        let mut result = vec![0u8; dst.shape().numel() * elem];
+        for (dst_offset, chunk) in result.chunks_exact_mut(elem).enumerate() {
+            let mut index = offset_to_index(dst_offset, &dst_strides);
+            let cache_offset = index_to_offset(&index, &cache_strides) * elem;
+            if let Some(position) = self.written_position(index[self.dim]) {
+                index[self.dim] = position - self.offset;
+                let source_offset = index_to_offset(&index, &source_strides) * elem;
+                let value = &source[source_offset..source_offset + elem];
+                cache[cache_offset..cache_offset + elem].copy_from_slice(value);
+                chunk.copy_from_slice(value);
+            } else {
+                chunk.copy_from_slice(&cache[cache_offset..cache_offset + elem]);
+            }
+        }
+        write_through(&self.cache, &cache, alignment);
+        Ok(CPUBuffer::from_bytes(&result, elem))
+    }
+}
+
+/// Quantizes the source into a Q8_0 cache, one block of [QK8_0] values at a time.
+/// Blocks already cached are copied as is.
+fn quantized_cache(op: &Cache, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+    let dt = dst.dt();
+    let scale_size = match dt {
+        DType::Q8_0F(_) => 4,
+        DType::Q8_0H(_) => 2,
+        _ => return Err(InvariantError::UnsupportedDType(dt).into()),
+    };
+    let scale_bytes = |d: f32| match dt {
+        DType::Q8_0F(_) => d.to_le_bytes().to_vec(),
+        _ => f16::from_f32(d).to_le_bytes().to_vec(),
+    };
+    let source = read_f32(&op.source)?;
+
+    let cache_strides = contiguous_strides(op.cache.shape());
+    let source_strides = contiguous_strides(op.source.shape());
+    let dst_strides = contiguous_strides(dst.shape());
+    let dst_numel = dst.shape().numel();
+    let cache_d = dt.segments(op.cache.shape().numel())[1].offset as usize;
+    let dst_d = dt.segments(dst_numel)[1].offset as usize;
+
+    let (mut cache, alignment) = read_for_write(&op.cache)?;
+
+    let mut result = vec![0u8; dst.num_bytes()];
+    for block in 0..dst_numel / QK8_0 {
+        let dst_offset = block * QK8_0;
+        let mut index = offset_to_index(dst_offset, &dst_strides);
+        let cache_offset = index_to_offset(&index, &cache_strides);
+        let cache_scale = cache_d + cache_offset / QK8_0 * scale_size;
+        let dst_scale = dst_d + block * scale_size;
+
+        let (qs, d) = if let Some(position) = op.written_position(index[op.dim]) {
+            index[op.dim] = position - op.offset;
+            let source_offset = index_to_offset(&index, &source_strides);
+            let values = &source[source_offset..source_offset + QK8_0];
+            let amax = values.iter().fold(0f32, |m, v| m.max(v.abs()));
+            let d = amax / 127.;
+            let id = if d > 0. { 1. / d } else { 0. };
+            let qs = values
+                .iter()
+                .map(|v| (v * id).round() as i8 as u8)
+                .collect::<Vec<_>>();
+            let d = scale_bytes(d);
+            cache[cache_offset..cache_offset + QK8_0].copy_from_slice(&qs);
+            cache[cache_scale..cache_scale + scale_size].copy_from_slice(&d);
+            (qs, d)
+        } else {
+            let qs = cache[cache_offset..cache_offset + QK8_0].to_vec();
+            let d = cache[cache_scale..cache_scale + scale_size].to_vec();
+            (qs, d)
+        };
+        result[dst_offset..dst_offset + QK8_0].copy_from_slice(&qs);
+        result[dst_scale..dst_scale + scale_size].copy_from_slice(&d);
+    }
+    write_through(&op.cache, &cache, alignment);
+    Ok(CPUBuffer::from_bytes(&result, 4))
+}
+
+impl CPUOperation for PagedCache {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let elem = element_size(dst)?;
+        let source_buffer = cpu_buffer(&self.source)?;
+        let table_buffer = cpu_buffer(&self.block_table)?;
+        let source = source_buffer.inner().as_bytes();
+        let table = bytemuck::cast_slice::<u8, u32>(table_buffer.inner().as_bytes());
+
+        let pool_strides = contiguous_strides(self.pool.shape());
+        let source_strides = contiguous_strides(self.source.shape());
+        let dst_strides = contiguous_strides(dst.shape());
+        let (block_size, max_blocks) = (self.block_size(), self.block_table.shape()[1]);
+
+        // As with [Cache], the source is written into the pool itself.
+        let (mut pool, alignment) = read_for_write(&self.pool)?;
+
+        let mut result = vec![0u8; dst.shape().numel() * elem];
+        for (dst_offset, chunk) in result.chunks_exact_mut(elem).enumerate() {
+            let mut index = offset_to_index(dst_offset, &dst_strides);
+            let position = index[self.dim];
+            let mut pool_index = index.clone();
+            pool_index[0] = table[index[0] * max_blocks + position / block_size] as usize;
+            pool_index[self.dim] = position % block_size;
+            let pool_offset = index_to_offset(&pool_index, &pool_strides) * elem;
+            if position < self.offset {
+                chunk.copy_from_slice(&pool[pool_offset..pool_offset + elem]);
+            } else {
+                index[self.dim] -= self.offset;
+                let source_offset = index_to_offset(&index, &source_strides) * elem;
+                let value = &source[source_offset..source_offset + elem];
+                pool[pool_offset..pool_offset + elem].copy_from_slice(value);
+                chunk.copy_from_slice(value);
+            }
+        }
+        write_through(&self.pool, &pool, alignment);
+        Ok(CPUBuffer::from_bytes(&result, elem))
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    cpu::{from_f32, read_f32, CPUOperation},
+    CPUBuffer, DType, OperationError, Sample, SampleStrategy, Tensor,
+};
+
+impl CPUOperation for Sample {
+    fn apply_cpu(&self, _: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let N = self.logits.shape()[self.logits.rank() - 1];
+        let logits = read_f32(&self.logits)?;
+
+        let result = logits
+            .chunks_exact(N)
+            .enumerate()
+            .map(|(row, x)| {
+                let u = Sample::uniform(self.seed, row as u32);
+                match self.strategy {
+                    SampleStrategy::Greedy => argmax(x),
+                    SampleStrategy::Temperature(t) => self.sample_temperature(x, t, u),
+                    SampleStrategy::TopK { temperature, .. } => self.sample_topk(x, temperature, u),
+                }
+            })
+            .map(|id| id as f32)
+            .collect::<Vec<_>>();
+        from_f32(&result, DType::I32)
+    }
+}
+
+/// Ties resolve to the lowest index.
+fn argmax(x: &[f32]) -> usize {
+    (1..x.len()).fold(0, |best, i| if x[i] > x[best] { i } else { best })
+}
+
+impl Sample {
+    /// Mirrors the kernel, which walks the CDF in invocation order.
+    /// Invocation `t` owns the elements `t, t + BLOCK_SIZE, ...`.
+    fn sample_temperature(&self, x: &[f32], temperature: f32, u: f32) -> usize {
+        let max = x.iter().fold(f32::NEG_INFINITY, |acc, &v| acc.max(v));
+        let p = |i: usize| ((x[i] - max) / temperature).exp();
+        let owned = |t: usize| (t..x.len()).step_by(Self::BLOCK_SIZE);
+
+        let partial = (0..Self::BLOCK_SIZE)
+            .map(|t| owned(t).map(p).sum::<f32>())
+            .collect::<Vec<_>>();
+        let target = u * partial.iter().sum::<f32>();
+
+        let (mut acc, mut chosen, mut residual) = (0., 0, target);
+        for (t, &s) in partial.iter().enumerate() {
+            if s > 0. {
+                chosen = t;
+                residual = target - acc;
+            }
+            acc += s;
+            if acc > target {
+                break;
+            }
+        }
+
+        let mut acc = 0.;
+        let mut selected = chosen;
+        for i in owned(chosen) {
+            acc += p(i);
+            selected = i;
+            if acc > residual {
+                break;
+            }
+        }
+        selected
+    }
+
+    fn sample_topk(&self, x: &[f32], temperature: f32, u: f32) -> usize {
+        //Stable sort, so equal logits are ordered by index as in the kernel.
+        let mut order = (0..x.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| x[b].total_cmp(&x[a]));
+        order.truncate(self.k());
+
+        let p = |i: usize| ((x[i] - x[order[0]]) / temperature).exp();
+        let target = u * order.iter().map(|&i| p(i)).sum::<f32>();
+        let mut acc = 0.;
+        for &i in &order {
+            acc += p(i);
+            if acc > target {
+                return i;
+            }
+        }
+        order[order.len() - 1]
+    }
+}
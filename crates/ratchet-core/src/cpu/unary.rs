@@ -0,0 +1,108 @@
+use crate::{
+    cpu::{from_f32, read_f32, CPUOperation},
+    Binary, BinaryOp, CPUBuffer, Cast, DType, FusedElementwise, FusedStep, OperationError, Tensor,
+    Unary, UnaryOp,
+};
+
+impl BinaryOp {
+    fn apply_scalar(&self, a: f32, b: f32) -> f32 {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+        }
+    }
+}
+
+impl CPUOperation for Binary {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let (lhs, rhs) = (read_f32(&self.lhs)?, read_f32(&self.rhs)?);
+        let result = lhs
+            .iter()
+            .zip(rhs.iter())
+            .map(|(&a, &b)| self.op.apply_scalar(a, b))
+            .collect::<Vec<_>>();
+        from_f32(&result, dst.dt())
+    }
+}
+
+impl UnaryOp {
+    fn apply_scalar(&self, x: f32) -> f32 {
+        let safe_tanh = |x: f32| if x.abs() >= 10. { x.signum() } else { x.tanh() };
+        let sigmoid = |x: f32| {
+            if x >= 0. {
+                1. / (1. + (-x).exp())
+            } else {
+                x.exp() / (1. + x.exp())
+            }
+        };
+        match self {
+            UnaryOp::Gelu => {
+                let inner = x * (Unary::SCALED_SQRT_2_OVER_PI * x * x + Unary::SQRT_2_OVER_PI);
+                x * (0.5 + 0.5 * safe_tanh(inner))
+            }
+            UnaryOp::Tanh => safe_tanh(x),
+            UnaryOp::Exp => x.exp(),
+            UnaryOp::Log => x.ln(),
+            UnaryOp::Sin => x.sin(),
+            UnaryOp::Cos => x.cos(),
+            UnaryOp::Abs => x.abs(),
+            UnaryOp::Sqrt => x.sqrt(),
+            UnaryOp::Relu => x.max(0.),
+            UnaryOp::Floor => x.floor(),
+            UnaryOp::Ceil => x.ceil(),
+            UnaryOp::Neg => -x,
+            UnaryOp::Silu => x * sigmoid(x),
+            UnaryOp::Sigmoid => sigmoid(x),
+        }
+    }
+}
+
+impl CPUOperation for Unary {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let result = read_f32(&self.input)?
+            .into_iter()
+            .map(|x| self.op.apply_scalar(x))
+            .collect::<Vec<_>>();
+        from_f32(&result, dst.dt())
+    }
+}
+
+impl CPUOperation for Cast {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        from_f32(&read_f32(&self.input)?, self.dst_dt)
+    }
+}
+
+impl CPUOperation for FusedElementwise {
+    fn apply_cpu(&self, dst: &Tensor) -> Result<CPUBuffer, OperationError> {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(read_f32)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        //Values are carried in f32, casts round them to the precision of their dtype
+        let round = |x: f32, dt: DType| match dt {
+            DType::F16 => half::f16::from_f32(x).to_f32(),
+            _ => x,
+        };
+
+        let mut values = vec![0f32; self.nodes.len()];
+        let result = (0..dst.shape().numel())
+            .map(|i| {
+                for (n, node) in self.nodes.iter().enumerate() {
+                    values[n] = match node.step {
+                        FusedStep::Input(x) => inputs[x][i],
+                        FusedStep::Unary(ref op, a) => op.apply_scalar(values[a]),
+                        FusedStep::Binary(ref op, a, b) => op.apply_scalar(values[a], values[b]),
+                        FusedStep::Cast(a) => round(values[a], node.dt),
+                    };
+                }
+                values[self.nodes.len() - 1]
+            })
+            .collect::<Vec<_>>();
+        from_f32(&result, dst.dt())
+    }
+}
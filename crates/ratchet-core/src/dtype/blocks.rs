@@ -94,3 +94,49 @@ impl Segments for Q8_0H {
         self.0.segments(numel)
     }
 }
+
+/// Signed 4 bit values, packed 8 to a `u32` in order, with the lowest nibble first.
+///
+/// Values are stored signed, rather than offset by 8 as in GGUF, so they can be unpacked with
+/// `extractBits`.
+pub struct BlockQ4_0<T> {
+    pub(crate) d: T,
+    pub(crate) qs: [u8; QK4_0 / 2],
+}
+
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, new)]
+pub struct Q4_0<T: std::fmt::Debug>(std::marker::PhantomData<T>);
+
+impl<T> Segments for Q4_0<T>
+where
+    T: std::fmt::Debug,
+{
+    fn segments(&self, numel: usize) -> RVec<BufferSegment> {
+        let mut offset = 0;
+        let qs_nbytes: u64 = (numel / 2).align_for_offset() as u64;
+        let qs_segment = BufferSegment::new(offset, qs_nbytes);
+        let d_nbytes: u64 = ((numel / QK4_0) * std::mem::size_of::<T>()).align_for_offset() as u64;
+        offset += qs_nbytes;
+        let d_segment = BufferSegment::new(offset, d_nbytes);
+        rvec![qs_segment, d_segment]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Q4_0F(Q4_0<f32>);
+
+impl Segments for Q4_0F {
+    fn segments(&self, numel: usize) -> RVec<BufferSegment> {
+        self.0.segments(numel)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Q4_0H(Q4_0<f16>);
+
+impl Segments for Q4_0H {
+    fn segments(&self, numel: usize) -> RVec<BufferSegment> {
+        self.0.segments(numel)
+    }
+}
@@ -17,6 +17,8 @@ pub enum DType {
     U32,
     Q8_0H(Q8_0H), //Equivalent to GGUF Q8_0, with f16
     Q8_0F(Q8_0F), //Equivalent to GGUF Q8_0, with f32
+    Q4_0H(Q4_0H), //Signed 4 bit blocks of 32, with f16
+    Q4_0F(Q4_0F), //Signed 4 bit blocks of 32, with f32
 }
 
 impl std::fmt::Display for DType {
@@ -29,23 +31,50 @@ impl std::fmt::Display for DType {
             DType::U32 => write!(f, "U32"),
             DType::Q8_0H(_) => write!(f, "Q8_0H"),
             DType::Q8_0F(_) => write!(f, "Q8_0F"),
+            DType::Q4_0H(_) => write!(f, "Q4_0H"),
+            DType::Q4_0F(_) => write!(f, "Q4_0F"),
         }
     }
 }
 
+impl std::str::FromStr for DType {
+    type Err = anyhow::Error;
+
+    /// Parses the name of a dtype, as written by [std::fmt::Display].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "F16" => DType::F16,
+            "BF16" => DType::BF16,
+            "F32" => DType::F32,
+            "I32" => DType::I32,
+            "U32" => DType::U32,
+            "Q8_0H" => DType::Q8_0H(Q8_0H::default()),
+            "Q8_0F" => DType::Q8_0F(Q8_0F::default()),
+            "Q4_0H" => DType::Q4_0H(Q4_0H::default()),
+            "Q4_0F" => DType::Q4_0F(Q4_0F::default()),
+            _ => anyhow::bail!("Unknown dtype: {}", s),
+        })
+    }
+}
+
 impl DType {
     pub fn to_u32(self) -> u32 {
         match self {
             DType::F32 => 0,
             DType::F16 => 1,
+            DType::BF16 => 30,
             _ => unimplemented!(),
         }
     }
 
+    /// The WGSL type used to bind buffers of this dtype.
+    ///
+    /// WGSL has no bfloat16 type, so BF16 is bound as pairs packed into a `u32`.
     pub fn as_wgsl(self) -> &'static str {
         match self {
             DType::F32 => "f32",
             DType::F16 => "f16",
+            DType::BF16 => "u32",
             DType::I32 => "i32",
             DType::U32 => "u32",
             _ => unimplemented!(),
@@ -62,11 +91,16 @@ impl DType {
             DType::U32 => 4,
             DType::Q8_0H(_) => std::mem::size_of::<BlockQ8_0<f16>>(),
             DType::Q8_0F(_) => std::mem::size_of::<BlockQ8_0<f32>>(),
+            DType::Q4_0H(_) => std::mem::size_of::<BlockQ4_0<f16>>(),
+            DType::Q4_0F(_) => std::mem::size_of::<BlockQ4_0<f32>>(),
         }
     }
 
     pub fn is_quantized(self) -> bool {
-        matches!(self, DType::Q8_0H(_) | DType::Q8_0F(_))
+        matches!(
+            self,
+            DType::Q8_0H(_) | DType::Q8_0F(_) | DType::Q4_0H(_) | DType::Q4_0F(_)
+        )
     }
 
     pub fn is_float(self) -> bool {
@@ -76,8 +110,8 @@ impl DType {
     /// Returns the activation dtype for the given quantized dtype.
     pub fn activation_dt(&self) -> DType {
         match self {
-            DType::Q8_0H(_) => DType::F16,
-            DType::Q8_0F(_) => DType::F32,
+            DType::Q8_0H(_) | DType::Q4_0H(_) => DType::F16,
+            DType::Q8_0F(_) | DType::Q4_0F(_) => DType::F32,
             _ => *self,
         }
     }
@@ -86,6 +120,8 @@ impl DType {
         match self {
             DType::Q8_0F(q) => q.segments(numel),
             DType::Q8_0H(q) => q.segments(numel),
+            DType::Q4_0F(q) => q.segments(numel),
+            DType::Q4_0H(q) => q.segments(numel),
             _ => {
                 let mut total_bytes = numel * self.size_of();
                 total_bytes = max(total_bytes, MIN_STORAGE_BUFFER_SIZE).align_for_copy();
@@ -99,37 +135,13 @@ impl DType {
         match dtype {
             "torch.float32" | "float32" => DType::F32,
             "torch.float16" | "float16" => DType::F16,
+            "torch.bfloat16" | "bfloat16" => DType::BF16,
             "torch.int32" | "int32" => DType::I32,
             _ => unimplemented!("Unsupported torch dtype: {}", dtype),
         }
     }
 }
 
-#[cfg(feature = "testing")]
-impl DType {
-    fn handle_type_str(ts: npyz::TypeStr) -> DType {
-        match ts.endianness() {
-            npyz::Endianness::Little => match (ts.type_char(), ts.size_field()) {
-                (npyz::TypeChar::Float, 4) => DType::F32,
-                (npyz::TypeChar::Int, 4) => DType::I32,
-                (npyz::TypeChar::Uint, 4) => DType::U32,
-                (t, s) => unimplemented!("{} {}", t, s),
-            },
-            _ => unimplemented!(),
-        }
-    }
-}
-
-#[cfg(feature = "testing")]
-impl From<npyz::DType> for DType {
-    fn from(dtype: npyz::DType) -> Self {
-        match dtype {
-            npyz::DType::Plain(ts) => Self::handle_type_str(ts),
-            _ => unimplemented!(),
-        }
-    }
-}
-
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct BufferSegment {
     pub offset: BufferAddress,
@@ -211,6 +223,7 @@ impl DType {
         match self {
             DType::F32 => "torch.float32",
             DType::F16 => "torch.float16",
+            DType::BF16 => "torch.bfloat16",
             DType::I32 => "torch.int32",
             _ => unimplemented!(),
         }
@@ -222,6 +235,7 @@ impl From<DType> for NpyDType {
         match val {
             DType::F32 => NpyDType::Plain("<f4".parse::<TypeStr>().unwrap()),
             DType::F16 => NpyDType::Plain("<f2".parse::<TypeStr>().unwrap()),
+            DType::BF16 => NpyDType::Plain("|V2".parse::<TypeStr>().unwrap()),
             DType::I32 => NpyDType::Plain("<i4".parse::<TypeStr>().unwrap()),
             DType::U32 => NpyDType::Plain("<u4".parse::<TypeStr>().unwrap()),
             _ => unimplemented!(),
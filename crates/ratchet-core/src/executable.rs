@@ -1,6 +1,7 @@
 use crate::gpu::{GpuUniform, PoolError, StaticResourcePoolAccessor, WgpuDevice};
-use crate::CompiledOp;
+use crate::{CompiledOp, Tensor, TensorError};
 use derive_new::new;
+use std::borrow::Cow;
 use wgpu::SubmissionIndex;
 
 /// # Executable
@@ -50,6 +51,7 @@ impl Executable {
                 cpass.dispatch_workgroups(x_count, y_count, z_count);
             }
         }
+        device.record_dispatches(self.steps.len());
         Ok(device.queue().submit(Some(encoder.finish())))
     }
 
@@ -87,6 +89,7 @@ impl Executable {
                 cpass.dispatch_workgroups(x_count, y_count, z_count);
             }
         }
+        device.record_dispatches(self.steps.len());
 
         profiler.resolve(&mut encoder);
         let index = device.queue().submit(Some(encoder.finish()));
@@ -94,3 +97,132 @@ impl Executable {
         Ok(index)
     }
 }
+
+/// # CapturedGraph
+///
+/// An [Executable] recorded by [Tensor::capture], along with the tensors it reads & writes.
+///
+/// The executable holds its pipelines, bind groups and uniform buffer, keeping every buffer
+/// of the graph alive. Replaying it skips computing the execution order, allocating, compiling
+/// and uploading metadata, which `resolve` performs on every call.
+///
+/// Shapes and operation parameters are fixed at capture time, only the contents of the inputs
+/// passed to [Tensor::capture] may change between replays.
+#[derive(new)]
+pub struct CapturedGraph {
+    executable: Executable,
+    inputs: Vec<Tensor>,
+    output: Tensor,
+    device: WgpuDevice,
+}
+
+impl CapturedGraph {
+    /// The tensor that was captured. Its contents are overwritten by each replay.
+    pub fn output(&self) -> &Tensor {
+        &self.output
+    }
+
+    /// Writes each `(input, data)` pair into the captured graph, then dispatches it again.
+    ///
+    /// `data` must be a resolved CPU tensor with the same shape & dtype as `input`.
+    pub fn replay(&self, updates: &[(&Tensor, &Tensor)]) -> Result<Tensor, TensorError> {
+        for (input, data) in updates {
+            self.write_input(input, data)?;
+        }
+        let index = self.executable.dispatch_operations(&self.device)?;
+        self.device
+            .poll(wgpu::MaintainBase::WaitForSubmissionIndex(index));
+        Ok(self.output.clone())
+    }
+
+    fn write_input(&self, input: &Tensor, data: &Tensor) -> Result<(), TensorError> {
+        if !self.inputs.iter().any(|i| i.id() == input.id()) {
+            return Err(TensorError::NotCaptured(input.id()));
+        }
+        if input.shape() != data.shape() || input.dt() != data.dt() {
+            return Err(TensorError::InvalidCaptureUpdate {
+                id: input.id(),
+                shape: input.shape().clone(),
+                dt: input.dt(),
+            });
+        }
+
+        let data_guard = data.storage();
+        let cpu_buf = data_guard
+            .as_ref()
+            .ok_or(TensorError::NotResolved)?
+            .try_cpu()?;
+        let bytes = &cpu_buf.inner().as_bytes()[..data.num_bytes()];
+
+        //Writes must be a multiple of 4 bytes, buffers are rounded up to match
+        let align = wgpu::COPY_BUFFER_ALIGNMENT as usize;
+        let bytes = if bytes.len() % align == 0 {
+            Cow::Borrowed(bytes)
+        } else {
+            let mut padded = bytes.to_vec();
+            padded.resize(bytes.len().next_multiple_of(align), 0);
+            Cow::Owned(padded)
+        };
+
+        let input_guard = input.storage();
+        let gpu_buf = input_guard
+            .as_ref()
+            .ok_or(TensorError::NoStorage(input.id()))?
+            .try_gpu()?;
+        self.device
+            .queue()
+            .write_buffer(&gpu_buf.inner, gpu_buf.offset, bytes.as_ref());
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "pyo3"))]
+mod tests {
+    use crate::{shape, Device, DeviceRequest, Tensor};
+
+    thread_local! {
+        static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
+    }
+
+    #[test]
+    fn capture_and_replay() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let a = Tensor::randn::<f32>(shape![32, 64], Device::CPU);
+        let b = Tensor::randn::<f32>(shape![64, 32], Device::CPU);
+
+        let x = a.to(&device)?;
+        let graph = x
+            .clone()
+            .matmul(b.to(&device)?, false, false)?
+            .gelu()?
+            .capture(&[&x])?;
+
+        let ground = a.matmul(b.clone(), false, false)?.gelu()?.resolve()?;
+        let captured = graph.output().to(&Device::CPU)?;
+        ground.all_close(&captured, 1e-3, 1e-3)?;
+
+        let c = Tensor::randn::<f32>(shape![32, 64], Device::CPU);
+        let replayed = graph.replay(&[(&x, &c)])?.to(&Device::CPU)?;
+        let ground = c.matmul(b, false, false)?.gelu()?.resolve()?;
+        ground.all_close(&replayed, 1e-3, 1e-3)?;
+        Ok(())
+    }
+
+    #[test]
+    fn capture_folds_constants() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let a = Tensor::randn::<f32>(shape![32, 64], Device::CPU);
+        let w = Tensor::randn::<f32>(shape![32, 64], Device::CPU);
+
+        let x = a.to(&device)?;
+        let w_abs = w.to(&device)?.abs()?;
+        let graph = x.clone().add(w_abs.clone())?.capture(&[&x])?;
+        assert!(w_abs.resolved());
+
+        let c = Tensor::randn::<f32>(shape![32, 64], Device::CPU);
+        let replayed = graph.replay(&[(&x, &c)])?.to(&Device::CPU)?;
+        let ground = c.add(w.abs()?)?.resolve()?;
+        ground.all_close(&replayed, 1e-3, 1e-3)?;
+        Ok(())
+    }
+}
@@ -1,13 +1,13 @@
-use super::TensorUsageRecord;
+use super::{MemoryPlan, TensorUsageRecord};
 use crate::{
     gpu::{
-        BufferDescriptor, BufferPool, BufferUsagesExt, CpuUniform, GpuBufferHandle,
+        Align, BufferDescriptor, BufferPool, BufferUsagesExt, CpuUniform, GpuBufferHandle,
         PooledGPUBuffer, TensorUsageRecords, WgpuDevice, UNIFORM_ALIGN,
     },
-    DeviceError, Tensor, TensorId,
+    DeviceError, Substitutions, Tensor, TensorId,
 };
 use parking_lot::RwLock;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use std::{borrow::Cow, sync::Arc};
 use wgpu::BufferUsages;
 
@@ -17,8 +17,24 @@ pub enum AllocatorError {
     BufferNotFound,
 }
 
+/// A region of a [PooledGPUBuffer], in bytes.
+#[derive(Debug, Clone, derive_new::new)]
+pub struct BufferRange {
+    pub buffer: PooledGPUBuffer,
+    pub offset: wgpu::BufferAddress,
+    pub size: wgpu::BufferAddress,
+}
+
+impl BufferRange {
+    pub fn whole(buffer: PooledGPUBuffer) -> Self {
+        let size = buffer.size();
+        Self::new(buffer, 0, size)
+    }
+}
+
 pub struct BufferAllocator {
     pool: RwLock<BufferPool>,
+    last_plan: RwLock<Option<MemoryPlan>>,
 }
 
 impl Default for BufferAllocator {
@@ -31,6 +47,7 @@ impl BufferAllocator {
     pub fn new() -> Self {
         Self {
             pool: BufferPool::new().into(),
+            last_plan: RwLock::new(None),
         }
     }
 
@@ -38,6 +55,23 @@ impl BufferAllocator {
         self.pool.write().begin_pass(pass_index);
     }
 
+    /// The memory plan of the most recent graph allocation.
+    pub fn last_plan(&self) -> Option<MemoryPlan> {
+        self.last_plan.read().clone()
+    }
+
+    pub fn num_buffers(&self) -> usize {
+        self.pool.read().num_resources()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.pool.read().total_gpu_size_in_bytes()
+    }
+
+    pub fn total_created(&self) -> u64 {
+        self.pool.read().total_created()
+    }
+
     pub fn get(&self, handle: GpuBufferHandle) -> PooledGPUBuffer {
         self.pool.read().get(handle).unwrap()
     }
@@ -138,8 +172,11 @@ impl BufferAllocator {
     /// 2. If the operation has an inplace kernel available
     /// 3. If our PARENT (i.e the buffer we are about to apply an operation to) has multiple consumers
     ///    if it has multiple consumers, you can't inplace
-    fn determine_tensor_source(source: &Tensor) -> &Tensor {
-        let mut true_source = source;
+    ///
+    /// Tensors are resolved through `subs` as we go, as a substitute may not support inplace
+    /// where the original did.
+    fn determine_tensor_source<'a>(source: &'a Tensor, subs: &'a Substitutions) -> &'a Tensor {
+        let mut true_source = subs.resolve(source);
         loop {
             if true_source.op().srcs().is_empty() || !true_source.op().supports_inplace() {
                 //If no sources, we are at the root
@@ -155,7 +192,7 @@ impl BufferAllocator {
                 break;
             }
 
-            true_source = to_modify;
+            true_source = subs.resolve(to_modify);
         }
         log::debug!("Traversed to true source: {:?}", true_source.id());
         true_source
@@ -167,6 +204,7 @@ impl BufferAllocator {
     //3. When we encounter the producer of a tensor, we stop recording the interval.
     fn calculate_usage_records(
         execution_order: &[&Tensor],
+        subs: &Substitutions,
     ) -> FxHashMap<TensorId, TensorUsageRecord> {
         let mut records =
             FxHashMap::with_capacity_and_hasher(execution_order.len(), Default::default());
@@ -179,7 +217,7 @@ impl BufferAllocator {
                 if source.resolved() {
                     continue;
                 }
-                let true_source = Self::determine_tensor_source(source);
+                let true_source = Self::determine_tensor_source(source, subs);
                 records
                     .entry(true_source.id())
                     .or_insert_with(|| TensorUsageRecord {
@@ -204,49 +242,77 @@ impl BufferAllocator {
         records
     }
 
-    //https://arxiv.org/pdf/2001.03288.pdf + inplace support
-    //Takes in const assignments as inplace may be performed on constants
-    pub fn greedy_by_size(
+    /// Pairs of tensors bound in the same dispatch, one as the destination and the other as a
+    /// source. These cannot share an arena, see [MemoryPlan].
+    fn calculate_conflicts(
+        execution_order: &[&Tensor],
+        subs: &Substitutions,
+    ) -> FxHashMap<TensorId, FxHashSet<TensorId>> {
+        let mut conflicts: FxHashMap<TensorId, FxHashSet<TensorId>> = FxHashMap::default();
+        for t in execution_order.iter().filter(|t| !t.resolved()) {
+            let dst = Self::determine_tensor_source(t, subs).id();
+            for source in t.op().srcs() {
+                if source.resolved() {
+                    continue;
+                }
+                let src = Self::determine_tensor_source(source, subs).id();
+                if src != dst {
+                    conflicts.entry(dst).or_default().insert(src);
+                    conflicts.entry(src).or_default().insert(dst);
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// # Intermediate allocation
+    ///
+    /// Plans the offset of every intermediate within a few arenas using the full usage intervals,
+    /// then allocates a single buffer per arena. See [MemoryPlan].
+    ///
+    /// Takes in const assignments as inplace may be performed on constants
+    fn allocate_intermediates(
         &self,
         execution_order: &[&Tensor],
-        assignments: &mut FxHashMap<TensorId, PooledGPUBuffer>,
+        subs: &Substitutions,
+        assignments: &mut FxHashMap<TensorId, BufferRange>,
         device: &WgpuDevice,
     ) -> Result<(), DeviceError> {
-        let record_map = Self::calculate_usage_records(execution_order);
+        let record_map = Self::calculate_usage_records(execution_order, subs);
         let records = TensorUsageRecords::from(record_map);
-        let mut shared_objects: Vec<PooledGPUBuffer> = Vec::with_capacity(records.0.len());
+        let conflicts = Self::calculate_conflicts(execution_order, subs);
+        let limits = device.compute_limits();
+        let plan = MemoryPlan::greedy_by_size(
+            &records,
+            &conflicts,
+            limits.max_buffer_size as usize,
+            limits.min_storage_buffer_offset_alignment as usize,
+        );
+        log::debug!("Memory plan: {}", plan);
 
-        for record in records.0.iter() {
-            let record_producer = record.producer.unwrap();
-            let mut best_obj = None;
-            for obj in shared_objects.iter() {
-                let mut suitable = true;
-                for inner_r in records.0.iter() {
-                    let max_first = std::cmp::max(record_producer, inner_r.producer.unwrap());
-                    let min_last = std::cmp::min(record.last_consumer, inner_r.last_consumer);
-                    if max_first <= min_last && assignments.get(&inner_r.id.unwrap()) == Some(obj) {
-                        suitable = false;
-                        break;
-                    }
-                }
-                if suitable {
-                    best_obj = Some(obj);
-                }
-            }
-            if let Some(obj) = best_obj {
-                assignments.insert(record.id.unwrap(), (*obj).clone());
-            } else {
-                //let rounded_size = (record.size - 1).next_power_of_two();
-                let rounded_size = record.size;
-                let buf = self.create_buffer(
-                    &BufferDescriptor::new(rounded_size as _, BufferUsages::standard(), false),
+        let arenas = plan
+            .arena_sizes()
+            .iter()
+            .map(|&size| {
+                self.create_buffer(
+                    &BufferDescriptor::new(size as _, BufferUsages::standard(), false),
                     device,
                     false,
-                );
-                shared_objects.push(buf.clone());
-                assignments.insert(record.id.unwrap(), buf);
-            }
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for record in records.0.iter() {
+            let id = record.id.unwrap();
+            let slot = plan.slot(&id).ok_or(AllocatorError::BufferNotFound)?;
+            let range = BufferRange::new(
+                arenas[slot.arena].clone(),
+                slot.offset as _,
+                record.size.align_for_copy() as _,
+            );
+            assignments.insert(id, range);
         }
+        *self.last_plan.write() = Some(plan);
 
         //Loop through and add inplace assignments
         for t in execution_order.iter() {
@@ -254,10 +320,10 @@ impl BufferAllocator {
                 continue;
             }
             for source in t.op().srcs() {
-                let true_source = Self::determine_tensor_source(source);
+                let true_source = Self::determine_tensor_source(source, subs);
                 if true_source.id() != source.id() {
-                    if let Some(buf) = assignments.get(&true_source.id()) {
-                        assignments.insert(source.id(), buf.clone());
+                    if let Some(range) = assignments.get(&true_source.id()) {
+                        assignments.insert(source.id(), range.clone());
                     }
                 }
             }
@@ -272,19 +338,17 @@ impl BufferAllocator {
 
     /// # Graph memory allocation
     ///
-    /// Simple greedy algorithm
-    /// 1. Iterate over all tensors in reverse order (leaf -> root)
-    /// 2. For each tensor, loop through it's input values.
-    ///     a. Assign a buffer for each input value, if it is not already assigned
-    ///     b. If the input value is an inplace operation, traverse upwards until we find
-    ///        the "true" buffer source (i.e the first non-inplace operation).
-    /// 3. We release our **output** buffer, because the value is no longer needed,
-    ///    and earlier tensors can use it.
+    /// 1. Constants keep the buffers they were created with.
+    /// 2. Intermediates are planned into arenas, see [BufferAllocator::allocate_intermediates].
+    ///    If an intermediate is an inplace operation, it shares the range of its "true"
+    ///    buffer source (i.e the first non-inplace operation).
+    /// 3. The output tensor gets its own buffer, unless it is inplace on an intermediate.
     pub fn allocate_cfg(
         &self,
         execution_order: &[&Tensor],
+        subs: &Substitutions,
         device: &WgpuDevice,
-    ) -> Result<FxHashMap<TensorId, PooledGPUBuffer>, DeviceError> {
+    ) -> Result<FxHashMap<TensorId, BufferRange>, DeviceError> {
         let mut free = Vec::with_capacity(execution_order.len()); //TODO: switch to BTreeMap
         let mut assignments =
             FxHashMap::with_capacity_and_hasher(execution_order.len(), Default::default());
@@ -292,17 +356,16 @@ impl BufferAllocator {
         for t in execution_order.iter().rev().filter(|t| t.resolved()) {
             //Consts are immediately resolved
             let storage_guard = t.storage();
-            let pooled = storage_guard
+            let range = storage_guard
                 .as_ref()
                 .ok_or(AllocatorError::BufferNotFound)?
                 .try_gpu()?
-                .inner
-                .clone();
-            assignments.insert(t.id(), pooled);
+                .range();
+            assignments.insert(t.id(), range);
         }
 
         //Allocate intermediates
-        self.greedy_by_size(execution_order, &mut assignments, device)?;
+        self.allocate_intermediates(execution_order, subs, &mut assignments, device)?;
 
         //The output tensor is a special case.
         //We know we need an allocation for the output.
@@ -310,22 +373,20 @@ impl BufferAllocator {
         //It's also handy to treat output as different, as we can handle getting data back to CPU
         //more efficiently in future.
         let output = execution_order.last().unwrap();
-        let output_source = Self::determine_tensor_source(output);
-        let output_buffer = assignments
+        let output_source = Self::determine_tensor_source(output, subs);
+        let output_range = assignments
             .get(&output_source.id())
             .cloned()
             .unwrap_or_else(|| {
-                self.graph_allocate(
-                    BufferDescriptor::new(
-                        output_source.num_bytes() as _,
-                        BufferUsages::standard(),
-                        false,
-                    ),
+                let size = output_source.num_bytes().align_for_copy() as _;
+                let buffer = self.graph_allocate(
+                    BufferDescriptor::new(size, BufferUsages::standard(), false),
                     &mut free,
                     device,
-                )
+                );
+                BufferRange::new(buffer, 0, size)
             });
-        assignments.insert(output.id(), output_buffer);
+        assignments.insert(output.id(), output_range);
 
         log::debug!(
             "Total bytes allocated: {}kb",
@@ -0,0 +1,244 @@
+use super::{TensorUsageRecord, TensorUsageRecords};
+use crate::{gpu::Align, TensorId};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// The region of an arena assigned to a tensor, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArenaSlot {
+    pub arena: usize,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// # Memory Plan
+///
+/// Offline assignment of every intermediate tensor to a slot within a small number of arenas.
+///
+/// Planning uses greedy by size for offset calculation (https://arxiv.org/pdf/2001.03288.pdf):
+/// records are visited largest first, and each is placed in the smallest gap between the
+/// records it is alive alongside.
+///
+/// WebGPU doesn't allow a buffer to be bound as both read-only & read-write in a single
+/// dispatch, so a tensor is never placed in the same arena as a tensor it `conflicts` with.
+/// Typically this results in 2 arenas.
+///
+/// Every slot starts at a multiple of the device's `min_storage_buffer_offset_alignment`, so
+/// that it can be bound on its own.
+///
+/// Each tensor holds a range of its arena, so an intermediate retained after the graph has run,
+/// e.g one the caller keeps a clone of, keeps its whole arena alive until it is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPlan {
+    slots: FxHashMap<TensorId, ArenaSlot>,
+    arena_sizes: Vec<usize>,
+    naive_bytes: usize,
+}
+
+impl MemoryPlan {
+    pub fn greedy_by_size(
+        records: &TensorUsageRecords,
+        conflicts: &FxHashMap<TensorId, FxHashSet<TensorId>>,
+        max_arena_size: usize,
+        offset_alignment: usize,
+    ) -> Self {
+        let mut plan = Self::default();
+        let mut residents: Vec<Vec<(&TensorUsageRecord, ArenaSlot)>> = vec![];
+        let no_conflicts = FxHashSet::default();
+
+        for record in records.0.iter() {
+            let id = record.id.unwrap();
+            let size = record.size + record.size.calculate_alignment(offset_alignment);
+            let conflicting = conflicts.get(&id).unwrap_or(&no_conflicts);
+            plan.naive_bytes += record.size;
+
+            //(growth, arena, offset)
+            let mut best: Option<(usize, usize, usize)> = None;
+            for (arena, placed) in residents.iter().enumerate() {
+                if placed
+                    .iter()
+                    .any(|(r, _)| conflicting.contains(&r.id.unwrap()))
+                {
+                    continue;
+                }
+                let offset = Self::find_offset(record, size, placed);
+                if offset + size > max_arena_size {
+                    continue;
+                }
+                let growth = (offset + size).saturating_sub(plan.arena_sizes[arena]);
+                if best.map_or(true, |(g, _, _)| growth < g) {
+                    best = Some((growth, arena, offset));
+                }
+            }
+
+            let (arena, offset) = match best {
+                Some((_, arena, offset)) => (arena, offset),
+                None => {
+                    residents.push(vec![]);
+                    plan.arena_sizes.push(0);
+                    (residents.len() - 1, 0)
+                }
+            };
+            let slot = ArenaSlot {
+                arena,
+                offset,
+                size,
+            };
+            plan.arena_sizes[arena] = plan.arena_sizes[arena].max(offset + size);
+            residents[arena].push((record, slot));
+            plan.slots.insert(id, slot);
+        }
+        plan
+    }
+
+    /// Finds the best fitting gap for `record` between the records alive at the same time.
+    /// If no gap is large enough, the record is placed after them.
+    fn find_offset(
+        record: &TensorUsageRecord,
+        size: usize,
+        placed: &[(&TensorUsageRecord, ArenaSlot)],
+    ) -> usize {
+        let mut overlapping = placed
+            .iter()
+            .filter(|(r, _)| {
+                let max_first = std::cmp::max(record.producer, r.producer);
+                let min_last = std::cmp::min(record.last_consumer, r.last_consumer);
+                max_first.unwrap() <= min_last
+            })
+            .map(|(_, slot)| slot)
+            .collect::<Vec<_>>();
+        overlapping.sort_unstable_by_key(|slot| slot.offset);
+
+        let mut best_offset = None;
+        let mut smallest_gap = usize::MAX;
+        let mut prev_end = 0;
+        for slot in overlapping {
+            if slot.offset > prev_end {
+                let gap = slot.offset - prev_end;
+                if gap >= size && gap < smallest_gap {
+                    smallest_gap = gap;
+                    best_offset = Some(prev_end);
+                }
+            }
+            prev_end = prev_end.max(slot.offset + slot.size);
+        }
+        best_offset.unwrap_or(prev_end)
+    }
+
+    pub fn slot(&self, id: &TensorId) -> Option<&ArenaSlot> {
+        self.slots.get(id)
+    }
+
+    pub fn arena_sizes(&self) -> &[usize] {
+        &self.arena_sizes
+    }
+
+    pub fn num_arenas(&self) -> usize {
+        self.arena_sizes.len()
+    }
+
+    /// Total size of all arenas, i.e the peak memory required by the intermediates.
+    pub fn planned_bytes(&self) -> usize {
+        self.arena_sizes.iter().sum()
+    }
+
+    /// Memory required if every intermediate had its own buffer.
+    pub fn naive_bytes(&self) -> usize {
+        self.naive_bytes
+    }
+}
+
+impl std::fmt::Display for MemoryPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} tensors in {} arenas: planned {}kb, naive {}kb",
+            self.slots.len(),
+            self.num_arenas(),
+            self.planned_bytes() / 1024,
+            self.naive_bytes() / 1024,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(
+        id: TensorId,
+        producer: usize,
+        last_consumer: usize,
+        size: usize,
+    ) -> TensorUsageRecord {
+        TensorUsageRecord {
+            id: Some(id),
+            producer: Some(producer),
+            last_consumer,
+            #[cfg(debug_assertions)]
+            last_consumer_id: id,
+            size,
+        }
+    }
+
+    #[test]
+    fn reuses_offsets_of_dead_tensors() {
+        let (a, b, c, d) = (
+            TensorId::new(),
+            TensorId::new(),
+            TensorId::new(),
+            TensorId::new(),
+        );
+        //a -> b -> c -> d, each only alive until its consumer runs
+        let records = TensorUsageRecords::from(FxHashMap::from_iter([
+            (a, record(a, 0, 1, 4096)),
+            (b, record(b, 1, 2, 1024)),
+            (c, record(c, 2, 3, 4096)),
+            (d, record(d, 3, 4, 1024)),
+        ]));
+
+        let plan = MemoryPlan::greedy_by_size(&records, &FxHashMap::default(), usize::MAX, 256);
+        assert_eq!(plan.num_arenas(), 1);
+        assert_eq!(plan.naive_bytes(), 10240);
+        assert_eq!(plan.planned_bytes(), 5120);
+        assert_eq!(plan.slot(&a).unwrap().offset, plan.slot(&c).unwrap().offset);
+    }
+
+    #[test]
+    fn separates_conflicting_tensors() {
+        let (a, b) = (TensorId::new(), TensorId::new());
+        let records = TensorUsageRecords::from(FxHashMap::from_iter([
+            (a, record(a, 0, 1, 256)),
+            (b, record(b, 1, 2, 256)),
+        ]));
+        let conflicts = FxHashMap::from_iter([
+            (a, FxHashSet::from_iter([b])),
+            (b, FxHashSet::from_iter([a])),
+        ]);
+
+        let plan = MemoryPlan::greedy_by_size(&records, &conflicts, usize::MAX, 256);
+        assert_eq!(plan.num_arenas(), 2);
+        assert_ne!(plan.slot(&a).unwrap().arena, plan.slot(&b).unwrap().arena);
+
+        let plan = MemoryPlan::greedy_by_size(&records, &FxHashMap::default(), 256, 256);
+        assert_eq!(plan.num_arenas(), 2);
+    }
+
+    #[test]
+    fn aligns_offsets_to_the_device() {
+        let (a, b) = (TensorId::new(), TensorId::new());
+        let records = TensorUsageRecords::from(FxHashMap::from_iter([
+            (a, record(a, 0, 2, 100)),
+            (b, record(b, 1, 2, 100)),
+        ]));
+        for alignment in [64, 256] {
+            let plan =
+                MemoryPlan::greedy_by_size(&records, &FxHashMap::default(), usize::MAX, alignment);
+            let offsets = [plan.slot(&a).unwrap().offset, plan.slot(&b).unwrap().offset];
+            assert!(offsets.iter().all(|o| o % alignment == 0));
+            assert_eq!(
+                plan.planned_bytes(),
+                2 * 100usize.next_multiple_of(alignment)
+            );
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{gpu::*, DType, MetaOperation, Tensor, TensorId};
+use crate::{gpu::*, DType, MetaOperation, Substitutions, Tensor, TensorId};
 use rustc_hash::FxHashMap;
 use std::{borrow::Cow, sync::Arc};
 use wgpu::{Adapter, Limits};
@@ -23,6 +23,7 @@ pub struct WgpuDevice {
     pipeline_layout_pool: Arc<PipelineLayoutPool>,
     compute_pipeline_pool: Arc<ComputePipelinePool>,
     kernel_module_pool: Arc<KernelModulePool>,
+    stats: Arc<StatsTracker>,
     device_limits: DeviceLimits,
     device_features: DeviceFeatures,
     device: Arc<wgpu::Device>,
@@ -109,6 +110,7 @@ impl WgpuDevice {
             pipeline_layout_pool: Arc::new(PipelineLayoutPool::new()),
             kernel_module_pool: Arc::new(KernelModulePool::new()),
             compute_pipeline_pool: Arc::new(ComputePipelinePool::new()),
+            stats: Arc::new(StatsTracker::default()),
             device: Arc::new(device),
             device_limits: limits,
             device_features: features,
@@ -264,13 +266,47 @@ impl WgpuDevice {
     pub fn allocate_cfg(
         &self,
         execution_order: &[&Tensor],
+        subs: &Substitutions,
         device: &WgpuDevice,
-    ) -> Result<FxHashMap<TensorId, PooledGPUBuffer>, DeviceError> {
-        self.buffer_allocator.allocate_cfg(execution_order, device)
+    ) -> Result<FxHashMap<TensorId, BufferRange>, DeviceError> {
+        self.buffer_allocator
+            .allocate_cfg(execution_order, subs, device)
+    }
+
+    /// The memory plan of the most recently allocated graph, reporting the planned peak
+    /// bytes against the naive total.
+    pub fn last_memory_plan(&self) -> Option<MemoryPlan> {
+        self.buffer_allocator.last_plan()
     }
 
     pub fn begin_pass(&self) {
         self.buffer_allocator.begin_pass(0);
+        self.stats.record_pass();
+        self.stats.set_pass_start(self.stats());
+    }
+
+    pub(crate) fn record_dispatches(&self, count: usize) {
+        self.stats.record_dispatches(count);
+    }
+
+    /// A snapshot of the resources held by the device, and the work it has performed.
+    pub fn stats(&self) -> DeviceStats {
+        DeviceStats {
+            buffers: self.buffer_allocator.num_buffers(),
+            buffer_bytes: self.buffer_allocator.total_bytes(),
+            buffers_created: self.buffer_allocator.total_created(),
+            bind_groups: self.bind_group_pool.num_resources(),
+            bind_groups_created: self.bind_group_pool.total_created(),
+            compute_pipelines: self.compute_pipeline_pool.num_resources(),
+            kernel_modules: self.kernel_module_pool.num_resources(),
+            passes: self.stats.passes(),
+            dispatches: self.stats.dispatches(),
+        }
+    }
+
+    /// The change in [WgpuDevice::stats] since the start of the most recent pass.
+    pub fn pass_stats(&self) -> StatsDelta {
+        self.stats().delta(&self.stats.pass_start())
     }
 
     pub fn compute_features(&self) -> &DeviceFeatures {
@@ -285,22 +321,28 @@ impl WgpuDevice {
 #[derive(Clone)]
 pub struct DeviceLimits {
     pub max_bind_groups: u32,
+    pub max_buffer_size: u64,
     pub max_storage_buffer_binding_size: u32,
     pub max_compute_invocations_per_workgroup: u32,
+    pub min_storage_buffer_offset_alignment: u32,
 }
 
 impl From<wgpu::Limits> for DeviceLimits {
     fn from(limits: wgpu::Limits) -> Self {
         let wgpu::Limits {
             max_bind_groups,
+            max_buffer_size,
             max_storage_buffer_binding_size,
             max_compute_invocations_per_workgroup,
+            min_storage_buffer_offset_alignment,
             ..
         } = limits;
         DeviceLimits {
             max_bind_groups,
+            max_buffer_size,
             max_storage_buffer_binding_size,
             max_compute_invocations_per_workgroup,
+            min_storage_buffer_offset_alignment,
         }
     }
 }
@@ -2,6 +2,7 @@ mod align;
 mod buffer_allocator;
 mod device;
 mod pools;
+mod stats;
 mod uniform;
 mod wgsl;
 mod workload;
@@ -13,6 +14,7 @@ pub use align::*;
 pub use buffer_allocator::*;
 pub use device::*;
 pub use pools::*;
+pub use stats::*;
 pub use uniform::*;
 pub use wgsl::*;
 pub use workload::*;
@@ -151,4 +151,12 @@ impl BindGroupPool {
     pub fn begin_pass(&mut self, pass_index: u64) {
         self.inner.begin_pass(pass_index, |_res| {});
     }
+
+    pub fn num_resources(&self) -> usize {
+        self.inner.num_resources()
+    }
+
+    pub fn total_created(&self) -> u64 {
+        self.inner.total_resources_created()
+    }
 }
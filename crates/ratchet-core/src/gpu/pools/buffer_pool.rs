@@ -138,4 +138,8 @@ impl BufferPool {
     pub fn total_gpu_size_in_bytes(&self) -> u64 {
         self.inner.total_resource_size_in_bytes()
     }
+
+    pub fn total_created(&self) -> u64 {
+        self.inner.total_resources_created()
+    }
 }
@@ -58,6 +58,7 @@ pub(super) struct DynamicResourcePool<Handle: Key, Desc: Debug, Res> {
     state: RwLock<DynamicResourcePoolProtectedState<Handle, Desc, Res>>,
     current_pass_index: u64,
     total_resource_size_in_bytes: AtomicU64,
+    total_resources_created: AtomicU64,
 }
 
 /// We cannot #derive(Default) as that would require Handle/Desc/Res to implement Default too.
@@ -73,6 +74,7 @@ where
             }),
             current_pass_index: Default::default(),
             total_resource_size_in_bytes: AtomicU64::new(0),
+            total_resources_created: AtomicU64::new(0),
         }
     }
 }
@@ -109,6 +111,8 @@ where
             desc.resource_size_in_bytes(),
             std::sync::atomic::Ordering::Relaxed,
         );
+        self.total_resources_created
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
         let handle = state.all_resources.insert_with_key(|handle| {
             Arc::new(DynamicResource {
@@ -205,6 +209,12 @@ where
         self.total_resource_size_in_bytes
             .load(std::sync::atomic::Ordering::Relaxed)
     }
+
+    /// Number of resources created over the lifetime of the pool, excluding reuse.
+    pub fn total_resources_created(&self) -> u64 {
+        self.total_resources_created
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
 }
 
 #[cfg(test)]
@@ -336,6 +346,7 @@ mod tests {
     ) {
         let drop_counter_before = DROP_COUNTER.with(|c| c.get());
         let byte_count_before = pool.total_resource_size_in_bytes();
+        let created_before = pool.total_resources_created();
         for &desc in descs {
             // Previous loop iteration didn't drop Resources despite dropping a handle.
             assert_eq!(drop_counter_before, DROP_COUNTER.with(|c| c.get()));
@@ -352,6 +363,10 @@ mod tests {
         }
 
         if expect_allocation {
+            assert_eq!(
+                created_before + descs.len() as u64,
+                pool.total_resources_created()
+            );
             assert_eq!(
                 byte_count_before
                     + descs
@@ -361,6 +376,7 @@ mod tests {
                 pool.total_resource_size_in_bytes()
             );
         } else {
+            assert_eq!(created_before, pool.total_resources_created());
             assert_eq!(byte_count_before, pool.total_resource_size_in_bytes());
         }
     }
@@ -0,0 +1,124 @@
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// # Device Stats
+///
+/// A snapshot of the resources held by a [super::WgpuDevice], and the work it has performed.
+///
+/// Use [DeviceStats::delta] to compare two snapshots, or [super::WgpuDevice::pass_stats] for
+/// the change since the start of the most recent pass (i.e the last `resolve`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    /// Buffers held by the [super::BufferPool], including those awaiting reuse.
+    pub buffers: usize,
+    pub buffer_bytes: u64,
+    /// Buffers created since the device was requested.
+    pub buffers_created: u64,
+    /// Bind groups held by the [super::BindGroupPool].
+    pub bind_groups: usize,
+    pub bind_groups_created: u64,
+    /// Pipelines cached by the [super::ComputePipelinePool].
+    pub compute_pipelines: usize,
+    /// Shader modules cached by the [super::KernelModulePool].
+    pub kernel_modules: usize,
+    pub passes: u64,
+    pub dispatches: u64,
+}
+
+impl DeviceStats {
+    /// The change from `earlier` to `self`.
+    ///
+    /// Buffers & bind groups may be reclaimed by their pools, so their counts are signed.
+    pub fn delta(&self, earlier: &DeviceStats) -> StatsDelta {
+        let signed = |now: u64, then: u64| now as i64 - then as i64;
+        StatsDelta {
+            buffers: signed(self.buffers as _, earlier.buffers as _),
+            buffer_bytes: signed(self.buffer_bytes, earlier.buffer_bytes),
+            buffers_created: self.buffers_created - earlier.buffers_created,
+            bind_groups: signed(self.bind_groups as _, earlier.bind_groups as _),
+            bind_groups_created: self.bind_groups_created - earlier.bind_groups_created,
+            compute_pipelines: self.compute_pipelines - earlier.compute_pipelines,
+            kernel_modules: self.kernel_modules - earlier.kernel_modules,
+            passes: self.passes - earlier.passes,
+            dispatches: self.dispatches - earlier.dispatches,
+        }
+    }
+}
+
+/// The difference between two [DeviceStats].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsDelta {
+    pub buffers: i64,
+    pub buffer_bytes: i64,
+    pub buffers_created: u64,
+    pub bind_groups: i64,
+    pub bind_groups_created: u64,
+    pub compute_pipelines: usize,
+    pub kernel_modules: usize,
+    pub passes: u64,
+    pub dispatches: u64,
+}
+
+/// Counters that aren't owned by any pool, along with the snapshot taken at the start of the
+/// current pass.
+#[derive(Debug, Default)]
+pub(crate) struct StatsTracker {
+    passes: AtomicU64,
+    dispatches: AtomicU64,
+    pass_start: RwLock<DeviceStats>,
+}
+
+impl StatsTracker {
+    pub fn record_pass(&self) {
+        self.passes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dispatches(&self, count: usize) {
+        self.dispatches.fetch_add(count as _, Ordering::Relaxed);
+    }
+
+    pub fn passes(&self) -> u64 {
+        self.passes.load(Ordering::Relaxed)
+    }
+
+    pub fn dispatches(&self) -> u64 {
+        self.dispatches.load(Ordering::Relaxed)
+    }
+
+    pub fn set_pass_start(&self, stats: DeviceStats) {
+        *self.pass_start.write() = stats;
+    }
+
+    pub fn pass_start(&self) -> DeviceStats {
+        *self.pass_start.read()
+    }
+}
+
+#[cfg(all(test, feature = "pyo3"))]
+mod tests {
+    use crate::{shape, Device, DeviceRequest, Tensor};
+
+    thread_local! {
+        static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
+    }
+
+    #[test]
+    fn stats_track_passes() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let gpu = device.try_gpu()?.clone();
+        let a = Tensor::randn::<f32>(shape![32, 64], Device::CPU).to(&device)?;
+        let b = Tensor::randn::<f32>(shape![64, 32], Device::CPU).to(&device)?;
+
+        let before = gpu.stats();
+        let graph = a.matmul(b, false, false)?.capture(&[])?;
+        let pass = gpu.pass_stats();
+        assert_eq!(pass.dispatches, 1);
+        assert!(gpu.stats().compute_pipelines >= 1);
+        assert_eq!(gpu.stats().delta(&before).passes, 1);
+
+        graph.replay(&[])?;
+        assert_eq!(gpu.pass_stats().dispatches, 2);
+        assert_eq!(gpu.pass_stats().buffers_created, pass.buffers_created);
+        Ok(())
+    }
+}
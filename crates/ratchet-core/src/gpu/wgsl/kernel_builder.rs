@@ -2,8 +2,8 @@ use inline_wgsl::wgsl;
 use std::fmt::Write;
 
 use crate::{
-    Array, BindingMode, BindingType, DType, DeviceFeatures, KernelBinding, KernelSource,
-    OpMetadata, RVec, Scalar, Vec3, WgslPrimitive, WorkgroupSize,
+    Array, BindingMode, BindingType, DType, DeviceFeatures, KernelBinding, KernelElement,
+    KernelSource, OpMetadata, RVec, Scalar, Vec3, WgslPrimitive, WorkgroupSize,
 };
 
 #[derive(Debug)]
@@ -70,6 +70,12 @@ impl From<&str> for Ident {
     }
 }
 
+impl From<String> for Ident {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
 pub struct WgslKernelBuilder {
     pub bindings: RVec<KernelBinding>,
     pub workgroup_size: WorkgroupSize,
@@ -176,6 +182,18 @@ impl WgslKernelBuilder {
         self.register_binding(BindingType::Storage, mode, name, format!("{}", array));
     }
 
+    /// Registers a storage buffer whose element type is only known at runtime.
+    pub(crate) fn register_storage_dyn(
+        &mut self,
+        name: impl Into<Ident>,
+        mode: BindingMode,
+        dt: DType,
+        kernel_element: &KernelElement,
+    ) {
+        let bind_type = format!("array<{}>", kernel_element.as_wgsl(dt));
+        self.register_binding(BindingType::Storage, mode, name, bind_type);
+    }
+
     pub(crate) fn register_uniform(&mut self) {
         self.register_binding(
             BindingType::Uniform,
@@ -235,11 +253,42 @@ impl WgslKernelBuilder {
                     }
                 });
             }
+            DType::Q4_0H(_) | DType::Q4_0F(_) => {
+                //Unpacks the 4 nibbles in the low half of `value`, extending their sign
+                let accessor = match dtype {
+                    DType::Q4_0H(_) => "vec4<f16>",
+                    _ => "vec4<f32>",
+                };
+                self.write_global(wgsl! {
+                    fn unpack(value: u32) -> 'accessor {
+                        let v = bitcast<i32>(value);
+                        return 'accessor(vec4<i32>(
+                            extractBits(v, 0u, 4u),
+                            extractBits(v, 4u, 4u),
+                            extractBits(v, 8u, 4u),
+                            extractBits(v, 12u, 4u)
+                        ));
+                    }
+                });
+            }
             _ => {}
         }
     }
 }
 
+/// Renders the expression unpacking the `index`th group of 4 values from the `packed` array,
+/// using the `unpack` function written by [WgslKernelBuilder::write_unpack].
+///
+/// A `u32` holds 4 values of Q8_0, and 8 values of Q4_0.
+pub(crate) fn render_unpack(dtype: DType, packed: &str, index: &str) -> String {
+    match dtype {
+        DType::Q4_0H(_) | DType::Q4_0F(_) => {
+            format!("unpack({packed}[({index}) / 2] >> (16u * u32(({index}) % 2)))")
+        }
+        _ => format!("unpack({packed}[{index}])"),
+    }
+}
+
 /// WGSL built-in variables.
 #[derive(Debug, Clone)]
 pub enum BuiltIn {
@@ -0,0 +1,775 @@
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    rvec, Attention, Binary, BinaryOp, Broadcast, Cache, Cast, CausalMask, Concat, Conv, Device,
+    GroupNorm, Im2Col, IndexSelect, IndexWrite, LazyOp, Matmul, Norm, NormOp, Operation,
+    OperationError, PaddingMask, PaddingSide, PagedCache, Permute, RVec, Reduce, ReduceOp, Reindex,
+    RoPE, RollingWindow, Sample, SampleStrategy, Shape, Slice, Softmax, StorageView, Tensor,
+    TensorId, Unary, UnaryOp, View,
+};
+
+/// Bumped whenever the layout of [GraphIR] changes.
+pub const IR_VERSION: u32 = 2;
+
+#[derive(thiserror::Error, Debug)]
+pub enum IrError {
+    #[error("Unsupported IR version {0}, expected {IR_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("Operation {0} cannot be exported")]
+    UnsupportedOperation(String),
+    #[error("Node {node} is missing input {input}")]
+    MissingInput { node: usize, input: usize },
+    #[error("Node {0} is referenced before it is defined")]
+    UnknownNode(usize),
+    #[error("Constant {0} was not provided")]
+    MissingConstant(usize),
+    #[error("Constant {0} is not on the target device")]
+    ConstantDevice(usize),
+    #[error(
+        "Node {node} requires shape {shape:?} and dtype {dt}, got {actual_shape:?} and {actual_dt}"
+    )]
+    NodeMismatch {
+        node: usize,
+        shape: Vec<usize>,
+        dt: String,
+        actual_shape: Vec<usize>,
+        actual_dt: String,
+    },
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    OperationError(#[from] OperationError),
+    #[error(transparent)]
+    UnknownError(#[from] anyhow::Error),
+}
+
+/// # Graph IR
+///
+/// A portable description of the lazy graph rooted at a [Tensor], see [Tensor::to_ir].
+///
+/// Nodes are listed in execution order, so every edge points from an earlier node to a later
+/// one. Tensors that were resolved at export time become `Const` nodes, referring to the
+/// constants returned alongside the IR by index. Their data is not part of the IR.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GraphIR {
+    pub version: u32,
+    pub nodes: Vec<IrNode>,
+    pub edges: Vec<IrEdge>,
+    /// The node the graph was exported from.
+    pub output: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IrNode {
+    pub id: usize,
+    pub op: IrOp,
+    pub shape: Vec<usize>,
+    pub dt: String,
+    pub strides: Vec<isize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IrEdge {
+    pub src: usize,
+    pub dst: usize,
+    /// Position of `src` among the sources of `dst`.
+    pub input: usize,
+}
+
+/// An operation and its parameters, serialized as `{"name": .., "params": {..}}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "name", content = "params")]
+pub enum IrOp {
+    Const {
+        index: usize,
+    },
+    Matmul {
+        trans_lhs: bool,
+        trans_rhs: bool,
+        trans_out: bool,
+    },
+    Binary {
+        op: BinaryOp,
+    },
+    Unary {
+        op: UnaryOp,
+    },
+    Permute {
+        dims: Vec<usize>,
+    },
+    Slice {
+        ranges: Vec<(usize, usize)>,
+    },
+    Broadcast {
+        shape: Vec<usize>,
+    },
+    Concat {
+        dim: usize,
+    },
+    LayerNorm {
+        eps: f32,
+    },
+    RMSNorm {
+        eps: f32,
+    },
+    GroupNorm {
+        num_groups: usize,
+        eps: f32,
+    },
+    Cast {
+        dt: String,
+    },
+    Reduce {
+        op: ReduceOp,
+        dim: usize,
+        keepdim: bool,
+    },
+    Sample {
+        strategy: SampleStrategy,
+        seed: u32,
+    },
+    RoPE {
+        dim: usize,
+        log2_base: f32,
+        offset: usize,
+    },
+    Softmax {
+        dim: usize,
+    },
+    Attention {
+        scale: f32,
+        causal: bool,
+    },
+    CausalMask {
+        q_len: usize,
+        kv_len: usize,
+        offset: usize,
+        window: Option<RollingWindow>,
+        dt: String,
+    },
+    PaddingMask {
+        q_len: usize,
+        kv_len: usize,
+        side: PaddingSide,
+        dt: String,
+    },
+    View {
+        shape: Vec<usize>,
+    },
+    Conv {
+        stride: usize,
+        padding: usize,
+        #[serde(default = "one")]
+        dilation: usize,
+        #[serde(default = "one")]
+        groups: usize,
+    },
+    Im2Col {
+        kernel_size: [usize; 2],
+        stride: [usize; 2],
+        padding: [usize; 2],
+        dilation: [usize; 2],
+        groups: usize,
+    },
+    IndexSelect {
+        dim: usize,
+    },
+    IndexWrite {
+        write_start: Vec<usize>,
+    },
+    Cache {
+        dim: usize,
+        offset: usize,
+        window: Option<RollingWindow>,
+    },
+    PagedCache {
+        dim: usize,
+        offset: usize,
+    },
+}
+
+fn one() -> usize {
+    1
+}
+
+impl IrOp {
+    pub(crate) fn from_lazy(op: &LazyOp) -> Result<Self, IrError> {
+        let ir_op = match op {
+            LazyOp::Matmul(m) => IrOp::Matmul {
+                trans_lhs: m.trans_lhs,
+                trans_rhs: m.trans_rhs,
+                trans_out: m.trans_out,
+            },
+            LazyOp::Binary(b) => IrOp::Binary { op: b.op.clone() },
+            LazyOp::Unary(u) => IrOp::Unary { op: u.op.clone() },
+            LazyOp::Reindex(Reindex::Permute(p)) => IrOp::Permute {
+                dims: p.dims.clone(),
+            },
+            LazyOp::Reindex(Reindex::Slice(s)) => IrOp::Slice {
+                ranges: s.indices().iter().map(|r| (r.start, r.end)).collect(),
+            },
+            LazyOp::Reindex(Reindex::Broadcast(b)) => IrOp::Broadcast {
+                shape: b.to().to_vec(),
+            },
+            LazyOp::Concat(c) => IrOp::Concat { dim: c.dim },
+            LazyOp::Norm(NormOp::LayerNorm(n)) => IrOp::LayerNorm { eps: n.eps },
+            LazyOp::Norm(NormOp::RMSNorm(n)) => IrOp::RMSNorm { eps: n.eps },
+            LazyOp::Norm(NormOp::GroupNorm(g)) => IrOp::GroupNorm {
+                num_groups: g.num_groups,
+                eps: g.norm.eps,
+            },
+            LazyOp::Cast(c) => IrOp::Cast {
+                dt: c.dst_dt.to_string(),
+            },
+            LazyOp::Reduce(r) => IrOp::Reduce {
+                op: r.op,
+                dim: r.dim,
+                keepdim: r.keepdim,
+            },
+            LazyOp::Sample(s) => IrOp::Sample {
+                strategy: s.strategy,
+                seed: s.seed,
+            },
+            LazyOp::RoPE(r) => IrOp::RoPE {
+                dim: r.dim,
+                log2_base: r.base,
+                offset: r.offset,
+            },
+            LazyOp::Softmax(s) => IrOp::Softmax { dim: s.dim },
+            LazyOp::Attention(a) => IrOp::Attention {
+                scale: a.scale,
+                causal: a.causal,
+            },
+            LazyOp::CausalMask(c) => IrOp::CausalMask {
+                q_len: c.q_len,
+                kv_len: c.kv_len,
+                offset: c.offset,
+                window: c.window,
+                dt: c.dt.to_string(),
+            },
+            LazyOp::PaddingMask(p) => IrOp::PaddingMask {
+                q_len: p.q_len,
+                kv_len: p.kv_len,
+                side: p.side,
+                dt: p.dt.to_string(),
+            },
+            LazyOp::View(v) => IrOp::View {
+                shape: v.shape.to_vec(),
+            },
+            LazyOp::Conv(c) => IrOp::Conv {
+                stride: c.stride,
+                padding: c.padding,
+                dilation: c.dilation,
+                groups: c.groups,
+            },
+            LazyOp::Im2Col(i) => IrOp::Im2Col {
+                kernel_size: i.kernel_size,
+                stride: i.stride,
+                padding: i.padding,
+                dilation: i.dilation,
+                groups: i.groups,
+            },
+            LazyOp::Select(s) => IrOp::IndexSelect { dim: s.dim },
+            LazyOp::IndexWrite(iw) => IrOp::IndexWrite {
+                write_start: iw.write_start.to_vec(),
+            },
+            LazyOp::Cache(c) => IrOp::Cache {
+                dim: c.dim,
+                offset: c.offset,
+                window: c.window,
+            },
+            LazyOp::PagedCache(p) => IrOp::PagedCache {
+                dim: p.dim,
+                offset: p.offset,
+            },
+            op => return Err(IrError::UnsupportedOperation(op.name())),
+        };
+        Ok(ir_op)
+    }
+
+    /// Rebuilds the operation from its sources, in the order of [LazyOp::srcs].
+    fn to_tensor(
+        &self,
+        node: usize,
+        srcs: RVec<Tensor>,
+        device: &Device,
+    ) -> Result<Tensor, IrError> {
+        if let IrOp::View { shape } = self {
+            //Views share the storage of their source
+            let src = srcs.into_iter().next();
+            let src = src.ok_or(IrError::MissingInput { node, input: 0 })?;
+            return Ok(src.view(Shape::from(shape.clone()))?);
+        }
+        let (op, view, device) = self.to_op(node, srcs, device)?;
+        Ok(Tensor::lazy(op, view, device))
+    }
+
+    /// Builds the [LazyOp] described by this node from `srcs`, along with its output view.
+    /// Operations without sources, e.g [CausalMask], are placed on `device`.
+    pub(crate) fn to_op(
+        &self,
+        node: usize,
+        srcs: RVec<Tensor>,
+        device: &Device,
+    ) -> Result<(LazyOp, StorageView, Device), IrError> {
+        let mut srcs = srcs.into_iter();
+        let mut next = |input: usize| srcs.next().ok_or(IrError::MissingInput { node, input });
+
+        let built = match self {
+            IrOp::Const { index } => return Err(IrError::MissingConstant(*index)),
+            IrOp::View { shape } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let view = View::new(input, Shape::from(shape.clone()));
+                let out_view = view.compute_view()?;
+                (LazyOp::View(view), out_view, device)
+            }
+            IrOp::Matmul {
+                trans_lhs,
+                trans_rhs,
+                trans_out,
+            } => {
+                let (lhs, rhs) = (next(0)?, next(1)?);
+                let device = lhs.device().clone();
+                let bias = next(2).ok();
+                let matmul = Matmul::new(lhs, rhs, bias, *trans_lhs, *trans_rhs, *trans_out);
+                let view = matmul.compute_view()?;
+                (LazyOp::Matmul(matmul), view, device)
+            }
+            IrOp::Binary { op } => {
+                let lhs = next(0)?;
+                let device = lhs.device().clone();
+                let binary = Binary::new(lhs, next(1)?, op.clone());
+                let view = binary.compute_view()?;
+                (LazyOp::Binary(binary), view, device)
+            }
+            IrOp::Unary { op } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let unary = Unary::new(input, op.clone());
+                let view = unary.compute_view()?;
+                (LazyOp::Unary(unary), view, device)
+            }
+            IrOp::Permute { dims } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let permute = Permute::new(input, dims.clone());
+                let view = permute.compute_view()?;
+                (LazyOp::Reindex(Reindex::Permute(permute)), view, device)
+            }
+            IrOp::Slice { ranges } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let ranges = ranges.iter().map(|&(start, end)| start..end).collect();
+                let slice = Slice::new(input, ranges);
+                let view = slice.compute_view()?;
+                (LazyOp::Reindex(Reindex::Slice(slice)), view, device)
+            }
+            IrOp::Broadcast { shape } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let broadcast = Broadcast::new(input, Shape::from(shape.clone()));
+                let view = broadcast.compute_view()?;
+                (LazyOp::Reindex(Reindex::Broadcast(broadcast)), view, device)
+            }
+            IrOp::Concat { dim } => {
+                let inputs = next(0).into_iter().chain(srcs).collect::<RVec<_>>();
+                let device = inputs[0].device().clone();
+                let concat = Concat::new(inputs, *dim);
+                let view = concat.compute_view()?;
+                (LazyOp::Concat(concat), view, device)
+            }
+            IrOp::LayerNorm { eps } | IrOp::RMSNorm { eps } => {
+                let (input, scale) = (next(0)?, next(1)?);
+                let device = input.device().clone();
+                let norm = Norm::new(input, scale, next(2).ok(), *eps);
+                let view = norm.compute_view()?;
+                let norm = match self {
+                    IrOp::LayerNorm { .. } => NormOp::LayerNorm(norm),
+                    _ => NormOp::RMSNorm(norm),
+                };
+                (LazyOp::Norm(norm), view, device)
+            }
+            IrOp::GroupNorm { num_groups, eps } => {
+                let (input, scale) = (next(0)?, next(1)?);
+                let device = input.device().clone();
+                let norm = Norm::new(input, scale, next(2).ok(), *eps);
+                let group_norm = GroupNorm::new(norm, *num_groups);
+                let view = group_norm.compute_view()?;
+                (LazyOp::Norm(NormOp::GroupNorm(group_norm)), view, device)
+            }
+            IrOp::Cast { dt } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let cast = Cast::new(input, dt.parse()?);
+                let view = cast.compute_view()?;
+                (LazyOp::Cast(cast), view, device)
+            }
+            IrOp::Reduce { op, dim, keepdim } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let reduce = Reduce::new(input, *dim, *keepdim, *op);
+                let view = reduce.compute_view()?;
+                (LazyOp::Reduce(reduce), view, device)
+            }
+            IrOp::Sample { strategy, seed } => {
+                let logits = next(0)?;
+                let device = logits.device().clone();
+                let sample = Sample::new(logits, *strategy, *seed);
+                let view = sample.compute_view()?;
+                (LazyOp::Sample(sample), view, device)
+            }
+            IrOp::RoPE {
+                dim,
+                log2_base,
+                offset,
+            } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let rope = RoPE::new(input, *dim, *log2_base, *offset, next(1).ok());
+                let view = rope.compute_view()?;
+                (LazyOp::RoPE(rope), view, device)
+            }
+            IrOp::Softmax { dim } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let softmax = Softmax::new(input, *dim);
+                let view = softmax.compute_view()?;
+                (LazyOp::Softmax(softmax), view, device)
+            }
+            IrOp::Attention { scale, causal } => {
+                let query = next(0)?;
+                let device = query.device().clone();
+                let attention =
+                    Attention::new(query, next(1)?, next(2)?, next(3).ok(), *scale, *causal);
+                let view = attention.compute_view()?;
+                (LazyOp::Attention(attention), view, device)
+            }
+            IrOp::CausalMask {
+                q_len,
+                kv_len,
+                offset,
+                window,
+                dt,
+            } => {
+                let mask = CausalMask::new(*q_len, *kv_len, *offset, *window, dt.parse()?);
+                let view = mask.compute_view()?;
+                (LazyOp::CausalMask(mask), view, device.clone())
+            }
+            IrOp::PaddingMask {
+                q_len,
+                kv_len,
+                side,
+                dt,
+            } => {
+                let lengths = next(0)?;
+                let device = lengths.device().clone();
+                let mask = PaddingMask::new(lengths, *q_len, *kv_len, *side, dt.parse()?);
+                let view = mask.compute_view()?;
+                (LazyOp::PaddingMask(mask), view, device)
+            }
+            IrOp::Conv {
+                stride,
+                padding,
+                dilation,
+                groups,
+            } => {
+                let (input, weight) = (next(0)?, next(1)?);
+                let device = input.device().clone();
+                let conv = Conv::new(
+                    input,
+                    weight,
+                    next(2).ok(),
+                    *stride,
+                    *padding,
+                    *dilation,
+                    *groups,
+                );
+                let view = conv.compute_view()?;
+                (LazyOp::Conv(conv), view, device)
+            }
+            IrOp::Im2Col {
+                kernel_size,
+                stride,
+                padding,
+                dilation,
+                groups,
+            } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let im2col =
+                    Im2Col::new(input, *kernel_size, *stride, *padding, *dilation, *groups);
+                let view = im2col.compute_view()?;
+                (LazyOp::Im2Col(im2col), view, device)
+            }
+            IrOp::IndexSelect { dim } => {
+                let input = next(0)?;
+                let device = input.device().clone();
+                let select = IndexSelect::new(input, next(1)?, *dim);
+                let view = select.compute_view()?;
+                (LazyOp::Select(select), view, device)
+            }
+            IrOp::IndexWrite { write_start } => {
+                let dst = next(0)?;
+                let device = dst.device().clone();
+                let write = IndexWrite::new(dst, next(1)?, write_start.iter().copied().collect());
+                let view = write.compute_view()?;
+                (LazyOp::IndexWrite(write), view, device)
+            }
+            IrOp::Cache {
+                dim,
+                offset,
+                window,
+            } => {
+                let cache = next(0)?;
+                let device = cache.device().clone();
+                let cache = Cache::new(cache, next(1)?, *dim, *offset, *window);
+                let view = cache.compute_view()?;
+                (LazyOp::Cache(cache), view, device)
+            }
+            IrOp::PagedCache { dim, offset } => {
+                let pool = next(0)?;
+                let device = pool.device().clone();
+                let paged = PagedCache::new(pool, next(1)?, next(2)?, *dim, *offset);
+                let view = paged.compute_view()?;
+                (LazyOp::PagedCache(paged), view, device)
+            }
+        };
+        Ok(built)
+    }
+}
+
+impl GraphIR {
+    pub fn to_json(&self) -> Result<String, IrError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, IrError> {
+        let ir: GraphIR = serde_json::from_str(json)?;
+        if ir.version != IR_VERSION {
+            return Err(IrError::UnsupportedVersion(ir.version));
+        }
+        Ok(ir)
+    }
+
+    /// Number of constants the graph refers to.
+    pub fn num_constants(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|n| matches!(n.op, IrOp::Const { .. }))
+            .count()
+    }
+}
+
+impl IrNode {
+    fn check(&self, t: &Tensor) -> Result<(), IrError> {
+        let (actual_shape, actual_dt) = (t.shape().to_vec(), t.dt().to_string());
+        if actual_shape != self.shape || actual_dt != self.dt {
+            return Err(IrError::NodeMismatch {
+                node: self.id,
+                shape: self.shape.clone(),
+                dt: self.dt.clone(),
+                actual_shape,
+                actual_dt,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Tensor {
+    /// # IR export
+    ///
+    /// Describes the graph rooted at this tensor as a [GraphIR].
+    ///
+    /// Traversal stops at resolved tensors, which are returned alongside the IR in the order
+    /// of their `Const` indices.
+    pub fn to_ir(&self) -> Result<(GraphIR, Vec<Tensor>), IrError> {
+        let order = self.ir_order();
+        let ids = order
+            .iter()
+            .enumerate()
+            .map(|(node, t)| (t.id(), node))
+            .collect::<FxHashMap<_, _>>();
+
+        let mut nodes = Vec::with_capacity(order.len());
+        let mut edges = vec![];
+        let mut constants = vec![];
+        for (node, t) in order.iter().enumerate() {
+            let op = if t.resolved() {
+                constants.push((*t).clone());
+                IrOp::Const {
+                    index: constants.len() - 1,
+                }
+            } else {
+                for (input, src) in t.op().srcs().iter().enumerate() {
+                    edges.push(IrEdge {
+                        src: ids[&src.id()],
+                        dst: node,
+                        input,
+                    });
+                }
+                IrOp::from_lazy(t.op())?
+            };
+            nodes.push(IrNode {
+                id: node,
+                op,
+                shape: t.shape().to_vec(),
+                dt: t.dt().to_string(),
+                strides: t.strides().to_vec(),
+            });
+        }
+
+        let ir = GraphIR {
+            version: IR_VERSION,
+            nodes,
+            edges,
+            output: order.len() - 1,
+        };
+        Ok((ir, constants))
+    }
+
+    /// # IR import
+    ///
+    /// Rebuilds the lazy graph described by `ir`, returning its output.
+    /// `constants` must match the shapes & dtypes of the `Const` nodes they are referred to by,
+    /// and be on `device`, where operations without sources are also placed.
+    pub fn from_ir(ir: &GraphIR, constants: &[Tensor], device: &Device) -> Result<Tensor, IrError> {
+        if ir.version != IR_VERSION {
+            return Err(IrError::UnsupportedVersion(ir.version));
+        }
+        if let Some(index) = constants.iter().position(|c| c.device() != device) {
+            return Err(IrError::ConstantDevice(index));
+        }
+        let mut inputs: FxHashMap<usize, Vec<&IrEdge>> = FxHashMap::default();
+        for edge in ir.edges.iter() {
+            inputs.entry(edge.dst).or_default().push(edge);
+        }
+
+        let mut tensors: FxHashMap<usize, Tensor> = FxHashMap::default();
+        for node in ir.nodes.iter() {
+            let tensor = match node.op {
+                IrOp::Const { index } => constants
+                    .get(index)
+                    .cloned()
+                    .ok_or(IrError::MissingConstant(index))?,
+                _ => {
+                    let mut edges = inputs.remove(&node.id).unwrap_or_default();
+                    edges.sort_unstable_by_key(|e| e.input);
+                    let mut srcs = rvec![];
+                    for (input, edge) in edges.into_iter().enumerate() {
+                        if edge.input != input {
+                            return Err(IrError::MissingInput {
+                                node: node.id,
+                                input,
+                            });
+                        }
+                        let src = tensors
+                            .get(&edge.src)
+                            .ok_or(IrError::UnknownNode(edge.src))?;
+                        srcs.push(src.clone());
+                    }
+                    node.op.to_tensor(node.id, srcs, device)?
+                }
+            };
+            node.check(&tensor)?;
+            tensors.insert(node.id, tensor);
+        }
+        tensors
+            .remove(&ir.output)
+            .ok_or(IrError::UnknownNode(ir.output))
+    }
+
+    /// Execution order of the graph, treating resolved tensors as leaves.
+    fn ir_order(&self) -> Vec<&Tensor> {
+        let mut order = vec![];
+        let mut visited: FxHashSet<TensorId> = FxHashSet::default();
+        let mut stack = vec![(self, false)];
+        while let Some((t, expanded)) = stack.pop() {
+            if expanded {
+                order.push(t);
+                continue;
+            }
+            if !visited.insert(t.id()) {
+                continue;
+            }
+            stack.push((t, true));
+            if !t.resolved() {
+                for src in t.op().srcs().into_iter().rev() {
+                    if !visited.contains(&src.id()) {
+                        stack.push((src, false));
+                    }
+                }
+            }
+        }
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{shape, DType, Device, GraphIR, IrOp, Tensor};
+
+    #[test]
+    fn ir_round_trip() -> anyhow::Result<()> {
+        let a = Tensor::from_data([1f32, -2., 3., -4.], shape![2, 2], Device::CPU);
+        let b = Tensor::from_data([0.5f32, 0.5], shape![1, 2], Device::CPU);
+        let out = a
+            .clone()
+            .add(b)?
+            .permute(&[1, 0])?
+            .matmul(a, false, false)?
+            .sum(1, true)?;
+
+        let (ir, constants) = out.to_ir()?;
+        assert_eq!(constants.len(), 2);
+        assert_eq!(ir.num_constants(), 2);
+        assert!(matches!(ir.nodes[ir.output].op, IrOp::Reduce { .. }));
+
+        let json = ir.to_json()?;
+        let loaded = GraphIR::from_json(&json)?;
+        assert_eq!(loaded, ir);
+
+        let rebuilt = Tensor::from_ir(&loaded, &constants, &Device::CPU)?;
+        assert_eq!(rebuilt.to_ir()?.0, ir);
+        assert_eq!(
+            rebuilt.resolve()?.to_vec::<f32>()?,
+            out.resolve()?.to_vec::<f32>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn causal_mask_round_trip() -> anyhow::Result<()> {
+        let scores = Tensor::randn::<f32>(shape![3, 5], Device::CPU);
+        let mask = Tensor::causal_mask(3, 5, 2, DType::F32, &Device::CPU)?;
+        let out = scores.add(mask)?;
+
+        let (ir, constants) = out.to_ir()?;
+        assert_eq!(constants.len(), 1);
+        let loaded = GraphIR::from_json(&ir.to_json()?)?;
+        let rebuilt = Tensor::from_ir(&loaded, &constants, &Device::CPU)?;
+        assert_eq!(
+            rebuilt.resolve()?.to_vec::<f32>()?,
+            out.resolve()?.to_vec::<f32>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn conv_defaults_to_dense() -> anyhow::Result<()> {
+        let op: IrOp =
+            serde_json::from_str(r#"{"name": "Conv", "params": {"stride": 2, "padding": 1}}"#)?;
+        assert_eq!(
+            op,
+            IrOp::Conv {
+                stride: 2,
+                padding: 1,
+                dilation: 1,
+                groups: 1,
+            }
+        );
+        Ok(())
+    }
+}
@@ -1,13 +1,16 @@
 #![allow(non_snake_case)]
 mod compiled_op;
+mod cpu;
 mod device;
 mod dtype;
 mod enforcer;
 mod executable;
 mod gpu;
+mod ir;
 mod ndarray_ext;
 mod op;
 mod ops;
+mod passes;
 mod plot;
 mod quant;
 mod shape;
@@ -17,14 +20,17 @@ mod tensor;
 mod tensor_id;
 
 pub use compiled_op::*;
+pub use cpu::*;
 pub use device::*;
 pub use dtype::*;
 pub use enforcer::*;
 pub use executable::*;
 pub use gpu::*;
+pub use ir::*;
 pub use ndarray_ext::*;
 pub use op::*;
 pub use ops::*;
+pub use passes::*;
 pub use quant::*;
 pub use shape::*;
 pub use storage::*;
@@ -22,14 +22,22 @@ pub enum LazyOp {
     Concat(Concat),
     Norm(NormOp),
     Cast(Cast),
+    Reduce(Reduce),
+    Sample(Sample),
+    Fused(FusedElementwise),
     // ---- Everything below this line shouldn't exist ----
     RoPE(RoPE),
     Softmax(Softmax),
-    View(View),             //Should be general class, metadata modification
-    Conv(Conv),             //Really it's a matmul
+    Attention(Attention),
+    CausalMask(CausalMask),
+    PaddingMask(PaddingMask),
+    View(View), //Should be general class, metadata modification
+    Conv(Conv), //Really it's a matmul
+    Im2Col(Im2Col),
     Select(IndexSelect),    //Can probably be Reindex
     IndexWrite(IndexWrite), //Above 2 should be merged
     Cache(Cache),           //Should be a general class
+    PagedCache(PagedCache),
 }
 
 impl LazyOp {
@@ -39,15 +47,23 @@ impl LazyOp {
             LazyOp::Cast(c) => c.kernel_name(),
             LazyOp::Matmul(m) => m.kernel_name(),
             LazyOp::Softmax(s) => s.kernel_name(),
+            LazyOp::Attention(a) => a.kernel_name(),
+            LazyOp::CausalMask(c) => c.kernel_name(),
+            LazyOp::PaddingMask(p) => p.kernel_name(),
             LazyOp::Unary(u) => u.kernel_name(),
             LazyOp::Reindex(r) => r.kernel_name(),
             LazyOp::Concat(c) => c.kernel_name(),
             LazyOp::Norm(n) => n.kernel_name(),
+            LazyOp::Reduce(r) => r.kernel_name(),
+            LazyOp::Sample(s) => s.kernel_name(),
+            LazyOp::Fused(f) => f.kernel_name(),
             LazyOp::Conv(c) => c.kernel_name(),
+            LazyOp::Im2Col(i) => i.kernel_name(),
             LazyOp::Select(s) => s.kernel_name(),
             LazyOp::IndexWrite(iw) => iw.kernel_name(),
             LazyOp::RoPE(r) => r.kernel_name(),
             LazyOp::Cache(c) => c.kernel_name(),
+            LazyOp::PagedCache(p) => p.kernel_name(),
             LazyOp::View(_) => "View".to_string(),
             LazyOp::Const => "Const".to_string(),
         }
@@ -60,14 +76,22 @@ impl LazyOp {
             LazyOp::Matmul(m) => m.srcs(),
             LazyOp::RoPE(r) => r.srcs(),
             LazyOp::Softmax(s) => s.srcs(),
+            LazyOp::Attention(a) => a.srcs(),
+            LazyOp::CausalMask(c) => c.srcs(),
+            LazyOp::PaddingMask(p) => p.srcs(),
             LazyOp::Unary(u) => u.srcs(),
             LazyOp::Reindex(r) => r.srcs(),
             LazyOp::Concat(c) => c.srcs(),
             LazyOp::Norm(n) => n.srcs(),
+            LazyOp::Reduce(r) => r.srcs(),
+            LazyOp::Sample(s) => s.srcs(),
+            LazyOp::Fused(f) => f.srcs(),
             LazyOp::Conv(c) => c.srcs(),
+            LazyOp::Im2Col(i) => i.srcs(),
             LazyOp::Select(s) => s.srcs(),
             LazyOp::IndexWrite(iw) => iw.srcs(),
             LazyOp::Cache(c) => c.srcs(),
+            LazyOp::PagedCache(p) => p.srcs(),
             LazyOp::View(v) => rvec![v.input()],
             LazyOp::Const => rvec![], //end of the line kid
         }
@@ -80,14 +104,22 @@ impl LazyOp {
             LazyOp::Matmul(m) => m.supports_inplace(),
             LazyOp::RoPE(r) => r.supports_inplace(),
             LazyOp::Softmax(s) => s.supports_inplace(),
+            LazyOp::Attention(a) => a.supports_inplace(),
+            LazyOp::CausalMask(c) => c.supports_inplace(),
+            LazyOp::PaddingMask(p) => p.supports_inplace(),
             LazyOp::Unary(u) => u.supports_inplace(),
             LazyOp::Reindex(r) => r.supports_inplace(),
             LazyOp::Concat(c) => c.supports_inplace(),
             LazyOp::Norm(n) => n.supports_inplace(),
+            LazyOp::Reduce(r) => r.supports_inplace(),
+            LazyOp::Sample(s) => s.supports_inplace(),
+            LazyOp::Fused(f) => f.supports_inplace(),
             LazyOp::Conv(c) => c.supports_inplace(),
+            LazyOp::Im2Col(i) => i.supports_inplace(),
             LazyOp::Select(s) => s.supports_inplace(),
             LazyOp::IndexWrite(iw) => iw.supports_inplace(),
             LazyOp::Cache(c) => c.supports_inplace(),
+            LazyOp::PagedCache(p) => p.supports_inplace(),
             LazyOp::View(_v) => true,
             LazyOp::Const => false,
         }
@@ -105,6 +137,9 @@ impl LazyOp {
             LazyOp::Matmul(m) => m.check_invariants(),
             LazyOp::RoPE(r) => r.check_invariants(),
             LazyOp::Softmax(s) => s.check_invariants(),
+            LazyOp::Attention(a) => a.check_invariants(),
+            LazyOp::CausalMask(c) => c.check_invariants(),
+            LazyOp::PaddingMask(p) => p.check_invariants(),
             LazyOp::Unary(u) => u.check_invariants(),
             LazyOp::Reindex(r) => match r {
                 Reindex::Permute(p) => p.check_invariants(),
@@ -117,10 +152,15 @@ impl LazyOp {
                 NormOp::RMSNorm(r) => r.check_invariants(),
                 NormOp::GroupNorm(g) => g.check_invariants(),
             },
+            LazyOp::Reduce(r) => r.check_invariants(),
+            LazyOp::Sample(s) => s.check_invariants(),
+            LazyOp::Fused(f) => f.check_invariants(),
             LazyOp::Conv(c) => c.check_invariants(),
+            LazyOp::Im2Col(i) => i.check_invariants(),
             LazyOp::Select(s) => s.check_invariants(),
             LazyOp::IndexWrite(iw) => iw.check_invariants(),
             LazyOp::Cache(c) => c.check_invariants(),
+            LazyOp::PagedCache(p) => p.check_invariants(),
             LazyOp::View(v) => v.check_invariants(),
             LazyOp::Const => {}
         }
@@ -140,6 +180,8 @@ pub enum OperationError {
     #[error(transparent)]
     UniformError(#[from] encase::internal::Error),
     #[error(transparent)]
+    DeviceError(#[from] crate::DeviceError),
+    #[error(transparent)]
     UnknownError(#[from] anyhow::Error),
 }
 
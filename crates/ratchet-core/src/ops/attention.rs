@@ -0,0 +1,726 @@
+use derive_new::new;
+use encase::ShaderType;
+use half::f16;
+use inline_wgsl::wgsl;
+use ratchet_macros::WgslMetadata;
+
+use crate::{
+    gpu::{dtype::WgslDType, BindGroupLayoutDescriptor, CpuUniform, WorkgroupCount},
+    rvec, wgc, wgs, Array, BindingMode, BuiltIn, DType, KernelElement, KernelKey, KernelSource,
+    MetaOperation, OpGuards, Operation, OperationError, RVec, Scalar, StorageView, Tensor,
+    WgslFragment, WgslKernelBuilder, WgslPrimitive, WorkgroupSize, Workload, QK8_0,
+};
+
+/// Number of query rows processed by each workgroup, one per thread.
+const BLOCK_ROWS: usize = 32;
+/// Upper bound on the number of elements in each of the K & V tiles, keeping both tiles within
+/// the default 16KiB of workgroup storage.
+const TILE_ELEMENTS: usize = 2048;
+/// Largest supported head dimension. Each thread keeps its query row & output accumulator in
+/// private arrays of `D` elements, which would spill far beyond the register file above this.
+pub const MAX_HEAD_DIM: usize = 256;
+/// Below this many query rows, e.g when decoding, each row is processed by a whole workgroup
+/// instead of a single thread, see [Attention::build_decode].
+const DECODE_ROWS: usize = 8;
+/// Number of keys scored in parallel by each decode workgroup, one per thread.
+const DECODE_KEYS: usize = 32;
+
+/// # Attention
+///
+/// Fused scaled dot-product attention, `softmax(scale * Q @ K^T + mask) @ V`.
+///
+/// Q is `[B, H_q, S_q, D]`, K & V are `[B, H_kv, S_k, D]`. When `H_kv < H_q`, each KV head is
+/// shared by `H_q / H_kv` query heads (GQA).
+///
+/// The scores are never materialized: each workgroup streams tiles of K & V through workgroup
+/// memory, maintaining a running softmax for each of its query rows, as in FlashAttention.
+/// With few query rows, as when decoding, the keys of each row are split across the threads of
+/// a workgroup instead.
+///
+/// When `causal` is set, query `i` attends to keys `0..=i + S_k - S_q`, so that the final query
+/// always sees every key, as is required when decoding with a KV cache.
+/// The optional additive `mask` is `[S_q, S_k]` or `[B | 1, H_q | 1, S_q, S_k]`.
+///
+/// K & V may be Q8_0, e.g when read from a quantized [crate::Cache], in which case they are
+/// dequantized as their tiles are loaded.
+#[derive(new, Debug, Clone)]
+pub struct Attention {
+    pub(crate) query: Tensor,
+    pub(crate) key: Tensor,
+    pub(crate) value: Tensor,
+    pub(crate) mask: Option<Tensor>,
+    pub(crate) scale: f32,
+    pub(crate) causal: bool,
+}
+
+#[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
+pub struct AttentionMeta {
+    Hq: u32,
+    Hkv: u32,
+    Sq: u32,
+    Sk: u32,
+    mask_batch_stride: u32,
+    mask_head_stride: u32,
+    scale: f32,
+}
+
+impl Attention {
+    fn head_dim(&self) -> usize {
+        self.query.shape()[3]
+    }
+
+    /// Number of keys in each tile of K & V.
+    fn block_cols(&self) -> usize {
+        (TILE_ELEMENTS / self.head_dim()).min(BLOCK_ROWS)
+    }
+
+    /// Strides of the leading dimensions of the mask, 0 where it is broadcast.
+    pub(crate) fn mask_strides(&self) -> (usize, usize) {
+        let Some(mask) = &self.mask else {
+            return (0, 0);
+        };
+        let [_, _, Sq, Sk]: [usize; 4] = self.query.shape().try_into().unwrap();
+        let matrix = Sq * Sk;
+        match mask.rank() {
+            4 => {
+                let (B, H) = (mask.shape()[0], mask.shape()[1]);
+                let head_stride = if H == 1 { 0 } else { matrix };
+                let batch_stride = if B == 1 { 0 } else { H * matrix };
+                (batch_stride, head_stride)
+            }
+            _ => (0, 0),
+        }
+    }
+
+    fn is_kv_quantized(&self) -> bool {
+        self.key.dt().is_quantized()
+    }
+
+    fn is_decode(&self) -> bool {
+        self.query.shape()[2] < DECODE_ROWS
+    }
+
+    fn register_bindings<P: WgslPrimitive>(
+        &self,
+        builder: &mut WgslKernelBuilder,
+        _: bool,
+    ) -> Result<(), OperationError> {
+        let arr = Array::<P>::default();
+        builder.register_storage("Q", BindingMode::ReadOnly, arr);
+        if self.is_kv_quantized() {
+            //Scales are stored in the activation dtype, matching Q
+            let packed_arr = Array::<Scalar<u32>>::default();
+            builder.register_storage("K", BindingMode::ReadOnly, packed_arr);
+            builder.register_storage("KS", BindingMode::ReadOnly, arr);
+            builder.register_storage("V", BindingMode::ReadOnly, packed_arr);
+            builder.register_storage("VS", BindingMode::ReadOnly, arr);
+        } else {
+            builder.register_storage("K", BindingMode::ReadOnly, arr);
+            builder.register_storage("V", BindingMode::ReadOnly, arr);
+        }
+        if self.mask.is_some() {
+            builder.register_storage("M", BindingMode::ReadOnly, arr);
+        }
+        builder.register_storage("Y", BindingMode::ReadWrite, arr);
+        builder.register_uniform();
+        Ok(())
+    }
+
+    fn build_attention<P: WgslPrimitive>(
+        &self,
+        inplace: bool,
+        _: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = self.query.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![BuiltIn::WorkgroupId, BuiltIn::LocalInvocationIndex],
+            device.compute_features().clone(),
+        );
+        self.register_bindings::<P>(&mut kernel_builder, inplace)?;
+        kernel_builder.write_metadata::<AttentionMeta>();
+        if self.is_kv_quantized() {
+            kernel_builder.write_unpack(self.key.dt());
+        }
+
+        let dt = P::T::DT;
+        let D = self.head_dim();
+        let TILE = self.block_cols() * D;
+        let HD = (D as u32).render();
+        let BR = (BLOCK_ROWS as u32).render();
+        let BC = (self.block_cols() as u32).render();
+        let TILE_U = (TILE as u32).render();
+        let minFloat = <f32 as WgslDType>::MIN.render();
+
+        kernel_builder.write_global(wgsl! {
+            var<workgroup> K_tile: array<f32, 'TILE>;
+            var<workgroup> V_tile: array<f32, 'TILE>;
+        });
+
+        kernel_builder.write_main(wgsl! {
+            let block_start = workgroup_id.x * 'BR;
+            let row = block_start + local_invocation_index;
+            let head = workgroup_id.y;
+            let batch = workgroup_id.z;
+            let kv_head = head / (metadata.Hq / metadata.Hkv);
+            let is_valid = row < metadata.Sq;
+
+            let q_offset = ((batch * metadata.Hq + head) * metadata.Sq + row) * 'HD;
+            let kv_offset = (batch * metadata.Hkv + kv_head) * metadata.Sk * 'HD;
+
+            var q: array<f32, 'D>;
+            var acc: array<f32, 'D>;
+            if (is_valid) {
+                for (var d = 0u; d < 'HD; d++) {
+                    q[d] = f32(Q[q_offset + d]) * metadata.scale;
+                }
+            }
+            var m_i = 'minFloat;
+            var l_i = 0f;
+            var kv_end = metadata.Sk;
+        });
+
+        if self.causal {
+            kernel_builder.write_main(wgsl! {
+                //Keys beyond those visible to the final row of the block can be skipped
+                let last_row = min(block_start + 'BR, metadata.Sq) - 1u;
+                kv_end = min(metadata.Sk, last_row + metadata.Sk - metadata.Sq + 1u);
+            });
+        }
+
+        let causal_check = if self.causal {
+            wgsl! {
+                if (key > row + metadata.Sk - metadata.Sq) {
+                    break;
+                }
+            }
+        } else {
+            WgslFragment::new(0)
+        };
+        let load_tiles = if self.is_kv_quantized() {
+            wgsl! {
+                let e = kv_offset + tile * 'HD + i;
+                K_tile[i] = f32(unpack(K[e / 4u])[e % 4u]) * f32(KS[e / 32u]);
+                V_tile[i] = f32(unpack(V[e / 4u])[e % 4u]) * f32(VS[e / 32u]);
+            }
+        } else {
+            wgsl! {
+                K_tile[i] = f32(K[kv_offset + tile * 'HD + i]);
+                V_tile[i] = f32(V[kv_offset + tile * 'HD + i]);
+            }
+        };
+        let apply_mask = if self.mask.is_some() {
+            wgsl! {
+                s += f32(M[mask_offset + key]);
+            }
+        } else {
+            WgslFragment::new(0)
+        };
+
+        kernel_builder.write_main(wgsl! {
+            let mask_offset = batch * metadata.mask_batch_stride + head * metadata.mask_head_stride + row * metadata.Sk;
+            for (var tile = 0u; tile < kv_end; tile += 'BC) {
+                for (var i = local_invocation_index; i < 'TILE_U; i += 'BR) {
+                    if (tile + i / 'HD < metadata.Sk) {
+                        'load_tiles
+                    }
+                }
+                workgroupBarrier();
+
+                if (is_valid) {
+                    let tile_end = min('BC, kv_end - tile);
+                    for (var j = 0u; j < tile_end; j++) {
+                        let key = tile + j;
+                        'causal_check
+
+                        var s = 0f;
+                        for (var d = 0u; d < 'HD; d++) {
+                            s = fma(q[d], K_tile[j * 'HD + d], s);
+                        }
+                        'apply_mask
+
+                        //Online softmax, rescaling the running sum & output to the new maximum
+                        let m_new = max(m_i, s);
+                        let correction = exp(m_i - m_new);
+                        let p = exp(s - m_new);
+                        l_i = fma(l_i, correction, p);
+                        for (var d = 0u; d < 'HD; d++) {
+                            acc[d] = fma(p, V_tile[j * 'HD + d], acc[d] * correction);
+                        }
+                        m_i = m_new;
+                    }
+                }
+                workgroupBarrier();
+            }
+
+            if (is_valid) {
+                //Fully masked rows produce zeros rather than NaNs
+                let inv_l = select(0f, 1f / l_i, l_i > 0f);
+                for (var d = 0u; d < 'HD; d++) {
+                    Y[q_offset + d] = 'dt(acc[d] * inv_l);
+                }
+            }
+        });
+
+        Ok(kernel_builder.build()?)
+    }
+
+    /// A workgroup per query row. Each tile of keys is scored with a thread per key, after which
+    /// each thread accumulates its share of the head dimension over the tile.
+    fn build_decode<P: WgslPrimitive>(
+        &self,
+        inplace: bool,
+        _: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = self.query.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![BuiltIn::WorkgroupId, BuiltIn::LocalInvocationIndex],
+            device.compute_features().clone(),
+        );
+        self.register_bindings::<P>(&mut kernel_builder, inplace)?;
+        kernel_builder.write_metadata::<AttentionMeta>();
+
+        let dt = P::T::DT;
+        let D = self.head_dim();
+        let HD = (D as u32).render();
+        let KEYS = DECODE_KEYS;
+        let NK = (DECODE_KEYS as u32).render();
+        let DPT = D.div_ceil(DECODE_KEYS);
+        let DPT_U = (DPT as u32).render();
+        let minFloat = <f32 as WgslDType>::MIN.render();
+
+        if self.is_kv_quantized() {
+            kernel_builder.write_unpack(self.key.dt());
+            kernel_builder.write_global(wgsl! {
+                fn load_k(e: u32) -> f32 {
+                    return f32(unpack(K[e / 4u])[e % 4u]) * f32(KS[e / 32u]);
+                }
+
+                fn load_v(e: u32) -> f32 {
+                    return f32(unpack(V[e / 4u])[e % 4u]) * f32(VS[e / 32u]);
+                }
+            });
+        } else {
+            kernel_builder.write_global(wgsl! {
+                fn load_k(e: u32) -> f32 {
+                    return f32(K[e]);
+                }
+
+                fn load_v(e: u32) -> f32 {
+                    return f32(V[e]);
+                }
+            });
+        }
+        kernel_builder.write_global(wgsl! {
+            var<workgroup> q_shared: array<f32, 'D>;
+            var<workgroup> scores: array<f32, 'KEYS>;
+        });
+
+        let causal_end = if self.causal {
+            wgsl! {
+                kv_end = min(metadata.Sk, row + metadata.Sk - metadata.Sq + 1u);
+            }
+        } else {
+            WgslFragment::new(0)
+        };
+        let apply_mask = if self.mask.is_some() {
+            wgsl! {
+                s += f32(M[mask_offset + key]);
+            }
+        } else {
+            WgslFragment::new(0)
+        };
+
+        kernel_builder.write_main(wgsl! {
+            let row = workgroup_id.x;
+            let head = workgroup_id.y;
+            let batch = workgroup_id.z;
+            let kv_head = head / (metadata.Hq / metadata.Hkv);
+
+            let q_offset = ((batch * metadata.Hq + head) * metadata.Sq + row) * 'HD;
+            let kv_offset = (batch * metadata.Hkv + kv_head) * metadata.Sk * 'HD;
+            let mask_offset = batch * metadata.mask_batch_stride + head * metadata.mask_head_stride + row * metadata.Sk;
+
+            for (var d = local_invocation_index; d < 'HD; d += 'NK) {
+                q_shared[d] = f32(Q[q_offset + d]) * metadata.scale;
+            }
+            workgroupBarrier();
+
+            var kv_end = metadata.Sk;
+            'causal_end
+
+            var acc: array<f32, 'DPT>;
+            var m_i = 'minFloat;
+            var l_i = 0f;
+            for (var tile = 0u; tile < kv_end; tile += 'NK) {
+                let key = tile + local_invocation_index;
+                var s = 'minFloat;
+                if (key < kv_end) {
+                    s = 0f;
+                    for (var d = 0u; d < 'HD; d++) {
+                        s = fma(q_shared[d], load_k(kv_offset + key * 'HD + d), s);
+                    }
+                    'apply_mask
+                }
+                scores[local_invocation_index] = s;
+                workgroupBarrier();
+
+                //Every thread tracks the same running maximum & sum, over the whole row
+                let tile_end = min('NK, kv_end - tile);
+                var m_new = m_i;
+                for (var j = 0u; j < tile_end; j++) {
+                    m_new = max(m_new, scores[j]);
+                }
+                let correction = exp(m_i - m_new);
+                l_i *= correction;
+                for (var i = 0u; i < 'DPT_U; i++) {
+                    acc[i] *= correction;
+                }
+                for (var j = 0u; j < tile_end; j++) {
+                    let p = exp(scores[j] - m_new);
+                    l_i += p;
+                    let v_offset = kv_offset + (tile + j) * 'HD;
+                    for (var i = 0u; i < 'DPT_U; i++) {
+                        let d = local_invocation_index + i * 'NK;
+                        if (d < 'HD) {
+                            acc[i] = fma(p, load_v(v_offset + d), acc[i]);
+                        }
+                    }
+                }
+                m_i = m_new;
+                workgroupBarrier();
+            }
+
+            //Fully masked rows produce zeros rather than NaNs
+            let inv_l = select(0f, 1f / l_i, l_i > 0f);
+            for (var i = 0u; i < 'DPT_U; i++) {
+                let d = local_invocation_index + i * 'NK;
+                if (d < 'HD) {
+                    Y[q_offset + d] = 'dt(acc[i] * inv_l);
+                }
+            }
+        });
+
+        Ok(kernel_builder.build()?)
+    }
+}
+
+impl OpGuards for Attention {
+    fn check_shapes(&self) {
+        let (q, k, v) = (&self.query, &self.key, &self.value);
+        assert_eq!(q.rank(), 4);
+        assert_eq!(k.shape(), v.shape());
+        let [B, Hq, Sq, D]: [usize; 4] = q.shape().try_into().unwrap();
+        let [Bk, Hkv, Sk, Dk]: [usize; 4] = k.shape().try_into().unwrap();
+        //Every input is indexed as a dense, row-major array
+        for t in self.srcs() {
+            assert!(
+                t.storage_view().is_contiguous(),
+                "Attention requires contiguous inputs"
+            );
+        }
+        assert_eq!(B, Bk);
+        assert_eq!(D, Dk);
+        assert!(
+            D <= MAX_HEAD_DIM,
+            "Head dimension {} is too large for attention",
+            D
+        );
+        assert!(
+            Hkv > 0 && Hq % Hkv == 0,
+            "{} query heads cannot be grouped over {} KV heads",
+            Hq,
+            Hkv
+        );
+        if self.causal {
+            assert!(
+                Sk >= Sq,
+                "Causal attention requires at least as many keys as queries"
+            );
+        }
+        if let Some(mask) = &self.mask {
+            let dims = mask.shape().to_vec();
+            match dims.as_slice() {
+                [m_q, m_k] => assert_eq!((*m_q, *m_k), (Sq, Sk)),
+                [m_b, m_h, m_q, m_k] => {
+                    assert!(*m_b == 1 || *m_b == B);
+                    assert!(*m_h == 1 || *m_h == Hq);
+                    assert_eq!((*m_q, *m_k), (Sq, Sk));
+                }
+                _ => panic!("Attention mask must be [S_q, S_k] or [B, H_q, S_q, S_k]"),
+            }
+        }
+    }
+
+    fn check_dtypes(&self) {
+        let dt = self.query.dt();
+        assert!(dt.is_float());
+        match self.key.dt() {
+            kv_dt @ (DType::Q8_0F(_) | DType::Q8_0H(_)) => {
+                assert_eq!(kv_dt.activation_dt(), dt);
+                assert_eq!(self.value.dt(), kv_dt);
+                //The kernel indexes whole Q8_0 blocks within each row of K & V
+                assert!(
+                    self.head_dim() % QK8_0 == 0,
+                    "Head dimension {} is not a multiple of the Q8_0 block size",
+                    self.head_dim()
+                );
+                assert!(self.key.storage_view().is_contiguous());
+                assert!(self.value.storage_view().is_contiguous());
+            }
+            kv_dt => {
+                assert_eq!(kv_dt, dt);
+                assert_eq!(self.value.dt(), dt);
+            }
+        }
+        if let Some(mask) = &self.mask {
+            assert_eq!(mask.dt(), dt);
+        }
+    }
+}
+
+impl Operation for Attention {
+    fn compute_view(&self) -> Result<StorageView, OperationError> {
+        Ok(self.query.storage_view().clone())
+    }
+}
+
+impl MetaOperation for Attention {
+    fn kernel_name(&self) -> String {
+        "attention".to_string()
+    }
+
+    fn kernel_key(
+        &self,
+        workgroup_size: &WorkgroupSize,
+        inplace: bool,
+        dst: &Tensor,
+        kernel_element: &KernelElement,
+    ) -> KernelKey {
+        let additional = format!(
+            "d{}_{}_{}_{}",
+            self.head_dim(),
+            if self.causal { "causal" } else { "" },
+            if self.mask.is_some() { "mask" } else { "" },
+            if self.is_decode() { "decode" } else { "" },
+        );
+        KernelKey::new(
+            &self.kernel_name(),
+            &self.srcs(),
+            dst,
+            workgroup_size,
+            inplace,
+            kernel_element,
+            Some(&additional),
+        )
+    }
+
+    fn srcs(&self) -> RVec<&Tensor> {
+        let mut srcs = rvec![&self.query, &self.key, &self.value];
+        if let Some(mask) = &self.mask {
+            srcs.push(mask);
+        }
+        srcs
+    }
+
+    fn kernel_element(&self, _dst: &Tensor) -> KernelElement {
+        KernelElement::Scalar
+    }
+
+    fn build_kernel(
+        &self,
+        inplace: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        match (self.query.dt(), self.is_decode()) {
+            (DType::F32, true) => self.build_decode::<Scalar<f32>>(inplace, dst, workgroup_size),
+            (DType::F16, true) => self.build_decode::<Scalar<f16>>(inplace, dst, workgroup_size),
+            (DType::F32, false) => {
+                self.build_attention::<Scalar<f32>>(inplace, dst, workgroup_size)
+            }
+            (DType::F16, false) => {
+                self.build_attention::<Scalar<f16>>(inplace, dst, workgroup_size)
+            }
+            (dt, _) => Err(OperationError::CompileError(format!(
+                "Unsupported dtype {:?} for attention",
+                dt
+            ))),
+        }
+    }
+
+    fn calculate_dispatch(&self, _dst: &Tensor) -> Result<Workload, OperationError> {
+        let [B, Hq, Sq, _]: [usize; 4] = self.query.shape().try_into()?;
+        if self.is_decode() {
+            return Ok(Workload {
+                workgroup_count: wgc![Sq as _, Hq as _, B as _],
+                workgroup_size: wgs![DECODE_KEYS as _, 1, 1],
+            });
+        }
+        let wgcx = WorkgroupCount::div_ceil(Sq, BLOCK_ROWS);
+        Ok(Workload {
+            workgroup_count: wgc![wgcx as _, Hq as _, B as _],
+            workgroup_size: wgs![BLOCK_ROWS as _, 1, 1],
+        })
+    }
+
+    fn storage_bind_group_layout(
+        &self,
+        _: bool,
+    ) -> Result<BindGroupLayoutDescriptor, OperationError> {
+        //Quantized K & V bind their scales separately
+        let n_scales = if self.is_kv_quantized() { 2 } else { 0 };
+        Ok(BindGroupLayoutDescriptor::nthary(
+            self.srcs().len() + n_scales,
+        ))
+    }
+
+    fn write_metadata(
+        &self,
+        uniform: &mut CpuUniform,
+        _: &Tensor,
+        _: &KernelElement,
+    ) -> Result<u64, OperationError> {
+        let [_, Hq, Sq, _]: [usize; 4] = self.query.shape().try_into()?;
+        let [_, Hkv, Sk, _]: [usize; 4] = self.key.shape().try_into()?;
+        let (mask_batch_stride, mask_head_stride) = self.mask_strides();
+        let meta = AttentionMeta::new(
+            Hq as _,
+            Hkv as _,
+            Sq as _,
+            Sk as _,
+            mask_batch_stride as _,
+            mask_head_stride as _,
+            self.scale,
+        );
+        Ok(uniform.write(&meta)?)
+    }
+}
+
+#[cfg(all(test, feature = "pyo3"))]
+mod tests {
+    use proptest::prelude::*;
+    use test_strategy::{proptest, Arbitrary};
+
+    use crate::test_util::run_py_prg;
+    use crate::{shape, DType, Device, DeviceRequest, Tensor};
+
+    thread_local! {
+        static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
+    }
+
+    fn ground_truth(
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        mask: Option<&Tensor>,
+        causal: bool,
+    ) -> anyhow::Result<Tensor> {
+        let prg = r#"
+import torch
+import torch.nn.functional as F
+def sdpa(q, k, v, *args):
+    causal = args[-1]
+    q, k, v = torch.from_numpy(q), torch.from_numpy(k), torch.from_numpy(v)
+    groups = q.shape[1] // k.shape[1]
+    k = k.repeat_interleave(groups, dim=1)
+    v = v.repeat_interleave(groups, dim=1)
+    mask = torch.from_numpy(args[0]) if len(args) == 2 else None
+    if causal:
+        (Sq, Sk) = (q.shape[2], k.shape[2])
+        causal_mask = torch.ones(Sq, Sk, dtype=torch.bool).tril(diagonal=Sk - Sq)
+        causal_mask = torch.zeros(Sq, Sk).masked_fill(~causal_mask, float("-inf"))
+        mask = causal_mask if mask is None else mask + causal_mask
+    return F.scaled_dot_product_attention(q, k, v, attn_mask=mask).numpy()
+"#;
+        match mask {
+            Some(m) => run_py_prg(prg.to_string(), &[q, k, v, m], &[&causal], q.dt()),
+            None => run_py_prg(prg.to_string(), &[q, k, v], &[&causal], q.dt()),
+        }
+    }
+
+    #[derive(Arbitrary, Debug)]
+    struct AttentionProblem {
+        #[strategy(1..=2usize)]
+        B: usize,
+        #[strategy(1..=4usize)]
+        Hkv: usize,
+        #[strategy(1..=4usize)]
+        groups: usize,
+        #[strategy(1..=96usize)]
+        Sq: usize,
+        #[strategy(0..=64usize)]
+        past: usize,
+        #[strategy(prop_oneof![Just(32usize), Just(64), Just(80), Just(128)])]
+        D: usize,
+        causal: bool,
+        with_mask: bool,
+    }
+
+    fn run_attention_trial(device: &Device, problem: AttentionProblem, dt: DType) {
+        let AttentionProblem {
+            B,
+            Hkv,
+            groups,
+            Sq,
+            past,
+            D,
+            causal,
+            with_mask,
+        } = problem;
+        let Sk = Sq + past;
+        let q = Tensor::randn::<f32>(shape![B, Hkv * groups, Sq, D], Device::CPU);
+        let k = Tensor::randn::<f32>(shape![B, Hkv, Sk, D], Device::CPU);
+        let v = Tensor::randn::<f32>(shape![B, Hkv, Sk, D], Device::CPU);
+        let mask = with_mask.then(|| Tensor::randn::<f32>(shape![B, 1, Sq, Sk], Device::CPU));
+        let ground = ground_truth(&q, &k, &v, mask.as_ref(), causal).unwrap();
+
+        let to_device = |t: Tensor| t.to(device).unwrap().cast(dt).unwrap();
+        let (q, k, v) = (to_device(q), to_device(k), to_device(v));
+        let mask = mask.map(to_device);
+        let ours = q
+            .sdpa(k, v, mask, causal, None)
+            .unwrap()
+            .cast(DType::F32)
+            .unwrap()
+            .resolve()
+            .unwrap();
+        let ours = ours.to(&Device::CPU).unwrap();
+
+        println!("ours = {:?}", ours);
+        println!("ground = {:?}", ground);
+        let tol = if dt == DType::F16 { 1e-2 } else { 1e-4 };
+        ground.all_close(&ours, tol, tol).unwrap();
+    }
+
+    #[proptest(cases = 16)]
+    fn test_attention(prob: AttentionProblem) {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        println!("{:?}", prob);
+        run_attention_trial(&device, prob, DType::F32);
+    }
+
+    #[proptest(cases = 8)]
+    fn test_attention_f16(prob: AttentionProblem) {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        println!("{:?}", prob);
+        run_attention_trial(&device, prob, DType::F16);
+    }
+
+    #[proptest(cases = 8)]
+    fn test_attention_decode(prob: AttentionProblem, #[strategy(1..8usize)] Sq: usize) {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let prob = AttentionProblem { Sq, ..prob };
+        println!("{:?}", prob);
+        run_attention_trial(&device, prob, DType::F32);
+    }
+
+    #[proptest(cases = 8)]
+    fn test_attention_cpu(prob: AttentionProblem) {
+        println!("{:?}", prob);
+        run_attention_trial(&Device::CPU, prob, DType::F32);
+    }
+}
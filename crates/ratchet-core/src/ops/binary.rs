@@ -3,6 +3,7 @@ use encase::ShaderType;
 use half::f16;
 use inline_wgsl::wgsl;
 use ratchet_macros::WgslMetadata;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     gpu::{dtype::WgslDType, BindGroupLayoutDescriptor, CpuUniform},
@@ -14,7 +15,8 @@ use crate::{
 use test_strategy::Arbitrary;
 
 #[cfg_attr(test, derive(Arbitrary))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BinaryOp {
     Add,
     Sub,
@@ -44,9 +46,9 @@ impl BinaryOp {
 
 #[derive(new, Debug, Clone)]
 pub struct Binary {
-    lhs: Tensor,
-    rhs: Tensor,
-    op: BinaryOp,
+    pub(crate) lhs: Tensor,
+    pub(crate) rhs: Tensor,
+    pub(crate) op: BinaryOp,
 }
 
 impl Binary {
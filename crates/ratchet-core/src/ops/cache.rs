@@ -4,15 +4,75 @@ use glam::UVec4;
 use half::f16;
 use inline_wgsl::wgsl;
 use ratchet_macros::WgslMetadata;
+use serde::{Deserialize, Serialize};
 use wgpu::BindGroupLayoutEntry;
 
 use crate::{
-    gpu::{BindGroupLayoutDescriptor, BindGroupLayoutEntryExt, CpuUniform},
+    gpu::{dtype::WgslDType, BindGroupLayoutDescriptor, BindGroupLayoutEntryExt, CpuUniform},
     rvec, Array, BindingMode, BuiltIn, DType, KernelElement, KernelSource, MetaOperation, OpGuards,
     Operation, OperationError, RVec, Scalar, Shape, StorageView, Strides, Tensor, Vec2, Vec4,
     WgslKernelBuilder, WgslPrimitive, WorkgroupSize, Workload,
 };
 
+/// A rolling window over a KV cache.
+///
+/// The first `sinks` positions are kept for the lifetime of the cache (attention sinks), after
+/// which positions wrap around the following `window` slots, overwriting the oldest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, new)]
+pub struct RollingWindow {
+    pub sinks: usize,
+    pub window: usize,
+}
+
+impl RollingWindow {
+    /// Number of slots used by the window, including the sinks.
+    pub fn capacity(&self) -> usize {
+        self.sinks + self.window
+    }
+
+    /// The slot holding `position`.
+    pub fn slot(&self, position: usize) -> usize {
+        if position < self.sinks {
+            position
+        } else {
+            self.sinks + (position - self.sinks) % self.window
+        }
+    }
+
+    /// The latest position before `end` held in `slot`, if any.
+    pub fn position(&self, slot: usize, end: usize) -> Option<usize> {
+        if slot < self.sinks {
+            return (slot < end).then_some(slot);
+        }
+        let last = end.checked_sub(self.sinks + 1)?;
+        let behind = (last + self.window - (slot - self.sinks)) % self.window;
+        Some(self.sinks + last.checked_sub(behind)?)
+    }
+
+    /// The sinks & window written to the uniform. Without a window, every slot is a sink.
+    pub(crate) fn meta(window: Option<&Self>, n_slots: usize) -> (u32, u32) {
+        window.map_or((n_slots as u32, 1), |w| (w.sinks as u32, w.window as u32))
+    }
+
+    /// Writes `slotPosition`, the WGSL equivalent of [RollingWindow::position], returning
+    /// `end` where the slot holds no position.
+    pub(crate) fn write_slot_position(builder: &mut WgslKernelBuilder) {
+        builder.write_global(wgsl! {
+            fn slotPosition(slot: u32, end: u32, sinks: u32, window: u32) -> u32 {
+                if (slot < sinks) {
+                    return select(end, slot, slot < end);
+                }
+                if (end <= sinks) {
+                    return end;
+                }
+                let last = end - 1u - sinks;
+                let behind = (last + window - (slot - sinks)) % window;
+                return select(end, sinks + last - behind, behind <= last);
+            }
+        });
+    }
+}
+
 /// # Cache
 ///
 /// Custom operator used for KV caching. Custom operator to support quantized KV caching.
@@ -21,15 +81,35 @@ use crate::{
 /// 1. Cache, large partially filled tensors. E.g [1, 512, 1024], with [1, 5, 1024] filled.
 /// 2. Source, new K or V tensor, e.g [1, 1, 1024]
 /// 3. offset, where to start the write in the cache tensor, e.g [1, 5, 1024], [1, 1, 1024], offset = 5 -> [1, 6, 1024]
+///
+/// With a [RollingWindow], writes wrap around the window rather than growing the cache, and the
+/// output holds every occupied slot in slot order, e.g [1, sinks + window, 1024] once full.
+/// A single write cannot exceed the window.
+///
+/// If the cache is Q8_0, the source is in its activation dtype, and is quantized as it is
+/// written. Each block of 32 values along the last dimension shares a scale, so the last
+/// dimension must be a multiple of 32, and cannot be `dim`. The output is quantized too, and
+/// should be read by an operation which dequantizes it, e.g [crate::Attention].
 #[derive(new, Debug, Clone)]
 pub struct Cache {
-    cache: Tensor,
-    source: Tensor,
-    dim: usize,
-    offset: usize,
+    pub(crate) cache: Tensor,
+    pub(crate) source: Tensor,
+    pub(crate) dim: usize,
+    pub(crate) offset: usize,
+    pub(crate) window: Option<RollingWindow>,
 }
 
 impl Cache {
+    /// The position written to `slot` of the cache by this step, if any.
+    pub(crate) fn written_position(&self, slot: usize) -> Option<usize> {
+        let end = self.offset + self.source.shape()[self.dim];
+        let position = match &self.window {
+            Some(window) => window.position(slot, end)?,
+            None => slot,
+        };
+        (self.offset..end).contains(&position).then_some(position)
+    }
+
     fn register_bindings<P: WgslPrimitive>(
         &self,
         builder: &mut WgslKernelBuilder,
@@ -66,6 +146,7 @@ impl Cache {
         kernel_builder.write_metadata::<CacheMeta>();
         kernel_builder.write_offset_to_index();
         kernel_builder.write_index_to_offset();
+        RollingWindow::write_slot_position(&mut kernel_builder);
 
         kernel_builder.write_main(wgsl! {
             //Dispatch 1 thread per output element
@@ -79,23 +160,97 @@ impl Cache {
             var dst_index = offsetToNdIndex(dst_offset, metadata.dst_stride);
 
             let dim = metadata.dim;
-            if (dst_index[dim] < metadata.cum0) {
+            let cache_offset = ndIndexToOffset(dst_index, metadata.cache_stride);
+            let position = slotPosition(dst_index[dim], metadata.cum1, metadata.sinks, metadata.window);
+            if (position < metadata.cum0 || position >= metadata.cum1) {
                 //Inside cache, just copy from cache to DST
-                let src_offset = ndIndexToOffset(dst_index, metadata.cache_stride);
-                D[dst_offset] = C[src_offset];
+                D[dst_offset] = C[cache_offset];
+                return;
+            }
+
+            //Inside src, copy from src to cache and then to DST
+            dst_index[dim] = position - metadata.cum0;
+            let src_offset = ndIndexToOffset(dst_index, metadata.src_stride);
+            let val = S[src_offset];
+            C[cache_offset] = val;
+            D[dst_offset] = val;
+        });
+
+        Ok(kernel_builder.build()?)
+    }
+
+    /// Quantizes the source into a Q8_0 cache, one thread per block of 32 values.
+    /// Blocks already cached are copied as is.
+    fn build_quantized_cache<P: WgslPrimitive, D: WgslPrimitive>(
+        &self,
+        _: bool,
+        _: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = self.cache.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![
+                BuiltIn::WorkgroupId,
+                BuiltIn::LocalInvocationIndex,
+                BuiltIn::NumWorkgroups,
+            ],
+            device.compute_features().clone(),
+        );
+        let packed_arr = Array::<Scalar<u32>>::default();
+        kernel_builder.register_storage("C", BindingMode::ReadWrite, packed_arr);
+        kernel_builder.register_storage("CS", BindingMode::ReadWrite, Array::<D>::default());
+        kernel_builder.register_storage("S", BindingMode::ReadOnly, Array::<P>::default());
+        kernel_builder.register_storage("D", BindingMode::ReadWrite, packed_arr);
+        kernel_builder.register_storage("DS", BindingMode::ReadWrite, Array::<D>::default());
+        kernel_builder.register_uniform();
+        kernel_builder.write_metadata::<CacheMeta>();
+        kernel_builder.write_offset_to_index();
+        kernel_builder.write_index_to_offset();
+        RollingWindow::write_slot_position(&mut kernel_builder);
+
+        let scale = D::T::DT;
+        kernel_builder.write_main(wgsl! {
+            //Dispatch 1 thread per output block
+            let x_offset = workgroup_id.x * 64u;
+            let block = (workgroup_id.y * num_workgroups.x * 64u) + x_offset + local_invocation_index;
+            if (block >= metadata.dst_numel / 32u) {
                 return;
             }
+            let dst_offset = block * 32u;
+            var dst_index = offsetToNdIndex(dst_offset, metadata.dst_stride);
+            let cache_offset = ndIndexToOffset(dst_index, metadata.cache_stride);
 
-            if (dst_index[dim] < metadata.cum1) {
-                //Inside src, copy from src to cache and then to DST
-                let cache_offset = ndIndexToOffset(dst_index, metadata.cache_stride);
-                dst_index[dim] -= metadata.cum0;
-                let src_offset = ndIndexToOffset(dst_index, metadata.src_stride);
-                let val = S[src_offset];
-                C[cache_offset] = val;
-                D[dst_offset] = val;
+            let dim = metadata.dim;
+            let position = slotPosition(dst_index[dim], metadata.cum1, metadata.sinks, metadata.window);
+            if (position < metadata.cum0 || position >= metadata.cum1) {
+                //Inside cache, just copy the block from cache to DST
+                for (var i = 0u; i < 8u; i++) {
+                    D[dst_offset / 4u + i] = C[cache_offset / 4u + i];
+                }
+                DS[block] = CS[cache_offset / 32u];
                 return;
             }
+
+            //Inside src, quantize the block into both cache and DST
+            dst_index[dim] = position - metadata.cum0;
+            let src_offset = ndIndexToOffset(dst_index, metadata.src_stride);
+            var amax = 0f;
+            for (var i = 0u; i < 32u; i++) {
+                amax = max(amax, abs(f32(S[src_offset + i])));
+            }
+            let d = amax / 127f;
+            let id = select(0f, 1f / d, d > 0f);
+            for (var i = 0u; i < 8u; i++) {
+                let s = src_offset + i * 4u;
+                let v = vec4<f32>(f32(S[s]), f32(S[s + 1u]), f32(S[s + 2u]), f32(S[s + 3u]));
+                //pack4x8snorm rounds v * 127, undone by unpack
+                let packed = pack4x8snorm(v * id / 127f);
+                C[cache_offset / 4u + i] = packed;
+                D[dst_offset / 4u + i] = packed;
+            }
+            CS[cache_offset / 32u] = 'scale(d);
+            DS[block] = 'scale(d);
         });
 
         Ok(kernel_builder.build()?)
@@ -111,23 +266,48 @@ pub struct CacheMeta {
     cum0: u32,
     cum1: u32,
     dim: u32,
+    sinks: u32,
+    window: u32,
 }
 
 impl OpGuards for Cache {
     fn check_shapes(&self) {
         assert!(self.cache.rank() >= 3);
-        assert!(self.offset <= self.cache.shape()[self.dim]);
+        match &self.window {
+            Some(window) => {
+                assert!(window.window > 0);
+                assert!(window.capacity() <= self.cache.shape()[self.dim]);
+                assert!(
+                    self.source.shape()[self.dim] <= window.window,
+                    "A single write cannot exceed the window"
+                );
+            }
+            None => assert!(self.offset <= self.cache.shape()[self.dim]),
+        }
     }
 
     fn check_dtypes(&self) {
-        assert_eq!(self.cache.dt(), self.source.dt());
+        let cache_dt = self.cache.dt();
+        match cache_dt {
+            DType::Q8_0F(_) | DType::Q8_0H(_) => {
+                assert_eq!(cache_dt.activation_dt(), self.source.dt());
+                let last = self.cache.rank() - 1;
+                assert_ne!(self.dim, last, "Cannot quantize along the cached dimension");
+                assert_eq!(self.cache.shape()[last] % 32, 0);
+            }
+            _ => assert_eq!(cache_dt, self.source.dt()),
+        }
     }
 }
 
 impl Operation for Cache {
     fn compute_view(&self) -> Result<StorageView, OperationError> {
         let mut result_shape = self.cache.shape().clone();
-        result_shape[self.dim] = self.offset + self.source.shape()[self.dim];
+        let end = self.offset + self.source.shape()[self.dim];
+        result_shape[self.dim] = match &self.window {
+            Some(window) => end.min(window.capacity()),
+            None => end,
+        };
         let result_strides = Strides::from(&result_shape);
         Ok(StorageView::new(
             result_shape,
@@ -155,20 +335,33 @@ impl MetaOperation for Cache {
     }
 
     fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
-        Ok(Workload::std(dst.shape().numel(), self.kernel_element(dst)))
+        let numel = match dst.dt() {
+            DType::Q8_0F(_) | DType::Q8_0H(_) => dst.shape().numel() / 32,
+            _ => dst.shape().numel(),
+        };
+        Ok(Workload::std(numel, self.kernel_element(dst)))
     }
 
     fn storage_bind_group_layout(
         &self,
         _: bool,
     ) -> Result<BindGroupLayoutDescriptor, OperationError> {
-        Ok(BindGroupLayoutDescriptor {
-            entries: rvec![
+        //Quantized tensors bind their values & scales separately
+        let entries = match self.cache.dt() {
+            DType::Q8_0F(_) | DType::Q8_0H(_) => rvec![
+                BindGroupLayoutEntry::compute_storage_buffer(0, false),
+                BindGroupLayoutEntry::compute_storage_buffer(1, false),
+                BindGroupLayoutEntry::compute_storage_buffer(2, true),
+                BindGroupLayoutEntry::compute_storage_buffer(3, false),
+                BindGroupLayoutEntry::compute_storage_buffer(4, false)
+            ],
+            _ => rvec![
                 BindGroupLayoutEntry::compute_storage_buffer(0, false),
                 BindGroupLayoutEntry::compute_storage_buffer(1, true),
                 BindGroupLayoutEntry::compute_storage_buffer(2, false)
             ],
-        })
+        };
+        Ok(BindGroupLayoutDescriptor { entries })
     }
 
     fn write_metadata(
@@ -192,6 +385,7 @@ impl MetaOperation for Cache {
 
         let cum0 = self.offset as u32;
         let cum1 = cum0 + source_shape[promoted_dim] as u32;
+        let (sinks, window) = RollingWindow::meta(self.window.as_ref(), cache_shape[promoted_dim]);
 
         let meta = CacheMeta {
             cache_stride: UVec4::from(&cache_strides),
@@ -201,6 +395,8 @@ impl MetaOperation for Cache {
             cum0,
             cum1,
             dim: promoted_dim as u32,
+            sinks,
+            window,
         };
 
         Ok(uniform.write(&meta)?)
@@ -232,6 +428,12 @@ impl MetaOperation for Cache {
             (DType::F16, KernelElement::Vec4) => {
                 self.build_cache::<Vec4<f16>>(inplace, dst, workgroup_size)
             }
+            (DType::Q8_0F(_), KernelElement::Scalar) => {
+                self.build_quantized_cache::<Scalar<f32>, Scalar<f32>>(inplace, dst, workgroup_size)
+            }
+            (DType::Q8_0H(_), KernelElement::Scalar) => {
+                self.build_quantized_cache::<Scalar<f16>, Scalar<f16>>(inplace, dst, workgroup_size)
+            }
             _ => Err(OperationError::CompileError(format!(
                 "Unsupported dtype {:?} or kernel element {:?}",
                 dst.dt(),
@@ -241,9 +443,232 @@ impl MetaOperation for Cache {
     }
 }
 
+/// # PagedCache
+///
+/// Paged counterpart of [Cache], used for paged KV caching.
+///
+/// Takes in 4 arguments:
+/// 1. Pool, fixed-size blocks shared by every sequence. E.g [64, 16, 1024], 64 blocks of 16 positions.
+/// 2. Source, new K or V tensor for each sequence in the batch, e.g [2, 1, 1024]
+/// 3. Block table, the blocks holding each sequence in order, e.g [2, 8] U32
+/// 4. offset, the number of positions already cached, shared by every sequence of the batch
+///
+/// The source is written into the pool at positions `offset..offset + S` of each sequence, and
+/// every cached position is gathered into a dense output, e.g [2, offset + 1, 1024].
+/// The first dimension of the pool indexes blocks, where the first dimension of the source
+/// indexes sequences. `dim` is the sequence dimension of both.
+#[derive(new, Debug, Clone)]
+pub struct PagedCache {
+    pub(crate) pool: Tensor,
+    pub(crate) source: Tensor,
+    pub(crate) block_table: Tensor,
+    pub(crate) dim: usize,
+    pub(crate) offset: usize,
+}
+
+impl PagedCache {
+    pub fn block_size(&self) -> usize {
+        self.pool.shape()[self.dim]
+    }
+
+    fn build_paged_cache<P: WgslPrimitive>(
+        &self,
+        _: bool,
+        _: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError>
+    where
+        P::T: num_traits::Float,
+    {
+        let device = self.pool.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![
+                BuiltIn::WorkgroupId,
+                BuiltIn::LocalInvocationIndex,
+                BuiltIn::NumWorkgroups,
+            ],
+            device.compute_features().clone(),
+        );
+        kernel_builder.register_storage("P", BindingMode::ReadWrite, Array::<P>::default());
+        kernel_builder.register_storage("S", BindingMode::ReadOnly, Array::<P>::default());
+        kernel_builder.register_storage(
+            "T",
+            BindingMode::ReadOnly,
+            Array::<Scalar<u32>>::default(),
+        );
+        kernel_builder.register_storage("D", BindingMode::ReadWrite, Array::<P>::default());
+        kernel_builder.register_uniform();
+        kernel_builder.write_metadata::<PagedCacheMeta>();
+        kernel_builder.write_offset_to_index();
+        kernel_builder.write_index_to_offset();
+
+        kernel_builder.write_main(wgsl! {
+            //Dispatch 1 thread per output element
+            let x_offset = workgroup_id.x * 64u;
+            let dst_offset = (workgroup_id.y * num_workgroups.x * 64u) + x_offset + local_invocation_index;
+            if (dst_offset >= metadata.dst_numel) {
+                return;
+            }
+            var dst_index = offsetToNdIndex(dst_offset, metadata.dst_stride);
+
+            //Translate the position within the sequence into a slot within one of its blocks
+            let dim = metadata.dim;
+            let position = dst_index[dim];
+            var pool_index = dst_index;
+            let table_offset = dst_index[metadata.batch_dim] * metadata.max_blocks;
+            pool_index[metadata.batch_dim] = T[table_offset + position / metadata.block_size];
+            pool_index[dim] = position % metadata.block_size;
+            let pool_offset = ndIndexToOffset(pool_index, metadata.pool_stride);
+
+            if (position < metadata.offset) {
+                //Already cached, just copy from the pool to DST
+                D[dst_offset] = P[pool_offset];
+                return;
+            }
+
+            //Inside src, copy from src to the pool and then to DST
+            dst_index[dim] -= metadata.offset;
+            let src_offset = ndIndexToOffset(dst_index, metadata.src_stride);
+            let val = S[src_offset];
+            P[pool_offset] = val;
+            D[dst_offset] = val;
+        });
+
+        Ok(kernel_builder.build()?)
+    }
+}
+
+#[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
+pub struct PagedCacheMeta {
+    pool_stride: glam::UVec4,
+    src_stride: glam::UVec4,
+    dst_stride: glam::UVec4,
+    dst_numel: u32,
+    offset: u32,
+    dim: u32,
+    batch_dim: u32,
+    block_size: u32,
+    max_blocks: u32,
+}
+
+impl OpGuards for PagedCache {
+    fn check_shapes(&self) {
+        let (pool, source, table) = (
+            self.pool.shape(),
+            self.source.shape(),
+            self.block_table.shape(),
+        );
+        assert!(matches!(pool.rank(), 3 | 4));
+        assert_eq!(pool.rank(), source.rank());
+        assert!(self.dim > 0 && self.dim < pool.rank());
+        for d in (1..pool.rank()).filter(|&d| d != self.dim) {
+            assert_eq!(pool[d], source[d]);
+        }
+        assert_eq!(table.rank(), 2);
+        assert_eq!(table[0], source[0]);
+        assert!(self.offset + source[self.dim] <= table[1] * self.block_size());
+    }
+
+    fn check_dtypes(&self) {
+        assert_eq!(self.pool.dt(), self.source.dt());
+        assert_eq!(self.block_table.dt(), DType::U32);
+    }
+}
+
+impl Operation for PagedCache {
+    fn compute_view(&self) -> Result<StorageView, OperationError> {
+        let mut result_shape = self.source.shape().clone();
+        result_shape[self.dim] += self.offset;
+        let result_strides = Strides::from(&result_shape);
+        Ok(StorageView::new(
+            result_shape,
+            self.pool.dt(),
+            result_strides,
+        ))
+    }
+}
+
+impl MetaOperation for PagedCache {
+    fn kernel_name(&self) -> String {
+        "paged_cache".to_string()
+    }
+
+    fn srcs(&self) -> RVec<&Tensor> {
+        rvec![&self.pool, &self.source, &self.block_table]
+    }
+
+    fn kernel_element(&self, _dst: &Tensor) -> KernelElement {
+        KernelElement::Scalar
+    }
+
+    fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        Ok(Workload::std(dst.shape().numel(), self.kernel_element(dst)))
+    }
+
+    fn storage_bind_group_layout(
+        &self,
+        _: bool,
+    ) -> Result<BindGroupLayoutDescriptor, OperationError> {
+        Ok(BindGroupLayoutDescriptor {
+            entries: rvec![
+                BindGroupLayoutEntry::compute_storage_buffer(0, false),
+                BindGroupLayoutEntry::compute_storage_buffer(1, true),
+                BindGroupLayoutEntry::compute_storage_buffer(2, true),
+                BindGroupLayoutEntry::compute_storage_buffer(3, false)
+            ],
+        })
+    }
+
+    fn write_metadata(
+        &self,
+        uniform: &mut CpuUniform,
+        dst: &Tensor,
+        _: &KernelElement,
+    ) -> Result<u64, OperationError> {
+        let promotion = 4 - self.pool.rank();
+
+        let pool_shape = Shape::promote(self.pool.shape().clone(), 4);
+        let source_shape = Shape::promote(self.source.shape().clone(), 4);
+        let dst_shape = Shape::promote(dst.shape().clone(), 4);
+
+        let meta = PagedCacheMeta {
+            pool_stride: UVec4::from(&Strides::from(&pool_shape)),
+            src_stride: UVec4::from(&Strides::from(&source_shape)),
+            dst_stride: UVec4::from(&Strides::from(&dst_shape)),
+            dst_numel: dst_shape.numel() as u32,
+            offset: self.offset as u32,
+            dim: (self.dim + promotion) as u32,
+            batch_dim: promotion as u32,
+            block_size: self.block_size() as u32,
+            max_blocks: self.block_table.shape()[1] as u32,
+        };
+
+        Ok(uniform.write(&meta)?)
+    }
+
+    fn build_kernel(
+        &self,
+        inplace: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        match dst.dt() {
+            DType::F32 => self.build_paged_cache::<Scalar<f32>>(inplace, dst, workgroup_size),
+            DType::F16 => self.build_paged_cache::<Scalar<f16>>(inplace, dst, workgroup_size),
+            dt => Err(OperationError::CompileError(format!(
+                "Unsupported dtype {:?} for paged cache",
+                dt
+            ))),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{rvec, shape, Device, DeviceRequest, Tensor};
+    use crate::{
+        rvec, shape, Device, DeviceRequest, Quantization, Quantizer, RollingWindow, Tensor,
+    };
 
     thread_local! {
         static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
@@ -285,4 +710,204 @@ mod tests {
         result.all_close(&ground_truth, 1e-5, 1e-5).unwrap();
         Ok(())
     }
+
+    fn run_rolling_cache_trial(device: &Device) -> anyhow::Result<()> {
+        let (H, D) = (2, 4);
+        let window = RollingWindow::new(1, 3);
+        let cache = Tensor::zeros::<f32>(&shape![1, H, window.capacity(), D], device);
+
+        //The host copy of each slot, for each head
+        let mut slots = vec![vec![vec![0f32; D]; window.capacity()]; H];
+        let mut offset = 0;
+        for S in [2, 2, 1, 3, 3] {
+            let source = Tensor::randn::<f32>(shape![1, H, S, D], Device::CPU);
+            let values = source.to_vec::<f32>()?;
+            for (h, head) in slots.iter_mut().enumerate() {
+                for s in 0..S {
+                    let start = (h * S + s) * D;
+                    head[window.slot(offset + s)] = values[start..start + D].to_vec();
+                }
+            }
+            offset += S;
+
+            let result = cache
+                .clone()
+                .rolling_cache(source.to(device)?, 2, offset - S, window)?
+                .resolve()?
+                .to(&Device::CPU)?;
+            let occupied = offset.min(window.capacity());
+            assert_eq!(result.shape(), &shape![1, H, occupied, D]);
+            let expected = slots
+                .iter()
+                .flat_map(|head| head[..occupied].concat())
+                .collect::<Vec<_>>();
+            assert_eq!(result.to_vec::<f32>()?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rolling_cache() -> anyhow::Result<()> {
+        run_rolling_cache_trial(&GPU_DEVICE.with(|d| d.clone()))
+    }
+
+    #[test]
+    fn test_rolling_cache_cpu() -> anyhow::Result<()> {
+        run_rolling_cache_trial(&Device::CPU)
+    }
+
+    fn run_quantized_cache_trial(device: &Device) -> anyhow::Result<()> {
+        let (H, S, D, offset) = (2, 6, 32, 2);
+        let quantizer = Quantizer::new(Quantization::SInt8);
+        let cache = quantizer.sint8_quantize(Tensor::randn::<f32>(shape![1, H, S, D], Device::CPU));
+        let source = Tensor::randn::<f32>(shape![1, H, 1, D], Device::CPU);
+
+        let cached =
+            quantizer
+                .sint8_dequantize(cache.deep_clone())
+                .slice(&[0..1, 0..H, 0..offset, 0..D])?;
+        let ground_truth = Tensor::cat(rvec![cached, source.clone()], 2)?.resolve()?;
+
+        let kv = cache
+            .to(device)?
+            .cache(source.to(device)?, 2, offset)?
+            .resolve()?;
+        let result = quantizer.sint8_dequantize(kv.to(&Device::CPU)?.deep_clone());
+        //Within half a step of a block with an absmax of ~5
+        result.all_close(&ground_truth, 0.02, 0.)?;
+
+        //Attention over the quantized K & V matches attention over their dequantized values
+        let query = Tensor::randn::<f32>(shape![1, H, 1, D], Device::CPU);
+        let expected = query
+            .clone()
+            .sdpa(result.clone(), result, None, false, None)?
+            .resolve()?;
+        let attended = query
+            .to(device)?
+            .sdpa(kv.clone(), kv, None, false, None)?
+            .resolve()?
+            .to(&Device::CPU)?;
+        attended.all_close(&expected, 1e-4, 1e-4)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantized_cache() -> anyhow::Result<()> {
+        run_quantized_cache_trial(&GPU_DEVICE.with(|d| d.clone()))
+    }
+
+    #[test]
+    fn test_quantized_cache_cpu() -> anyhow::Result<()> {
+        run_quantized_cache_trial(&Device::CPU)
+    }
+
+    fn run_quantized_rolling_cache_trial(device: &Device) -> anyhow::Result<()> {
+        let (H, D) = (2, 32);
+        let window = RollingWindow::new(1, 3);
+        let quantizer = Quantizer::new(Quantization::SInt8);
+        let zeros = Tensor::zeros::<f32>(&shape![1, H, window.capacity(), D], &Device::CPU);
+        let cache = quantizer.sint8_quantize(zeros).to(device)?;
+
+        //The host copy of each slot, for each head
+        let mut slots = vec![vec![vec![0f32; D]; window.capacity()]; H];
+        let mut offset = 0;
+        for S in [2, 2, 1, 3, 3] {
+            let source = Tensor::randn::<f32>(shape![1, H, S, D], Device::CPU);
+            let values = source.to_vec::<f32>()?;
+            for (h, head) in slots.iter_mut().enumerate() {
+                for s in 0..S {
+                    let start = (h * S + s) * D;
+                    head[window.slot(offset + s)] = values[start..start + D].to_vec();
+                }
+            }
+            offset += S;
+
+            let kv = cache
+                .clone()
+                .rolling_cache(source.to(device)?, 2, offset - S, window)?
+                .resolve()?;
+            let result = quantizer.sint8_dequantize(kv.to(&Device::CPU)?.deep_clone());
+            let occupied = offset.min(window.capacity());
+            let expected = slots
+                .iter()
+                .flat_map(|head| head[..occupied].concat())
+                .collect::<Vec<_>>();
+            let expected = Tensor::from_data(expected, shape![1, H, occupied, D], Device::CPU);
+            //Each slot is a block of its own, within half a step of an absmax of ~5
+            result.all_close(&expected, 0.02, 0.)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantized_rolling_cache() -> anyhow::Result<()> {
+        run_quantized_rolling_cache_trial(&GPU_DEVICE.with(|d| d.clone()))
+    }
+
+    #[test]
+    fn test_quantized_rolling_cache_cpu() -> anyhow::Result<()> {
+        run_quantized_rolling_cache_trial(&Device::CPU)
+    }
+
+    /// The dense cache of each sequence, gathered from its blocks on the host.
+    fn gather_pages(pool: &Tensor, blocks: &[u32], len: usize) -> anyhow::Result<Tensor> {
+        let [_, H, BS, D]: [usize; 4] = pool.shape().try_into()?;
+        let pages = blocks
+            .iter()
+            .map(|&b| {
+                pool.clone()
+                    .slice(&[b as usize..b as usize + 1, 0..H, 0..BS, 0..D])
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Tensor::cat(pages, 2)?
+            .slice(&[0..1, 0..H, 0..len, 0..D])?
+            .resolve()
+    }
+
+    fn run_paged_cache_trial(device: &Device) -> anyhow::Result<()> {
+        let (H, BS, D, offset, S) = (2, 4, 8, 5, 3);
+        let tables = [[3u32, 0, 5], [1, 4, 2]];
+        let pool = Tensor::randn::<f32>(shape![6, H, BS, D], Device::CPU);
+        let source = Tensor::randn::<f32>(shape![2, H, S, D], Device::CPU);
+
+        //Positions before the offset come from the pool, the rest from the source
+        let ground_truth = (0..2)
+            .map(|b| {
+                let cached = gather_pages(&pool, &tables[b], offset)?;
+                let new = source.clone().slice(&[b..b + 1, 0..H, 0..S, 0..D])?;
+                Tensor::cat(rvec![cached, new], 2)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let ground_truth = Tensor::cat(ground_truth, 0)?.resolve()?;
+
+        let block_table = Tensor::from_data(tables.concat(), shape![2, 3], device.clone());
+        let pool = pool.to(device)?;
+        let result = pool
+            .clone()
+            .paged_cache(source.to(device)?, block_table, 2, offset)?
+            .resolve()?
+            .to(&Device::CPU)?;
+        result.all_close(&ground_truth, 1e-5, 1e-5)?;
+
+        //The source must also have been written into the pages
+        let pool = pool.to(&Device::CPU)?;
+        for (b, table) in tables.iter().enumerate() {
+            let written = gather_pages(&pool, table, offset + S)?;
+            let expected = ground_truth
+                .clone()
+                .slice(&[b..b + 1, 0..H, 0..offset + S, 0..D])?;
+            written.all_close(&expected.resolve()?, 1e-5, 1e-5)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_paged_cache() -> anyhow::Result<()> {
+        run_paged_cache_trial(&GPU_DEVICE.with(|d| d.clone()))
+    }
+
+    #[test]
+    fn test_paged_cache_cpu() -> anyhow::Result<()> {
+        run_paged_cache_trial(&Device::CPU)
+    }
 }
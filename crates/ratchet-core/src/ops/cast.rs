@@ -13,8 +13,8 @@ use crate::{
 
 #[derive(new, Debug, Clone)]
 pub struct Cast {
-    input: Tensor,
-    dst_dt: DType,
+    pub(crate) input: Tensor,
+    pub(crate) dst_dt: DType,
 }
 
 impl Cast {
@@ -65,6 +65,84 @@ impl Cast {
 
         Ok(kernel_builder.build()?)
     }
+
+    fn is_bf16(&self) -> bool {
+        self.input.dt() == DType::BF16 || self.dst_dt == DType::BF16
+    }
+
+    /// WGSL has no bfloat16 type, so BF16 is stored as pairs packed into a `u32`, low half first.
+    /// Each invocation packs or unpacks a single pair, to or from F32.
+    fn build_bf16_cast(
+        &self,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = self.input.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![
+                BuiltIn::WorkgroupId,
+                BuiltIn::LocalInvocationIndex,
+                BuiltIn::NumWorkgroups
+            ],
+            device.compute_features().clone(),
+        );
+
+        let pack = match (self.input.dt(), self.dst_dt) {
+            (DType::F32, DType::BF16) => true,
+            (DType::BF16, DType::F32) => false,
+            (src, dst) => {
+                return Err(OperationError::CompileError(format!(
+                    "BF16 can only be cast to or from F32, got {:?} to {:?}",
+                    src, dst
+                )))
+            }
+        };
+        if pack {
+            self.register_bindings::<Scalar<f32>, Scalar<u32>>(&mut kernel_builder, false)?;
+        } else {
+            self.register_bindings::<Scalar<u32>, Scalar<f32>>(&mut kernel_builder, false)?;
+        }
+        kernel_builder.write_metadata::<CastMeta>();
+
+        kernel_builder.write_main(wgsl! {
+            let x_offset = workgroup_id.x * 64u;
+            let index = (workgroup_id.y * num_workgroups.x * 64u) + x_offset + local_invocation_index;
+            if (index >= (metadata.numel + 1u) / 2u) {
+                return;
+            }
+            let has_hi = index * 2u + 1u < metadata.numel;
+        });
+
+        if pack {
+            //Round to nearest even, keeping NaNs quiet
+            kernel_builder.write_global(wgsl! {
+                fn pack_bf16(x: f32) -> u32 {
+                    let bits = bitcast<u32>(x);
+                    if ((bits & 2147483647u) > 2139095040u) {
+                        return (bits >> 16u) | 64u;
+                    }
+                    return (bits + 32767u + ((bits >> 16u) & 1u)) >> 16u;
+                }
+            });
+            kernel_builder.write_main(wgsl! {
+                var hi = 0u;
+                if (has_hi) {
+                    hi = pack_bf16(X[index * 2u + 1u]);
+                }
+                Y[index] = pack_bf16(X[index * 2u]) | (hi << 16u);
+            });
+        } else {
+            kernel_builder.write_main(wgsl! {
+                let packed = X[index];
+                Y[index * 2u] = bitcast<f32>(packed << 16u);
+                if (has_hi) {
+                    Y[index * 2u + 1u] = bitcast<f32>(packed & 4294901760u);
+                }
+            });
+        }
+
+        Ok(kernel_builder.build()?)
+    }
 }
 
 #[derive(Debug, ShaderType, WgslMetadata)]
@@ -103,7 +181,9 @@ impl MetaOperation for Cast {
 
     fn kernel_element(&self, _: &Tensor) -> KernelElement {
         let numel = self.input.shape().numel();
-        if numel % 4 == 0 {
+        if self.is_bf16() {
+            KernelElement::Scalar
+        } else if numel % 4 == 0 {
             KernelElement::Vec4
         } else if numel % 2 == 0 {
             KernelElement::Vec2
@@ -113,6 +193,10 @@ impl MetaOperation for Cast {
     }
 
     fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        if self.is_bf16() {
+            let pairs = dst.shape().numel().div_ceil(2);
+            return Ok(Workload::std(pairs, KernelElement::Scalar));
+        }
         Ok(Workload::std(dst.shape().numel(), self.kernel_element(dst)))
     }
 
@@ -140,6 +224,9 @@ impl MetaOperation for Cast {
         dst: &Tensor,
         workgroup_size: &WorkgroupSize,
     ) -> Result<KernelSource, OperationError> {
+        if self.is_bf16() {
+            return self.build_bf16_cast(workgroup_size);
+        }
         let kernel_element = self.kernel_element(dst);
         match (self.input.dt(), self.dst_dt, &kernel_element) {
             (DType::F32, DType::F16, KernelElement::Scalar) => {
@@ -207,7 +294,7 @@ impl MetaOperation for Cast {
 
 #[cfg(all(test, feature = "pyo3"))]
 mod tests {
-    use half::f16;
+    use half::{bf16, f16};
     use test_strategy::{proptest, Arbitrary};
 
     use crate::{shape, test_util::run_py_prg, DType, Device, DeviceRequest, Tensor};
@@ -264,4 +351,24 @@ def cast(a):
     fn test_type_cast(prob: CastProblem) {
         run_cast_trial(prob).unwrap();
     }
+
+    #[test]
+    fn test_bf16_round_trip() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        //Odd number of elements, so the last pair is half empty
+        let input = Tensor::randn::<f32>(shape![3, 5, 7], Device::CPU);
+        let expected = input
+            .to_vec::<f32>()?
+            .into_iter()
+            .map(bf16::from_f32)
+            .collect::<Vec<_>>();
+
+        let packed = input.to(&device)?.cast(DType::BF16)?.resolve()?;
+        assert_eq!(packed.to(&Device::CPU)?.to_vec::<bf16>()?, expected);
+
+        let unpacked = packed.cast(DType::F32)?.resolve()?.to(&Device::CPU)?;
+        let expected = expected.iter().map(|x| x.to_f32()).collect::<Vec<_>>();
+        assert_eq!(unpacked.to_vec::<f32>()?, expected);
+        Ok(())
+    }
 }
@@ -12,8 +12,8 @@ use crate::{
 
 #[derive(new, Debug, Clone)]
 pub struct Concat {
-    inputs: RVec<Tensor>,
-    dim: usize,
+    pub(crate) inputs: RVec<Tensor>,
+    pub(crate) dim: usize,
 }
 
 impl Concat {
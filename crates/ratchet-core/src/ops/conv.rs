@@ -14,15 +14,22 @@ use inline_wgsl::wgsl;
 
 #[derive(new, Debug, Clone)]
 pub struct Conv {
-    input: Tensor,
-    weight: Tensor,
-    bias: Option<Tensor>,
-    stride: usize,
-    padding: usize,
-    //dilation: usize, TODO: implement dilation
+    pub(crate) input: Tensor,
+    pub(crate) weight: Tensor,
+    pub(crate) bias: Option<Tensor>,
+    pub(crate) stride: usize,
+    pub(crate) padding: usize,
+    pub(crate) dilation: usize,
+    pub(crate) groups: usize,
 }
 
 impl Conv {
+    /// The hand-tuned kernel only handles dense, undilated convolutions with a kernel size of 3.
+    fn is_standard(&self) -> bool {
+        let [_, _, KS]: [usize; 3] = self.weight.shape().try_into().unwrap();
+        KS == 3 && self.dilation == 1 && self.groups == 1
+    }
+
     fn register_bindings<P: WgslPrimitive>(
         &self,
         builder: &mut WgslKernelBuilder,
@@ -122,26 +129,90 @@ impl Conv {
 
         Ok(kernel_builder.build()?)
     }
+
+    /// One thread per output element, supporting any kernel size, dilation & groups.
+    fn build_conv_general<P: WgslPrimitive>(
+        &self,
+        inplace: bool,
+        _: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = self.input.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![
+                BuiltIn::WorkgroupId,
+                BuiltIn::LocalInvocationIndex,
+                BuiltIn::NumWorkgroups
+            ],
+            device.compute_features().clone(),
+        );
+        self.register_bindings::<P>(&mut kernel_builder, inplace)?;
+        kernel_builder.write_metadata::<ConvMeta>();
+
+        let dt = P::T::DT;
+        kernel_builder.write_main(wgsl! {
+            let x_offset = workgroup_id.x * 64u;
+            let index = (workgroup_id.y * num_workgroups.x * 64u) + x_offset + local_invocation_index;
+            if (index >= metadata.dst_numel) {
+                return;
+            }
+
+            let l = index % metadata.Lout;
+            let co = (index / metadata.Lout) % metadata.Cout;
+            let n = index / (metadata.Lout * metadata.Cout);
+            let Cg = metadata.Cin / metadata.groups;
+            let g = co / (metadata.Cout / metadata.groups);
+
+            var acc = B[co];
+            for (var ci = 0u; ci < Cg; ci++) {
+                let input_start = (n * metadata.Cin + g * Cg + ci) * metadata.Lin;
+                let filter_start = (co * Cg + ci) * metadata.KS;
+                for (var k = 0u; k < metadata.KS; k++) {
+                    let pos = i32(l * metadata.stride + k * metadata.dilation) - i32(metadata.padding);
+                    if (pos >= 0 && pos < i32(metadata.Lin)) {
+                        acc = fma(X[input_start + u32(pos)], W[filter_start + k], acc);
+                    }
+                }
+            }
+            Y[index] = acc;
+        });
+
+        Ok(kernel_builder.build()?)
+    }
 }
 
 #[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
 pub struct ConvMeta {
     stride: u32,
     padding: u32,
+    dilation: u32,
+    groups: u32,
     Cin: u32,
     Lin: u32,
+    Cout: u32,
     KS: u32,
     F_numel: u32,
     Lout: u32,
     Fperthread: u32,
+    dst_numel: u32,
 }
 
 impl OpGuards for Conv {
     fn check_shapes(&self) {
         assert_eq!(self.input.rank(), 3);
         assert_eq!(self.weight.rank(), 3);
-        let [_, _, KS]: [usize; 3] = self.weight.shape().try_into().unwrap();
-        assert_eq!(KS, 3); //only have 3 kernel size for now
+        let [_, C_in, L_in]: [usize; 3] = self.input.shape().try_into().unwrap();
+        let [C_out, C_g, KS]: [usize; 3] = self.weight.shape().try_into().unwrap();
+        assert!(self.groups > 0 && C_in % self.groups == 0 && C_out % self.groups == 0);
+        assert_eq!(C_in / self.groups, C_g);
+        assert!(self.stride > 0 && self.dilation > 0);
+        assert!(
+            L_in + 2 * self.padding > self.dilation * (KS - 1),
+            "Kernel of size {} with dilation {} does not fit the padded input",
+            KS,
+            self.dilation
+        );
     }
 
     fn check_dtypes(&self) {
@@ -160,14 +231,10 @@ impl Operation for Conv {
         let input_t = &self.input;
         let weight_t = &self.weight;
         let (input_shape, weight_shape) = (input_t.shape(), weight_t.shape());
-        let calc_dim = |i_size, k_size, pad, dil, stride| {
-            ((i_size + (2 * pad) - dil * (k_size - 1) - 1) / stride) + 1 //TODO: Missing floor
-        };
         let [N, _C_in, L_in]: [usize; 3] = input_shape.try_into()?;
         let [C_out, _, KS]: [usize; 3] = weight_shape.try_into()?;
-        assert!(KS == 3, "Only 3 kernel size is supported");
 
-        let L_out = calc_dim(L_in, KS, self.padding, 1, self.stride);
+        let L_out = conv_output_size(L_in, KS, self.padding, self.dilation, self.stride);
         let out_shape = shape![N, C_out, L_out];
         let out_strides = Strides::from(&out_shape);
         Ok(StorageView::new(out_shape, input_t.dt(), out_strides))
@@ -176,7 +243,11 @@ impl Operation for Conv {
 
 impl MetaOperation for Conv {
     fn kernel_name(&self) -> String {
-        "conv".to_string()
+        if self.is_standard() {
+            "conv".to_string()
+        } else {
+            "conv_general".to_string()
+        }
     }
 
     fn srcs(&self) -> RVec<&Tensor> {
@@ -194,6 +265,16 @@ impl MetaOperation for Conv {
         workgroup_size: &WorkgroupSize,
     ) -> Result<KernelSource, OperationError> {
         let kernel_element = self.kernel_element(dst);
+        if !self.is_standard() {
+            return match self.input.dt() {
+                DType::F32 => self.build_conv_general::<Scalar<f32>>(inplace, dst, workgroup_size),
+                DType::F16 => self.build_conv_general::<Scalar<f16>>(inplace, dst, workgroup_size),
+                dt => Err(OperationError::CompileError(format!(
+                    "Unsupported dtype {:?} for conv",
+                    dt
+                ))),
+            };
+        }
         match (self.input.dt(), &kernel_element) {
             (DType::F32, KernelElement::Scalar) => {
                 self.build_conv::<Scalar<f32>>(inplace, dst, workgroup_size)
@@ -221,7 +302,10 @@ impl MetaOperation for Conv {
         }
     }
 
-    fn calculate_dispatch(&self, _dst: &Tensor) -> Result<Workload, OperationError> {
+    fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        if !self.is_standard() {
+            return Ok(Workload::std(dst.shape().numel(), self.kernel_element(dst)));
+        }
         let workgroup_size = wgs![256, 1, 1];
 
         let input = &self.input;
@@ -250,25 +334,245 @@ impl MetaOperation for Conv {
         _: &KernelElement,
     ) -> Result<u64, OperationError> {
         let [_N, Cin, Lin]: [usize; 3] = self.input.shape().try_into()?;
-        let [_Cout, _, KS]: [usize; 3] = self.weight.shape().try_into()?;
+        let [Cout, _, KS]: [usize; 3] = self.weight.shape().try_into()?;
         let [_, _, Lout]: [usize; 3] = dst.shape().try_into()?;
         let F_numel = Cin * KS;
         let Fperthread = WorkgroupCount::div_ceil(F_numel, 256);
         let meta = ConvMeta::new(
             self.stride as _,
             self.padding as _,
+            self.dilation as _,
+            self.groups as _,
             Cin as _,
             Lin as _,
+            Cout as _,
             KS as _,
             F_numel as _,
             Lout as _,
             Fperthread as _,
+            dst.shape().numel() as _,
         );
 
         Ok(uniform.write(&meta)?)
     }
 }
 
+/// Output length of a convolution along a single spatial dimension.
+pub(crate) fn conv_output_size(
+    input: usize,
+    kernel: usize,
+    padding: usize,
+    dilation: usize,
+    stride: usize,
+) -> usize {
+    (input + 2 * padding - dilation * (kernel - 1) - 1) / stride + 1
+}
+
+/// # Im2Col
+///
+/// Unfolds the receptive field of every output position of a 2-D convolution into a column,
+/// turning the convolution into a GEMM against the flattened filters (see [Tensor::conv2d]).
+///
+/// The input must be a contiguous `[N, C, H, W]`, and is unfolded into
+/// `[N, groups, (C / groups) * KH * KW, H_out * W_out]`.
+/// Positions that fall into the padding are zero.
+#[derive(new, Debug, Clone)]
+pub struct Im2Col {
+    pub(crate) input: Tensor,
+    pub(crate) kernel_size: [usize; 2],
+    pub(crate) stride: [usize; 2],
+    pub(crate) padding: [usize; 2],
+    pub(crate) dilation: [usize; 2],
+    pub(crate) groups: usize,
+}
+
+impl Im2Col {
+    pub(crate) fn output_size(&self) -> [usize; 2] {
+        let [_, _, H, W]: [usize; 4] = self.input.shape().try_into().unwrap();
+        let [KH, KW] = self.kernel_size;
+        [
+            conv_output_size(H, KH, self.padding[0], self.dilation[0], self.stride[0]),
+            conv_output_size(W, KW, self.padding[1], self.dilation[1], self.stride[1]),
+        ]
+    }
+
+    fn build_im2col<P: WgslPrimitive>(
+        &self,
+        _: bool,
+        _: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = self.input.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![
+                BuiltIn::WorkgroupId,
+                BuiltIn::LocalInvocationIndex,
+                BuiltIn::NumWorkgroups
+            ],
+            device.compute_features().clone(),
+        );
+        let arr = Array::<P>::default();
+        kernel_builder.register_storage("X", BindingMode::ReadOnly, arr);
+        kernel_builder.register_storage("Y", BindingMode::ReadWrite, arr);
+        kernel_builder.register_uniform();
+        kernel_builder.write_metadata::<Im2ColMeta>();
+
+        let dt = P::T::DT;
+        kernel_builder.write_main(wgsl! {
+            let x_offset = workgroup_id.x * 64u;
+            let index = (workgroup_id.y * num_workgroups.x * 64u) + x_offset + local_invocation_index;
+            if (index >= metadata.dst_numel) {
+                return;
+            }
+
+            let L = metadata.Hout * metadata.Wout;
+            let K = metadata.Cg * metadata.KH * metadata.KW;
+            let l = index % L;
+            let k = (index / L) % K;
+            //Input channels of a group are contiguous, so (batch, group) selects the first plane
+            let plane = (index / (L * K)) * metadata.Cg + k / (metadata.KH * metadata.KW);
+            let kh = (k / metadata.KW) % metadata.KH;
+            let kw = k % metadata.KW;
+
+            let ih = i32((l / metadata.Wout) * metadata.stride_h + kh * metadata.dilation_h) - i32(metadata.pad_h);
+            let iw = i32((l % metadata.Wout) * metadata.stride_w + kw * metadata.dilation_w) - i32(metadata.pad_w);
+            if (ih < 0 || iw < 0 || ih >= i32(metadata.H) || iw >= i32(metadata.W)) {
+                Y[index] = 'dt(0.0);
+                return;
+            }
+            Y[index] = X[(plane * metadata.H + u32(ih)) * metadata.W + u32(iw)];
+        });
+
+        Ok(kernel_builder.build()?)
+    }
+}
+
+#[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
+pub struct Im2ColMeta {
+    Cg: u32,
+    H: u32,
+    W: u32,
+    KH: u32,
+    KW: u32,
+    stride_h: u32,
+    stride_w: u32,
+    pad_h: u32,
+    pad_w: u32,
+    dilation_h: u32,
+    dilation_w: u32,
+    Hout: u32,
+    Wout: u32,
+    dst_numel: u32,
+}
+
+impl OpGuards for Im2Col {
+    fn check_shapes(&self) {
+        assert_eq!(self.input.rank(), 4);
+        assert!(
+            self.input.storage_view().is_contiguous(),
+            "Im2Col requires a contiguous input"
+        );
+        let [_, C, H, W]: [usize; 4] = self.input.shape().try_into().unwrap();
+        assert!(self.groups > 0 && C % self.groups == 0);
+        assert!(self.stride.iter().all(|&s| s > 0));
+        assert!(self.dilation.iter().all(|&d| d > 0));
+        for (i, size) in [H, W].into_iter().enumerate() {
+            let receptive_field = self.dilation[i] * (self.kernel_size[i] - 1) + 1;
+            assert!(
+                size + 2 * self.padding[i] >= receptive_field,
+                "Kernel of size {:?} with dilation {:?} does not fit the padded input",
+                self.kernel_size,
+                self.dilation
+            );
+        }
+    }
+
+    fn check_dtypes(&self) {
+        assert!(self.input.dt().is_float());
+    }
+}
+
+impl Operation for Im2Col {
+    fn compute_view(&self) -> Result<StorageView, OperationError> {
+        let [N, C, _, _]: [usize; 4] = self.input.shape().try_into()?;
+        let [KH, KW] = self.kernel_size;
+        let [H_out, W_out] = self.output_size();
+        let out_shape = shape![N, self.groups, C / self.groups * KH * KW, H_out * W_out];
+        let out_strides = Strides::from(&out_shape);
+        Ok(StorageView::new(out_shape, self.input.dt(), out_strides))
+    }
+}
+
+impl MetaOperation for Im2Col {
+    fn kernel_name(&self) -> String {
+        "im2col".to_string()
+    }
+
+    fn srcs(&self) -> RVec<&Tensor> {
+        rvec![&self.input]
+    }
+
+    fn kernel_element(&self, _dst: &Tensor) -> KernelElement {
+        KernelElement::Scalar
+    }
+
+    fn build_kernel(
+        &self,
+        inplace: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        match self.input.dt() {
+            DType::F32 => self.build_im2col::<Scalar<f32>>(inplace, dst, workgroup_size),
+            DType::F16 => self.build_im2col::<Scalar<f16>>(inplace, dst, workgroup_size),
+            dt => Err(OperationError::CompileError(format!(
+                "Unsupported dtype {:?} for im2col",
+                dt
+            ))),
+        }
+    }
+
+    fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        Ok(Workload::std(dst.shape().numel(), self.kernel_element(dst)))
+    }
+
+    fn storage_bind_group_layout(
+        &self,
+        _: bool,
+    ) -> Result<BindGroupLayoutDescriptor, OperationError> {
+        Ok(BindGroupLayoutDescriptor::unary())
+    }
+
+    fn write_metadata(
+        &self,
+        uniform: &mut CpuUniform,
+        dst: &Tensor,
+        _: &KernelElement,
+    ) -> Result<u64, OperationError> {
+        let [_, C, H, W]: [usize; 4] = self.input.shape().try_into()?;
+        let [KH, KW] = self.kernel_size;
+        let [Hout, Wout] = self.output_size();
+        let meta = Im2ColMeta::new(
+            (C / self.groups) as _,
+            H as _,
+            W as _,
+            KH as _,
+            KW as _,
+            self.stride[0] as _,
+            self.stride[1] as _,
+            self.padding[0] as _,
+            self.padding[1] as _,
+            self.dilation[0] as _,
+            self.dilation[1] as _,
+            Hout as _,
+            Wout as _,
+            dst.shape().numel() as _,
+        );
+        Ok(uniform.write(&meta)?)
+    }
+}
+
 #[cfg(all(test, feature = "pyo3"))]
 mod tests {
     use test_strategy::{proptest, Arbitrary};
@@ -286,20 +590,22 @@ mod tests {
         bias: &Tensor,
         stride: usize,
         padding: usize,
+        dilation: usize,
+        groups: usize,
     ) -> anyhow::Result<Tensor> {
         let prg = r#"
 import torch
 import torch.nn.functional as F
-def conv(input, filters, bias, stride, padding):
+def conv(input, filters, bias, stride, padding, dilation, groups):
     input = torch.from_numpy(input)
     filters = torch.from_numpy(filters)
     bias = torch.from_numpy(bias)
-    return F.conv1d(input, filters, bias, stride=stride, padding=padding).numpy()
+    return F.conv1d(input, filters, bias, stride=stride, padding=padding, dilation=dilation, groups=groups).numpy()
 "#;
         run_py_prg(
             prg.to_string(),
             &[input, filters, bias],
-            &[&stride, &padding],
+            &[&stride, &padding, &dilation, &groups],
             input.dt(),
         )
     }
@@ -314,13 +620,13 @@ def conv(input, filters, bias, stride, padding):
         let input = Tensor::randn::<f32>(shape![1, Cin, Lin], Device::CPU);
         let weight = Tensor::randn::<f32>(shape![Cout, Cin, 3], Device::CPU);
         let bias = Tensor::randn::<f32>(shape![Cout], Device::CPU);
-        let ground = ground_truth(&input, &weight, &bias, stride, 1).unwrap();
+        let ground = ground_truth(&input, &weight, &bias, stride, 1, 1, 1).unwrap();
 
         let input = input.to(device).unwrap();
         let weight = weight.to(device).unwrap();
         let bias = bias.to(device).unwrap();
         let ours = input
-            .conv1d(weight, Some(bias), stride, 1)
+            .conv1d(weight, Some(bias), stride, 1, 1, 1)
             .unwrap()
             .resolve()
             .unwrap();
@@ -359,4 +665,187 @@ def conv(input, filters, bias, stride, padding):
         );
         run_conv_trial(&device, prob);
     }
+
+    fn run_general_conv_trial(device: &Device, problem: GeneralConvProblem) {
+        let GeneralConvProblem {
+            N,
+            Cg,
+            Cout_g,
+            Lin,
+            KS,
+            stride,
+            padding,
+            dilation,
+            groups,
+        } = problem;
+        let (Cin, Cout) = (Cg * groups, Cout_g * groups);
+        let input = Tensor::randn::<f32>(shape![N, Cin, Lin], Device::CPU);
+        let weight = Tensor::randn::<f32>(shape![Cout, Cg, KS], Device::CPU);
+        let bias = Tensor::randn::<f32>(shape![Cout], Device::CPU);
+        let ground =
+            ground_truth(&input, &weight, &bias, stride, padding, dilation, groups).unwrap();
+
+        let input = input.to(device).unwrap();
+        let weight = weight.to(device).unwrap();
+        let bias = bias.to(device).unwrap();
+        let ours = input
+            .conv1d(weight, Some(bias), stride, padding, dilation, groups)
+            .unwrap()
+            .resolve()
+            .unwrap();
+        let ours = ours.to(&Device::CPU).unwrap();
+
+        println!("ours = {:?}", ours);
+        println!("ground = {:?}", ground);
+        ground.all_close(&ours, 5e-3, 5e-3).unwrap();
+    }
+
+    #[derive(Arbitrary, Debug)]
+    struct GeneralConvProblem {
+        #[strategy(1..=2usize)]
+        N: usize,
+        #[strategy(1..=32usize)]
+        Cg: usize,
+        #[strategy(1..=32usize)]
+        Cout_g: usize,
+        #[strategy(64..=512usize)]
+        Lin: usize,
+        #[strategy(1..=7usize)]
+        KS: usize,
+        #[strategy(1..=2usize)]
+        stride: usize,
+        #[strategy(0..=3usize)]
+        padding: usize,
+        #[strategy(1..=4usize)]
+        dilation: usize,
+        #[strategy(1..=4usize)]
+        groups: usize,
+    }
+
+    #[proptest(cases = 8)]
+    fn test_conv_dilated(mut prob: GeneralConvProblem) {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        prob.groups = 1;
+        println!("{:?}", prob);
+        run_general_conv_trial(&device, prob);
+    }
+
+    #[proptest(cases = 8)]
+    fn test_conv_grouped(prob: GeneralConvProblem) {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        println!("{:?}", prob);
+        run_general_conv_trial(&device, prob);
+    }
+
+    #[proptest(cases = 8)]
+    fn test_conv_depthwise(mut prob: GeneralConvProblem, #[strategy(1..=64usize)] Cin: usize) {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        prob.Cg = 1;
+        prob.groups = Cin;
+        println!("{:?}", prob);
+        run_general_conv_trial(&device, prob);
+    }
+
+    #[proptest(cases = 8)]
+    fn test_conv_general_cpu(prob: GeneralConvProblem) {
+        println!("{:?}", prob);
+        run_general_conv_trial(&Device::CPU, prob);
+    }
+
+    fn ground_truth_2d(
+        input: &Tensor,
+        filters: &Tensor,
+        bias: &Tensor,
+        problem: &Conv2dProblem,
+    ) -> anyhow::Result<Tensor> {
+        let prg = r#"
+import torch
+import torch.nn.functional as F
+def conv(input, filters, bias, stride, padding, dilation, groups):
+    input = torch.from_numpy(input)
+    filters = torch.from_numpy(filters)
+    bias = torch.from_numpy(bias)
+    return F.conv2d(input, filters, bias, stride=stride, padding=padding, dilation=dilation, groups=groups).numpy()
+"#;
+        let stride = (problem.stride[0], problem.stride[1]);
+        let padding = (problem.padding[0], problem.padding[1]);
+        let dilation = (problem.dilation[0], problem.dilation[1]);
+        run_py_prg(
+            prg.to_string(),
+            &[input, filters, bias],
+            &[&stride, &padding, &dilation, &problem.groups],
+            input.dt(),
+        )
+    }
+
+    fn run_conv2d_trial(device: &Device, problem: Conv2dProblem) {
+        let Conv2dProblem {
+            N,
+            Cg,
+            H,
+            W,
+            Cout_g,
+            K,
+            stride,
+            padding,
+            dilation,
+            groups,
+        } = problem;
+        let (Cin, Cout) = (Cg * groups, Cout_g * groups);
+        let input = Tensor::randn::<f32>(shape![N, Cin, H, W], Device::CPU);
+        let weight = Tensor::randn::<f32>(shape![Cout, Cg, K, K], Device::CPU);
+        let bias = Tensor::randn::<f32>(shape![Cout], Device::CPU);
+        let ground = ground_truth_2d(&input, &weight, &bias, &problem).unwrap();
+
+        let input = input.to(device).unwrap();
+        let weight = weight.to(device).unwrap();
+        let bias = bias.to(device).unwrap();
+        let ours = input
+            .conv2d(weight, Some(bias), stride, padding, dilation, groups)
+            .unwrap()
+            .resolve()
+            .unwrap();
+        let ours = ours.to(&Device::CPU).unwrap();
+
+        println!("ours = {:?}", ours);
+        println!("ground = {:?}", ground);
+        ground.all_close(&ours, 5e-3, 5e-3).unwrap();
+    }
+
+    #[derive(Arbitrary, Debug, Clone)]
+    struct Conv2dProblem {
+        #[strategy(1..=2usize)]
+        N: usize,
+        #[strategy(1..=16usize)]
+        Cg: usize,
+        #[strategy(8..=32usize)]
+        H: usize,
+        #[strategy(8..=32usize)]
+        W: usize,
+        #[strategy(1..=16usize)]
+        Cout_g: usize,
+        #[strategy(1..=3usize)]
+        K: usize,
+        #[strategy([1..=2usize, 1..=2usize])]
+        stride: [usize; 2],
+        #[strategy([0..=2usize, 0..=2usize])]
+        padding: [usize; 2],
+        #[strategy([1..=2usize, 1..=2usize])]
+        dilation: [usize; 2],
+        #[strategy(1..=4usize)]
+        groups: usize,
+    }
+
+    #[proptest(cases = 16)]
+    fn test_conv2d(prob: Conv2dProblem) {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        println!("{:?}", prob);
+        run_conv2d_trial(&device, prob);
+    }
+
+    #[proptest(cases = 8)]
+    fn test_conv2d_cpu(prob: Conv2dProblem) {
+        println!("{:?}", prob);
+        run_conv2d_trial(&Device::CPU, prob);
+    }
 }
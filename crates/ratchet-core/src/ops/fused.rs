@@ -0,0 +1,337 @@
+use derive_new::new;
+use encase::ShaderType;
+use half::f16;
+use inline_wgsl::wgsl;
+use ratchet_macros::WgslMetadata;
+use rustc_hash::FxHashSet;
+
+use crate::{
+    gpu::{BindGroupLayoutDescriptor, CpuUniform},
+    rvec, BinaryOp, BindingMode, BuiltIn, DType, KernelElement, KernelKey, KernelSource,
+    MetaOperation, OpGuards, Operation, OperationError, RVec, Scalar, StorageView, Strides, Tensor,
+    Unary, UnaryOp, Vec2, Vec4, WgslKernelBuilder, WgslPrimitive, WorkgroupSize, Workload,
+};
+
+/// A single step of a fused elementwise expression.
+///
+/// Operands refer to the index of an earlier [FusedNode] in the expression.
+#[derive(Debug, Clone)]
+pub enum FusedStep {
+    /// Reads the input at the given index.
+    Input(usize),
+    Unary(UnaryOp, usize),
+    Binary(BinaryOp, usize, usize),
+    Cast(usize),
+}
+
+#[derive(new, Debug, Clone)]
+pub struct FusedNode {
+    pub(crate) step: FusedStep,
+    /// The dtype produced by this step.
+    pub(crate) dt: DType,
+}
+
+impl FusedNode {
+    fn key(&self) -> String {
+        match &self.step {
+            FusedStep::Input(i) => format!("x{}", i),
+            FusedStep::Unary(op, a) => format!("{}_{}", op.kernel_name(), a),
+            FusedStep::Binary(op, a, b) => format!("{}_{}_{}", op.kernel_name(), a, b),
+            FusedStep::Cast(a) => format!("{}_{}", self.dt, a),
+        }
+    }
+}
+
+/// # FusedElementwise
+///
+/// A chain of [crate::Unary], [crate::Binary] and [crate::Cast] operations, collapsed into a
+/// single kernel by the fusion pass.
+///
+/// Every input and intermediate has the same number of elements, so each invocation
+/// evaluates the whole expression for its own index. The last node is the output.
+#[derive(new, Debug, Clone)]
+pub struct FusedElementwise {
+    pub(crate) inputs: RVec<Tensor>,
+    pub(crate) nodes: Vec<FusedNode>,
+}
+
+impl FusedElementwise {
+    /// Each input takes a binding, and the output takes the last.
+    pub const MAX_INPUTS: usize = 7;
+
+    /// The dtype the unary & binary steps are computed in.
+    ///
+    /// The fusion pass only groups float operations of a single dtype, casts may move
+    /// values in and out of it.
+    pub(crate) fn compute_dt(&self) -> DType {
+        self.nodes
+            .iter()
+            .find(|n| matches!(n.step, FusedStep::Unary(..) | FusedStep::Binary(..)))
+            .unwrap_or(self.nodes.last().unwrap())
+            .dt
+    }
+
+    /// Uniquely identifies the expression, e.g `x0.x1.add_0_1.gelu_2`.
+    pub fn expression_key(&self) -> String {
+        self.nodes
+            .iter()
+            .map(FusedNode::key)
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    fn register_bindings(
+        &self,
+        builder: &mut WgslKernelBuilder,
+        dst: &Tensor,
+        kernel_element: &KernelElement,
+    ) -> Result<(), OperationError> {
+        for (i, input) in self.inputs.iter().enumerate() {
+            let name = format!("X{}", i);
+            builder.register_storage_dyn(name, BindingMode::ReadOnly, input.dt(), kernel_element);
+        }
+        builder.register_storage_dyn("Y", BindingMode::ReadWrite, dst.dt(), kernel_element);
+        builder.register_uniform();
+        Ok(())
+    }
+
+    fn build_fused<P: WgslPrimitive>(
+        &self,
+        _: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = dst.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![
+                BuiltIn::WorkgroupId,
+                BuiltIn::LocalInvocationIndex,
+                BuiltIn::NumWorkgroups
+            ],
+            device.compute_features().clone(),
+        );
+
+        let kernel_element = self.kernel_element(dst);
+        self.register_bindings(&mut kernel_builder, dst, &kernel_element)?;
+        kernel_builder.write_metadata::<FusedMeta>();
+
+        let mut written = FxHashSet::default();
+        for node in self.nodes.iter() {
+            if let FusedStep::Unary(op, _) = &node.step {
+                for (name, helper) in Unary::render_helpers::<P>(op) {
+                    if written.insert(name) {
+                        kernel_builder.write_global(helper);
+                    }
+                }
+            }
+        }
+
+        let n = P::W;
+        kernel_builder.write_main(wgsl! {
+            let x_offset = workgroup_id.x * 64u;
+            let index = (workgroup_id.y * num_workgroups.x * 64u) + x_offset + local_invocation_index;
+            if (index >= metadata.numel / 'n) {
+                return;
+            }
+        });
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let expr = match &node.step {
+                FusedStep::Input(x) => format!("X{}[index]", x),
+                FusedStep::Unary(op, a) => format!("{}(v{})", op.kernel_operation(), a),
+                FusedStep::Binary(op, a, b) => {
+                    format!("v{} {} v{}", a, op.kernel_operator(), b)
+                }
+                FusedStep::Cast(a) => format!("{}(v{})", kernel_element.as_wgsl(node.dt), a),
+            };
+            kernel_builder.write_main(format!("let v{} = {};\n", i, expr));
+        }
+
+        let out = self.nodes.len() - 1;
+        kernel_builder.write_main(format!("Y[index] = v{};\n", out));
+
+        Ok(kernel_builder.build()?)
+    }
+}
+
+#[derive(Debug, ShaderType, WgslMetadata)]
+pub struct FusedMeta {
+    numel: u32,
+}
+
+impl OpGuards for FusedElementwise {
+    fn check_shapes(&self) {
+        let numel = self.inputs[0].shape().numel();
+        assert!(self.inputs.iter().all(|i| i.shape().numel() == numel));
+    }
+
+    fn check_dtypes(&self) {
+        assert!(self.inputs.len() <= Self::MAX_INPUTS);
+        assert!(matches!(self.compute_dt(), DType::F32 | DType::F16));
+    }
+}
+
+impl Operation for FusedElementwise {
+    fn compute_view(&self) -> Result<StorageView, OperationError> {
+        let shape = self.inputs[0].shape().clone();
+        let strides = Strides::from(&shape);
+        let dt = self.nodes.last().unwrap().dt;
+        Ok(StorageView::new(shape, dt, strides))
+    }
+}
+
+impl MetaOperation for FusedElementwise {
+    fn kernel_name(&self) -> String {
+        "fused".to_string()
+    }
+
+    fn kernel_key(
+        &self,
+        workgroup_size: &WorkgroupSize,
+        inplace: bool,
+        dst: &Tensor,
+        kernel_element: &KernelElement,
+    ) -> KernelKey {
+        KernelKey::new(
+            &self.kernel_name(),
+            &self.srcs(),
+            dst,
+            workgroup_size,
+            inplace,
+            kernel_element,
+            Some(&self.expression_key()),
+        )
+    }
+
+    fn srcs(&self) -> RVec<&Tensor> {
+        self.inputs.iter().collect()
+    }
+
+    fn kernel_element(&self, dst: &Tensor) -> KernelElement {
+        let numel = dst.shape().numel();
+        if numel % 4 == 0 {
+            KernelElement::Vec4
+        } else if numel % 2 == 0 {
+            KernelElement::Vec2
+        } else {
+            KernelElement::Scalar
+        }
+    }
+
+    fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        Ok(Workload::std(dst.shape().numel(), self.kernel_element(dst)))
+    }
+
+    fn storage_bind_group_layout(
+        &self,
+        _: bool,
+    ) -> Result<BindGroupLayoutDescriptor, OperationError> {
+        Ok(BindGroupLayoutDescriptor::nthary(self.inputs.len()))
+    }
+
+    fn write_metadata(
+        &self,
+        uniform: &mut CpuUniform,
+        dst: &Tensor,
+        _: &KernelElement,
+    ) -> Result<u64, OperationError> {
+        let numel = dst.shape().numel() as u32;
+        let meta = FusedMeta { numel };
+        Ok(uniform.write(&meta)?)
+    }
+
+    fn build_kernel(
+        &self,
+        inplace: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let kernel_element = self.kernel_element(dst);
+        match (self.compute_dt(), &kernel_element) {
+            (DType::F32, KernelElement::Scalar) => {
+                self.build_fused::<Scalar<f32>>(inplace, dst, workgroup_size)
+            }
+            (DType::F32, KernelElement::Vec2) => {
+                self.build_fused::<Vec2<f32>>(inplace, dst, workgroup_size)
+            }
+            (DType::F32, KernelElement::Vec4) => {
+                self.build_fused::<Vec4<f32>>(inplace, dst, workgroup_size)
+            }
+            (DType::F16, KernelElement::Scalar) => {
+                self.build_fused::<Scalar<f16>>(inplace, dst, workgroup_size)
+            }
+            (DType::F16, KernelElement::Vec2) => {
+                self.build_fused::<Vec2<f16>>(inplace, dst, workgroup_size)
+            }
+            (DType::F16, KernelElement::Vec4) => {
+                self.build_fused::<Vec4<f16>>(inplace, dst, workgroup_size)
+            }
+            _ => Err(OperationError::CompileError(format!(
+                "Unsupported dtype {:?} or kernel element {:?}",
+                self.compute_dt(),
+                kernel_element
+            ))),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "pyo3"))]
+mod tests {
+    use half::f16;
+    use test_strategy::{proptest, Arbitrary};
+
+    use crate::{shape, test_util::run_py_prg, DType, Device, DeviceRequest, Tensor};
+
+    thread_local! {
+        static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
+    }
+
+    fn ground_truth(a: &Tensor, b: &Tensor) -> anyhow::Result<Tensor> {
+        let prg = r#"
+import torch
+import torch.nn.functional as F
+def fused(a, b):
+    (a, b) = (torch.from_numpy(a), torch.from_numpy(b))
+    return F.gelu((a + b) * b, approximate="tanh").exp().half().numpy()
+"#;
+        run_py_prg(prg.to_string(), &[a, b], &[], DType::F16)
+    }
+
+    #[derive(Arbitrary, Debug)]
+    struct FusedProblem {
+        #[strategy(1..=2usize)]
+        B: usize,
+        #[strategy(1..=128usize)]
+        M: usize,
+        #[strategy(1..=128usize)]
+        N: usize,
+    }
+
+    fn run_fused_trial(prob: FusedProblem) -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let FusedProblem { B, M, N } = prob;
+        let a = Tensor::randn::<f32>(shape![B, M, N], Device::CPU);
+        let b = Tensor::randn::<f32>(shape![B, M, N], Device::CPU);
+        let ground = ground_truth(&a, &b)?;
+
+        let a_gpu = a.to(&device)?;
+        let b_gpu = b.to(&device)?;
+        let result = a_gpu
+            .add(b_gpu.clone())?
+            .mul(b_gpu)?
+            .gelu()?
+            .exp()?
+            .cast(DType::F16)?
+            .resolve()?;
+
+        let result = result.to(&Device::CPU)?;
+        ground.all_close::<f16>(&result, f16::from_f32(1e-2), f16::from_f32(1e-2))?;
+        Ok(())
+    }
+
+    #[proptest(cases = 16)]
+    fn test_fused_elementwise(prob: FusedProblem) {
+        run_fused_trial(prob).unwrap();
+    }
+}
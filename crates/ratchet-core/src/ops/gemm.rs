@@ -3,8 +3,9 @@ use half::f16;
 use ratchet_macros::WgslMetadata;
 
 use crate::{
-    gpu::dtype::WgslDType, rvec, Array, BindingMode, BuiltIn, DType, GEMMSpec, InvariantError,
-    KernelElement, KernelSource, Matmul, OperationError, Scalar, Tensor, Vec2, Vec4, WgslFragment,
+    gpu::{dtype::WgslDType, render_unpack},
+    rvec, Array, BindingMode, BuiltIn, DType, GEMMSpec, InvariantError, KernelElement,
+    KernelSource, Matmul, OperationError, Scalar, Tensor, Vec2, Vec4, WgslFragment,
     WgslKernelBuilder, WgslPrimitive, WorkgroupSize,
 };
 use glam::IVec3;
@@ -92,6 +93,11 @@ impl GEMM {
         let accessor = P::render_type();
         let W = P::W;
         builder.write_unpack(A.dt());
+        let unpack_a = render_unpack(
+            A.dt(),
+            "A",
+            "getAIndexFromCoords3D(vec3<i32>(d0,d1,d2)) / 4",
+        );
 
         let a_getters = match A.dt() {
             DType::F32 | DType::F16 => {
@@ -101,10 +107,10 @@ impl GEMM {
                     }
                 }
             }
-            DType::Q8_0F(_) => {
+            DType::Q8_0F(_) | DType::Q4_0F(_) => {
                 wgsl! {
                     fn getA(d0 : i32, d1 : i32, d2 : i32) -> vec4<f32> {
-                        return unpack(A[getAIndexFromCoords3D(vec3<i32>(d0,d1,d2)) / 4]);
+                        return 'unpack_a;
                     }
 
                     fn getAbsMax(d0 : i32, d1 : i32, d2 : i32) -> f32 {
@@ -113,10 +119,10 @@ impl GEMM {
                     }
                 }
             }
-            DType::Q8_0H(_) => {
+            DType::Q8_0H(_) | DType::Q4_0H(_) => {
                 wgsl! {
                     fn getA(d0 : i32, d1 : i32, d2 : i32) -> vec4<f16> {
-                        return unpack(A[getAIndexFromCoords3D(vec3<i32>(d0,d1,d2)) / 4]);
+                        return 'unpack_a;
                     }
 
                     fn getAbsMax(d0 : i32, d1 : i32, d2 : i32) -> f16 {
@@ -137,14 +143,14 @@ impl GEMM {
                     }
                 });
             }
-            DType::Q8_0F(_) => {
+            DType::Q8_0F(_) | DType::Q4_0F(_) => {
                 builder.write_global(wgsl! {
                     fn getB(d0 : i32, d1 : i32, d2 : i32) -> f32 {
                         return f32(B[getBIndexFromCoords3D(vec3<i32>(d0,d1,d2)) / 'W]);
                     }
                 });
             }
-            DType::Q8_0H(_) => {
+            DType::Q8_0H(_) | DType::Q4_0H(_) => {
                 builder.write_global(wgsl! {
                     fn getB(d0 : i32, d1 : i32, d2 : i32) -> f16 {
                         return f16(B[getBIndexFromCoords3D(vec3<i32>(d0,d1,d2)) / 'W]);
@@ -190,8 +196,8 @@ impl GEMM {
         };
 
         let aAccessor = match self.lhs.dt() {
-            DType::Q8_0F(_) => Vec4::<f32>::render_type(),
-            DType::Q8_0H(_) => Vec4::<f16>::render_type(),
+            DType::Q8_0F(_) | DType::Q4_0F(_) => Vec4::<f32>::render_type(),
+            DType::Q8_0H(_) | DType::Q4_0H(_) => Vec4::<f16>::render_type(),
             _ => accessor.clone(),
         };
 
@@ -277,7 +283,7 @@ impl GEMM {
                 }
                 builder.register_storage("result", BindingMode::ReadWrite, float_arr);
             }
-            DType::Q8_0F(_) => {
+            DType::Q8_0F(_) | DType::Q4_0F(_) => {
                 builder.register_storage("A", ro, Array::<Scalar<u32>>::default());
                 builder.register_storage("scale", ro, float_arr);
                 builder.register_storage("B", ro, Array::<Scalar<f32>>::default());
@@ -286,7 +292,7 @@ impl GEMM {
                 }
                 builder.register_storage("result", BindingMode::ReadWrite, float_arr);
             }
-            DType::Q8_0H(_) => {
+            DType::Q8_0H(_) | DType::Q4_0H(_) => {
                 builder.register_storage("A", ro, Array::<Scalar<u32>>::default());
                 builder.register_storage("scale", ro, float_arr);
                 builder.register_storage("B", ro, Array::<Scalar<f16>>::default());
@@ -329,10 +335,10 @@ impl GEMM {
             (DType::F16, KernelElement::Vec4) => {
                 self.build_gemm::<Vec4<f16>>(inplace, dst, workgroup_size, spec)
             }
-            (DType::Q8_0F(_), _) => {
+            (DType::Q8_0F(_) | DType::Q4_0F(_), _) => {
                 self.build_gemm::<Scalar<f32>>(inplace, dst, workgroup_size, spec)
             }
-            (DType::Q8_0H(_), _) => {
+            (DType::Q8_0H(_) | DType::Q4_0H(_), _) => {
                 self.build_gemm::<Scalar<f16>>(inplace, dst, workgroup_size, spec)
             }
             _ => panic!("Unsupported dtype"),
@@ -392,7 +398,7 @@ impl GEMM {
                     }
                 }
             }
-            DType::Q8_0F(_) | DType::Q8_0H(_) => {
+            dt if dt.is_quantized() => {
                 let mut inner = wgsl! {
                     let curRow = globalRow + innerRow;
                     let curCol = kStart + i32(local_invocation_id.x) * 4;
@@ -539,7 +545,7 @@ impl GEMM {
             DType::F32 | DType::F16 => {
                 wgsl! { mm_Asub[inputRow][inputCol] = mm_readA(batchA, globalRow + innerRow, kStart + inputCol * 'W); }
             }
-            DType::Q8_0F(_) | DType::Q8_0H(_) => {
+            dt if dt.is_quantized() => {
                 wgsl! {
                     let curRow = globalRow + innerRow;
                     let curCol = kStart + inputCol * 'W;
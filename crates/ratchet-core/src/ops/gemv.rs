@@ -3,9 +3,10 @@ use half::f16;
 use ratchet_macros::WgslMetadata;
 
 use crate::{
-    gpu::dtype::WgslDType, rvec, Array, BindingMode, BuiltIn, CpuUniform, DType, GEMMSpec,
-    InvariantError, KernelElement, KernelSource, Matmul, MatmulMeta, OperationError, Scalar,
-    Tensor, Vec4, WgslFragment, WgslKernelBuilder, WgslPrimitive, WorkgroupSize,
+    gpu::{dtype::WgslDType, render_unpack},
+    rvec, Array, BindingMode, BuiltIn, CpuUniform, DType, GEMMSpec, InvariantError, KernelElement,
+    KernelSource, Matmul, MatmulMeta, OperationError, Scalar, Tensor, Vec4, WgslFragment,
+    WgslKernelBuilder, WgslPrimitive, WorkgroupSize,
 };
 use glam::IVec3;
 use inline_wgsl::wgsl;
@@ -168,10 +169,10 @@ impl GEMV {
             (DType::F16, KernelElement::Scalar) => {
                 self.render_gemv::<Scalar<f16>>(inplace, dst, workgroup_size, spec)
             }
-            (DType::Q8_0F(_), _) => {
+            (DType::Q8_0F(_) | DType::Q4_0F(_), _) => {
                 self.render_gemv::<Vec4<f32>>(inplace, dst, workgroup_size, spec)
             }
-            (DType::Q8_0H(_), _) => {
+            (DType::Q8_0H(_) | DType::Q4_0H(_), _) => {
                 self.render_gemv::<Vec4<f16>>(inplace, dst, workgroup_size, spec)
             }
             _ => panic!("Unsupported dtype"),
@@ -238,10 +239,15 @@ impl GEMV {
                     }
                 }
             }
-            (true, DType::Q8_0F(_)) | (true, DType::Q8_0H(_)) => {
+            (true, dt) if dt.is_quantized() => {
+                let unpack_a = render_unpack(
+                    dt,
+                    "A",
+                    "dot(metadata.aStrides, vec3<i32>(batch, row, col))",
+                );
                 wgsl! {
                     fn readA(batch: i32, row: i32, col: i32) -> vec4<'scalar> {
-                        return unpack(A[dot(metadata.aStrides, vec3<i32>(batch, row, col))]);
+                        return 'unpack_a;
                     }
                 }
             }
@@ -268,11 +274,12 @@ impl GEMV {
 
         let workgroup_size_y = workgroup_size.y;
         let main_loop = match self.lhs.dt() {
-            DType::Q8_0F(_) | DType::Q8_0H(_) => {
+            dt if dt.is_quantized() => {
+                let unpack_a = render_unpack(dt, "A", "aIndex + k");
                 wgsl! {
                     let sIndex = (aOffset / 4) + row * metadata.aStrides.y / 32;
                     for (var k = i32(global_invocation_id.y); k < metadata.dimInner / 4; k+='workgroup_size_y / 4) {
-                        sum += 'fp32_accessor(unpack(A[aIndex + k]) * scale[sIndex + (k/8)] * X[k]);
+                        sum += 'fp32_accessor('unpack_a * scale[sIndex + (k/8)] * X[k]);
                     }
                 }
             }
@@ -342,7 +349,7 @@ impl GEMV {
         const BM: usize = 8;
         const BN: usize = 32;
 
-        if matches!(self.lhs.dt(), DType::Q8_0F(_) | DType::Q8_0H(_)) {
+        if self.lhs.dt().is_quantized() {
             assert!(TN == 4);
         }
 
@@ -378,7 +385,7 @@ impl GEMV {
                     var vCoeff: array<'dt, 'TN>;
                 }
             }
-            DType::Q8_0F(_) | DType::Q8_0H(_) => {
+            dt if dt.is_quantized() => {
                 wgsl! {
                     var result: array<f32, 'TM>;
                     var inter = vec4<'dt>('zero);
@@ -503,10 +510,11 @@ impl GEMV {
                     'accumulate
                 }
             }
-            DType::Q8_0F(_) | DType::Q8_0H(_) => {
+            dt if dt.is_quantized() => {
+                let unpack_mat = render_unpack(dt, "mat", "matIdx / 4");
                 wgsl! {
                     let matIdx = matOffset + tm * metadata.IVL + bn;
-                    inter = unpack(mat[matIdx / 4]) * scale[matIdx / 32];
+                    inter = 'unpack_mat * scale[matIdx / 32];
 
                     // Accumulate results
                     'accumulate
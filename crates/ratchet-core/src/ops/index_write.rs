@@ -13,9 +13,9 @@ use crate::{
 
 #[derive(new, Debug, Clone)]
 pub struct IndexWrite {
-    dst: Tensor,
-    src: Tensor,
-    write_start: RVec<usize>,
+    pub(crate) dst: Tensor,
+    pub(crate) src: Tensor,
+    pub(crate) write_start: RVec<usize>,
 }
 
 impl IndexWrite {
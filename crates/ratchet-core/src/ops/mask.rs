@@ -0,0 +1,472 @@
+use derive_new::new;
+use encase::ShaderType;
+use half::f16;
+use inline_wgsl::wgsl;
+use ratchet_macros::WgslMetadata;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    gpu::{dtype::WgslDType, BindGroupLayoutDescriptor, CpuUniform},
+    rvec, shape, Array, BindingMode, BuiltIn, DType, KernelElement, KernelSource, MetaOperation,
+    OpGuards, Operation, OperationError, RVec, RollingWindow, Scalar, StorageView, Strides, Tensor,
+    WgslKernelBuilder, WgslPrimitive, WorkgroupSize, Workload,
+};
+
+/// Which end of each sequence holds the padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaddingSide {
+    Left,
+    Right,
+}
+
+impl PaddingSide {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaddingSide::Left => "left",
+            PaddingSide::Right => "right",
+        }
+    }
+}
+
+#[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
+pub struct MaskMeta {
+    q_len: u32,
+    kv_len: u32,
+    offset: u32,
+    dst_numel: u32,
+}
+
+#[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
+pub struct CausalMaskMeta {
+    q_len: u32,
+    kv_len: u32,
+    offset: u32,
+    sinks: u32,
+    window: u32,
+    dst_numel: u32,
+}
+
+/// The value written to masked positions, the same as [crate::Softmax] uses for its maximum.
+pub(crate) fn masked_value(dt: DType) -> f32 {
+    match dt {
+        DType::F16 => f16::MIN.to_f32(),
+        _ => <f32 as WgslDType>::MIN,
+    }
+}
+
+fn mask_kernel_builder(
+    dst: &Tensor,
+    workgroup_size: &WorkgroupSize,
+) -> Result<WgslKernelBuilder, OperationError> {
+    let device = dst.device().try_gpu()?;
+    Ok(WgslKernelBuilder::new(
+        workgroup_size.clone(),
+        rvec![
+            BuiltIn::WorkgroupId,
+            BuiltIn::LocalInvocationIndex,
+            BuiltIn::NumWorkgroups
+        ],
+        device.compute_features().clone(),
+    ))
+}
+
+/// # CausalMask
+///
+/// An additive `[q_len, kv_len]` mask, generated on the device. Query `i` may attend to keys
+/// `0..=i + offset`, all other positions hold a large negative value.
+///
+/// `offset` is the number of keys preceding the queries, i.e `kv_len - q_len` when decoding
+/// with a KV cache.
+///
+/// With a [RollingWindow], the keys are the slots of a rolling cache, see [crate::Cache]. Key
+/// `j` is then the latest position held in slot `j`, and is masked if it follows query `i`.
+#[derive(new, Debug, Clone)]
+pub struct CausalMask {
+    pub(crate) q_len: usize,
+    pub(crate) kv_len: usize,
+    pub(crate) offset: usize,
+    pub(crate) window: Option<RollingWindow>,
+    pub(crate) dt: DType,
+}
+
+impl CausalMask {
+    /// The position of key `j`, if any.
+    pub(crate) fn key_position(&self, j: usize) -> Option<usize> {
+        match &self.window {
+            Some(window) => window.position(j, self.offset + self.q_len),
+            None => Some(j),
+        }
+    }
+}
+
+impl CausalMask {
+    fn build_causal_mask<P: WgslPrimitive>(
+        &self,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError>
+    where
+        P::T: num_traits::Float,
+    {
+        let mut kernel_builder = mask_kernel_builder(dst, workgroup_size)?;
+        kernel_builder.register_storage("Y", BindingMode::ReadWrite, Array::<P>::default());
+        kernel_builder.register_uniform();
+        kernel_builder.write_metadata::<CausalMaskMeta>();
+        RollingWindow::write_slot_position(&mut kernel_builder);
+
+        let dt = P::T::DT;
+        let masked = P::T::MIN.render();
+        kernel_builder.write_main(wgsl! {
+            let x_offset = workgroup_id.x * 64u;
+            let index = (workgroup_id.y * num_workgroups.x * 64u) + x_offset + local_invocation_index;
+            if (index >= metadata.dst_numel) {
+                return;
+            }
+
+            let i = index / metadata.kv_len;
+            let j = index % metadata.kv_len;
+            let end = metadata.offset + metadata.q_len;
+            let key = slotPosition(j, end, metadata.sinks, metadata.window);
+            Y[index] = select('dt(0.0), 'masked, key > i + metadata.offset);
+        });
+
+        Ok(kernel_builder.build()?)
+    }
+}
+
+impl OpGuards for CausalMask {
+    fn check_shapes(&self) {
+        assert!(self.q_len > 0 && self.kv_len > 0);
+        if let Some(window) = &self.window {
+            assert!(window.window > 0 && self.kv_len <= window.capacity());
+        }
+    }
+
+    fn check_dtypes(&self) {
+        assert!(matches!(self.dt, DType::F32 | DType::F16));
+    }
+}
+
+impl Operation for CausalMask {
+    fn compute_view(&self) -> Result<StorageView, OperationError> {
+        let shape = shape![self.q_len, self.kv_len];
+        let strides = Strides::from(&shape);
+        Ok(StorageView::new(shape, self.dt, strides))
+    }
+}
+
+impl MetaOperation for CausalMask {
+    fn kernel_name(&self) -> String {
+        "causal_mask".to_string()
+    }
+
+    fn srcs(&self) -> RVec<&Tensor> {
+        rvec![]
+    }
+
+    fn kernel_element(&self, _dst: &Tensor) -> KernelElement {
+        KernelElement::Scalar
+    }
+
+    fn build_kernel(
+        &self,
+        _: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        match self.dt {
+            DType::F32 => self.build_causal_mask::<Scalar<f32>>(dst, workgroup_size),
+            DType::F16 => self.build_causal_mask::<Scalar<f16>>(dst, workgroup_size),
+            dt => Err(OperationError::CompileError(format!(
+                "Unsupported dtype {:?} for causal mask",
+                dt
+            ))),
+        }
+    }
+
+    fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        Ok(Workload::std(dst.shape().numel(), self.kernel_element(dst)))
+    }
+
+    fn storage_bind_group_layout(
+        &self,
+        _: bool,
+    ) -> Result<BindGroupLayoutDescriptor, OperationError> {
+        Ok(BindGroupLayoutDescriptor::nthary(0))
+    }
+
+    fn write_metadata(
+        &self,
+        uniform: &mut CpuUniform,
+        dst: &Tensor,
+        _: &KernelElement,
+    ) -> Result<u64, OperationError> {
+        let (sinks, window) = RollingWindow::meta(self.window.as_ref(), self.kv_len);
+        let meta = CausalMaskMeta::new(
+            self.q_len as _,
+            self.kv_len as _,
+            self.offset as _,
+            sinks,
+            window,
+            dst.shape().numel() as _,
+        );
+        Ok(uniform.write(&meta)?)
+    }
+}
+
+/// # PaddingMask
+///
+/// An additive `[B, 1, q_len, kv_len]` mask, generated on the device from the length of each
+/// sequence in the batch. Keys in the padding of sequence `b` hold a large negative value.
+///
+/// With [PaddingSide::Right], the first `lengths[b]` keys are valid. With [PaddingSide::Left],
+/// the last `lengths[b]` keys are valid.
+#[derive(new, Debug, Clone)]
+pub struct PaddingMask {
+    pub(crate) lengths: Tensor,
+    pub(crate) q_len: usize,
+    pub(crate) kv_len: usize,
+    pub(crate) side: PaddingSide,
+    pub(crate) dt: DType,
+}
+
+impl PaddingMask {
+    fn build_padding_mask<P: WgslPrimitive>(
+        &self,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError>
+    where
+        P::T: num_traits::Float,
+    {
+        let mut kernel_builder = mask_kernel_builder(dst, workgroup_size)?;
+        //Negative lengths are treated as empty sequences
+        let length = match self.lengths.dt() {
+            DType::U32 => {
+                kernel_builder.register_storage(
+                    "L",
+                    BindingMode::ReadOnly,
+                    Array::<Scalar<u32>>::default(),
+                );
+                wgsl! { L[b] }
+            }
+            _ => {
+                kernel_builder.register_storage(
+                    "L",
+                    BindingMode::ReadOnly,
+                    Array::<Scalar<i32>>::default(),
+                );
+                wgsl! { u32(max(L[b], 0)) }
+            }
+        };
+        kernel_builder.register_storage("Y", BindingMode::ReadWrite, Array::<P>::default());
+        kernel_builder.register_uniform();
+        kernel_builder.write_metadata::<MaskMeta>();
+
+        let dt = P::T::DT;
+        let masked = P::T::MIN.render();
+        let is_padding = match self.side {
+            PaddingSide::Left => wgsl! { j + length < metadata.kv_len },
+            PaddingSide::Right => wgsl! { j >= length },
+        };
+        kernel_builder.write_main(wgsl! {
+            let x_offset = workgroup_id.x * 64u;
+            let index = (workgroup_id.y * num_workgroups.x * 64u) + x_offset + local_invocation_index;
+            if (index >= metadata.dst_numel) {
+                return;
+            }
+
+            let b = index / (metadata.q_len * metadata.kv_len);
+            let j = index % metadata.kv_len;
+            let length = min('length, metadata.kv_len);
+            Y[index] = select('dt(0.0), 'masked, 'is_padding);
+        });
+
+        Ok(kernel_builder.build()?)
+    }
+}
+
+impl OpGuards for PaddingMask {
+    fn check_shapes(&self) {
+        assert_eq!(self.lengths.rank(), 1);
+        assert!(self.q_len > 0 && self.kv_len > 0);
+    }
+
+    fn check_dtypes(&self) {
+        assert!(matches!(self.lengths.dt(), DType::U32 | DType::I32));
+        assert!(matches!(self.dt, DType::F32 | DType::F16));
+    }
+}
+
+impl Operation for PaddingMask {
+    fn compute_view(&self) -> Result<StorageView, OperationError> {
+        let shape = shape![self.lengths.shape()[0], 1, self.q_len, self.kv_len];
+        let strides = Strides::from(&shape);
+        Ok(StorageView::new(shape, self.dt, strides))
+    }
+}
+
+impl MetaOperation for PaddingMask {
+    fn kernel_name(&self) -> String {
+        format!("padding_mask_{}", self.side.as_str())
+    }
+
+    fn srcs(&self) -> RVec<&Tensor> {
+        rvec![&self.lengths]
+    }
+
+    fn kernel_element(&self, _dst: &Tensor) -> KernelElement {
+        KernelElement::Scalar
+    }
+
+    fn build_kernel(
+        &self,
+        _: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        match self.dt {
+            DType::F32 => self.build_padding_mask::<Scalar<f32>>(dst, workgroup_size),
+            DType::F16 => self.build_padding_mask::<Scalar<f16>>(dst, workgroup_size),
+            dt => Err(OperationError::CompileError(format!(
+                "Unsupported dtype {:?} for padding mask",
+                dt
+            ))),
+        }
+    }
+
+    fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        Ok(Workload::std(dst.shape().numel(), self.kernel_element(dst)))
+    }
+
+    fn storage_bind_group_layout(
+        &self,
+        _: bool,
+    ) -> Result<BindGroupLayoutDescriptor, OperationError> {
+        Ok(BindGroupLayoutDescriptor::unary())
+    }
+
+    fn write_metadata(
+        &self,
+        uniform: &mut CpuUniform,
+        dst: &Tensor,
+        _: &KernelElement,
+    ) -> Result<u64, OperationError> {
+        let meta = MaskMeta::new(
+            self.q_len as _,
+            self.kv_len as _,
+            0,
+            dst.shape().numel() as _,
+        );
+        Ok(uniform.write(&meta)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{shape, DType, Device, DeviceRequest, PaddingSide, RollingWindow, Tensor};
+
+    thread_local! {
+        static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
+    }
+
+    fn host_causal_mask(q_len: usize, kv_len: usize, offset: usize, masked: f32) -> Vec<f32> {
+        (0..q_len)
+            .flat_map(|i| (0..kv_len).map(move |j| if j > i + offset { masked } else { 0. }))
+            .collect()
+    }
+
+    fn run_causal_trial(device: &Device) -> anyhow::Result<()> {
+        let masked = super::masked_value(DType::F32);
+        for (q_len, kv_len, offset) in [(7, 7, 0), (1, 13, 12), (5, 21, 16), (4, 9, 0)] {
+            let mask = Tensor::causal_mask(q_len, kv_len, offset, DType::F32, device)?;
+            let ours = mask.resolve()?.to(&Device::CPU)?.to_vec::<f32>()?;
+            assert_eq!(ours, host_causal_mask(q_len, kv_len, offset, masked));
+        }
+        Ok(())
+    }
+
+    fn run_rolling_causal_trial(device: &Device) -> anyhow::Result<()> {
+        let masked = super::masked_value(DType::F32);
+        let window = RollingWindow::new(2, 6);
+        for (q_len, offset) in [(5, 0), (1, 7), (3, 9), (6, 14)] {
+            //Write every position into its slot, later positions overwriting earlier ones
+            let end = offset + q_len;
+            let mut slots = vec![0; end.min(window.capacity())];
+            (0..end).for_each(|p| slots[window.slot(p)] = p);
+            let expected = (0..q_len)
+                .flat_map(|i| {
+                    slots
+                        .iter()
+                        .map(move |&p| if p > i + offset { masked } else { 0. })
+                })
+                .collect::<Vec<_>>();
+
+            let mask = Tensor::rolling_causal_mask(q_len, offset, window, DType::F32, device)?;
+            assert_eq!(mask.shape(), &shape![q_len, slots.len()]);
+            let ours = mask.resolve()?.to(&Device::CPU)?.to_vec::<f32>()?;
+            assert_eq!(ours, expected);
+        }
+        Ok(())
+    }
+
+    fn run_padding_trial(device: &Device) -> anyhow::Result<()> {
+        let masked = super::masked_value(DType::F32);
+        let lengths = Tensor::from_data([3u32, 6, 0], shape![3], device.clone());
+        for side in [PaddingSide::Left, PaddingSide::Right] {
+            let mask = Tensor::padding_mask(lengths.clone(), 2, 6, side, DType::F32)?;
+            assert_eq!(mask.shape(), &shape![3, 1, 2, 6]);
+            let ours = mask.resolve()?.to(&Device::CPU)?.to_vec::<f32>()?;
+
+            let mut expected = vec![];
+            for length in [3, 6, 0] {
+                let row = (0..6).map(|j| {
+                    let is_padding = match side {
+                        PaddingSide::Left => j + length < 6,
+                        PaddingSide::Right => j >= length,
+                    };
+                    if is_padding {
+                        masked
+                    } else {
+                        0.
+                    }
+                });
+                let row = row.collect::<Vec<_>>();
+                expected.extend(row.iter().chain(row.iter()));
+            }
+            assert_eq!(ours, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn causal_mask_cpu() -> anyhow::Result<()> {
+        run_causal_trial(&Device::CPU)
+    }
+
+    #[test]
+    fn rolling_causal_mask_cpu() -> anyhow::Result<()> {
+        run_rolling_causal_trial(&Device::CPU)
+    }
+
+    #[test]
+    fn padding_mask_cpu() -> anyhow::Result<()> {
+        run_padding_trial(&Device::CPU)
+    }
+
+    #[test]
+    fn causal_mask_gpu() -> anyhow::Result<()> {
+        run_causal_trial(&GPU_DEVICE.with(|d| d.clone()))
+    }
+
+    #[test]
+    fn rolling_causal_mask_gpu() -> anyhow::Result<()> {
+        run_rolling_causal_trial(&GPU_DEVICE.with(|d| d.clone()))
+    }
+
+    #[test]
+    fn padding_mask_gpu() -> anyhow::Result<()> {
+        run_padding_trial(&GPU_DEVICE.with(|d| d.clone()))
+    }
+}
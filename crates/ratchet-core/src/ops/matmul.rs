@@ -6,7 +6,8 @@ use crate::{
     gpu::{BindGroupLayoutDescriptor, CpuUniform, WorkgroupCount},
     rvec, wgc, wgs, DType, InvariantError, KernelElement, KernelKey, KernelSource, MetaOperation,
     OpGuards, OpMetadata, Operation, OperationError, RVec, Shape, StorageView, Strides,
-    SubgroupGEMVMeta, Tensor, WorkgroupGEMVMeta, WorkgroupSize, Workload, GEMM, GEMV, Q8_0F, Q8_0H,
+    SubgroupGEMVMeta, Tensor, WorkgroupGEMVMeta, WorkgroupSize, Workload, GEMM, GEMV, Q4_0F, Q4_0H,
+    Q8_0F, Q8_0H,
 };
 
 //https://link.springer.com/chapter/10.1007/978-3-642-29737-3_42
@@ -455,8 +456,11 @@ impl OpGuards for Matmul {
         let allowed_pairs = [
             (DType::F32, DType::F32),
             (DType::F16, DType::F16),
+            (DType::BF16, DType::BF16),
             (DType::Q8_0F(Q8_0F::default()), DType::F32),
             (DType::Q8_0H(Q8_0H::default()), DType::F16),
+            (DType::Q4_0F(Q4_0F::default()), DType::F32),
+            (DType::Q4_0H(Q4_0H::default()), DType::F16),
         ];
         if !allowed_pairs.contains(&(self.lhs.dt(), self.rhs.dt())) {
             panic!(
@@ -601,10 +605,18 @@ impl MetaOperation for Matmul {
             (DType::F32, DType::F32, true) => BindGroupLayoutDescriptor::ternary(),
             (DType::F16, DType::F16, false) => BindGroupLayoutDescriptor::binary(),
             (DType::F16, DType::F16, true) => BindGroupLayoutDescriptor::ternary(),
-            (DType::Q8_0F(_), DType::F32, false) => BindGroupLayoutDescriptor::ternary(),
-            (DType::Q8_0H(_), DType::F16, false) => BindGroupLayoutDescriptor::ternary(),
-            (DType::Q8_0F(_), DType::F32, true) => BindGroupLayoutDescriptor::nthary(4),
-            (DType::Q8_0H(_), DType::F16, true) => BindGroupLayoutDescriptor::nthary(4),
+            (DType::Q8_0F(_) | DType::Q4_0F(_), DType::F32, false) => {
+                BindGroupLayoutDescriptor::ternary()
+            }
+            (DType::Q8_0H(_) | DType::Q4_0H(_), DType::F16, false) => {
+                BindGroupLayoutDescriptor::ternary()
+            }
+            (DType::Q8_0F(_) | DType::Q4_0F(_), DType::F32, true) => {
+                BindGroupLayoutDescriptor::nthary(4)
+            }
+            (DType::Q8_0H(_) | DType::Q4_0H(_), DType::F16, true) => {
+                BindGroupLayoutDescriptor::nthary(4)
+            }
             _ => return Err(InvariantError::UnsupportedDType(RHS.dt()).into()),
         };
         Ok(layout)
@@ -851,6 +863,59 @@ def matmul(a, b{}):
         Ok(())
     }
 
+    #[test]
+    fn test_q4gemm() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let a = Tensor::randn::<f32>(shape![6, 256, 64], Device::CPU);
+        let b = Tensor::randn::<f32>(shape![6, 64, 128], Device::CPU);
+
+        let quantizer = Quantizer::new(Quantization::SInt4);
+        let aq = quantizer.quantize(a);
+        //Compare against the dequantized weights, so only the kernel is under test
+        let ground = ground_truth(
+            &quantizer.sint4_dequantize(aq.deep_clone()),
+            &b,
+            None,
+            false,
+            false,
+            false,
+        )?;
+
+        let c_gpu = aq
+            .to(&device)?
+            .matmul(b.to(&device)?, false, false)?
+            .resolve()?;
+        let ours = c_gpu.to(&Device::CPU)?;
+        ground.all_close(&ours, 1e-3, 1e-3)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_q4gemv() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let a = Tensor::randn::<f32>(shape![1, 512, 384], Device::CPU);
+        let b = Tensor::randn::<f32>(shape![1, 1, 384], Device::CPU);
+
+        let quantizer = Quantizer::new(Quantization::SInt4);
+        let aq = quantizer.quantize(a);
+        let ground = ground_truth(
+            &quantizer.sint4_dequantize(aq.deep_clone()),
+            &b,
+            None,
+            false,
+            true,
+            true,
+        )?;
+
+        let c_gpu = aq
+            .to(&device)?
+            .gemm(b.to(&device)?, None, false, true, true)?
+            .resolve()?;
+        let ours = c_gpu.to(&Device::CPU)?;
+        ground.all_close(&ours, 1e-3, 1e-3)?;
+        Ok(())
+    }
+
     #[test]
     fn debug_gemm() -> anyhow::Result<()> {
         let _ = env_logger::builder().is_test(true).try_init();
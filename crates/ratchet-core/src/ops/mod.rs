@@ -1,36 +1,46 @@
+mod attention;
 mod binary;
 mod cache;
 mod cast;
 mod concat;
 mod conv;
+mod fused;
 mod gemm;
 mod gemv;
 mod index_write;
+mod mask;
 mod matmul;
 mod norm;
+mod reduce;
 mod reindex;
 mod rope;
+mod sample;
 mod select;
 mod softmax;
 mod unary;
 
+pub use attention::*;
 pub use binary::*;
 pub use cache::*;
 pub use cast::*;
 pub use concat::*;
 pub use conv::*;
+pub use fused::*;
 pub use gemm::*;
 pub use gemv::*;
 pub use index_write::*;
+pub use mask::*;
 pub use matmul::*;
 pub use norm::*;
+pub use reduce::*;
 pub use reindex::*;
 pub use rope::*;
+pub use sample::*;
 pub use select::*;
 pub use softmax::*;
 pub use unary::*;
 
-use crate::{OpGuards, Operation, Shape, StorageView, Strides, Tensor};
+use crate::{DType, OpGuards, Operation, Shape, StorageView, Strides, Tensor};
 
 /// # KernelElement
 ///
@@ -55,6 +65,15 @@ impl KernelElement {
             KernelElement::Scalar => "scalar",
         }
     }
+
+    /// Renders the WGSL type of this element for `dt`, e.g `vec4<f16>`.
+    pub fn as_wgsl(&self, dt: DType) -> String {
+        match self {
+            KernelElement::Vec4 => format!("vec4<{}>", dt.as_wgsl()),
+            KernelElement::Vec2 => format!("vec2<{}>", dt.as_wgsl()),
+            KernelElement::Scalar => dt.as_wgsl().to_string(),
+        }
+    }
 }
 
 impl From<&KernelElement> for usize {
@@ -69,8 +88,8 @@ impl From<&KernelElement> for usize {
 
 #[derive(Debug, derive_new::new, Clone)]
 pub struct View {
-    src: Tensor,
-    shape: Shape,
+    pub(crate) src: Tensor,
+    pub(crate) shape: Shape,
 }
 
 impl View {
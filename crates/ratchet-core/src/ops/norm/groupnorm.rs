@@ -16,10 +16,12 @@ impl OpGuards for GroupNorm {
     }
 
     fn check_dtypes(&self) {
-        assert!(self.norm.input.dt() == DType::F32);
-        assert!(self.norm.scale.dt() == DType::F32);
+        //BF16 is computed in F32, see `emulate_bf16`
+        let supported = |dt: DType| matches!(dt, DType::F32 | DType::BF16);
+        assert!(supported(self.norm.input.dt()));
+        assert!(supported(self.norm.scale.dt()));
         if self.norm.bias.is_some() {
-            assert!(self.norm.bias.as_ref().unwrap().dt() == DType::F32);
+            assert!(supported(self.norm.bias.as_ref().unwrap().dt()));
         }
     }
 }
@@ -0,0 +1,448 @@
+use derive_new::new;
+use encase::ShaderType;
+use half::f16;
+use inline_wgsl::wgsl;
+use num_traits::Zero;
+use ratchet_macros::WgslMetadata;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    gpu::{dtype::WgslDType, BindGroupLayoutDescriptor, CpuUniform, WorkgroupCount},
+    rvec, wgc, wgs, Array, BindingMode, BuiltIn, DType, KernelElement, KernelSource, MetaOperation,
+    OpGuards, Operation, OperationError, RVec, Scalar, StorageView, Strides, Tensor,
+    WgslKernelBuilder, WgslPrimitive, WorkgroupSize, Workload,
+};
+
+#[cfg(test)]
+use test_strategy::Arbitrary;
+
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReduceOp {
+    Sum,
+    Mean,
+    Max,
+    Min,
+    ArgMax,
+    ArgMin,
+}
+
+impl ReduceOp {
+    pub fn kernel_name(&self) -> &'static str {
+        match self {
+            ReduceOp::Sum => "sum",
+            ReduceOp::Mean => "mean",
+            ReduceOp::Max => "max",
+            ReduceOp::Min => "min",
+            ReduceOp::ArgMax => "argmax",
+            ReduceOp::ArgMin => "argmin",
+        }
+    }
+
+    /// Arg reductions return the I32 index of the selected element, rather than the element.
+    pub fn is_arg(&self) -> bool {
+        matches!(self, ReduceOp::ArgMax | ReduceOp::ArgMin)
+    }
+}
+
+/// # Reduce
+///
+/// Reduces `input` along `dim`.
+/// If `keepdim` is true, the reduced dimension is retained with size 1.
+///
+/// One workgroup is dispatched per output element, and the reduced dimension is
+/// strided over by the workgroup before a tree reduction in shared memory.
+#[derive(new, Debug, Clone)]
+pub struct Reduce {
+    pub(crate) input: Tensor,
+    pub(crate) dim: usize,
+    pub(crate) keepdim: bool,
+    pub(crate) op: ReduceOp,
+}
+
+#[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
+pub struct ReduceMeta {
+    numel: u32,
+    N: u32,
+    inner: u32,
+}
+
+impl Reduce {
+    /// Returns (outer, N, inner), where N is the size of the reduced dimension.
+    pub(crate) fn reduction_dims(&self) -> (usize, usize, usize) {
+        let shape = self.input.shape();
+        let N = shape[self.dim];
+        let inner = shape[self.dim + 1..].iter().product::<usize>();
+        let outer = shape.numel() / (N * inner);
+        (outer, N, inner)
+    }
+
+    fn register_bindings<SP: WgslPrimitive, DP: WgslPrimitive>(
+        &self,
+        builder: &mut WgslKernelBuilder,
+        _: bool,
+    ) -> Result<(), OperationError> {
+        builder.register_storage("X", BindingMode::ReadOnly, Array::<SP>::default());
+        builder.register_storage("Y", BindingMode::ReadWrite, Array::<DP>::default());
+        builder.register_uniform();
+        Ok(())
+    }
+
+    fn build_reduce<SP: WgslPrimitive, DP: WgslPrimitive>(
+        &self,
+        inplace: bool,
+        _: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = self.input.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![
+                BuiltIn::LocalInvocationId,
+                BuiltIn::WorkgroupId,
+                BuiltIn::NumWorkgroups
+            ],
+            device.compute_features().clone(),
+        );
+        self.register_bindings::<SP, DP>(&mut kernel_builder, inplace)?;
+        kernel_builder.write_metadata::<ReduceMeta>();
+
+        let dt = SP::T::DT;
+        let dst_dt = DP::T::DT;
+        let BLOCK_SIZE = workgroup_size.x.render();
+        let minFloat = SP::T::MIN.render();
+        let zero = SP::T::zero().render();
+
+        kernel_builder.write_global(wgsl! {
+            var<workgroup> smem: array<'dt, 'BLOCK_SIZE>;
+        });
+        if self.op.is_arg() {
+            kernel_builder.write_global(wgsl! {
+                var<workgroup> sidx: array<u32, 'BLOCK_SIZE>;
+            });
+        }
+
+        let (init, accumulate, combine) = match self.op {
+            ReduceOp::Sum | ReduceOp::Mean => (
+                wgsl! { smem[index] = 'zero; },
+                wgsl! { smem[index] += val; },
+                wgsl! { smem[index] += smem[index + stride]; },
+            ),
+            ReduceOp::Max => (
+                wgsl! { smem[index] = 'minFloat; },
+                wgsl! { smem[index] = max(smem[index], val); },
+                wgsl! { smem[index] = max(smem[index], smem[index + stride]); },
+            ),
+            ReduceOp::Min => (
+                wgsl! { smem[index] = -('minFloat); },
+                wgsl! { smem[index] = min(smem[index], val); },
+                wgsl! { smem[index] = min(smem[index], smem[index + stride]); },
+            ),
+            //Ties are broken towards the lowest index, matching PyTorch.
+            //Idle invocations hold index N, so they never win a tie.
+            ReduceOp::ArgMax => (
+                wgsl! {
+                    smem[index] = 'minFloat;
+                    sidx[index] = metadata.N;
+                },
+                wgsl! {
+                    if val > smem[index] || sidx[index] == metadata.N {
+                        smem[index] = val;
+                        sidx[index] = i;
+                    }
+                },
+                wgsl! {
+                    let other = smem[index + stride];
+                    let other_idx = sidx[index + stride];
+                    if other > smem[index] || (other == smem[index] && other_idx < sidx[index]) {
+                        smem[index] = other;
+                        sidx[index] = other_idx;
+                    }
+                },
+            ),
+            ReduceOp::ArgMin => (
+                wgsl! {
+                    smem[index] = -('minFloat);
+                    sidx[index] = metadata.N;
+                },
+                wgsl! {
+                    if val < smem[index] || sidx[index] == metadata.N {
+                        smem[index] = val;
+                        sidx[index] = i;
+                    }
+                },
+                wgsl! {
+                    let other = smem[index + stride];
+                    let other_idx = sidx[index + stride];
+                    if other < smem[index] || (other == smem[index] && other_idx < sidx[index]) {
+                        smem[index] = other;
+                        sidx[index] = other_idx;
+                    }
+                },
+            ),
+        };
+
+        kernel_builder.write_global(wgsl! {
+            fn block_reduce(index: u32, stride: u32) {
+                if index < stride {
+                    'combine
+                }
+                workgroupBarrier();
+            }
+        });
+
+        kernel_builder.write_main(wgsl! {
+            let out_index = workgroup_id.y * num_workgroups.x + workgroup_id.x;
+            if out_index >= metadata.numel {
+                return;
+            }
+            let outer_index = out_index / metadata.inner;
+            let inner_index = out_index % metadata.inner;
+            let row_start = outer_index * metadata.N * metadata.inner + inner_index;
+            let index = local_invocation_id.x;
+
+            'init
+            for (var i: u32 = index; i < metadata.N; i += 'BLOCK_SIZE) {
+                let val = X[row_start + i * metadata.inner];
+                'accumulate
+            }
+            workgroupBarrier();
+        });
+
+        let steps = (workgroup_size.x - 1).ilog2();
+        for i in (0..=steps).rev().map(|x| 2u32.pow(x)) {
+            let v = i.render();
+            kernel_builder.write_main(wgsl! { block_reduce(index, 'v); });
+        }
+
+        let result = match self.op {
+            ReduceOp::Mean => wgsl! { smem[0] / 'dt(metadata.N) },
+            ReduceOp::ArgMax | ReduceOp::ArgMin => wgsl! { 'dst_dt(sidx[0]) },
+            _ => wgsl! { smem[0] },
+        };
+        kernel_builder.write_main(wgsl! {
+            if index == 0 {
+                Y[out_index] = 'result;
+            }
+        });
+
+        Ok(kernel_builder.build()?)
+    }
+}
+
+impl OpGuards for Reduce {
+    fn check_shapes(&self) {
+        let input = &self.input;
+        assert!(self.dim < input.rank());
+        assert!(input.shape()[self.dim] > 0);
+    }
+
+    fn check_dtypes(&self) {
+        let input = &self.input;
+        assert!(input.dt().is_float());
+    }
+}
+
+impl Operation for Reduce {
+    fn compute_view(&self) -> Result<StorageView, OperationError> {
+        let mut shape = self.input.shape().clone();
+        if self.keepdim {
+            shape[self.dim] = 1;
+        } else {
+            shape.remove(self.dim);
+        }
+        let dt = if self.op.is_arg() {
+            DType::I32
+        } else {
+            self.input.dt()
+        };
+        let strides = Strides::from(&shape);
+        Ok(StorageView::new(shape, dt, strides))
+    }
+}
+
+impl MetaOperation for Reduce {
+    fn kernel_name(&self) -> String {
+        self.op.kernel_name().to_string()
+    }
+
+    fn srcs(&self) -> RVec<&Tensor> {
+        rvec![&self.input]
+    }
+
+    fn kernel_element(&self, _: &Tensor) -> KernelElement {
+        //The reduced dimension is generally strided, so we read a scalar at a time.
+        KernelElement::Scalar
+    }
+
+    fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        let numel = dst.shape().numel();
+        let (x_groups, y_groups) = if numel > WorkgroupCount::MAX_WGS_PER_DIM {
+            let y_groups = WorkgroupCount::div_ceil(numel, WorkgroupCount::MAX_WGS_PER_DIM);
+            (WorkgroupCount::MAX_WGS_PER_DIM, y_groups)
+        } else {
+            (numel, 1)
+        };
+        Ok(Workload {
+            workgroup_size: wgs![128, 1, 1],
+            workgroup_count: wgc![x_groups as _, y_groups as _, 1],
+        })
+    }
+
+    fn storage_bind_group_layout(
+        &self,
+        _: bool,
+    ) -> Result<BindGroupLayoutDescriptor, OperationError> {
+        Ok(BindGroupLayoutDescriptor::unary())
+    }
+
+    fn write_metadata(
+        &self,
+        uniform: &mut CpuUniform,
+        dst: &Tensor,
+        _: &KernelElement,
+    ) -> Result<u64, OperationError> {
+        let (_, N, inner) = self.reduction_dims();
+        let meta = ReduceMeta {
+            numel: dst.shape().numel() as u32,
+            N: N as u32,
+            inner: inner as u32,
+        };
+        Ok(uniform.write(&meta)?)
+    }
+
+    fn build_kernel(
+        &self,
+        inplace: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let kernel_element = self.kernel_element(dst);
+        match (self.input.dt(), self.op.is_arg()) {
+            (DType::F32, false) => {
+                self.build_reduce::<Scalar<f32>, Scalar<f32>>(inplace, dst, workgroup_size)
+            }
+            (DType::F32, true) => {
+                self.build_reduce::<Scalar<f32>, Scalar<i32>>(inplace, dst, workgroup_size)
+            }
+            (DType::F16, false) => {
+                self.build_reduce::<Scalar<f16>, Scalar<f16>>(inplace, dst, workgroup_size)
+            }
+            (DType::F16, true) => {
+                self.build_reduce::<Scalar<f16>, Scalar<i32>>(inplace, dst, workgroup_size)
+            }
+            _ => Err(OperationError::CompileError(format!(
+                "Unsupported dtype {:?} or kernel element {:?}",
+                self.input.dt(),
+                kernel_element
+            ))),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "pyo3"))]
+mod tests {
+    use test_strategy::{proptest, Arbitrary};
+
+    use crate::{shape, test_util::run_py_prg, DType, Device, DeviceRequest, ReduceOp, Tensor};
+
+    thread_local! {
+        static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
+    }
+
+    #[derive(Arbitrary, Debug)]
+    struct ReduceProblem {
+        op: ReduceOp,
+        #[strategy(1..=3usize)]
+        B: usize,
+        #[strategy(1..=64usize)]
+        M: usize,
+        #[strategy(1..=300usize)]
+        N: usize,
+        #[strategy(0..=2usize)]
+        dim: usize,
+        keepdim: bool,
+    }
+
+    fn ground_truth(a: &Tensor, op: ReduceOp, dim: usize, keepdim: bool) -> anyhow::Result<Tensor> {
+        let (torch_op, dst_dt) = match op {
+            ReduceOp::Sum => ("torch.sum", DType::F32),
+            ReduceOp::Mean => ("torch.mean", DType::F32),
+            ReduceOp::Max => ("torch.amax", DType::F32),
+            ReduceOp::Min => ("torch.amin", DType::F32),
+            ReduceOp::ArgMax => ("torch.argmax", DType::I32),
+            ReduceOp::ArgMin => ("torch.argmin", DType::I32),
+        };
+        let keepdim = if keepdim { "True" } else { "False" };
+        let prg = format!(
+            r#"
+import torch
+def reduce(a):
+    result = {}(torch.from_numpy(a), dim={}, keepdim={})
+    return result.numpy() if result.is_floating_point() else result.int().numpy()
+"#,
+            torch_op, dim, keepdim
+        );
+        run_py_prg(prg.to_string(), &[a], &[], dst_dt)
+    }
+
+    fn reduce(a: Tensor, op: ReduceOp, dim: usize, keepdim: bool) -> anyhow::Result<Tensor> {
+        match op {
+            ReduceOp::Sum => a.sum(dim, keepdim),
+            ReduceOp::Mean => a.mean(dim, keepdim),
+            ReduceOp::Max => a.max(dim, keepdim),
+            ReduceOp::Min => a.min(dim, keepdim),
+            ReduceOp::ArgMax => a.argmax(dim, keepdim),
+            ReduceOp::ArgMin => a.argmin(dim, keepdim),
+        }
+    }
+
+    fn run_reduce_trial(problem: ReduceProblem) -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let ReduceProblem {
+            op,
+            B,
+            M,
+            N,
+            dim,
+            keepdim,
+        } = problem;
+        let a = Tensor::randn::<f32>(shape![B, M, N], Device::CPU);
+        let ground = ground_truth(&a, op, dim, keepdim)?;
+
+        let a_gpu = a.to(&device)?;
+        let b = reduce(a_gpu, op, dim, keepdim)?.resolve()?;
+
+        let ours = b.to(&Device::CPU)?;
+        if op.is_arg() {
+            assert_eq!(ours.shape(), ground.shape());
+            assert_eq!(ours.to_vec::<i32>()?, ground.to_vec::<i32>()?);
+        } else {
+            ground.all_close(&ours, 1e-4, 1e-4)?;
+        }
+        Ok(())
+    }
+
+    #[proptest(cases = 16)]
+    fn test_reduce(prob: ReduceProblem) {
+        run_reduce_trial(prob).unwrap();
+    }
+
+    #[test]
+    fn test_reduce_cpu() -> anyhow::Result<()> {
+        let a = Tensor::randn::<f32>(shape![2, 17, 33], Device::CPU);
+        for op in [ReduceOp::Sum, ReduceOp::Max, ReduceOp::ArgMin] {
+            for dim in 0..3 {
+                let ground = ground_truth(&a, op, dim, false)?;
+                let ours = reduce(a.clone(), op, dim, false)?.resolve()?;
+                if op.is_arg() {
+                    assert_eq!(ours.to_vec::<i32>()?, ground.to_vec::<i32>()?);
+                } else {
+                    ground.all_close(&ours, 1e-4, 1e-4)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
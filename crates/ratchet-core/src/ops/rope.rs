@@ -8,16 +8,24 @@ use crate::{
     gpu::{BindGroupLayoutDescriptor, CpuUniform, WorkgroupCount},
     rvec, wgc, wgs, Array, BindingMode, BuiltIn, DType, KernelElement, KernelSource, MetaOperation,
     OpGuards, Operation, OperationError, RVec, Scalar, StorageView, Strides, Tensor, Vec2, Vec4,
-    WgslKernelBuilder, WgslPrimitive, WorkgroupSize, Workload,
+    WgslFragment, WgslKernelBuilder, WgslPrimitive, WorkgroupSize, Workload,
 };
 use inline_wgsl::wgsl;
 
+/// # RoPE
+///
+/// Rotary positional encoding, applied inplace to a `[B, H, S, D]` input. Token `s` is at
+/// position `offset + s`.
+///
+/// The optional `row_offsets` (I32 `[B]`) shift the positions of each batch row, e.g by minus
+/// the length of its left padding, so that the first token of every sequence is at position 0.
 #[derive(new, Debug, Clone)]
 pub struct RoPE {
-    input: Tensor,
-    dim: usize,
-    base: f32,
-    offset: usize,
+    pub(crate) input: Tensor,
+    pub(crate) dim: usize,
+    pub(crate) base: f32,
+    pub(crate) offset: usize,
+    pub(crate) row_offsets: Option<Tensor>,
 }
 
 impl RoPE {
@@ -31,6 +39,10 @@ impl RoPE {
         }
         let arr = Array::<P>::default();
         builder.register_storage("in", BindingMode::ReadWrite, arr);
+        if self.row_offsets.is_some() {
+            let offsets_arr = Array::<Scalar<i32>>::default();
+            builder.register_storage("O", BindingMode::ReadOnly, offsets_arr);
+        }
         builder.register_uniform();
         Ok(())
     }
@@ -58,6 +70,11 @@ impl RoPE {
         kernel_builder.write_metadata::<RoPEMeta>();
 
         let dt = P::T::DT;
+        let row_offset = if self.row_offsets.is_some() {
+            wgsl! { + O[global_invocation_id.z / metadata.n_heads] }
+        } else {
+            WgslFragment::new(0)
+        };
 
         kernel_builder.write_main(wgsl! {
             if(global_invocation_id.y >= metadata.seq_len) {
@@ -72,7 +89,8 @@ impl RoPE {
             let in_index_1 = dot(global_invocation_id, vec3<u32>(metadata.in_strides[2], metadata.in_strides[1], metadata.in_strides[0]));
             let in_index_2 = in_index_1 + grid.x * metadata.in_strides[2];
 
-            let L = metadata.scale * f32(global_invocation_id.y + metadata.offset);
+            let position = i32(global_invocation_id.y + metadata.offset) 'row_offset;
+            let L = metadata.scale * f32(position);
             let d = f32(global_invocation_id.x) / f32(grid.x);
 
             let theta = L * exp2(-d * metadata.base);
@@ -98,6 +116,7 @@ pub struct RoPEMeta {
     in_strides: glam::UVec3,
     out_strides: glam::UVec3,
     seq_len: u32,
+    n_heads: u32,
     offset: u32,
     base: f32,
     scale: f32,
@@ -110,11 +129,17 @@ impl OpGuards for RoPE {
         assert!(input.rank() == 4);
         assert!(input.shape()[3] >= self.dim);
         assert!(self.dim % 8 == 0);
+        if let Some(row_offsets) = &self.row_offsets {
+            assert_eq!(row_offsets.shape().to_vec(), vec![input.shape()[0]]);
+        }
     }
 
     fn check_dtypes(&self) {
         let input = &self.input;
         assert!(input.dt().is_float());
+        if let Some(row_offsets) = &self.row_offsets {
+            assert_eq!(row_offsets.dt(), DType::I32);
+        }
     }
 }
 
@@ -134,7 +159,10 @@ impl MetaOperation for RoPE {
     }
 
     fn srcs(&self) -> RVec<&Tensor> {
-        rvec![&self.input]
+        match &self.row_offsets {
+            Some(row_offsets) => rvec![&self.input, row_offsets],
+            None => rvec![&self.input],
+        }
     }
 
     fn kernel_element(&self, _dst: &Tensor) -> KernelElement {
@@ -170,7 +198,10 @@ impl MetaOperation for RoPE {
         inplace: bool,
     ) -> Result<BindGroupLayoutDescriptor, OperationError> {
         if inplace {
-            return Ok(BindGroupLayoutDescriptor::unary_inplace());
+            return match self.row_offsets {
+                Some(_) => Ok(BindGroupLayoutDescriptor::binary_inplace()),
+                None => Ok(BindGroupLayoutDescriptor::unary_inplace()),
+            };
         }
         panic!("RoPE does not support out-of-place operation");
     }
@@ -181,8 +212,8 @@ impl MetaOperation for RoPE {
         dst: &Tensor,
         _: &KernelElement,
     ) -> Result<u64, OperationError> {
+        let [_, NH, SL, _]: [usize; 4] = self.input.shape().try_into()?;
         let mut input_shape = self.input.shape().clone();
-        let SL = input_shape[2];
         let mut out_shape = dst.shape().clone();
         input_shape.remove(0);
         out_shape.remove(0);
@@ -192,6 +223,7 @@ impl MetaOperation for RoPE {
             (&in_strides).into(),
             (&out_strides).into(),
             SL as u32,
+            NH as u32,
             self.offset as u32,
             self.base,
             1.0,
@@ -284,6 +316,35 @@ def mlx_rope(input, dim, offset):
         ground.all_close(&ours, 1e-3, 1e-3).unwrap();
     }
 
+    #[test]
+    fn test_rope_row_offsets() {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let (NH, SL, HD, dim, offset) = (4, 7, 64, 32, 5);
+        let row_offsets = [-3i32, 0, -5];
+        let BS = row_offsets.len();
+        let a = Tensor::randn::<f32>(shape![BS, NH, SL, HD], Device::CPU);
+
+        let offsets_gpu = Tensor::from_data(row_offsets, shape![BS], device.clone());
+        let b = a
+            .to(&device)
+            .unwrap()
+            .rope_with_offsets(dim, 10000.0, offset, offsets_gpu)
+            .unwrap()
+            .resolve()
+            .unwrap();
+        let ours = b.to(&Device::CPU).unwrap();
+
+        //Each row matches the reference at its own offset
+        for (row, row_offset) in row_offsets.into_iter().enumerate() {
+            let range = [row..row + 1, 0..NH, 0..SL, 0..HD];
+            let input = a.deep_clone().slice(&range).unwrap().resolve().unwrap();
+            let row_start = (offset as i32 + row_offset) as usize;
+            let ground = ground_truth(&input, dim, row_start).unwrap();
+            let actual = ours.clone().slice(&range).unwrap().resolve().unwrap();
+            ground.all_close(&actual, 1e-3, 1e-3).unwrap();
+        }
+    }
+
     #[derive(Arbitrary, Debug)]
     struct RoPEProblem {
         #[strategy(1..=2usize)]
@@ -0,0 +1,515 @@
+use derive_new::new;
+use encase::ShaderType;
+use half::f16;
+use inline_wgsl::wgsl;
+use ratchet_macros::WgslMetadata;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    gpu::{dtype::WgslDType, BindGroupLayoutDescriptor, CpuUniform},
+    rvec, wgc, wgs, Array, BindingMode, BuiltIn, DType, KernelElement, KernelSource, MetaOperation,
+    OpGuards, Operation, OperationError, RVec, Scalar, StorageView, Strides, Tensor,
+    WgslKernelBuilder, WgslPrimitive, WorkgroupSize, Workload,
+};
+
+/// # SampleStrategy
+///
+/// How a token is selected from a row of logits.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleStrategy {
+    /// Select the most likely token.
+    Greedy,
+    /// Sample from `softmax(logits / temperature)`.
+    Temperature(f32),
+    /// Sample from the `k` most likely tokens, after applying `temperature`.
+    /// `k` cannot exceed [Sample::MAX_TOP_K].
+    TopK { k: usize, temperature: f32 },
+}
+
+impl SampleStrategy {
+    pub fn kernel_name(&self) -> &'static str {
+        match self {
+            SampleStrategy::Greedy => "sample_greedy",
+            SampleStrategy::Temperature(_) => "sample_temperature",
+            SampleStrategy::TopK { .. } => "sample_topk",
+        }
+    }
+
+    fn temperature(&self) -> f32 {
+        match self {
+            SampleStrategy::Greedy => 1.,
+            SampleStrategy::Temperature(t) | SampleStrategy::TopK { temperature: t, .. } => *t,
+        }
+    }
+}
+
+/// # Sample
+///
+/// Samples a token id from each row of `logits`, reducing the last dimension.
+/// Returns an I32 tensor, so only the selected ids need to be read back to the host.
+///
+/// One workgroup is dispatched per row. The random number for a row is derived from
+/// `seed` and the row index, so results are reproducible for a given seed.
+#[derive(new, Debug, Clone)]
+pub struct Sample {
+    pub(crate) logits: Tensor,
+    pub(crate) strategy: SampleStrategy,
+    pub(crate) seed: u32,
+}
+
+#[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
+pub struct SampleMeta {
+    N: u32,
+    k: u32,
+    temperature: f32,
+    seed: u32,
+}
+
+impl Sample {
+    pub(crate) const BLOCK_SIZE: usize = 128;
+    /// Upper bound on `k` for top-k sampling, the candidates are held in workgroup memory.
+    pub const MAX_TOP_K: usize = 64;
+
+    /// Number of candidates considered for top-k sampling.
+    pub(crate) fn k(&self) -> usize {
+        let N = self.logits.shape()[self.logits.rank() - 1];
+        match self.strategy {
+            SampleStrategy::TopK { k, .. } => k.min(N),
+            _ => 0,
+        }
+    }
+
+    /// PCG hash, shared with the kernel so that host and device agree on the random stream.
+    pub(crate) fn pcg_hash(input: u32) -> u32 {
+        let state = input.wrapping_mul(747796405).wrapping_add(2891336453);
+        let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+        (word >> 22) ^ word
+    }
+
+    /// Uniform random number in [0, 1) for the given row.
+    pub(crate) fn uniform(seed: u32, row: u32) -> f32 {
+        (Self::pcg_hash(seed ^ Self::pcg_hash(row)) >> 8) as f32 / 16777216.
+    }
+
+    fn register_bindings<P: WgslPrimitive>(
+        &self,
+        builder: &mut WgslKernelBuilder,
+        _: bool,
+    ) -> Result<(), OperationError> {
+        builder.register_storage("X", BindingMode::ReadOnly, Array::<P>::default());
+        builder.register_storage("Y", BindingMode::ReadWrite, Array::<Scalar<i32>>::default());
+        builder.register_uniform();
+        Ok(())
+    }
+
+    fn build_sample<P: WgslPrimitive>(
+        &self,
+        inplace: bool,
+        _: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let device = self.logits.device().try_gpu().unwrap();
+        let mut kernel_builder = WgslKernelBuilder::new(
+            workgroup_size.clone(),
+            rvec![BuiltIn::LocalInvocationId, BuiltIn::WorkgroupId],
+            device.compute_features().clone(),
+        );
+        self.register_bindings::<P>(&mut kernel_builder, inplace)?;
+        kernel_builder.write_metadata::<SampleMeta>();
+
+        let BLOCK_SIZE = workgroup_size.x.render();
+        let MAX_TOP_K = (Self::MAX_TOP_K as u32).render();
+        let minFloat = <f32 as WgslDType>::MIN.render();
+
+        //All arithmetic is performed in f32, regardless of the logits dtype.
+        kernel_builder.write_global(wgsl! {
+            var<workgroup> smem: array<f32, 'BLOCK_SIZE>;
+            var<workgroup> sidx: array<u32, 'BLOCK_SIZE>;
+            var<workgroup> partial: array<f32, 'BLOCK_SIZE>;
+            var<workgroup> topk_vals: array<f32, 'MAX_TOP_K>;
+            var<workgroup> topk_idx: array<u32, 'MAX_TOP_K>;
+            var<workgroup> maximum: f32;
+            var<workgroup> chosen: u32;
+            var<workgroup> residual: f32;
+
+            fn pcg_hash(input: u32) -> u32 {
+                let state = input * 747796405u + 2891336453u;
+                let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+                return (word >> 22u) ^ word;
+            }
+
+            fn uniform(row: u32) -> f32 {
+                return f32(pcg_hash(metadata.seed ^ pcg_hash(row)) >> 8u) / 16777216.0;
+            }
+
+            //Ties are broken towards the lowest index, matching argmax.
+            fn block_argmax(index: u32, stride: u32) {
+                if index < stride {
+                    let other = smem[index + stride];
+                    let other_idx = sidx[index + stride];
+                    if other > smem[index] || (other == smem[index] && other_idx < sidx[index]) {
+                        smem[index] = other;
+                        sidx[index] = other_idx;
+                    }
+                }
+                workgroupBarrier();
+            }
+
+            fn block_max(index: u32, stride: u32) {
+                if index < stride {
+                    smem[index] = max(smem[index], smem[index + stride]);
+                }
+                workgroupBarrier();
+            }
+        });
+
+        kernel_builder.write_main(wgsl! {
+            let row = workgroup_id.x;
+            let row_start = row * metadata.N;
+            let index = local_invocation_id.x;
+        });
+
+        let steps = (workgroup_size.x - 1).ilog2();
+        let strides = (0..=steps)
+            .rev()
+            .map(|x| 2u32.pow(x).render())
+            .collect::<Vec<_>>();
+        let block_argmax = strides
+            .iter()
+            .map(|v| wgsl! { block_argmax(index, 'v); })
+            .collect::<String>();
+        let block_max = strides
+            .iter()
+            .map(|v| wgsl! { block_max(index, 'v); })
+            .collect::<String>();
+
+        match self.strategy {
+            SampleStrategy::Greedy => {
+                kernel_builder.write_main(wgsl! {
+                    smem[index] = 'minFloat;
+                    sidx[index] = metadata.N;
+                    for (var i: u32 = index; i < metadata.N; i += 'BLOCK_SIZE) {
+                        let val = f32(X[row_start + i]);
+                        if val > smem[index] || sidx[index] == metadata.N {
+                            smem[index] = val;
+                            sidx[index] = i;
+                        }
+                    }
+                    workgroupBarrier();
+                    'block_argmax
+                    if index == 0u {
+                        Y[row] = i32(sidx[0]);
+                    }
+                });
+            }
+            SampleStrategy::TopK { .. } => {
+                //Each round selects the next largest logit, in (value desc, index asc) order.
+                kernel_builder.write_main(wgsl! {
+                    var prev_val = 0.0;
+                    var prev_idx = 0u;
+                    for (var r: u32 = 0u; r < metadata.k; r++) {
+                        smem[index] = 'minFloat;
+                        sidx[index] = metadata.N;
+                        for (var i: u32 = index; i < metadata.N; i += 'BLOCK_SIZE) {
+                            let val = f32(X[row_start + i]);
+                            let eligible = r == 0u || val < prev_val || (val == prev_val && i > prev_idx);
+                            if eligible && (val > smem[index] || sidx[index] == metadata.N) {
+                                smem[index] = val;
+                                sidx[index] = i;
+                            }
+                        }
+                        workgroupBarrier();
+                        'block_argmax
+                        if index == 0u {
+                            topk_vals[r] = smem[0];
+                            topk_idx[r] = sidx[0];
+                        }
+                        workgroupBarrier();
+                        prev_val = topk_vals[r];
+                        prev_idx = topk_idx[r];
+                    }
+
+                    if index == 0u {
+                        var total = 0.0;
+                        for (var r: u32 = 0u; r < metadata.k; r++) {
+                            total += exp((topk_vals[r] - topk_vals[0]) / metadata.temperature);
+                        }
+                        let target = uniform(row) * total;
+                        var acc = 0.0;
+                        var selected = topk_idx[0];
+                        for (var r: u32 = 0u; r < metadata.k; r++) {
+                            acc += exp((topk_vals[r] - topk_vals[0]) / metadata.temperature);
+                            selected = topk_idx[r];
+                            if acc > target {
+                                break;
+                            }
+                        }
+                        Y[row] = i32(selected);
+                    }
+                });
+            }
+            SampleStrategy::Temperature(_) => {
+                //The CDF is walked in invocation order rather than token order.
+                //Each invocation owns a strided subset of the row, so the partial sums
+                //locate the owning invocation, which then locates the token.
+                kernel_builder.write_main(wgsl! {
+                    smem[index] = 'minFloat;
+                    for (var i: u32 = index; i < metadata.N; i += 'BLOCK_SIZE) {
+                        smem[index] = max(smem[index], f32(X[row_start + i]));
+                    }
+                    workgroupBarrier();
+                    'block_max
+                    if index == 0u {
+                        maximum = smem[0];
+                    }
+                    workgroupBarrier();
+
+                    var local_sum = 0.0;
+                    for (var i: u32 = index; i < metadata.N; i += 'BLOCK_SIZE) {
+                        local_sum += exp((f32(X[row_start + i]) - maximum) / metadata.temperature);
+                    }
+                    partial[index] = local_sum;
+                    workgroupBarrier();
+
+                    if index == 0u {
+                        var total = 0.0;
+                        for (var t: u32 = 0u; t < 'BLOCK_SIZE; t++) {
+                            total += partial[t];
+                        }
+                        let target = uniform(row) * total;
+                        var acc = 0.0;
+                        for (var t: u32 = 0u; t < 'BLOCK_SIZE; t++) {
+                            if partial[t] > 0.0 {
+                                chosen = t;
+                                residual = target - acc;
+                            }
+                            acc += partial[t];
+                            if acc > target {
+                                break;
+                            }
+                        }
+                    }
+                    workgroupBarrier();
+
+                    if index == chosen {
+                        var acc = 0.0;
+                        var selected = index;
+                        for (var i: u32 = index; i < metadata.N; i += 'BLOCK_SIZE) {
+                            acc += exp((f32(X[row_start + i]) - maximum) / metadata.temperature);
+                            selected = i;
+                            if acc > residual {
+                                break;
+                            }
+                        }
+                        Y[row] = i32(selected);
+                    }
+                });
+            }
+        }
+
+        Ok(kernel_builder.build()?)
+    }
+}
+
+impl OpGuards for Sample {
+    fn check_shapes(&self) {
+        let logits = &self.logits;
+        assert!(logits.rank() >= 1);
+        assert!(logits.shape()[logits.rank() - 1] > 0);
+        if let SampleStrategy::TopK { k, .. } = self.strategy {
+            assert!(
+                k <= Self::MAX_TOP_K,
+                "Top-k sampling supports k <= {}",
+                Self::MAX_TOP_K
+            );
+        }
+    }
+
+    fn check_dtypes(&self) {
+        let logits = &self.logits;
+        assert!(logits.dt().is_float());
+    }
+}
+
+impl Operation for Sample {
+    fn compute_view(&self) -> Result<StorageView, OperationError> {
+        let mut shape = self.logits.shape().clone();
+        shape.remove(shape.rank() - 1);
+        let strides = Strides::from(&shape);
+        Ok(StorageView::new(shape, DType::I32, strides))
+    }
+}
+
+impl MetaOperation for Sample {
+    fn kernel_name(&self) -> String {
+        self.strategy.kernel_name().to_string()
+    }
+
+    fn srcs(&self) -> RVec<&Tensor> {
+        rvec![&self.logits]
+    }
+
+    fn kernel_element(&self, _: &Tensor) -> KernelElement {
+        KernelElement::Scalar
+    }
+
+    fn calculate_dispatch(&self, dst: &Tensor) -> Result<Workload, OperationError> {
+        let rows = dst.shape().numel();
+        Ok(Workload {
+            workgroup_size: wgs![Self::BLOCK_SIZE as _, 1, 1],
+            workgroup_count: wgc![rows as _, 1, 1],
+        })
+    }
+
+    fn storage_bind_group_layout(
+        &self,
+        _: bool,
+    ) -> Result<BindGroupLayoutDescriptor, OperationError> {
+        Ok(BindGroupLayoutDescriptor::unary())
+    }
+
+    fn write_metadata(
+        &self,
+        uniform: &mut CpuUniform,
+        _: &Tensor,
+        _: &KernelElement,
+    ) -> Result<u64, OperationError> {
+        let N = self.logits.shape()[self.logits.rank() - 1] as u32;
+        let meta = SampleMeta {
+            N,
+            k: self.k() as u32,
+            temperature: self.strategy.temperature(),
+            seed: self.seed,
+        };
+        Ok(uniform.write(&meta)?)
+    }
+
+    fn build_kernel(
+        &self,
+        inplace: bool,
+        dst: &Tensor,
+        workgroup_size: &WorkgroupSize,
+    ) -> Result<KernelSource, OperationError> {
+        let kernel_element = self.kernel_element(dst);
+        match self.logits.dt() {
+            DType::F32 => self.build_sample::<Scalar<f32>>(inplace, dst, workgroup_size),
+            DType::F16 => self.build_sample::<Scalar<f16>>(inplace, dst, workgroup_size),
+            _ => Err(OperationError::CompileError(format!(
+                "Unsupported dtype {:?} or kernel element {:?}",
+                self.logits.dt(),
+                kernel_element
+            ))),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "pyo3"))]
+mod tests {
+    use test_strategy::{proptest, Arbitrary};
+
+    use crate::{
+        shape, test_util::run_py_prg, DType, Device, DeviceRequest, Sample, SampleStrategy, Tensor,
+    };
+
+    thread_local! {
+        static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
+    }
+
+    #[derive(Arbitrary, Debug)]
+    struct SampleProblem {
+        #[strategy(1..=4usize)]
+        B: usize,
+        #[strategy(1..=8usize)]
+        M: usize,
+        #[strategy(1..=60000usize)]
+        V: usize,
+    }
+
+    fn ground_truth(logits: &Tensor) -> anyhow::Result<Tensor> {
+        let prg = r#"
+import torch
+def argmax(logits):
+    return torch.argmax(torch.from_numpy(logits), dim=-1).int().numpy()
+"#;
+        run_py_prg(prg.to_string(), &[logits], &[], DType::I32)
+    }
+
+    fn run_greedy_trial(problem: SampleProblem) -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let SampleProblem { B, M, V } = problem;
+        let logits = Tensor::randn::<f32>(shape![B, M, V], Device::CPU);
+        let ground = ground_truth(&logits)?;
+
+        let ours = logits
+            .to(&device)?
+            .sample(SampleStrategy::Greedy, 0)?
+            .resolve()?
+            .to(&Device::CPU)?;
+        assert_eq!(ours.shape(), ground.shape());
+        assert_eq!(ours.to_vec::<i32>()?, ground.to_vec::<i32>()?);
+        Ok(())
+    }
+
+    #[proptest(cases = 8)]
+    fn test_sample_greedy(prob: SampleProblem) {
+        run_greedy_trial(prob).unwrap();
+    }
+
+    #[test]
+    fn test_sample_matches_cpu() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        //Well separated logits, so both backends walk the same CDF buckets.
+        let data = (0..16 * 300).map(|i| (i % 7) as f32).collect::<Vec<_>>();
+        let logits = Tensor::from_data(data, shape![16, 300], Device::CPU);
+
+        for strategy in [
+            SampleStrategy::Temperature(0.7),
+            SampleStrategy::TopK {
+                k: 5,
+                temperature: 1.0,
+            },
+        ] {
+            let cpu = logits.clone().sample(strategy, 42)?.resolve()?;
+            let gpu = logits
+                .to(&device)?
+                .sample(strategy, 42)?
+                .resolve()?
+                .to(&Device::CPU)?;
+            assert_eq!(cpu.to_vec::<i32>()?, gpu.to_vec::<i32>()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_topk_rejects_large_k() {
+        let logits = Tensor::randn::<f32>(shape![2, 1000], Device::CPU);
+        let strategy = |k| SampleStrategy::TopK {
+            k,
+            temperature: 1.0,
+        };
+        assert!(logits
+            .clone()
+            .sample(strategy(Sample::MAX_TOP_K), 0)
+            .is_ok());
+        assert!(logits.sample(strategy(Sample::MAX_TOP_K + 1), 0).is_err());
+    }
+
+    #[test]
+    fn test_topk_one_is_greedy() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let logits = Tensor::randn::<f32>(shape![4, 1000], Device::CPU);
+        let ground = ground_truth(&logits)?;
+        let strategy = SampleStrategy::TopK {
+            k: 1,
+            temperature: 1.0,
+        };
+        let ours = logits
+            .to(&device)?
+            .sample(strategy, 7)?
+            .resolve()?
+            .to(&Device::CPU)?;
+        assert_eq!(ours.to_vec::<i32>()?, ground.to_vec::<i32>()?);
+        Ok(())
+    }
+}
@@ -13,9 +13,9 @@ use inline_wgsl::wgsl;
 
 #[derive(new, Debug, Clone)]
 pub struct IndexSelect {
-    src: Tensor,
-    indices: Tensor,
-    dim: usize,
+    pub(crate) src: Tensor,
+    pub(crate) indices: Tensor,
+    pub(crate) dim: usize,
 }
 
 impl IndexSelect {
@@ -13,8 +13,8 @@ use crate::{
 
 #[derive(new, Debug, Clone)]
 pub struct Softmax {
-    input: Tensor,
-    dim: usize,
+    pub(crate) input: Tensor,
+    pub(crate) dim: usize,
 }
 
 #[derive(Debug, derive_new::new, ShaderType, WgslMetadata)]
@@ -6,6 +6,7 @@ use encase::ShaderType;
 use half::f16;
 use inline_wgsl::wgsl;
 use ratchet_macros::WgslMetadata;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     gpu::{dtype::WgslDType, BindGroupLayoutDescriptor, CpuUniform},
@@ -18,7 +19,8 @@ use crate::{
 use test_strategy::Arbitrary;
 
 #[cfg_attr(test, derive(Arbitrary))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum UnaryOp {
     Gelu,
     Tanh,
@@ -67,13 +69,13 @@ impl UnaryOp {
 
 #[derive(new, Debug, Clone)]
 pub struct Unary {
-    input: Tensor,
-    op: UnaryOp,
+    pub(crate) input: Tensor,
+    pub(crate) op: UnaryOp,
 }
 
 impl Unary {
-    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
-    const SCALED_SQRT_2_OVER_PI: f32 = 0.035_677_407;
+    pub(crate) const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    pub(crate) const SCALED_SQRT_2_OVER_PI: f32 = 0.035_677_407;
 
     pub fn op(&self) -> &UnaryOp {
         &self.op
@@ -129,6 +131,46 @@ impl Unary {
         }
     }
 
+    fn render_relu<P: WgslPrimitive>() -> String {
+        let accessor = P::render_type();
+
+        wgsl! {
+            fn relu(val: 'accessor) -> 'accessor {
+                return max(val, 'accessor(0.0));
+            }
+        }
+    }
+
+    fn render_silu<P: WgslPrimitive>() -> String {
+        let accessor = P::render_type();
+
+        wgsl! {
+            fn silu(val: 'accessor) -> 'accessor {
+                return val * sigmoid(val);
+            }
+        }
+    }
+
+    /// The global functions required by `op`, in dependency order.
+    ///
+    /// Each helper is paired with its name, so kernels applying multiple ops can deduplicate them.
+    pub(crate) fn render_helpers<P: WgslPrimitive>(op: &UnaryOp) -> RVec<(&'static str, String)> {
+        match op {
+            UnaryOp::Gelu => rvec![
+                ("safe_tanh", Self::render_tanh::<P>()),
+                ("gelu", Self::render_gelu::<P>())
+            ],
+            UnaryOp::Tanh => rvec![("safe_tanh", Self::render_tanh::<P>())],
+            UnaryOp::Sigmoid => rvec![("sigmoid", Self::render_sigmoid::<P>())],
+            UnaryOp::Silu => rvec![
+                ("sigmoid", Self::render_sigmoid::<P>()),
+                ("silu", Self::render_silu::<P>())
+            ],
+            UnaryOp::Relu => rvec![("relu", Self::render_relu::<P>())],
+            _ => rvec![],
+        }
+    }
+
     fn build_unary<P: WgslPrimitive>(
         &self,
         inplace: bool,
@@ -149,36 +191,9 @@ impl Unary {
         self.register_bindings::<P>(&mut kernel_builder, inplace)?;
         kernel_builder.write_metadata::<UnaryMeta>();
 
-        let accessor = P::render_type();
-        //Write global functions
-        match self.op {
-            UnaryOp::Gelu => {
-                kernel_builder.write_global(Unary::render_tanh::<P>());
-                kernel_builder.write_global(Unary::render_gelu::<P>());
-            }
-            UnaryOp::Tanh => {
-                kernel_builder.write_global(Unary::render_tanh::<P>());
-            }
-            UnaryOp::Sigmoid => {
-                kernel_builder.write_global(Unary::render_sigmoid::<P>());
-            }
-            UnaryOp::Silu => {
-                kernel_builder.write_global(Unary::render_sigmoid::<P>());
-                kernel_builder.write_global(wgsl! {
-                    fn silu(val: 'accessor) -> 'accessor {
-                        return val * sigmoid(val);
-                    }
-                });
-            }
-            UnaryOp::Relu => {
-                kernel_builder.write_global(wgsl! {
-                    fn relu(val: 'accessor) -> 'accessor {
-                        return max(val, 'accessor(0.0));
-                    }
-                });
-            }
-            _ => {}
-        };
+        for (_, helper) in Unary::render_helpers::<P>(&self.op) {
+            kernel_builder.write_global(helper);
+        }
 
         let n = P::W;
 
@@ -0,0 +1,117 @@
+use rustc_hash::FxHashMap;
+
+use crate::{Cast, DType, IrOp, LazyOp, OperationError, RVec, Tensor, TensorId};
+
+/// # BF16 emulation
+///
+/// WGSL has no bfloat16 type, so BF16 tensors are stored as pairs packed into a `u32`, and only
+/// a [Cast] to or from F32 can read or write them. Every other operation touching BF16 is
+/// computed in F32: its BF16 sources are unpacked, and its result is packed back into BF16.
+/// Casts between BF16 and any dtype other than F32 go through F32.
+///
+/// Returns a substitute for each emulated operation, or an error if an operation can't be
+/// emulated. Operations that write into an existing buffer ([crate::IndexWrite], [crate::Cache]
+/// & [crate::PagedCache]) never can, as they would write into an unpacked copy.
+pub(crate) fn emulate_bf16(execution_order: &[&Tensor]) -> Result<Vec<Tensor>, OperationError> {
+    let mut substitutes = vec![];
+    let mut unpacked: FxHashMap<TensorId, Tensor> = FxHashMap::default();
+    let mut unpack = |t: &Tensor| {
+        if t.dt() != DType::BF16 {
+            return t.clone();
+        }
+        unpacked
+            .entry(t.id())
+            .or_insert_with(|| cast(t.clone(), DType::F32))
+            .clone()
+    };
+
+    for t in execution_order {
+        let srcs = t.op().srcs();
+        if t.resolved() || !(t.dt() == DType::BF16 || srcs.iter().any(|s| s.dt() == DType::BF16)) {
+            continue;
+        }
+        match t.op() {
+            LazyOp::Const | LazyOp::View(_) => continue,
+            LazyOp::IndexWrite(_) | LazyOp::Cache(_) | LazyOp::PagedCache(_) => {
+                return Err(OperationError::CompileError(format!(
+                    "{} cannot write into BF16 on the GPU",
+                    t.op().name()
+                )));
+            }
+            LazyOp::Cast(c) => {
+                if c.input.dt() == DType::F32 || c.dst_dt == DType::F32 {
+                    continue;
+                }
+                let via_f32 = cast(unpack(&c.input), DType::F32);
+                substitutes.push(t.substitute(LazyOp::Cast(Cast::new(via_f32, c.dst_dt))));
+                continue;
+            }
+            _ => {}
+        }
+
+        let srcs = srcs.into_iter().map(&mut unpack).collect::<RVec<_>>();
+        let (op, view, device) = IrOp::from_lazy(t.op())
+            .and_then(|op| op.to_op(0, srcs, t.device()))
+            .map_err(|e| {
+                OperationError::CompileError(format!(
+                    "Failed to emulate BF16 for {}: {}",
+                    t.op().name(),
+                    e
+                ))
+            })?;
+        if t.dt() == DType::BF16 {
+            let computed = Tensor::lazy(op, view, device);
+            substitutes.push(t.substitute(LazyOp::Cast(Cast::new(computed, DType::BF16))));
+        } else {
+            substitutes.push(t.substitute(op));
+        }
+    }
+    Ok(substitutes)
+}
+
+fn cast(t: Tensor, dst_dt: DType) -> Tensor {
+    t.cast(dst_dt).expect("Casts are always valid")
+}
+
+#[cfg(all(test, feature = "pyo3"))]
+mod tests {
+    use crate::{rvec, shape, DType, Device, DeviceRequest, Tensor};
+
+    thread_local! {
+        static GPU_DEVICE: Device = Device::request_device(DeviceRequest::GPU).unwrap();
+    }
+
+    fn bf16_randn(shape: crate::Shape) -> anyhow::Result<Tensor> {
+        Ok(Tensor::randn::<f32>(shape, Device::CPU)
+            .cast(DType::BF16)?
+            .resolve()?)
+    }
+
+    #[test]
+    fn emulates_bf16() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let (a, b) = (bf16_randn(shape![8, 16])?, bf16_randn(shape![16, 12])?);
+        let scale = bf16_randn(shape![12])?;
+
+        //Both devices round to BF16 after every operation
+        let graph = |a: Tensor, b: Tensor, scale: Tensor| {
+            a.matmul(b, false, false)?
+                .rms_norm(scale, 1e-5)?
+                .gelu()?
+                .cast(DType::F32)
+        };
+        let ground = graph(a.clone(), b.clone(), scale.clone())?.resolve()?;
+        let result = graph(a.to(&device)?, b.to(&device)?, scale.to(&device)?)?.resolve()?;
+        ground.all_close::<f32>(&result.to(&Device::CPU)?, 1e-2, 1e-2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_bf16_writes() -> anyhow::Result<()> {
+        let device = GPU_DEVICE.with(|d| d.clone());
+        let dst = bf16_randn(shape![4, 8])?.to(&device)?;
+        let src = bf16_randn(shape![1, 8])?.to(&device)?;
+        assert!(dst.index_write(src, rvec![0, 0])?.resolve().is_err());
+        Ok(())
+    }
+}
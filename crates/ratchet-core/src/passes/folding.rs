@@ -0,0 +1,149 @@
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{LazyOp, Tensor, TensorId};
+
+/// # Constant folding
+///
+/// A resolved tensor is a *constant* unless it is one of the `inputs` of the graph (i.e it may be
+/// updated after the graph is built, see [crate::CapturedGraph]), or the graph writes through it
+/// ([crate::IndexWrite], [crate::Cache] & [crate::PagedCache]).
+/// An unresolved tensor is *foldable* if every one of its sources is a constant or foldable,
+/// and it doesn't write through its source.
+///
+/// Returns the roots of the maximal foldable subgraphs, in execution order: the foldable tensors
+/// consumed by a tensor that isn't foldable. Each root is evaluated once, on its own, and is
+/// then a constant of the graph. A foldable tensor read by the subgraphs of several roots is a
+/// root itself, as the intermediates of each evaluation don't outlive it.
+/// Views share the storage of their source, so the source of a view is folded in its place.
+///
+/// If the output is foldable, the whole graph is constant and nothing is folded.
+/// Folding is disabled alongside simplification with `RATCHET_NO_SIMPLIFY`.
+pub(crate) fn fold_constants<'a>(
+    execution_order: &[&'a Tensor],
+    inputs: &[&Tensor],
+) -> Vec<&'a Tensor> {
+    let Some(output) = execution_order.last() else {
+        return vec![];
+    };
+    if std::env::var("RATCHET_NO_SIMPLIFY").is_ok() {
+        return vec![];
+    }
+
+    let mut variables = inputs.iter().map(|t| t.id()).collect::<FxHashSet<_>>();
+    for t in execution_order.iter() {
+        match t.op() {
+            LazyOp::IndexWrite(iw) => variables.insert(iw.dst.id()),
+            LazyOp::Cache(c) => variables.insert(c.cache.id()),
+            LazyOp::PagedCache(p) => variables.insert(p.pool.id()),
+            _ => continue,
+        };
+    }
+
+    let mut foldable = FxHashSet::default();
+    let mut consumers: FxHashMap<TensorId, Vec<&Tensor>> = FxHashMap::default();
+    for &t in execution_order.iter() {
+        for s in t.op().srcs() {
+            consumers.entry(s.id()).or_default().push(t);
+        }
+        if t.resolved() {
+            continue;
+        }
+        let writes_through = matches!(
+            t.op(),
+            LazyOp::IndexWrite(_) | LazyOp::Cache(_) | LazyOp::PagedCache(_)
+        );
+        let constant_srcs =
+            t.op().srcs().iter().all(|s| {
+                foldable.contains(&s.id()) || (s.resolved() && !variables.contains(&s.id()))
+            });
+        if !writes_through && constant_srcs {
+            foldable.insert(t.id());
+        }
+    }
+    if foldable.contains(&output.id()) {
+        return vec![];
+    }
+
+    //Walking backwards, every foldable tensor is assigned the root whose subgraph computes it
+    let mut owners: FxHashMap<TensorId, TensorId> = FxHashMap::default();
+    let mut forced = FxHashSet::default();
+    let mut roots = FxHashSet::default();
+    for &t in execution_order.iter().rev() {
+        if !foldable.contains(&t.id()) {
+            continue;
+        }
+        let mut owner = None;
+        let mut is_root = forced.contains(&t.id());
+        for c in consumers.get(&t.id()).into_iter().flatten() {
+            match owners.get(&c.id()) {
+                Some(o) if owner.is_none() || owner == Some(*o) => owner = Some(*o),
+                _ => is_root = true,
+            }
+        }
+        if let (true, LazyOp::View(v)) = (is_root, t.op()) {
+            //A view of a resolved tensor already reads constant storage, nothing to fold
+            forced.insert(v.src.id());
+            owners.insert(t.id(), v.src.id());
+        } else if is_root {
+            roots.insert(t.id());
+            owners.insert(t.id(), t.id());
+        } else if let Some(owner) = owner {
+            owners.insert(t.id(), owner);
+        }
+    }
+    execution_order
+        .iter()
+        .filter(|t| roots.contains(&t.id()))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold_constants;
+    use crate::{shape, Device, Tensor};
+
+    #[test]
+    fn folds_subgraphs_of_constants() -> anyhow::Result<()> {
+        let w = Tensor::from_data([1f32, 2., 3., 4.], shape![2, 2], Device::CPU);
+        let x = Tensor::from_data([1f32, 1.], shape![1, 2], Device::CPU);
+
+        let w_t = w.permute(&[1, 0])?.neg()?;
+        let out = x.clone().abs()?.matmul(w_t.clone(), false, false)?;
+        let order = out.execution_order();
+        let roots = fold_constants(&order, &[&x]);
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].id(), w_t.id());
+
+        //Without any inputs, the whole graph is constant
+        assert!(fold_constants(&order, &[]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn shared_constants_are_folded_once() -> anyhow::Result<()> {
+        let w = Tensor::from_data([1f32, 2., 3., 4.], shape![2, 2], Device::CPU);
+        let x = Tensor::from_data([1f32, 1., 1., 1.], shape![2, 2], Device::CPU);
+
+        //`w_abs` is read by both roots, `w_abs + w_abs` through a view
+        let w_abs = w.abs()?;
+        let lhs = w_abs.clone().neg()?;
+        let rhs = w_abs.clone().add(w_abs.clone())?.view(shape![4])?;
+        let out = x
+            .clone()
+            .mul(lhs.clone())?
+            .view(shape![4])?
+            .add(rhs.clone())?;
+        let order = out.execution_order();
+        let roots = fold_constants(&order, &[&x])
+            .iter()
+            .map(|t| t.id())
+            .collect::<Vec<_>>();
+        assert_eq!(roots.len(), 3);
+        for t in [&w_abs, &lhs] {
+            assert!(roots.contains(&t.id()));
+        }
+        assert!(!roots.contains(&rhs.id()));
+        Ok(())
+    }
+}
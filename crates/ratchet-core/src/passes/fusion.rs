@@ -0,0 +1,169 @@
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    passes::Substitutions, DType, FusedElementwise, FusedNode, FusedStep, LazyOp, RVec, Tensor,
+    TensorId,
+};
+
+/// # Elementwise fusion
+///
+/// Collapses chains of [crate::Unary], [crate::Binary] and [crate::Cast] operations into a
+/// single [FusedElementwise] kernel, saving a dispatch and an intermediate buffer per node.
+///
+/// The graph is walked from the outputs upwards. Each elementwise node that hasn't been
+/// absorbed becomes the tail of a group, which then absorbs its elementwise sources if:
+/// 1. They are unresolved, and have no consumers other than the group (strong count of 1).
+/// 2. They have the same number of elements as the tail (no broadcasting).
+/// 3. Their unary & binary operations are computed in the same float dtype.
+///
+/// Anything else becomes an input of the group. Sources are read through `subs`, so that
+/// fusion sees the graph as rewritten by earlier passes.
+///
+/// Returns a substitute for each tail, computed by the fused operation.
+///
+/// Fusion can be disabled with `RATCHET_NO_FUSION`.
+pub(crate) fn fuse_elementwise(execution_order: &[&Tensor], subs: &Substitutions) -> Vec<Tensor> {
+    let mut fused_tails = vec![];
+    if std::env::var("RATCHET_NO_FUSION").is_ok() {
+        return fused_tails;
+    }
+
+    let mut absorbed = FxHashSet::default();
+    for tail in execution_order.iter().rev() {
+        if absorbed.contains(&tail.id()) || tail.resolved() {
+            continue;
+        }
+        let mut group = Group::new(tail.shape().numel(), subs);
+        if !group.accepts(tail) {
+            continue;
+        }
+        group.visit(tail, true);
+
+        if group.absorbed.is_empty() || group.inputs.len() > FusedElementwise::MAX_INPUTS {
+            continue;
+        }
+        log::debug!(
+            "Fusing {} operations into {:?}",
+            group.absorbed.len() + 1,
+            tail.id()
+        );
+        absorbed.extend(group.absorbed.iter().copied());
+        let fused = FusedElementwise::new(group.inputs, group.nodes);
+        fused_tails.push(tail.substitute(LazyOp::Fused(fused)));
+    }
+    fused_tails
+}
+
+struct Group<'a> {
+    subs: &'a Substitutions,
+    numel: usize,
+    compute_dt: Option<DType>,
+    inputs: RVec<Tensor>,
+    input_nodes: FxHashMap<TensorId, usize>,
+    nodes: Vec<FusedNode>,
+    absorbed: Vec<TensorId>,
+}
+
+impl<'a> Group<'a> {
+    fn new(numel: usize, subs: &'a Substitutions) -> Self {
+        Self {
+            subs,
+            numel,
+            compute_dt: None,
+            inputs: RVec::new(),
+            input_nodes: FxHashMap::default(),
+            nodes: Vec::new(),
+            absorbed: Vec::new(),
+        }
+    }
+
+    /// Whether `t` can be computed as part of this group.
+    fn accepts(&mut self, t: &Tensor) -> bool {
+        let is_float = |t: &Tensor| matches!(t.dt(), DType::F32 | DType::F16);
+        let compatible = |t: &Tensor| is_float(t) && t.shape().numel() == self.numel;
+
+        let computes = match t.op() {
+            LazyOp::Unary(_) | LazyOp::Binary(_) => true,
+            LazyOp::Cast(_) => false,
+            _ => return false,
+        };
+        if !compatible(t) || !t.op().srcs().iter().all(|s| compatible(s)) {
+            return false;
+        }
+        if computes {
+            match self.compute_dt {
+                Some(dt) if dt != t.dt() => return false,
+                _ => self.compute_dt = Some(t.dt()),
+            }
+        }
+        true
+    }
+
+    /// Adds `t` to the expression, returning the index of its node.
+    fn visit(&mut self, t: &Tensor, is_tail: bool) -> usize {
+        //Consumers hold the original, so it decides whether `t` has other consumers
+        let sole_consumer = t.strong_count() == 1;
+        let subs = self.subs;
+        let t = subs.resolve(t);
+        let absorb = is_tail || (!t.resolved() && sole_consumer && self.accepts(t));
+        if !absorb {
+            return self.input(t);
+        }
+
+        let step = match t.op() {
+            LazyOp::Unary(u) => FusedStep::Unary(u.op.clone(), self.visit(&u.input, false)),
+            LazyOp::Binary(b) => {
+                let lhs = self.visit(&b.lhs, false);
+                let rhs = self.visit(&b.rhs, false);
+                FusedStep::Binary(b.op.clone(), lhs, rhs)
+            }
+            LazyOp::Cast(c) => FusedStep::Cast(self.visit(&c.input, false)),
+            _ => unreachable!("Only elementwise operations are accepted"),
+        };
+        if !is_tail {
+            self.absorbed.push(t.id());
+        }
+        self.nodes.push(FusedNode::new(step, t.dt()));
+        self.nodes.len() - 1
+    }
+
+    fn input(&mut self, t: &Tensor) -> usize {
+        if let Some(&node) = self.input_nodes.get(&t.id()) {
+            return node;
+        }
+        self.nodes
+            .push(FusedNode::new(FusedStep::Input(self.inputs.len()), t.dt()));
+        self.inputs.push(t.clone());
+        let node = self.nodes.len() - 1;
+        self.input_nodes.insert(t.id(), node);
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fuse_elementwise;
+    use crate::{shape, Device, LazyOp, Substitutions, Tensor};
+
+    #[test]
+    fn fuses_elementwise_chain() -> anyhow::Result<()> {
+        let a = Tensor::from_data([1f32, -2., 3., -4.], shape![2, 2], Device::CPU);
+        let b = Tensor::from_data([0.5f32, 0.5, 2., 2.], shape![2, 2], Device::CPU);
+        let out = a.add(b.clone())?.mul(b)?.abs()?.neg()?;
+
+        let mut subs = Substitutions::default();
+        subs.extend(fuse_elementwise(&out.execution_order(), &subs));
+        let fused = subs.resolve(&out).clone();
+        match fused.op() {
+            LazyOp::Fused(f) => {
+                assert_eq!(f.inputs.len(), 2);
+                assert_eq!(f.expression_key(), "x0.x1.add_0_1.mul_2_1.abs_3.neg_4");
+            }
+            op => panic!("Expected fused operation, got {:?}", op.name()),
+        }
+
+        fused.resolve()?;
+        assert_eq!(out.to_vec::<f32>()?, vec![-0.75, -0.75, -10., -4.]);
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+mod emulation;
+mod folding;
+mod fusion;
+mod simplify;
+
+pub(crate) use emulation::*;
+pub(crate) use folding::*;
+pub(crate) use fusion::*;
+pub(crate) use simplify::*;
+
+use crate::{Tensor, TensorId};
+use rustc_hash::FxHashMap;
+
+/// # Substitutions
+///
+/// Tensors are immutable, so graph passes cannot rewrite the operation of a node in place.
+/// Instead, a pass records a substitute for each node it rewrites, keyed by the id of the original.
+///
+/// A substitute shares the id & storage of the tensor it replaces (see `Tensor::substitute`),
+/// so consumers of the original read its result without being rewritten themselves.
+/// The execution order and the allocator resolve tensors through the substitutions when
+/// walking the graph.
+#[derive(Debug, Default)]
+pub struct Substitutions(FxHashMap<TensorId, Tensor>);
+
+impl Substitutions {
+    pub fn insert(&mut self, substitute: Tensor) {
+        self.0.insert(substitute.id(), substitute);
+    }
+
+    pub fn extend(&mut self, substitutes: impl IntoIterator<Item = Tensor>) {
+        for substitute in substitutes {
+            self.insert(substitute);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the substitute for `t`, or `t` itself if it was not rewritten.
+    pub fn resolve<'a>(&'a self, t: &'a Tensor) -> &'a Tensor {
+        self.0.get(&t.id()).unwrap_or(t)
+    }
+}
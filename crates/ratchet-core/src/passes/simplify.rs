@@ -0,0 +1,191 @@
+use std::collections::hash_map::Entry;
+
+use rustc_hash::FxHashMap;
+
+use crate::{passes::Substitutions, IrOp, LazyOp, Permute, RVec, Reindex, Tensor, TensorId};
+
+/// # Graph simplification
+///
+/// Removes redundant work from the graph before it is allocated:
+/// 1. Casts to the dtype the tensor already has are dropped.
+/// 2. Views to the shape the tensor already has, and permutes that don't move any dimension,
+///    are dropped.
+/// 3. Chains of views, and chains of permutes, are folded into a single operation.
+/// 4. Common subexpressions are eliminated: a node computing the same operation on the same
+///    sources as an earlier node is replaced by it.
+///
+/// Nodes are visited in execution order. When a node is *replaced* by another tensor, every
+/// consumer is rebuilt to read from the replacement instead, and the node drops out of the
+/// graph. Rebuilt & rewritten nodes are returned as substitutions, along with the id of the
+/// replacement of each replaced node, so that it can share its storage once allocated.
+///
+/// The output is never replaced, as it must receive storage of its own.
+///
+/// Simplification can be disabled with `RATCHET_NO_SIMPLIFY`.
+pub(crate) fn simplify(
+    execution_order: &[&Tensor],
+) -> (Substitutions, FxHashMap<TensorId, TensorId>) {
+    let mut subs = Substitutions::default();
+    let Some(output) = execution_order.last() else {
+        return (subs, FxHashMap::default());
+    };
+    if std::env::var("RATCHET_NO_SIMPLIFY").is_ok() {
+        return (subs, FxHashMap::default());
+    }
+
+    let mut replacements: FxHashMap<TensorId, Tensor> = FxHashMap::default();
+    let mut expressions: FxHashMap<String, Tensor> = FxHashMap::default();
+    for &t in execution_order {
+        if t.resolved() {
+            continue;
+        }
+        let mut node = rebuild(t, &replacements);
+        if let Some(simpler) = simplify_node(node.as_ref().unwrap_or(t), &subs) {
+            node = Some(simpler);
+        }
+
+        let current = node.as_ref().unwrap_or(t);
+        if current.id() == t.id() {
+            if let Some(key) = expression_key(current) {
+                match expressions.entry(key) {
+                    Entry::Occupied(e) => node = Some(e.get().clone()),
+                    Entry::Vacant(e) => {
+                        e.insert(current.clone());
+                    }
+                }
+            }
+        }
+
+        match node {
+            Some(n) if n.id() != t.id() => {
+                if t.id() != output.id() {
+                    log::debug!("Replacing {:?} with {:?}", t.id(), n.id());
+                    replacements.insert(t.id(), n);
+                }
+            }
+            Some(n) => subs.insert(n),
+            None => {}
+        }
+    }
+    let replaced = replacements
+        .into_iter()
+        .map(|(id, r)| (id, r.id()))
+        .collect();
+    (subs, replaced)
+}
+
+/// Rebuilds `t` to read from the replacements of its sources, if any were replaced.
+fn rebuild(t: &Tensor, replacements: &FxHashMap<TensorId, Tensor>) -> Option<Tensor> {
+    let srcs = t.op().srcs();
+    if !srcs.iter().any(|s| replacements.contains_key(&s.id())) {
+        return None;
+    }
+    let mut srcs = srcs
+        .into_iter()
+        .map(|s| replacements.get(&s.id()).unwrap_or(s).clone())
+        .collect::<RVec<_>>();
+
+    if let LazyOp::View(v) = t.op() {
+        //A view shares the storage of its source, so it can't be substituted in place
+        return srcs.remove(0).view(v.shape.clone()).ok();
+    }
+    let (op, _, _) = IrOp::from_lazy(t.op())
+        .ok()?
+        .to_op(0, srcs, t.device())
+        .ok()?;
+    Some(t.substitute(op))
+}
+
+/// Applies the local rewrites to `t`, returning either a replacement or a substitute.
+fn simplify_node(t: &Tensor, subs: &Substitutions) -> Option<Tensor> {
+    match t.op() {
+        LazyOp::Cast(c) if c.input.dt() == c.dst_dt => Some(c.input.clone()),
+        LazyOp::View(v) => {
+            if v.shape == *v.src.shape() {
+                return Some(v.src.clone());
+            }
+            match subs.resolve(&v.src).op() {
+                LazyOp::View(inner) => inner.src.clone().view(v.shape.clone()).ok(),
+                _ => None,
+            }
+        }
+        LazyOp::Reindex(Reindex::Permute(p)) => {
+            if is_identity(&p.dims) {
+                return Some(p.src.clone());
+            }
+            match subs.resolve(&p.src).op() {
+                LazyOp::Reindex(Reindex::Permute(inner)) => {
+                    let dims = p.dims.iter().map(|&d| inner.dims[d]).collect::<Vec<_>>();
+                    if is_identity(&dims) {
+                        return Some(inner.src.clone());
+                    }
+                    let permute = Permute::new(inner.src.clone(), dims);
+                    Some(t.substitute(LazyOp::Reindex(Reindex::Permute(permute))))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_identity(dims: &[usize]) -> bool {
+    dims.iter().enumerate().all(|(i, &d)| i == d)
+}
+
+/// Identifies the computation performed by `t`: its operation, parameters & sources.
+///
+/// Operations that write into an existing buffer, or that sample, are never merged.
+fn expression_key(t: &Tensor) -> Option<String> {
+    if matches!(
+        t.op(),
+        LazyOp::IndexWrite(_) | LazyOp::Cache(_) | LazyOp::Sample(_)
+    ) {
+        return None;
+    }
+    let op = IrOp::from_lazy(t.op()).ok()?;
+    let srcs = t.op().srcs().iter().map(|s| s.id()).collect::<Vec<_>>();
+    Some(format!("{:?}{:?}", op, srcs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::simplify;
+    use crate::{shape, Cast, DType, Device, LazyOp, Operation, Tensor};
+
+    fn build_graph() -> anyhow::Result<Tensor> {
+        let a = Tensor::from_data([1f32, -2., 3., -4., 5., -6.], shape![2, 3], Device::CPU);
+        let b = Tensor::from_data([0.5f32, 0.5, 2., 2., 1., 1.], shape![3, 2], Device::CPU);
+
+        //Cast to the same dtype, bypassing the check in `Tensor::cast`
+        let cast = Cast::new(a.clone().add(a.clone())?, DType::F32);
+        let view = cast.compute_view()?;
+        let cast = Tensor::lazy(LazyOp::Cast(cast), view, Device::CPU);
+
+        let lhs = cast.permute(&[1, 0])?.permute(&[1, 0])?;
+        let scale = a.clone().add(a)?.abs()?;
+        let rhs = b.abs()?.view(shape![6])?.view(shape![2, 3])?;
+        lhs.mul(scale)?.add(rhs)
+    }
+
+    #[test]
+    fn simplifies_redundant_operations() -> anyhow::Result<()> {
+        let expected = build_graph()?.resolve()?.to_vec::<f32>()?;
+
+        let out = build_graph()?;
+        let (subs, replaced) = simplify(&out.execution_order());
+        let simplified = out.execution_order_with(&subs);
+        //a, a + a, abs, mul, b, abs, view, add
+        assert_eq!(simplified.len(), 8);
+        assert!(replaced
+            .values()
+            .all(|r| simplified.iter().any(|t| t.id() == *r)));
+        assert!(!simplified
+            .iter()
+            .any(|t| matches!(t.op(), LazyOp::Cast(_) | LazyOp::Reindex(_))));
+
+        subs.resolve(&out).clone().resolve()?;
+        assert_eq!(out.to_vec::<f32>()?, expected);
+        Ok(())
+    }
+}
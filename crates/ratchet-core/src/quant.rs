@@ -1,9 +1,6 @@
 use num::integer::div_floor;
-use num_traits::{AsPrimitive, Float};
 
-use std::fmt::Debug;
-
-use crate::{gpu::STORAGE_BUFFER_ALIGN, DType, Device, Tensor, Q8_0F};
+use crate::{gpu::STORAGE_BUFFER_ALIGN, DType, Device, Tensor, Q4_0F, Q8_0F};
 
 /// Quantizer
 ///
@@ -18,7 +15,7 @@ impl Quantizer {
         match self.format {
             Quantization::None => tensor,
             Quantization::SInt8 => self.sint8_quantize(tensor),
-            Quantization::SInt4 => todo!(),
+            Quantization::SInt4 => self.sint4_quantize(tensor),
         }
     }
 
@@ -123,53 +120,98 @@ impl Quantizer {
         Tensor::from_data(dequantized, original_shape, Device::CPU)
     }
 
-    pub fn sint4_quantize<F: Float + AsPrimitive<i32> + Debug>(
-        matrix: &[F],
-        K: usize,
-        N: usize,
-    ) -> (Vec<u32>, F) {
-        assert!(matrix.len() == K * N);
-        assert!(matrix.len() % 4 == 0);
-        let pack_size = 8;
-        let mut quantized_matrix = vec![0u32; K * N / pack_size];
-
-        let absmax = matrix.iter().fold(F::zero(), |acc, &x| acc.max(x.abs()));
-        let sf = F::from(7.).unwrap();
-
-        for i in (0..(K * N)).step_by(pack_size) {
-            let packed_value: i32 = ((matrix[i] / absmax * sf).round().as_() & 0xF)
-                | (((matrix[i + 1] / absmax * sf).round().as_() & 0xF) << 4)
-                | (((matrix[i + 2] / absmax * sf).round().as_() & 0xF) << 8)
-                | (((matrix[i + 3] / absmax * sf).round().as_() & 0xF) << 12)
-                | (((matrix[i + 4] / absmax * sf).round().as_() & 0xF) << 16)
-                | (((matrix[i + 5] / absmax * sf).round().as_() & 0xF) << 20)
-                | (((matrix[i + 6] / absmax * sf).round().as_() & 0xF) << 24)
-                | (((matrix[i + 7] / absmax * sf).round().as_() & 0xF) << 28);
-            quantized_matrix[i / pack_size] = packed_value as u32
+    /// Quantizes a float 32 tensor into signed 4 bit blocks, packed 8 to a `u32`.
+    ///
+    /// Each block of 32 values is scaled by its absmax, such that it spans [-7, 7].
+    pub fn sint4_quantize(&self, tensor: Tensor) -> Tensor {
+        let numel = tensor.shape().numel();
+        let pack_size = self.format.pack_size();
+        let group_size = self.format.group_size();
+
+        assert!(numel % pack_size == 0 && numel % group_size == 0);
+        assert!(tensor.dt() == DType::F32);
+        let qmatrix_len = numel / pack_size;
+        let amatrix_len = numel / group_size;
+
+        let mut quantized_matrix = vec![0u32; aligned_len::<u32>(qmatrix_len)];
+        let mut absmax_matrix = vec![0f32; aligned_len::<f32>(amatrix_len)];
+
+        let matrix = tensor.to_vec::<f32>().unwrap();
+
+        for (g, group) in matrix.chunks_exact(group_size).enumerate() {
+            let amax = group.iter().fold(0f32, |acc, &x| acc.max(x.abs()));
+            let d = amax / ((1 << 3) - 1) as f32;
+            let id = if d != 0. { 1. / d } else { 0. };
+            absmax_matrix[g] = d;
+
+            for (p, pack) in group.chunks_exact(pack_size).enumerate() {
+                let packed_value = pack.iter().enumerate().fold(0u32, |acc, (i, &x)| {
+                    let q = (x * id).round().clamp(-8., 7.) as i32;
+                    acc | ((q as u32 & 0xF) << (4 * i))
+                });
+                quantized_matrix[(g * group_size) / pack_size + p] = packed_value;
+            }
+        }
+        quantized_matrix.append(&mut unsafe { std::mem::transmute(absmax_matrix) });
+        unsafe {
+            Tensor::from_quantized(
+                quantized_matrix,
+                DType::Q4_0F(Q4_0F::default()),
+                tensor.shape().clone(),
+                Device::CPU,
+            )
         }
-        (quantized_matrix, absmax)
     }
 
-    pub fn sint4_dequantize(quantized_matrix: &[u32], absmax: f32, K: usize, N: usize) -> Vec<f32> {
-        let pack_size = 8;
-        let mut matrix = vec![0.0; K * N];
+    pub fn sint4_dequantize(&self, quantized: Tensor) -> Tensor {
+        assert!(matches!(quantized.dt(), DType::Q4_0F(_)));
+        let numel = quantized.shape().numel();
+        let original_shape = quantized.shape().clone();
+
+        let pack_size = self.format.pack_size();
+        let group_size = self.format.group_size();
 
-        for i in (0..(K * N)).step_by(pack_size) {
+        let num_q = numel / pack_size;
+        let num_q_bytes = num_q * std::mem::size_of::<u32>();
+        let aligned_q_bytes = aligned_len::<u32>(num_q) * std::mem::size_of::<u32>();
+
+        let num_absmax = numel / group_size;
+        let num_absmax_bytes = num_absmax * std::mem::size_of::<f32>();
+
+        let raw_bytes = unsafe { quantized.into_bytes().unwrap() };
+
+        let quantized_matrix = bytemuck::cast_slice::<u8, u32>(&raw_bytes[..num_q_bytes]);
+        let absmax_matrix = bytemuck::cast_slice::<u8, f32>(
+            &raw_bytes[aligned_q_bytes..aligned_q_bytes + num_absmax_bytes],
+        );
+
+        let mut dequantized = vec![0.0f32; numel];
+
+        for i in (0..numel).step_by(pack_size) {
+            let block_absmax = absmax_matrix[div_floor(i, group_size)];
             let packed_value = quantized_matrix[div_floor(i, pack_size)] as i32;
-            matrix[i] = ((packed_value << 28) >> 28) as f32 / 7.0 * absmax;
-            matrix[i + 1] = ((packed_value << 24) >> 28) as f32 / 7.0 * absmax;
-            matrix[i + 2] = ((packed_value << 20) >> 28) as f32 / 7.0 * absmax;
-            matrix[i + 3] = ((packed_value << 16) >> 28) as f32 / 7.0 * absmax;
-            matrix[i + 4] = ((packed_value << 12) >> 28) as f32 / 7.0 * absmax;
-            matrix[i + 5] = ((packed_value << 8) >> 28) as f32 / 7.0 * absmax;
-            matrix[i + 6] = ((packed_value << 4) >> 28) as f32 / 7.0 * absmax;
-            matrix[i + 7] = (packed_value >> 28) as f32 / 7.0 * absmax;
+            for j in 0..pack_size {
+                let shift = 28 - 4 * j;
+                dequantized[i + j] = ((packed_value << shift) >> 28) as f32 * block_absmax;
+            }
         }
 
-        matrix
+        Tensor::from_data(dequantized, original_shape, Device::CPU)
     }
 }
 
+/// Returns the number of elements of `T` in `numel` elements, padded to [STORAGE_BUFFER_ALIGN].
+fn aligned_len<T>(numel: usize) -> usize {
+    let size_t = std::mem::size_of::<T>();
+    let nbytes = numel * size_t;
+    let aligned = if nbytes % STORAGE_BUFFER_ALIGN != 0 {
+        nbytes + STORAGE_BUFFER_ALIGN - nbytes % STORAGE_BUFFER_ALIGN
+    } else {
+        nbytes
+    };
+    aligned / size_t
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Quantization {
     None,
@@ -190,7 +232,7 @@ impl Quantization {
         match self {
             Quantization::None => 1,
             Quantization::SInt8 => 32,
-            Quantization::SInt4 => 8,
+            Quantization::SInt4 => 32,
         }
     }
 }
@@ -204,4 +246,14 @@ mod tests {
         let quantizer = Quantizer::new(Quantization::SInt8);
         let _quantized = quantizer.sint8_quantize(ground.deep_clone());
     }
+
+    #[test]
+    pub fn test_sint4_qdq() {
+        let ground = Tensor::randn::<f32>(shape![64, 64], Device::CPU);
+        let quantizer = Quantizer::new(Quantization::SInt4);
+        let quantized = quantizer.quantize(ground.deep_clone());
+        let dequantized = quantizer.sint4_dequantize(quantized);
+        //Half a step of a block with an absmax of 5.6
+        ground.all_close::<f32>(&dequantized, 0.4, 0.).unwrap();
+    }
 }
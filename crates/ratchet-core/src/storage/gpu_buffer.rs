@@ -1,5 +1,5 @@
 use crate::{
-    gpu::{BufferDescriptor, WgpuDevice},
+    gpu::{BufferDescriptor, BufferRange, WgpuDevice},
     gpu::{BufferUsagesExt, PooledGPUBuffer},
     storage::{CPUBuffer, DeviceStorage},
     Device, DeviceError, Shape, TensorDType,
@@ -10,10 +10,15 @@ use wgpu::BufferUsages;
 
 use crate::DType;
 
-#[derive(Clone, Debug, derive_new::new)]
+#[derive(Clone, Debug)]
 pub struct GPUBuffer {
     pub(crate) inner: PooledGPUBuffer,
     pub(crate) alignment: usize,
+    /// Offset of the data within `inner`.
+    /// Non-zero when the memory planner has placed the tensor within an arena.
+    pub(crate) offset: wgpu::BufferAddress,
+    /// Size of the data within `inner`, in bytes.
+    pub(crate) size: wgpu::BufferAddress,
 }
 
 impl GPUBuffer {
@@ -57,7 +62,21 @@ impl GPUBuffer {
             .unwrap();
         device.queue().submit(None);
         device.poll(wgpu::Maintain::Wait);
-        Self { inner, alignment }
+        Self::from_range(BufferRange::whole(inner), alignment)
+    }
+
+    pub(crate) fn from_range(range: BufferRange, alignment: usize) -> Self {
+        Self {
+            inner: range.buffer,
+            alignment,
+            offset: range.offset,
+            size: range.size,
+        }
+    }
+
+    /// The range of the underlying buffer holding this tensor's data.
+    pub(crate) fn range(&self) -> BufferRange {
+        BufferRange::new(self.inner.clone(), self.offset, self.size)
     }
 
     /// Returns true if the buffer has all the given usages.
@@ -80,19 +99,16 @@ impl GPUBuffer {
     pub fn deep_clone(&self, device: &WgpuDevice) -> Self {
         let clone = device
             .get_or_create_buffer(
-                &BufferDescriptor::new(self.inner.size(), self.inner.usage(), false),
+                &BufferDescriptor::new(self.size, self.inner.usage(), false),
                 true,
             )
             .unwrap();
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        encoder.copy_buffer_to_buffer(&self.inner, 0, &clone, 0, self.inner.size());
+        encoder.copy_buffer_to_buffer(&self.inner, self.offset, &clone, 0, self.size);
         device.queue().submit(Some(encoder.finish()));
         device.poll(wgpu::Maintain::Wait);
-        Self {
-            inner: clone,
-            alignment: self.alignment,
-        }
+        Self::from_range(BufferRange::whole(clone), self.alignment)
     }
 
     pub fn from_disk<T: TensorDType, R: std::io::BufRead + std::io::Seek>(
@@ -117,7 +133,7 @@ impl GPUBuffer {
     #[cfg(feature = "plotting")]
     pub fn plot_fmt(&self) -> String {
         let id_string = Self::trim_id(self.inner().global_id()).unwrap_or_default();
-        format!("GPU:#{}\n{} bytes", id_string, self.inner.size())
+        format!("GPU:#{}\n{} bytes", id_string, self.size)
     }
 }
 
@@ -131,7 +147,7 @@ impl DeviceStorage for GPUBuffer {
     async fn to_cpu(&self, device: &Device) -> Result<CPUBuffer, DeviceError> {
         self.validate_usages(BufferUsages::COPY_SRC)?;
         let device = device.try_gpu()?;
-        let buffer_slice = self.inner.slice(..);
+        let buffer_slice = self.inner.slice(self.offset..self.offset + self.size);
         let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
         let alignment = self.alignment;
 
@@ -155,7 +171,7 @@ impl DeviceStorage for GPUBuffer {
     fn to_cpu(&self, device: &Device) -> Result<CPUBuffer, DeviceError> {
         self.validate_usages(BufferUsages::COPY_SRC)?;
         let device = device.try_gpu()?;
-        let buffer_slice = self.inner.slice(..);
+        let buffer_slice = self.inner.slice(self.offset..self.offset + self.size);
         let (tx, rx) = std::sync::mpsc::channel();
         let alignment = self.alignment;
 
@@ -178,14 +194,14 @@ impl DeviceStorage for GPUBuffer {
     }
 
     fn n_bytes(&self) -> usize {
-        self.inner.size() as usize
+        self.size as usize
     }
 
     fn dump(&self, _: DType, _: bool) -> String {
         let mut result = String::new();
         let id_string = Self::trim_id(self.inner().global_id()).unwrap_or_default();
         result.push_str(&format!("GPU Buffer #{}\n", id_string));
-        result.push_str(&format!("Size: {} bytes\n", self.inner.size()));
+        result.push_str(&format!("Size: {} bytes\n", self.size));
         result
     }
 }
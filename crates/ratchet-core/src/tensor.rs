@@ -1,13 +1,14 @@
 use crate::gpu::{BindGroupEntry, CpuUniform, WgpuDevice};
 use crate::{
-    dtype::Segments, ops::*, rvec, BufferSegment, CPUBuffer, CompiledOp, DType, Device,
-    DeviceStorage, Executable, GPUBuffer, InvariantError, LazyOp, MetaOperation, Operation,
-    OperationError, RVec, RawCPUBuffer, Shape, Storage, Strides, TensorDType, TensorId,
+    dtype::Segments, ops::*, passes::*, rvec, BufferSegment, CPUBuffer, CPUOperation,
+    CapturedGraph, CompiledOp, DType, Device, DeviceStorage, Executable, ExecutionError, GPUBuffer,
+    InvariantError, LazyOp, MetaOperation, Operation, OperationError, RVec, RawCPUBuffer, Shape,
+    Storage, Strides, TensorDType, TensorId,
 };
 use derive_new::new;
 use npyz::WriterBuilder;
 use parking_lot::{RwLock, RwLockReadGuard};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io::{BufRead, Seek};
 use std::ops::Bound;
 use std::path::Path;
@@ -35,6 +36,16 @@ pub enum TensorError {
     TransferError,
     #[error(transparent)]
     OperationError(#[from] OperationError),
+    #[error(transparent)]
+    ExecutionError(#[from] ExecutionError),
+    #[error("Tensor {0:?} is not an input of the captured graph")]
+    NotCaptured(TensorId),
+    #[error("Captured input {id:?} requires data of shape {shape:?} and dtype {dt:?}")]
+    InvalidCaptureUpdate {
+        id: TensorId,
+        shape: Shape,
+        dt: DType,
+    },
 }
 
 /// A multi-dimensional array of data.
@@ -56,7 +67,7 @@ impl Tensor {
     }
 
     #[track_caller]
-    fn lazy(op: LazyOp, meta: StorageView, device: Device) -> Self {
+    pub(crate) fn lazy(op: LazyOp, meta: StorageView, device: Device) -> Self {
         op.check_invariants();
         Self::new(op, meta, None, device)
     }
@@ -76,9 +87,26 @@ impl Tensor {
         Arc::strong_count(&self.inner)
     }
 
-    fn update_storage(&self, storage: Storage) {
+    pub(crate) fn update_storage(&self, storage: Storage) {
         *self.inner.storage.write() = Some(storage);
     }
+
+    /// Creates a replacement for this tensor, computed by `op` instead.
+    ///
+    /// The substitute shares the id, view & storage of the original, see [Substitutions].
+    #[track_caller]
+    pub(crate) fn substitute(&self, op: LazyOp) -> Tensor {
+        op.check_invariants();
+        Self {
+            inner: Arc::new(Inner {
+                id: self.id,
+                op,
+                device: self.device.clone(),
+                view: self.view.clone(),
+                storage: self.inner.storage.clone(),
+            }),
+        }
+    }
 }
 
 impl std::fmt::Debug for Tensor {
@@ -119,7 +147,7 @@ pub struct StorageView {
 
 impl StorageView {
     pub fn is_contiguous(&self) -> bool {
-        todo!()
+        self.strides == Strides::from(&self.shape)
     }
 }
 
@@ -190,8 +218,11 @@ impl Tensor {
         &self.view.strides
     }
 
-    //WARNING: very wrong for quantized types!
     pub fn num_bytes(&self) -> usize {
+        if self.dt().is_quantized() {
+            //The size of a quantized dtype is that of a whole block
+            return self.segments().iter().map(|s| s.size.get() as usize).sum();
+        }
         self.view.shape.numel() * self.view.dt.size_of()
     }
 
@@ -261,6 +292,17 @@ macro_rules! impl_binary_op {
     };
 }
 
+macro_rules! impl_reduce_op {
+    ($method_name:ident, $op:expr) => {
+        pub fn $method_name(self, dim: usize, keepdim: bool) -> anyhow::Result<Tensor> {
+            let device = self.device.clone();
+            let reduce = Reduce::new(self, dim, keepdim, $op);
+            let new_view = reduce.compute_view()?;
+            Ok(Tensor::lazy(LazyOp::Reduce(reduce), new_view, device))
+        }
+    };
+}
+
 macro_rules! impl_unary_op {
     ($method_name:ident, $op:expr) => {
         pub fn $method_name(self) -> anyhow::Result<Tensor> {
@@ -293,6 +335,13 @@ impl Tensor {
     impl_unary_op!(sigmoid, UnaryOp::Sigmoid);
     impl_unary_op!(silu, UnaryOp::Silu);
 
+    impl_reduce_op!(sum, ReduceOp::Sum);
+    impl_reduce_op!(mean, ReduceOp::Mean);
+    impl_reduce_op!(max, ReduceOp::Max);
+    impl_reduce_op!(min, ReduceOp::Min);
+    impl_reduce_op!(argmax, ReduceOp::ArgMax);
+    impl_reduce_op!(argmin, ReduceOp::ArgMin);
+
     pub fn cast(self, dst_dt: DType) -> anyhow::Result<Tensor> {
         if self.dt() == dst_dt {
             return Ok(self);
@@ -349,19 +398,88 @@ impl Tensor {
         Ok(Tensor::lazy(op, new_view, device))
     }
 
+    /// 1-D convolution of an `[N, C_in, L]` input with `[C_out, C_in / groups, KS]` filters.
+    ///
+    /// Depthwise convolutions are expressed with `groups == C_in`.
     pub fn conv1d(
         self,
         weight: Tensor,
         bias: Option<Tensor>,
         stride: usize,
         padding: usize,
+        dilation: usize,
+        groups: usize,
     ) -> anyhow::Result<Tensor> {
         let device = self.device.clone();
-        let conv = Conv::new(self, weight, bias, stride, padding);
+        let conv = Conv::new(self, weight, bias, stride, padding, dilation, groups);
         let new_view = conv.compute_view()?;
         Ok(Tensor::lazy(LazyOp::Conv(conv), new_view, device))
     }
 
+    /// 2-D convolution of an `[N, C_in, H, W]` input with `[C_out, C_in / groups, KH, KW]`
+    /// filters, computed as an [Im2Col] followed by a GEMM.
+    ///
+    /// `stride`, `padding` & `dilation` are given as `[height, width]`.
+    pub fn conv2d(
+        self,
+        weight: Tensor,
+        bias: Option<Tensor>,
+        stride: [usize; 2],
+        padding: [usize; 2],
+        dilation: [usize; 2],
+        groups: usize,
+    ) -> anyhow::Result<Tensor> {
+        let [N, C_in, H, W]: [usize; 4] = self.shape().try_into()?;
+        let [C_out, C_g, KH, KW]: [usize; 4] = weight.shape().try_into()?;
+        if groups == 0 || C_in % groups != 0 || C_out % groups != 0 || C_in / groups != C_g {
+            anyhow::bail!(
+                "Cannot convolve {} input channels into {} output channels with {} groups of {}",
+                C_in,
+                C_out,
+                groups,
+                C_g
+            );
+        }
+        if stride.contains(&0) || dilation.contains(&0) || KH == 0 || KW == 0 {
+            anyhow::bail!(
+                "Invalid convolution: kernel {:?}, stride {:?} & dilation {:?} must be non-zero",
+                [KH, KW],
+                stride,
+                dilation
+            );
+        }
+        for (i, (size, kernel)) in [(H, KH), (W, KW)].into_iter().enumerate() {
+            if dilation[i] * (kernel - 1) + 1 > size + 2 * padding[i] {
+                anyhow::bail!(
+                    "Kernel {:?} with dilation {:?} does not fit the padded input {:?}",
+                    [KH, KW],
+                    dilation,
+                    [H + 2 * padding[0], W + 2 * padding[1]]
+                );
+            }
+        }
+
+        let device = self.device.clone();
+        let im2col = Im2Col::new(self, [KH, KW], stride, padding, dilation, groups);
+        let [H_out, W_out] = im2col.output_size();
+        let new_view = im2col.compute_view()?;
+        let columns = Tensor::lazy(LazyOp::Im2Col(im2col), new_view, device);
+
+        //[groups, C_out / groups, K] @ [N, groups, K, H_out * W_out]
+        let mut filters = weight.view(shape![groups, C_out / groups, C_g * KH * KW])?;
+        if groups > 1 && N > 1 {
+            //GEMM can only broadcast a single stack, so each batch needs its own filters
+            filters = filters.broadcast_to(shape![N, groups, C_out / groups, C_g * KH * KW])?;
+        }
+        let output = filters
+            .matmul(columns, false, false)?
+            .view(shape![N, C_out, H_out, W_out])?;
+        match bias {
+            Some(bias) => output.add(bias.view(shape![1, C_out, 1, 1])?),
+            None => Ok(output),
+        }
+    }
+
     //TODO: switch dim to isize and allow negative indexing
     pub fn softmax(self, dim: usize) -> anyhow::Result<Tensor> {
         let device = self.device.clone();
@@ -370,9 +488,152 @@ impl Tensor {
         Ok(Tensor::lazy(LazyOp::Softmax(softmax), new_view, device))
     }
 
+    /// Scaled dot-product attention, computed by a single fused [Attention] kernel.
+    ///
+    /// `self` is the query of shape `[B, H_q, S_q, D]`, `key` & `value` are `[B, H_kv, S_k, D]`.
+    /// `H_q` must be a multiple of `H_kv` (GQA). The additive `mask` is `[S_q, S_k]` or
+    /// `[B, H_q, S_q, S_k]`, where the leading dimensions may be 1. `scale` defaults to
+    /// `1 / sqrt(D)`. `D` cannot exceed [MAX_HEAD_DIM].
+    pub fn sdpa(
+        self,
+        key: Tensor,
+        value: Tensor,
+        mask: Option<Tensor>,
+        causal: bool,
+        scale: Option<f32>,
+    ) -> anyhow::Result<Tensor> {
+        let device = self.device.clone();
+        let head_dim = self.shape()[self.rank() - 1];
+        if head_dim > MAX_HEAD_DIM {
+            anyhow::bail!(
+                "Head dimension {} exceeds the maximum of {} supported by attention",
+                head_dim,
+                MAX_HEAD_DIM
+            );
+        }
+        let scale = scale.unwrap_or_else(|| (head_dim as f32).sqrt().recip());
+        let mask = match mask {
+            Some(m) if m.dt() != self.dt() => Some(m.cast(self.dt())?),
+            m => m,
+        };
+        let attention = Attention::new(self, key, value, mask, scale, causal);
+        let new_view = attention.compute_view()?;
+        Ok(Tensor::lazy(LazyOp::Attention(attention), new_view, device))
+    }
+
+    /// An additive causal mask of shape `[q_len, kv_len]`, generated on the device.
+    ///
+    /// Query `i` may attend to keys `0..=i + offset`, where `offset` is the number of keys
+    /// preceding the queries (e.g the length of a KV cache). All other positions hold a large
+    /// negative value.
+    pub fn causal_mask(
+        q_len: usize,
+        kv_len: usize,
+        offset: usize,
+        dt: DType,
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        if dt == DType::BF16 {
+            return Tensor::causal_mask(q_len, kv_len, offset, DType::F32, device)?.cast(dt);
+        }
+        let mask = CausalMask::new(q_len, kv_len, offset, None, dt);
+        let new_view = mask.compute_view()?;
+        Ok(Tensor::lazy(
+            LazyOp::CausalMask(mask),
+            new_view,
+            device.clone(),
+        ))
+    }
+
+    /// The causal mask matching the output of [Tensor::rolling_cache], where `offset` is the
+    /// number of positions written before the queries. Its keys are the occupied slots of the
+    /// cache, each masked if the position it holds follows the query.
+    pub fn rolling_causal_mask(
+        q_len: usize,
+        offset: usize,
+        window: RollingWindow,
+        dt: DType,
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        if dt == DType::BF16 {
+            return Tensor::rolling_causal_mask(q_len, offset, window, DType::F32, device)?
+                .cast(dt);
+        }
+        let kv_len = (offset + q_len).min(window.capacity());
+        let mask = CausalMask::new(q_len, kv_len, offset, Some(window), dt);
+        let new_view = mask.compute_view()?;
+        Ok(Tensor::lazy(
+            LazyOp::CausalMask(mask),
+            new_view,
+            device.clone(),
+        ))
+    }
+
+    /// An additive padding mask of shape `[B, 1, q_len, kv_len]`, generated on the device from
+    /// the `[B]` sequence `lengths` (U32 or I32).
+    ///
+    /// Keys in the padding of each sequence hold a large negative value. The padding is at the
+    /// end of each sequence for [PaddingSide::Right], and at the start for [PaddingSide::Left].
+    pub fn padding_mask(
+        lengths: Tensor,
+        q_len: usize,
+        kv_len: usize,
+        side: PaddingSide,
+        dt: DType,
+    ) -> anyhow::Result<Tensor> {
+        if dt == DType::BF16 {
+            return Tensor::padding_mask(lengths, q_len, kv_len, side, DType::F32)?.cast(dt);
+        }
+        let device = lengths.device.clone();
+        let mask = PaddingMask::new(lengths, q_len, kv_len, side, dt);
+        let new_view = mask.compute_view()?;
+        Ok(Tensor::lazy(LazyOp::PaddingMask(mask), new_view, device))
+    }
+
+    /// Samples a token id from each row of logits, reducing the last dimension.
+    /// A non-positive temperature, or `k == 0`, falls back to greedy sampling.
+    /// Top-k sampling supports `k` up to [Sample::MAX_TOP_K].
+    pub fn sample(self, strategy: SampleStrategy, seed: u32) -> anyhow::Result<Tensor> {
+        if let SampleStrategy::TopK { k, .. } = strategy {
+            if k > Sample::MAX_TOP_K {
+                anyhow::bail!(
+                    "Top-k sampling supports k <= {}, got {}",
+                    Sample::MAX_TOP_K,
+                    k
+                );
+            }
+        }
+        let strategy = match strategy {
+            SampleStrategy::Temperature(t) if t <= 0. => SampleStrategy::Greedy,
+            SampleStrategy::TopK { k, temperature } if k == 0 || temperature <= 0. => {
+                SampleStrategy::Greedy
+            }
+            s => s,
+        };
+        let device = self.device.clone();
+        let sample = Sample::new(self, strategy, seed);
+        let new_view = sample.compute_view()?;
+        Ok(Tensor::lazy(LazyOp::Sample(sample), new_view, device))
+    }
+
     pub fn rope(self, dim: usize, base: f32, offset: usize) -> anyhow::Result<Tensor> {
         let device = self.device.clone();
-        let rope = RoPE::new(self, dim, f32::log2(base), offset);
+        let rope = RoPE::new(self, dim, f32::log2(base), offset, None);
+        let new_view = rope.compute_view()?;
+        Ok(Tensor::lazy(LazyOp::RoPE(rope), new_view, device))
+    }
+
+    /// As [Tensor::rope], with the positions of each batch row shifted by `row_offsets`
+    /// (I32 `[B]`). See [RoPE].
+    pub fn rope_with_offsets(
+        self,
+        dim: usize,
+        base: f32,
+        offset: usize,
+        row_offsets: Tensor,
+    ) -> anyhow::Result<Tensor> {
+        let device = self.device.clone();
+        let rope = RoPE::new(self, dim, f32::log2(base), offset, Some(row_offsets));
         let new_view = rope.compute_view()?;
         Ok(Tensor::lazy(LazyOp::RoPE(rope), new_view, device))
     }
@@ -461,11 +722,41 @@ impl Tensor {
 
     pub fn cache(self, source: Tensor, dim: usize, offset: usize) -> anyhow::Result<Tensor> {
         let device = self.device.clone();
-        let cache = Cache::new(self, source, dim, offset);
+        let cache = Cache::new(self, source, dim, offset, None);
         let new_view = cache.compute_view()?;
         Ok(Tensor::lazy(LazyOp::Cache(cache), new_view, device))
     }
 
+    /// Writes `source` into a cache whose slots wrap around `window`, where `offset` is the
+    /// number of positions written so far. See [Cache].
+    pub fn rolling_cache(
+        self,
+        source: Tensor,
+        dim: usize,
+        offset: usize,
+        window: RollingWindow,
+    ) -> anyhow::Result<Tensor> {
+        let device = self.device.clone();
+        let cache = Cache::new(self, source, dim, offset, Some(window));
+        let new_view = cache.compute_view()?;
+        Ok(Tensor::lazy(LazyOp::Cache(cache), new_view, device))
+    }
+
+    /// Writes `source` into the blocks of `self`, a pool shared by every sequence, and returns
+    /// every cached position of each sequence. See [PagedCache].
+    pub fn paged_cache(
+        self,
+        source: Tensor,
+        block_table: Tensor,
+        dim: usize,
+        offset: usize,
+    ) -> anyhow::Result<Tensor> {
+        let device = self.device.clone();
+        let paged = PagedCache::new(self, source, block_table, dim, offset);
+        let new_view = paged.compute_view()?;
+        Ok(Tensor::lazy(LazyOp::PagedCache(paged), new_view, device))
+    }
+
     pub fn broadcast_to(self, shape: Shape) -> anyhow::Result<Tensor> {
         let device = self.device.clone();
         let broadcast = Broadcast::new(self, shape);
@@ -637,7 +928,7 @@ impl Tensor {
                 let (offset, size) = (segment.offset, segment.size);
                 entries.push(BindGroupEntry {
                     handle,
-                    offset,
+                    offset: gpu_buf.offset + offset,
                     size: Some(size),
                 });
                 entries
@@ -665,11 +956,23 @@ impl Tensor {
     }
 
     pub(crate) fn execution_order(&self) -> Vec<&Tensor> {
+        self.execution_order_by(|t| t)
+    }
+
+    /// The execution order of the graph after the rewrites recorded in `subs`.
+    pub(crate) fn execution_order_with<'a>(&'a self, subs: &'a Substitutions) -> Vec<&'a Tensor> {
+        self.execution_order_by(|t| subs.resolve(t))
+    }
+
+    fn execution_order_by<'a>(
+        &'a self,
+        resolve: impl Fn(&'a Tensor) -> &'a Tensor,
+    ) -> Vec<&'a Tensor> {
         let mut done = HashSet::new();
         let mut pending = HashSet::new();
         let mut order = Vec::new();
 
-        let mut stack: Vec<(&Tensor, usize)> = vec![(self, 0)];
+        let mut stack: Vec<(&Tensor, usize)> = vec![(resolve(self), 0)];
         while let Some((cur_t, cur_src)) = stack.pop() {
             let all_deps_done = cur_src == cur_t.op().srcs().len();
 
@@ -683,7 +986,8 @@ impl Tensor {
             let (srcs_with_deps, srcs_without_deps): (Vec<_>, Vec<_>) = cur_t
                 .op()
                 .srcs()
-                .iter()
+                .into_iter()
+                .map(&resolve)
                 .partition(|s| s.op().srcs().is_empty());
 
             let all_srcs = srcs_with_deps
@@ -721,29 +1025,150 @@ impl Tensor {
             LazyOp::Cast(c) => c.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Matmul(m) => m.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Softmax(s) => s.compile(self, uniform, device, can_inplace).ok(),
+            LazyOp::Attention(a) => a.compile(self, uniform, device, can_inplace).ok(),
+            LazyOp::CausalMask(c) => c.compile(self, uniform, device, can_inplace).ok(),
+            LazyOp::PaddingMask(p) => p.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::RoPE(r) => r.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Unary(u) => u.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Reindex(r) => r.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Concat(c) => c.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Norm(n) => n.compile(self, uniform, device, can_inplace).ok(),
+            LazyOp::Reduce(r) => r.compile(self, uniform, device, can_inplace).ok(),
+            LazyOp::Sample(s) => s.compile(self, uniform, device, can_inplace).ok(),
+            LazyOp::Fused(f) => f.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Conv(c) => c.compile(self, uniform, device, can_inplace).ok(),
+            LazyOp::Im2Col(i) => i.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Select(i) => i.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::IndexWrite(i) => i.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Cache(c) => c.compile(self, uniform, device, can_inplace).ok(),
+            LazyOp::PagedCache(p) => p.compile(self, uniform, device, can_inplace).ok(),
             LazyOp::Const => None,
             LazyOp::View(_) => None,
         }
     }
 
+    /// Applies the operation on the host, returning the buffer for this tensor.
+    ///
+    /// Returns `None` for operations which produce no new data.
+    fn apply_cpu(&self) -> Result<Option<CPUBuffer>, OperationError> {
+        match self.op() {
+            LazyOp::Binary(b) => b.apply_cpu(self).map(Some),
+            LazyOp::Cast(c) => c.apply_cpu(self).map(Some),
+            LazyOp::Matmul(m) => m.apply_cpu(self).map(Some),
+            LazyOp::Softmax(s) => s.apply_cpu(self).map(Some),
+            LazyOp::Attention(a) => a.apply_cpu(self).map(Some),
+            LazyOp::CausalMask(c) => c.apply_cpu(self).map(Some),
+            LazyOp::PaddingMask(p) => p.apply_cpu(self).map(Some),
+            LazyOp::RoPE(r) => r.apply_cpu(self).map(Some),
+            LazyOp::Unary(u) => u.apply_cpu(self).map(Some),
+            LazyOp::Reindex(r) => r.apply_cpu(self).map(Some),
+            LazyOp::Concat(c) => c.apply_cpu(self).map(Some),
+            LazyOp::Norm(n) => n.apply_cpu(self).map(Some),
+            LazyOp::Reduce(r) => r.apply_cpu(self).map(Some),
+            LazyOp::Sample(s) => s.apply_cpu(self).map(Some),
+            LazyOp::Fused(f) => f.apply_cpu(self).map(Some),
+            LazyOp::Conv(c) => c.apply_cpu(self).map(Some),
+            LazyOp::Im2Col(i) => i.apply_cpu(self).map(Some),
+            LazyOp::Select(i) => i.apply_cpu(self).map(Some),
+            LazyOp::IndexWrite(i) => i.apply_cpu(self).map(Some),
+            LazyOp::Cache(c) => c.apply_cpu(self).map(Some),
+            LazyOp::PagedCache(p) => p.apply_cpu(self).map(Some),
+            LazyOp::Const => Ok(None),
+            LazyOp::View(_) => Ok(None),
+        }
+    }
+
     pub fn resolve(self) -> Result<Tensor, TensorError> {
-        let mut uniform = CpuUniform::new();
+        match self.device() {
+            Device::CPU => self.resolve_cpu(),
+            Device::GPU(_) => self.resolve_gpu(),
+        }
+    }
+
+    /// # CPU execution
+    ///
+    /// Walks the same execution order as the GPU, computing each tensor on the host.
+    /// There is no memory planning, every unresolved tensor receives its own buffer.
+    fn resolve_cpu(self) -> Result<Tensor, TensorError> {
+        let execution_order = self.execution_order();
+
+        for t in execution_order.iter() {
+            log::debug!("Running on CPU: {:?}", t.op().name());
+            assert!(t.device().is_cpu());
+            if t.resolved() {
+                continue;
+            }
+
+            if let Some(buffer) = t.apply_cpu()? {
+                t.update_storage(Storage::CPU(buffer));
+            } else {
+                log::warn!("No CPU implementation for {:?}", t.op().name());
+            }
+        }
+        Ok(self)
+    }
+
+    fn resolve_gpu(self) -> Result<Tensor, TensorError> {
         let device = self.device().try_gpu()?;
+        let executable = self.build_executable(device, None)?;
+        let index = executable.dispatch_operations(device)?;
+        device.poll(wgpu::MaintainBase::WaitForSubmissionIndex(index));
+        Ok(self)
+    }
+
+    /// # Capture
+    ///
+    /// Resolves the tensor like [Tensor::resolve], additionally recording the executable so that
+    /// the graph can be replayed without being rebuilt. See [CapturedGraph].
+    ///
+    /// Only `inputs` may be updated between replays. Every subgraph computed from other resolved
+    /// tensors alone is folded into a constant, and isn't recomputed when replaying.
+    pub fn capture(self, inputs: &[&Tensor]) -> Result<CapturedGraph, TensorError> {
+        let device = self.device().try_gpu()?.clone();
+        let executable = self.build_executable(&device, Some(inputs))?;
+        let inputs = inputs.iter().map(|&t| t.clone()).collect();
+        let graph = CapturedGraph::new(executable, inputs, self, device);
+        graph.replay(&[])?;
+        Ok(graph)
+    }
+
+    /// Allocates & compiles every unresolved tensor in the graph.
+    ///
+    /// Before allocation, the graph is rewritten by a series of passes:
+    /// 1. Subgraphs that don't depend on the `inputs` are folded into constants, if the graph is
+    ///    being captured.
+    /// 2. Redundant operations are simplified away.
+    /// 3. Operations on BF16 are emulated in F32.
+    /// 4. Chains of elementwise operations are fused.
+    fn build_executable(
+        &self,
+        device: &WgpuDevice,
+        inputs: Option<&[&Tensor]>,
+    ) -> Result<Executable, TensorError> {
+        let mut subs = Substitutions::default();
+        if let Some(inputs) = inputs {
+            for root in fold_constants(&self.execution_order(), inputs) {
+                log::debug!("Folding constant: {:?}", root.id());
+                //Submissions execute in order, so there's no need to wait
+                let executable = root.build_executable(device, None)?;
+                executable.dispatch_operations(device)?;
+                subs.insert(root.substitute(LazyOp::Const));
+            }
+        }
+
+        let mut uniform = CpuUniform::new();
         device.begin_pass();
 
-        let execution_order = self.execution_order();
+        let (simplified, replacements) = simplify(&self.execution_order_with(&subs));
+        subs.extend(simplified);
+        let emulated = emulate_bf16(&self.execution_order_with(&subs))?;
+        subs.extend(emulated);
+        let fused = fuse_elementwise(&self.execution_order_with(&subs), &subs);
+        subs.extend(fused);
+        let execution_order = self.execution_order_with(&subs);
 
         let mut compiled_ops = Vec::with_capacity(execution_order.len());
-        let mut allocations = device.allocate_cfg(&execution_order, device)?;
+        let mut allocations = device.allocate_cfg(&execution_order, &subs, device)?;
 
         #[cfg(feature = "plotting")]
         {
@@ -759,14 +1184,11 @@ impl Tensor {
             }
 
             let id = t.id();
-            let inner = allocations.remove(&id).ok_or(TensorError::NoStorage(id))?;
-            t.update_storage(Storage::GPU(GPUBuffer {
-                inner,
-                alignment: t.dt().size_of(),
-            }));
+            let range = allocations.remove(&id).ok_or(TensorError::NoStorage(id))?;
+            t.update_storage(Storage::GPU(GPUBuffer::from_range(range, t.dt().size_of())));
 
-            let to_modify = t.op().srcs()[0];
-            let can_inplace = t.op().supports_inplace() && to_modify.strong_count() == 1;
+            //Operations without sources (e.g masks) never run inplace
+            let can_inplace = t.op().supports_inplace() && t.op().srcs()[0].strong_count() == 1;
 
             if let Some(compiled_op) = t.compile(&mut uniform, device, can_inplace) {
                 compiled_ops.push(compiled_op);
@@ -780,10 +1202,21 @@ impl Tensor {
             crate::plot::render_to_file(last, "alloc.svg").unwrap();
         }
 
-        let executable = Executable::new(compiled_ops, uniform.into_gpu(device)?);
-        let index = executable.dispatch_operations(device).unwrap();
-        device.poll(wgpu::MaintainBase::WaitForSubmissionIndex(index));
-        Ok(self)
+        //Replaced tensors drop out of the graph, but may be held elsewhere
+        let compiled = execution_order
+            .iter()
+            .map(|t| (t.id(), *t))
+            .collect::<HashMap<_, _>>();
+        for t in self.execution_order().iter().filter(|t| !t.resolved()) {
+            let Some(replacement) = replacements.get(&t.id()).and_then(|r| compiled.get(r)) else {
+                continue;
+            };
+            let buffer = replacement.storage().as_ref().map(|s| s.try_gpu().cloned());
+            if let Some(buffer) = buffer.transpose()? {
+                t.update_storage(Storage::GPU(buffer));
+            }
+        }
+        Ok(Executable::new(compiled_ops, uniform.into_gpu(device)?))
     }
 
     fn to_gpu(&self, dst_device: &Device) -> Result<Tensor, TensorError> {
@@ -960,12 +1393,9 @@ impl<T: TensorDType + Default + num_traits::Float> CloseStats<T> {
 
 #[cfg(feature = "testing")]
 impl Tensor {
-    pub fn read_npy<T, P>(path: P, device: &Device) -> anyhow::Result<Tensor>
-    where
-        T: TensorDType + npyz::Deserialize,
-        P: AsRef<Path>,
-    {
-        Self::from_npy_bytes::<T>(&std::fs::read(path)?, device)
+    /// Reads an `.npy` file, see [Tensor::from_npy].
+    pub fn read_npy<P: AsRef<Path>>(path: P, device: &Device) -> anyhow::Result<Tensor> {
+        Self::from_npy_bytes(&std::fs::read(path)?, device)
     }
 
     pub fn write_npy<T, P>(&self, path: P) -> anyhow::Result<()>
@@ -996,19 +1426,162 @@ impl Tensor {
         Ok(())
     }
 
-    pub fn from_npy_bytes<T: TensorDType + npyz::Deserialize>(
-        bytes: &[u8],
+    pub fn from_npy_bytes(bytes: &[u8], device: &Device) -> anyhow::Result<Tensor> {
+        Self::from_npy(npyz::NpyFile::new(bytes)?, device)
+    }
+
+    /// Reads an npy array of any plain dtype, in either byte order.
+    ///
+    /// Types without a native equivalent are converted: `f8` to F32, other integer widths to
+    /// I32/U32 and bools to U32. NumPy has no bfloat16, `ml_dtypes` writes it as 2 bytes of raw
+    /// data, which is read as BF16. Any other dtype is an error.
+    pub fn from_npy<R: std::io::Read>(
+        npy: npyz::NpyFile<R>,
         device: &Device,
     ) -> anyhow::Result<Tensor> {
-        let reader = npyz::NpyFile::new(bytes)?;
-        let shape = reader
+        use half::{bf16, f16};
+        use npyz::TypeChar;
+
+        let shape: Shape = npy
             .shape()
             .iter()
             .map(|&x| x as usize)
             .collect::<Vec<_>>()
             .into();
-        let data = reader.into_vec::<T>()?;
-        Ok(Tensor::from_data(data, shape, device.clone()))
+        let ts = match npy.dtype() {
+            npyz::DType::Plain(ts) => ts,
+            dtype => anyhow::bail!("Unsupported npy dtype {:?}", dtype),
+        };
+        let device = device.clone();
+        let tensor = match (ts.type_char(), ts.size_field()) {
+            (TypeChar::RawData, 2) => {
+                let data = npy.into_vec::<npyz::FixedSizeBytes<2>>()?;
+                let data = data.into_iter().map(|b| bf16::from_le_bytes(b.0));
+                Tensor::from_data(data.collect::<Vec<_>>(), shape, device)
+            }
+            (TypeChar::Float, 2) => Tensor::from_data(npy.into_vec::<f16>()?, shape, device),
+            (TypeChar::Float, 4) => Tensor::from_data(npy.into_vec::<f32>()?, shape, device),
+            (TypeChar::Float, 8) => {
+                let data = npy.into_vec::<f64>()?.into_iter().map(|x| x as f32);
+                Tensor::from_data(data.collect::<Vec<_>>(), shape, device)
+            }
+            (TypeChar::Int, 1) => {
+                let data = npy.into_vec::<i8>()?.into_iter().map(i32::from);
+                Tensor::from_data(data.collect::<Vec<_>>(), shape, device)
+            }
+            (TypeChar::Int, 2) => {
+                let data = npy.into_vec::<i16>()?.into_iter().map(i32::from);
+                Tensor::from_data(data.collect::<Vec<_>>(), shape, device)
+            }
+            (TypeChar::Int, 4) => Tensor::from_data(npy.into_vec::<i32>()?, shape, device),
+            (TypeChar::Int, 8) => {
+                let data = npy.into_vec::<i64>()?.into_iter().map(i32::try_from);
+                Tensor::from_data(data.collect::<Result<Vec<_>, _>>()?, shape, device)
+            }
+            (TypeChar::Uint, 1) => {
+                let data = npy.into_vec::<u8>()?.into_iter().map(u32::from);
+                Tensor::from_data(data.collect::<Vec<_>>(), shape, device)
+            }
+            (TypeChar::Uint, 2) => {
+                let data = npy.into_vec::<u16>()?.into_iter().map(u32::from);
+                Tensor::from_data(data.collect::<Vec<_>>(), shape, device)
+            }
+            (TypeChar::Uint, 4) => Tensor::from_data(npy.into_vec::<u32>()?, shape, device),
+            (TypeChar::Uint, 8) => {
+                let data = npy.into_vec::<u64>()?.into_iter().map(u32::try_from);
+                Tensor::from_data(data.collect::<Result<Vec<_>, _>>()?, shape, device)
+            }
+            (TypeChar::Bool, 1) => {
+                let data = npy.into_vec::<bool>()?.into_iter().map(u32::from);
+                Tensor::from_data(data.collect::<Vec<_>>(), shape, device)
+            }
+            (t, s) => anyhow::bail!("Unsupported npy dtype {}{}", t, s),
+        };
+        Ok(tensor)
+    }
+
+    /// Reads every array in an `.npz` archive, keyed by array name.
+    pub fn read_npz<P: AsRef<Path>>(
+        path: P,
+        device: &Device,
+    ) -> anyhow::Result<HashMap<String, Tensor>> {
+        Self::from_npz_bytes(&std::fs::read(path)?, device)
+    }
+
+    pub fn from_npz_bytes(
+        bytes: &[u8],
+        device: &Device,
+    ) -> anyhow::Result<HashMap<String, Tensor>> {
+        let mut archive = npyz::npz::NpzArchive::new(std::io::Cursor::new(bytes))?;
+        let names = archive.array_names().map(String::from).collect::<Vec<_>>();
+        let mut tensors = HashMap::with_capacity(names.len());
+        for name in names {
+            let npy = archive
+                .by_name(&name)?
+                .ok_or_else(|| anyhow::anyhow!("Missing array {name} in npz"))?;
+            tensors.insert(name, Self::from_npy(npy, device)?);
+        }
+        Ok(tensors)
+    }
+
+    /// Writes named tensors to an uncompressed `.npz` archive, as `np.savez` does.
+    pub fn write_npz<'a, P, S, I>(tensors: I, path: P) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        S: AsRef<str>,
+        I: IntoIterator<Item = (S, &'a Tensor)>,
+    {
+        let mut npz = npyz::npz::NpzWriter::create(path)?;
+        let options = npyz::zip::write::FileOptions::default()
+            .compression_method(npyz::zip::CompressionMethod::Stored);
+        for (name, tensor) in tensors {
+            let (name, tensor) = (name.as_ref(), tensor.to(&Device::CPU)?);
+            match tensor.dt() {
+                DType::F32 => tensor.write_npz_array::<f32, _>(&mut npz, name, options)?,
+                DType::F16 => tensor.write_npz_array::<half::f16, _>(&mut npz, name, options)?,
+                DType::I32 => tensor.write_npz_array::<i32, _>(&mut npz, name, options)?,
+                DType::U32 => tensor.write_npz_array::<u32, _>(&mut npz, name, options)?,
+                DType::BF16 => {
+                    let shape = tensor.shape().iter().map(|&x| x as u64).collect::<Vec<_>>();
+                    let mut writer = npz
+                        .array::<npyz::FixedSizeBytes<2>>(name, options)?
+                        .dtype(tensor.dt().into())
+                        .shape(&shape)
+                        .begin_nd()?;
+                    let ndarray = tensor.to_ndarray_view::<half::bf16>();
+                    writer.extend(
+                        ndarray
+                            .iter()
+                            .map(|x| npyz::FixedSizeBytes(x.to_le_bytes())),
+                    )?;
+                    writer.finish()?;
+                }
+                dt => anyhow::bail!("Cannot write {dt} tensor {name} to npz"),
+            }
+        }
+        npz.zip_writer().finish()?;
+        Ok(())
+    }
+
+    fn write_npz_array<T, W>(
+        &self,
+        npz: &mut npyz::npz::NpzWriter<W>,
+        name: &str,
+        options: npyz::zip::write::FileOptions,
+    ) -> anyhow::Result<()>
+    where
+        T: TensorDType + npyz::Serialize,
+        W: std::io::Write + Seek,
+    {
+        let shape = self.shape().iter().map(|&x| x as u64).collect::<Vec<_>>();
+        let mut writer = npz
+            .array::<T>(name, options)?
+            .dtype(self.dt().into())
+            .shape(&shape)
+            .begin_nd()?;
+        writer.extend(self.to_ndarray_view::<T>().iter().copied())?;
+        writer.finish()?;
+        Ok(())
     }
 
     pub fn into_ndarray<T: TensorDType>(self) -> ArrayD<T> {
@@ -1113,7 +1686,7 @@ impl<T: TensorDType> From<ArrayD<T>> for Tensor {
 mod tests {
     use half::f16;
 
-    use crate::{rvec, shape, Device, Tensor};
+    use crate::{rvec, shape, Device, Tensor, MAX_HEAD_DIM};
 
     #[test]
     fn has_nan_works() {
@@ -1130,4 +1703,143 @@ mod tests {
         println!("RESULT: {:?}", result);
         assert!(result.has_nan::<f16>());
     }
+
+    #[test]
+    fn conv2d_rejects_invalid_geometry() {
+        let conv = |kernel: [usize; 2], stride, padding, dilation| {
+            let input = Tensor::randn::<f32>(shape![1, 2, 5, 5], Device::CPU);
+            let weight = Tensor::randn::<f32>(shape![4, 2, kernel[0], kernel[1]], Device::CPU);
+            input.conv2d(weight, None, stride, padding, dilation, 1)
+        };
+        assert!(conv([3, 3], [1, 1], [0, 0], [1, 1]).is_ok());
+        assert!(conv([0, 3], [1, 1], [0, 0], [1, 1]).is_err());
+        assert!(conv([3, 3], [0, 1], [0, 0], [1, 1]).is_err());
+        assert!(conv([3, 3], [1, 1], [0, 0], [1, 0]).is_err());
+        assert!(conv([7, 3], [1, 1], [0, 0], [1, 1]).is_err());
+        assert!(conv([7, 3], [1, 1], [1, 0], [1, 1]).is_ok());
+        assert!(conv([3, 3], [1, 1], [0, 0], [3, 1]).is_err());
+    }
+
+    #[test]
+    fn sdpa_rejects_large_head_dim() {
+        let sdpa = |D: usize| {
+            let q = Tensor::randn::<f32>(shape![1, 2, 4, D], Device::CPU);
+            let kv = Tensor::randn::<f32>(shape![1, 2, 4, D], Device::CPU);
+            q.sdpa(kv.clone(), kv, None, true, None)
+        };
+        assert!(sdpa(MAX_HEAD_DIM).is_ok());
+        assert!(sdpa(MAX_HEAD_DIM + 16).is_err());
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod npy_tests {
+    use std::io::Cursor;
+
+    use half::{bf16, f16};
+    use npyz::WriterBuilder;
+
+    use crate::{shape, DType, Device, Tensor};
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn npz_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fixtures.npz");
+        let a = Tensor::randn::<f32>(shape![2, 3], Device::CPU);
+        let b = Tensor::from_data(vec![f16::from_f32(1.5); 4], shape![4], Device::CPU);
+        let c = Tensor::from_data(vec![bf16::from_f32(-2.); 4], shape![2, 2], Device::CPU);
+        Tensor::write_npz([("a", &a), ("b", &b), ("c", &c)], &path)?;
+
+        let read = Tensor::read_npz(&path, &Device::CPU)?;
+        assert_eq!(read.len(), 3);
+        assert_eq!(read["a"].shape(), a.shape());
+        assert_eq!(read["a"].to_vec::<f32>()?, a.to_vec::<f32>()?);
+        assert_eq!(read["b"].to_vec::<f16>()?, b.to_vec::<f16>()?);
+        assert_eq!(read["c"].to_vec::<bf16>()?, c.to_vec::<bf16>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn npz_converts_unsupported_dtypes() -> anyhow::Result<()> {
+        let mut bytes = Cursor::new(vec![]);
+        {
+            let mut npz = npyz::npz::NpzWriter::new(&mut bytes);
+            let options = npyz::zip::write::FileOptions::default()
+                .compression_method(npyz::zip::CompressionMethod::Stored);
+            let mut ids = npz
+                .array::<i64>("ids", options)?
+                .default_dtype()
+                .shape(&[3])
+                .begin_nd()?;
+            ids.extend([1i64, -2, 3])?;
+            ids.finish()?;
+            let mut mask = npz
+                .array::<bool>("mask", options)?
+                .default_dtype()
+                .shape(&[2])
+                .begin_nd()?;
+            mask.extend([true, false])?;
+            mask.finish()?;
+            let mut x = npz
+                .array::<f64>("x", options)?
+                .dtype(npyz::DType::Plain(">f8".parse()?))
+                .shape(&[2])
+                .begin_nd()?;
+            x.extend([0.5f64, -1.])?;
+            x.finish()?;
+            npz.zip_writer().finish()?;
+        }
+
+        let read = Tensor::from_npz_bytes(bytes.get_ref(), &Device::CPU)?;
+        assert_eq!(read["ids"].dt(), DType::I32);
+        assert_eq!(read["ids"].to_vec::<i32>()?, [1, -2, 3]);
+        assert_eq!(read["mask"].dt(), DType::U32);
+        assert_eq!(read["mask"].to_vec::<u32>()?, [1, 0]);
+        assert_eq!(read["x"].dt(), DType::F32);
+        assert_eq!(read["x"].to_vec::<f32>()?, [0.5, -1.]);
+        Ok(())
+    }
+
+    #[test]
+    fn npy_converts_unsupported_dtypes() -> anyhow::Result<()> {
+        let mut bytes = vec![];
+        let mut writer = npyz::WriteOptions::new()
+            .default_dtype()
+            .shape(&[3])
+            .writer(&mut bytes)
+            .begin_nd()?;
+        writer.extend([true, false, true])?;
+        writer.finish()?;
+        let mask = Tensor::from_npy_bytes(&bytes, &Device::CPU)?;
+        assert_eq!(mask.dt(), DType::U32);
+        assert_eq!(mask.to_vec::<u32>()?, [1, 0, 1]);
+
+        let mut bytes = vec![];
+        let mut writer = npyz::WriteOptions::new()
+            .default_dtype()
+            .shape(&[2])
+            .writer(&mut bytes)
+            .begin_nd()?;
+        writer.extend([0.25f64, 4.])?;
+        writer.finish()?;
+        let x = Tensor::from_npy_bytes(&bytes, &Device::CPU)?;
+        assert_eq!(x.dt(), DType::F32);
+        assert_eq!(x.to_vec::<f32>()?, [0.25, 4.]);
+        Ok(())
+    }
+
+    #[test]
+    fn npy_rejects_unsupported_dtypes() -> anyhow::Result<()> {
+        let mut bytes = vec![];
+        let mut writer = npyz::WriteOptions::<npyz::FixedSizeBytes<4>>::new()
+            .dtype(npyz::DType::Plain("<V4".parse()?))
+            .shape(&[1])
+            .writer(&mut bytes)
+            .begin_nd()?;
+        writer.push(&npyz::FixedSizeBytes([0; 4]))?;
+        writer.finish()?;
+        assert!(Tensor::from_npy_bytes(&bytes, &Device::CPU).is_err());
+        Ok(())
+    }
 }
@@ -1,7 +1,8 @@
 #![allow(non_camel_case_types)]
-use half::f16;
+use half::{bf16, f16};
 use ratchet::{DType, Device, Padding, Shape, Tensor};
-use ratchet::{Q8_0F, Q8_0H};
+use ratchet::{Q4_0F, Q4_0H, Q8_0F, Q8_0H};
+use std::marker::PhantomData;
 
 use crate::k_quants::*;
 
@@ -109,6 +110,120 @@ impl GGUFInterop for Q8_0H {
     }
 }
 
+/// Repacks GGUF Q4_0 blocks into the Ratchet layout.
+///
+/// GGUF stores element `j` of a block in the low nibble of byte `j`, and element `j + 16` in the
+/// high nibble, offset by 8. Ratchet stores the nibbles signed & in order.
+fn repack_q4_0(block: &BlockQ4_0) -> [u8; QK4_0 / 2] {
+    let mut qs = [0u8; QK4_0 / 2];
+    for (j, &byte) in block.qs.iter().enumerate() {
+        for (i, nibble) in [(j, byte & 0xF), (j + QK4_0 / 2, byte >> 4)] {
+            let q = (nibble as i8 - 8) as u8 & 0xF;
+            qs[i / 2] |= q << (4 * (i % 2));
+        }
+    }
+    qs
+}
+
+impl GGUFInterop for Q4_0F {
+    type GGUF_TYPE = BlockQ4_0;
+    const BLCK_NUMEL: usize = QK4_0;
+
+    fn transcode(
+        data: &[Self::GGUF_TYPE],
+        n_blocks: usize,
+        shape: Shape,
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        let mut qs_bytes = Vec::with_capacity(n_blocks * QK4_0 / 2);
+        let mut ds_bytes = Vec::with_capacity(n_blocks * 4);
+
+        for block in data {
+            ds_bytes.extend_from_slice(&block.d.to_f32().to_le_bytes());
+            qs_bytes.extend_from_slice(&repack_q4_0(block));
+        }
+
+        let _ = ds_bytes.pad_to_offset();
+        let _ = qs_bytes.pad_to_offset();
+
+        qs_bytes.append(&mut ds_bytes);
+        let casted = bytemuck::cast_slice::<u8, u32>(&qs_bytes);
+        unsafe {
+            Ok(Tensor::from_quantized::<u32, _>(
+                casted,
+                DType::Q4_0F(Q4_0F::default()),
+                shape,
+                device.clone(),
+            ))
+        }
+    }
+}
+
+impl GGUFInterop for Q4_0H {
+    type GGUF_TYPE = BlockQ4_0;
+    const BLCK_NUMEL: usize = QK4_0;
+
+    fn transcode(
+        data: &[Self::GGUF_TYPE],
+        n_blocks: usize,
+        shape: Shape,
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        let mut qs_bytes = Vec::with_capacity(n_blocks * QK4_0 / 2);
+        let mut ds_bytes = Vec::with_capacity(n_blocks * 2);
+
+        for block in data {
+            ds_bytes.extend_from_slice(&block.d.to_le_bytes());
+            qs_bytes.extend_from_slice(&repack_q4_0(block));
+        }
+
+        let _ = ds_bytes.pad_to_offset();
+        let _ = qs_bytes.pad_to_offset();
+
+        qs_bytes.append(&mut ds_bytes);
+        let casted = bytemuck::cast_slice::<u8, u32>(&qs_bytes);
+        unsafe {
+            Ok(Tensor::from_quantized::<u32, _>(
+                casted,
+                DType::Q4_0H(Q4_0H::default()),
+                shape,
+                device.clone(),
+            ))
+        }
+    }
+}
+
+/// Quantized GGUF types dequantized on load: those without a native Ratchet equivalent (e.g the
+/// k-quants), and any type loaded onto the CPU.
+///
+/// Tensors are F16 on GPUs that support it, and F32 otherwise.
+pub struct Dequantized<B>(PhantomData<B>);
+
+impl<B: Dequantize> GGUFInterop for Dequantized<B> {
+    type GGUF_TYPE = B;
+    const BLCK_NUMEL: usize = B::BLCK_NUMEL;
+
+    fn transcode(
+        data: &[Self::GGUF_TYPE],
+        n_blocks: usize,
+        shape: Shape,
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        let mut dequantized = vec![0f32; n_blocks * B::BLCK_NUMEL];
+        B::to_float(data, &mut dequantized);
+        match device {
+            Device::GPU(gpu) if gpu.compute_features().SHADER_F16 => {
+                let f16_data = dequantized
+                    .iter()
+                    .map(|&f| f16::from_f32(f))
+                    .collect::<Vec<_>>();
+                Ok(Tensor::from_data(f16_data, shape, device.clone()))
+            }
+            _ => Ok(Tensor::from_data(dequantized, shape, device.clone())),
+        }
+    }
+}
+
 impl GGUFInterop for f32 {
     type GGUF_TYPE = f32;
     const BLCK_NUMEL: usize = 1;
@@ -150,3 +265,66 @@ impl GGUFInterop for f16 {
         }
     }
 }
+
+impl GGUFInterop for bf16 {
+    type GGUF_TYPE = bf16;
+    const BLCK_NUMEL: usize = 1;
+
+    /// BF16 has no native WGSL type, but is supported on every device through emulation.
+    fn transcode(
+        data: &[Self::GGUF_TYPE],
+        _n_blocks: usize,
+        shape: Shape,
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        Ok(Tensor::from_data(data, shape, device.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratchet::{shape, Quantization, Quantizer};
+
+    const N_BLOCKS: usize = 4;
+
+    fn scale(b: usize) -> f16 {
+        f16::from_f32(0.25 * (b + 1) as f32)
+    }
+
+    #[test]
+    fn native_q4_0_matches_dequantize() -> anyhow::Result<()> {
+        let blocks = (0..N_BLOCKS)
+            .map(|b| BlockQ4_0 {
+                d: scale(b),
+                qs: std::array::from_fn(|j| ((j * 37 + b * 11) % 256) as u8),
+            })
+            .collect::<Vec<_>>();
+        let shape = shape![N_BLOCKS, QK4_0];
+
+        let native = Q4_0F::transcode(&blocks, N_BLOCKS, shape.clone(), &Device::CPU)?;
+        let native = Quantizer::new(Quantization::SInt4).sint4_dequantize(native);
+        let reference =
+            Dequantized::<BlockQ4_0>::transcode(&blocks, N_BLOCKS, shape, &Device::CPU)?;
+        assert_eq!(native.to_vec::<f32>()?, reference.to_vec::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn native_q8_0_matches_dequantize() -> anyhow::Result<()> {
+        let blocks = (0..N_BLOCKS)
+            .map(|b| BlockQ8_0 {
+                d: scale(b),
+                qs: std::array::from_fn(|j| ((j * 37 + b * 11) % 256) as u8 as i8),
+            })
+            .collect::<Vec<_>>();
+        let shape = shape![N_BLOCKS, QK8_0];
+
+        let native = Q8_0F::transcode(&blocks, N_BLOCKS, shape.clone(), &Device::CPU)?;
+        let native = Quantizer::new(Quantization::SInt8).sint8_dequantize(native);
+        let reference =
+            Dequantized::<BlockQ8_0>::transcode(&blocks, N_BLOCKS, shape, &Device::CPU)?;
+        assert_eq!(native.to_vec::<f32>()?, reference.to_vec::<f32>()?);
+        Ok(())
+    }
+}
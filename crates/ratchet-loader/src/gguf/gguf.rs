@@ -2,11 +2,18 @@
 //! Spec: https://github.com/philpax/ggml/blob/gguf-spec/docs/gguf.md
 //! Adapted from https://github.com/huggingface/candle/blob/5ebcfeaf0f5af69bb2f74385e8d6b020d4a3b8df/candle-core/src/quantized/gguf_file.rs
 
-use super::dtype::GGUFInterop;
-use crate::{error::Result, GgmlDType};
-
-use byteorder::{LittleEndian, ReadBytesExt};
-use ratchet::{Device, Shape, Tensor, Q8_0F, Q8_0H};
+use super::dtype::{Dequantized, GGUFInterop};
+use crate::{
+    error::Result,
+    k_quants::{
+        BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ4_1, BlockQ5K, BlockQ5_0, BlockQ5_1,
+        BlockQ6K, BlockQ8K, BlockQ8_0, BlockQ8_1,
+    },
+    GgmlDType,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ratchet::{Device, Shape, Tensor, Q4_0F, Q4_0H, Q8_0F, Q8_0H};
 use std::collections::HashMap;
 use std::ops::Range;
 
@@ -49,6 +56,17 @@ impl VersionedMagic {
         };
         Ok(versioned_magic)
     }
+
+    pub(crate) fn write<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        let version = match self {
+            Self::GgufV1 => crate::bail!("gguf: writing v1 is not supported"),
+            Self::GgufV2 => 2,
+            Self::GgufV3 => 3,
+        };
+        writer.write_u32::<LittleEndian>(0x46554747)?;
+        writer.write_u32::<LittleEndian>(version)?;
+        Ok(())
+    }
 }
 
 #[cfg_attr(target_arch = "wasm32", derive(serde::Serialize, serde::Deserialize))]
@@ -127,6 +145,7 @@ pub fn ratchet_from_gguf(
     match ggml_dtype {
         GgmlDType::F32 => from_raw_data::<f32>(raw_data, size_in_bytes, shape, device),
         GgmlDType::F16 => from_raw_data::<half::f16>(raw_data, size_in_bytes, shape, device),
+        GgmlDType::BF16 => from_raw_data::<half::bf16>(raw_data, size_in_bytes, shape, device),
         GgmlDType::Q8_0 => match device {
             Device::GPU(gpu) => {
                 if gpu.compute_features().SHADER_F16 {
@@ -135,9 +154,52 @@ pub fn ratchet_from_gguf(
                     from_raw_data::<Q8_0F>(raw_data, size_in_bytes, shape, device)
                 }
             }
-            _ => panic!("Loading from GGUF -> Ratchet using CPU device, no way of knowing if F16 is supported"),
+            Device::CPU => {
+                from_raw_data::<Dequantized<BlockQ8_0>>(raw_data, size_in_bytes, shape, device)
+            }
         },
-        _ => anyhow::bail!("unsupported ggml dtype {ggml_dtype:?}"),
+        GgmlDType::Q4_0 => match device {
+            Device::GPU(gpu) => {
+                if gpu.compute_features().SHADER_F16 {
+                    from_raw_data::<Q4_0H>(raw_data, size_in_bytes, shape, device)
+                } else {
+                    from_raw_data::<Q4_0F>(raw_data, size_in_bytes, shape, device)
+                }
+            }
+            Device::CPU => {
+                from_raw_data::<Dequantized<BlockQ4_0>>(raw_data, size_in_bytes, shape, device)
+            }
+        },
+        GgmlDType::Q4_1 => {
+            from_raw_data::<Dequantized<BlockQ4_1>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q5_0 => {
+            from_raw_data::<Dequantized<BlockQ5_0>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q5_1 => {
+            from_raw_data::<Dequantized<BlockQ5_1>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q8_1 => {
+            from_raw_data::<Dequantized<BlockQ8_1>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q2K => {
+            from_raw_data::<Dequantized<BlockQ2K>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q3K => {
+            from_raw_data::<Dequantized<BlockQ3K>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q4K => {
+            from_raw_data::<Dequantized<BlockQ4K>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q5K => {
+            from_raw_data::<Dequantized<BlockQ5K>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q6K => {
+            from_raw_data::<Dequantized<BlockQ6K>>(raw_data, size_in_bytes, shape, device)
+        }
+        GgmlDType::Q8K => {
+            from_raw_data::<Dequantized<BlockQ8K>>(raw_data, size_in_bytes, shape, device)
+        }
     }
 }
 
@@ -179,6 +241,13 @@ fn read_string<R: std::io::Read>(reader: &mut R, magic: &VersionedMagic) -> Resu
     Ok(String::from_utf8_lossy(&v).into_owned())
 }
 
+pub(crate) fn write_string<W: std::io::Write>(writer: &mut W, v: &str) -> Result<()> {
+    let bytes = v.as_bytes();
+    writer.write_u64::<LittleEndian>(bytes.len() as u64)?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ValueType {
     // The value is a 8-bit unsigned integer.
@@ -381,6 +450,40 @@ impl Value {
         };
         Ok(v)
     }
+
+    /// Writes the value, without its type. Only v2 & v3 are supported.
+    pub(crate) fn write<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Self::U8(v) => writer.write_u8(*v)?,
+            Self::I8(v) => writer.write_i8(*v)?,
+            Self::U16(v) => writer.write_u16::<LittleEndian>(*v)?,
+            Self::I16(v) => writer.write_i16::<LittleEndian>(*v)?,
+            Self::U32(v) => writer.write_u32::<LittleEndian>(*v)?,
+            Self::I32(v) => writer.write_i32::<LittleEndian>(*v)?,
+            Self::U64(v) => writer.write_u64::<LittleEndian>(*v)?,
+            Self::I64(v) => writer.write_i64::<LittleEndian>(*v)?,
+            Self::F32(v) => writer.write_f32::<LittleEndian>(*v)?,
+            Self::F64(v) => writer.write_f64::<LittleEndian>(*v)?,
+            Self::Bool(v) => writer.write_u8(*v as u8)?,
+            Self::String(v) => write_string(writer, v)?,
+            Self::Array(vs) => {
+                let value_type = match vs.first() {
+                    Some(first) => first.value_type(),
+                    // The type of an empty array is irrelevant
+                    None => ValueType::U32,
+                };
+                if vs.iter().any(|v| v.value_type() != value_type) {
+                    crate::bail!("gguf: arrays must hold values of a single type {vs:?}")
+                }
+                writer.write_u32::<LittleEndian>(value_type.to_u32())?;
+                writer.write_u64::<LittleEndian>(vs.len() as u64)?;
+                for v in vs {
+                    v.write(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl ValueType {
@@ -403,6 +506,42 @@ impl ValueType {
         };
         Ok(v)
     }
+
+    pub(crate) fn to_u32(self) -> u32 {
+        match self {
+            Self::U8 => 0,
+            Self::I8 => 1,
+            Self::U16 => 2,
+            Self::I16 => 3,
+            Self::U32 => 4,
+            Self::I32 => 5,
+            Self::F32 => 6,
+            Self::Bool => 7,
+            Self::String => 8,
+            Self::Array => 9,
+            Self::U64 => 10,
+            Self::I64 => 11,
+            Self::F64 => 12,
+        }
+    }
+}
+
+/// The alignment of tensor data, given the value of `general.alignment`.
+/// GGUF requires a power of two.
+pub(crate) fn alignment(value: Option<&Value>) -> Result<u64> {
+    let alignment = match value {
+        Some(Value::U8(v)) => *v as u64,
+        Some(Value::U16(v)) => *v as u64,
+        Some(Value::U32(v)) => *v as u64,
+        Some(Value::I8(v)) if *v >= 0 => *v as u64,
+        Some(Value::I16(v)) if *v >= 0 => *v as u64,
+        Some(Value::I32(v)) if *v >= 0 => *v as u64,
+        _ => DEFAULT_ALIGNMENT,
+    };
+    if !alignment.is_power_of_two() {
+        crate::bail!("gguf: alignment {alignment} is not a power of two")
+    }
+    Ok(alignment)
 }
 
 impl Header {
@@ -461,15 +600,7 @@ impl Header {
             );
         }
         let position = reader.stream_position()?;
-        let alignment = match metadata.get("general.alignment") {
-            Some(Value::U8(v)) => *v as u64,
-            Some(Value::U16(v)) => *v as u64,
-            Some(Value::U32(v)) => *v as u64,
-            Some(Value::I8(v)) if *v >= 0 => *v as u64,
-            Some(Value::I16(v)) if *v >= 0 => *v as u64,
-            Some(Value::I32(v)) if *v >= 0 => *v as u64,
-            _ => DEFAULT_ALIGNMENT,
-        };
+        let alignment = alignment(metadata.get("general.alignment"))?;
         let tensor_data_offset = (position + alignment - 1) / alignment * alignment;
         Ok(Self {
             magic,
@@ -0,0 +1,166 @@
+use std::{
+    fs::File,
+    io::Cursor,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use memmap2::Mmap;
+use ratchet::{Device, Tensor};
+
+use super::gguf::{ratchet_from_gguf, Header, TensorInfo};
+
+/// # MmapGGUF
+///
+/// A memory-mapped GGUF file.
+///
+/// Unlike [Header::tensor], tensors are transcoded directly from the mapped pages, so tensors
+/// already in Ratchet's layout (e.g F32, F16) are uploaded to the device without an intermediate
+/// heap copy.
+#[derive(Debug)]
+pub struct MmapGGUF {
+    header: Header,
+    mmap: Mmap,
+}
+
+impl MmapGGUF {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        //Safety: the file must not be modified while it is mapped
+        let mmap = unsafe { Mmap::map(&file)? };
+        let header = Header::read(&mut Cursor::new(&mmap[..]))?;
+        Ok(Self { header, mmap })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn tensor_info(&self, name: &str) -> anyhow::Result<&TensorInfo> {
+        match self.header.tensor_infos.get(name) {
+            Some(tensor_info) => Ok(tensor_info),
+            None => anyhow::bail!("cannot find tensor info for {name}"),
+        }
+    }
+
+    /// # Tensor
+    /// Load the GGUF tensor from the mapped file onto the device.
+    pub fn tensor(&self, name: &str, device: &Device) -> anyhow::Result<Tensor> {
+        let tensor_info = self.tensor_info(name)?;
+        log::info!("Loading tensor {tensor_info:#?}");
+        let range = tensor_info.byte_range(self.header.tensor_data_offset);
+        let raw_data = self
+            .mmap
+            .get(range.start as usize..range.end as usize)
+            .ok_or_else(|| anyhow::anyhow!("tensor {name} is out of bounds of the file"))?;
+        ratchet_from_gguf(
+            tensor_info.ggml_dtype,
+            raw_data,
+            tensor_info.shape.clone(),
+            device,
+        )
+    }
+
+    /// # Lazy Tensor
+    /// Defers loading the tensor onto the device until [LazyTensor::get] is first called.
+    pub fn lazy_tensor(
+        self: &Arc<Self>,
+        name: &str,
+        device: &Device,
+    ) -> anyhow::Result<LazyTensor> {
+        self.tensor_info(name)?;
+        Ok(LazyTensor {
+            source: self.clone(),
+            name: name.to_string(),
+            device: device.clone(),
+            tensor: OnceLock::new(),
+        })
+    }
+}
+
+/// # LazyTensor
+///
+/// A tensor in a [MmapGGUF] which is loaded on first use, and cached thereafter.
+#[derive(Debug)]
+pub struct LazyTensor {
+    source: Arc<MmapGGUF>,
+    name: String,
+    device: Device,
+    tensor: OnceLock<Tensor>,
+}
+
+impl LazyTensor {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn info(&self) -> &TensorInfo {
+        &self.source.header.tensor_infos[&self.name]
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.tensor.get().is_some()
+    }
+
+    pub fn get(&self) -> anyhow::Result<Tensor> {
+        if let Some(tensor) = self.tensor.get() {
+            return Ok(tensor.clone());
+        }
+        let tensor = self.source.tensor(&self.name, &self.device)?;
+        Ok(self.tensor.get_or_init(|| tensor).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ratchet::{shape, Device, DeviceRequest, Tensor};
+
+    use super::MmapGGUF;
+    use crate::gguf::{gguf::VersionedMagic, Writer};
+
+    #[test]
+    fn loads_from_mapped_file() -> anyhow::Result<()> {
+        let a = Tensor::randn::<f32>(shape![8, 16], Device::CPU);
+        let b = Tensor::randn::<f32>(shape![16], Device::CPU);
+
+        let mut writer = Writer::new(VersionedMagic::GgufV3);
+        writer.add_tensor("a", &a)?;
+        writer.add_tensor("b", &b)?;
+        let file = tempfile::NamedTempFile::new()?;
+        writer.write(&mut file.as_file())?;
+
+        let gguf = Arc::new(MmapGGUF::open(file.path())?);
+        let read_a = gguf.tensor("a", &Device::CPU)?;
+        assert_eq!(read_a.to_vec::<f32>()?, a.to_vec::<f32>()?);
+
+        let lazy_b = gguf.lazy_tensor("b", &Device::CPU)?;
+        assert!(!lazy_b.is_loaded());
+        assert_eq!(lazy_b.get()?.to_vec::<f32>()?, b.to_vec::<f32>()?);
+        assert!(lazy_b.is_loaded());
+
+        assert!(gguf.lazy_tensor("c", &Device::CPU).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn uploads_mapped_tensors_to_gpu() -> anyhow::Result<()> {
+        let device = Device::request_device(DeviceRequest::GPU)?;
+        let a = Tensor::randn::<f32>(shape![64, 32], Device::CPU);
+
+        let mut writer = Writer::new(VersionedMagic::GgufV3);
+        writer.add_tensor("a", &a)?;
+        let file = tempfile::NamedTempFile::new()?;
+        writer.write(&mut file.as_file())?;
+
+        let gguf = Arc::new(MmapGGUF::open(file.path())?);
+        let lazy_a = gguf.lazy_tensor("a", &device)?;
+        let gpu_a = lazy_a.get()?;
+        assert!(gpu_a.device().is_gpu());
+        let read_a = gpu_a.to(&Device::CPU)?;
+        assert_eq!(read_a.shape(), a.shape());
+        assert_eq!(read_a.to_vec::<f32>()?, a.to_vec::<f32>()?);
+        Ok(())
+    }
+}
@@ -1,3 +1,10 @@
 pub mod dtype;
 pub mod gguf;
+#[cfg(not(target_arch = "wasm32"))]
+mod mmap;
 pub mod utils;
+mod writer;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use mmap::*;
+pub use writer::*;
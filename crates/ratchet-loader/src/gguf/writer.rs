@@ -0,0 +1,262 @@
+use std::io::{Seek, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use half::f16;
+use ratchet::{read_scales, DType, Device, Shape, Tensor};
+
+use super::gguf::{alignment, write_string, Value, VersionedMagic};
+use crate::{
+    error::Result,
+    k_quants::{QK4_0, QK8_0},
+    GgmlDType,
+};
+
+/// # Writer
+///
+/// Writes GGUF files (v2 & v3), which can be read back with [super::gguf::Header::read].
+///
+/// Metadata is written in the order it was added. Tensor data is aligned to
+/// `general.alignment`, or [super::gguf::DEFAULT_ALIGNMENT] if it isn't set.
+#[derive(Debug)]
+pub struct Writer {
+    magic: VersionedMagic,
+    metadata: Vec<(String, Value)>,
+    tensors: Vec<EncodedTensor>,
+}
+
+#[derive(Debug)]
+struct EncodedTensor {
+    name: String,
+    ggml_dtype: GgmlDType,
+    shape: Shape,
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(magic: VersionedMagic) -> Self {
+        Self {
+            magic,
+            metadata: vec![],
+            tensors: vec![],
+        }
+    }
+
+    /// Adds a metadata entry, replacing any existing entry with the same key.
+    pub fn add_metadata<S: Into<String>>(&mut self, key: S, value: Value) {
+        let key = key.into();
+        match self.metadata.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.metadata.push((key, value)),
+        }
+    }
+
+    /// Encodes a resolved tensor into its GGUF equivalent.
+    ///
+    /// F32, F16, BF16, Q8_0 & Q4_0 tensors are supported.
+    pub fn add_tensor<S: Into<String>>(&mut self, name: S, tensor: &Tensor) -> anyhow::Result<()> {
+        let (ggml_dtype, data) = gguf_from_ratchet(tensor)?;
+        self.add_raw_tensor(name, ggml_dtype, tensor.shape().clone(), data)
+    }
+
+    /// Adds a tensor that is already encoded, e.g one copied from another GGUF file using
+    /// [super::gguf::TensorInfo::byte_range].
+    pub fn add_raw_tensor<S: Into<String>>(
+        &mut self,
+        name: S,
+        ggml_dtype: GgmlDType,
+        shape: Shape,
+        data: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let name = name.into();
+        let numel = shape.numel();
+        if numel % ggml_dtype.block_numel() != 0 {
+            anyhow::bail!(
+                "the number of elements {numel} is not divisible by the block size {}",
+                ggml_dtype.block_numel()
+            )
+        }
+        if data.len() != ggml_dtype.tensor_size(numel) {
+            anyhow::bail!(
+                "expected {} bytes for tensor {name}, got {}",
+                ggml_dtype.tensor_size(numel),
+                data.len()
+            )
+        }
+        self.tensors.push(EncodedTensor {
+            name,
+            ggml_dtype,
+            shape,
+            data,
+        });
+        Ok(())
+    }
+
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
+        let alignment = alignment(
+            self.metadata
+                .iter()
+                .find(|(k, _)| k == "general.alignment")
+                .map(|(_, v)| v),
+        )?;
+
+        self.magic.write(writer)?;
+        writer.write_u64::<LittleEndian>(self.tensors.len() as u64)?;
+        writer.write_u64::<LittleEndian>(self.metadata.len() as u64)?;
+        for (key, value) in self.metadata.iter() {
+            write_string(writer, key)?;
+            writer.write_u32::<LittleEndian>(value.value_type().to_u32())?;
+            value.write(writer)?;
+        }
+
+        let mut offset = 0;
+        for tensor in self.tensors.iter() {
+            write_string(writer, &tensor.name)?;
+            writer.write_u32::<LittleEndian>(tensor.shape.rank() as u32)?;
+            //GGUF dimensions are innermost first
+            for &d in tensor.shape.iter().rev() {
+                writer.write_u64::<LittleEndian>(d as u64)?;
+            }
+            writer.write_u32::<LittleEndian>(tensor.ggml_dtype.to_u32())?;
+            writer.write_u64::<LittleEndian>(offset)?;
+            offset = (offset + tensor.data.len() as u64).next_multiple_of(alignment);
+        }
+
+        pad(writer, alignment)?;
+        for tensor in self.tensors.iter() {
+            writer.write_all(&tensor.data)?;
+            pad(writer, alignment)?;
+        }
+        Ok(())
+    }
+}
+
+fn pad<W: Write + Seek>(writer: &mut W, alignment: u64) -> Result<()> {
+    let position = writer.stream_position()?;
+    let padding = position.next_multiple_of(alignment) - position;
+    writer.write_all(&vec![0u8; padding as usize])?;
+    Ok(())
+}
+
+/// Encodes a tensor as GGUF blocks, the inverse of [super::gguf::ratchet_from_gguf].
+fn gguf_from_ratchet(tensor: &Tensor) -> anyhow::Result<(GgmlDType, Vec<u8>)> {
+    let tensor = tensor.to(&Device::CPU)?;
+    if !tensor.resolved() || !tensor.is_contiguous() {
+        anyhow::bail!("Only resolved, contiguous tensors can be written to GGUF")
+    }
+    let storage_guard = tensor.storage();
+    let buffer = storage_guard.as_ref().unwrap().try_cpu()?;
+    let bytes = buffer.inner().as_bytes();
+
+    let dt = tensor.dt();
+    let numel = tensor.shape().numel();
+    let encoded = match dt {
+        DType::F32 => (GgmlDType::F32, bytes[..numel * 4].to_vec()),
+        DType::F16 => (GgmlDType::F16, bytes[..numel * 2].to_vec()),
+        DType::BF16 => (GgmlDType::BF16, bytes[..numel * 2].to_vec()),
+        //GGUF stores the scales of Q8_0 & Q4_0 in F16
+        DType::Q8_0F(_) | DType::Q8_0H(_) => {
+            let scales = read_scales(bytes, dt, numel, QK8_0);
+            let scales = scales.iter().map(|&d| f16::from_f32(d));
+            let mut data = Vec::with_capacity(GgmlDType::Q8_0.tensor_size(numel));
+            for (d, qs) in scales.zip(bytes[..numel].chunks_exact(QK8_0)) {
+                data.extend_from_slice(&d.to_le_bytes());
+                data.extend_from_slice(qs);
+            }
+            (GgmlDType::Q8_0, data)
+        }
+        DType::Q4_0F(_) | DType::Q4_0H(_) => {
+            let scales = read_scales(bytes, dt, numel, QK4_0);
+            let scales = scales.iter().map(|&d| f16::from_f32(d));
+            let mut data = Vec::with_capacity(GgmlDType::Q4_0.tensor_size(numel));
+            for (d, qs) in scales.zip(bytes[..numel / 2].chunks_exact(QK4_0 / 2)) {
+                data.extend_from_slice(&d.to_le_bytes());
+                //Ratchet nibbles are signed & in order, GGUF nibbles are offset by 8, with
+                //element `j + 16` in the high nibble of byte `j`
+                let nibble = |i: usize| ((qs[i / 2] >> (4 * (i % 2))) & 0xF) ^ 0x8;
+                data.extend((0..QK4_0 / 2).map(|j| nibble(j) | (nibble(j + QK4_0 / 2) << 4)));
+            }
+            (GgmlDType::Q4_0, data)
+        }
+        _ => anyhow::bail!("Unsupported dtype for GGUF: {dt}"),
+    };
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use ratchet::{shape, Device, Quantization, Quantizer, Tensor};
+
+    use super::Writer;
+    use crate::gguf::gguf::{Header, Value, VersionedMagic};
+
+    #[test]
+    fn round_trips_with_header_read() -> anyhow::Result<()> {
+        let weight = Tensor::randn::<f32>(shape![64, 32], Device::CPU);
+        let quantizer = Quantizer::new(Quantization::SInt8);
+        let quantized = quantizer.quantize(weight.deep_clone());
+        let bias = Tensor::randn::<f32>(shape![64], Device::CPU);
+
+        let mut writer = Writer::new(VersionedMagic::GgufV3);
+        writer.add_metadata("general.name", Value::String("ratchet".to_string()));
+        writer.add_metadata("general.alignment", Value::U32(64));
+        writer.add_metadata(
+            "tokenizer.scores",
+            Value::Array(vec![Value::F32(0.5), Value::F32(-1.)]),
+        );
+        writer.add_tensor("weight", &quantized)?;
+        writer.add_tensor("bias", &bias)?;
+
+        let mut file = Cursor::new(vec![]);
+        writer.write(&mut file)?;
+
+        let header = Header::read(&mut file)?;
+        assert_eq!(header.magic, VersionedMagic::GgufV3);
+        assert_eq!(header.tensor_data_offset % 64, 0);
+        assert_eq!(header.metadata.get("general.name")?.to_string()?, "ratchet");
+        assert_eq!(header.metadata.get("tokenizer.scores")?.to_vec()?.len(), 2);
+
+        let read_bias = header.tensor(&mut file, "bias", &Device::CPU)?;
+        assert_eq!(read_bias.to_vec::<f32>()?, bias.to_vec::<f32>()?);
+
+        //GGUF stores Q8_0 scales in F16
+        let read_weight = header.tensor(&mut file, "weight", &Device::CPU)?;
+        let dequantized = quantizer.sint8_dequantize(quantized);
+        dequantized.all_close(&read_weight, 1e-2, 1e-2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_v2() -> anyhow::Result<()> {
+        let a = Tensor::randn::<f32>(shape![16, 8], Device::CPU);
+        let mut writer = Writer::new(VersionedMagic::GgufV2);
+        writer.add_metadata("general.name", Value::String("ratchet".to_string()));
+        writer.add_metadata("llama.block_count", Value::U64(2));
+        writer.add_tensor("a", &a)?;
+
+        let mut file = Cursor::new(vec![]);
+        writer.write(&mut file)?;
+
+        let header = Header::read(&mut file)?;
+        assert_eq!(header.magic, VersionedMagic::GgufV2);
+        assert_eq!(header.metadata.get("general.name")?.to_string()?, "ratchet");
+        assert_eq!(header.metadata.get("llama.block_count")?.to_u64()?, 2);
+        let read_a = header.tensor(&mut file, "a", &Device::CPU)?;
+        assert_eq!(read_a.shape(), a.shape());
+        assert_eq!(read_a.to_vec::<f32>()?, a.to_vec::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_alignment() -> anyhow::Result<()> {
+        let a = Tensor::randn::<f32>(shape![4], Device::CPU);
+        for alignment in [0, 48] {
+            let mut writer = Writer::new(VersionedMagic::GgufV3);
+            writer.add_metadata("general.alignment", Value::U32(alignment));
+            writer.add_tensor("a", &a)?;
+            assert!(writer.write(&mut Cursor::new(vec![])).is_err());
+        }
+        Ok(())
+    }
+}
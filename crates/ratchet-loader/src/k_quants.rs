@@ -1,5 +1,5 @@
 // Credit: https://github.com/huggingface/candle/blob/main/candle-core/src/quantized/k_quants.rs
-use half::f16;
+use half::{bf16, f16};
 
 use crate::GgmlDType;
 // Default to QK_K 256 rather than 64.
@@ -133,11 +133,26 @@ pub struct BlockQ8K {
 }
 const _: () = assert!(4 + QK_K + QK_K / 16 * 2 == std::mem::size_of::<BlockQ8K>());
 
+impl GGType for BlockQ2K {
+    const DTYPE: GgmlDType = GgmlDType::Q2K;
+    const BLCK_NUMEL: usize = QK_K;
+}
+
+impl GGType for BlockQ3K {
+    const DTYPE: GgmlDType = GgmlDType::Q3K;
+    const BLCK_NUMEL: usize = QK_K;
+}
+
 impl GGType for BlockQ4K {
     const DTYPE: GgmlDType = GgmlDType::Q4K;
     const BLCK_NUMEL: usize = QK_K;
 }
 
+impl GGType for BlockQ5K {
+    const DTYPE: GgmlDType = GgmlDType::Q5K;
+    const BLCK_NUMEL: usize = QK_K;
+}
+
 impl GGType for BlockQ6K {
     const DTYPE: GgmlDType = GgmlDType::Q6K;
     const BLCK_NUMEL: usize = QK_K;
@@ -148,11 +163,36 @@ impl GGType for BlockQ4_0 {
     const BLCK_NUMEL: usize = QK4_0;
 }
 
+impl GGType for BlockQ4_1 {
+    const DTYPE: GgmlDType = GgmlDType::Q4_1;
+    const BLCK_NUMEL: usize = QK4_1;
+}
+
+impl GGType for BlockQ5_0 {
+    const DTYPE: GgmlDType = GgmlDType::Q5_0;
+    const BLCK_NUMEL: usize = QK5_0;
+}
+
+impl GGType for BlockQ5_1 {
+    const DTYPE: GgmlDType = GgmlDType::Q5_1;
+    const BLCK_NUMEL: usize = QK5_1;
+}
+
 impl GGType for BlockQ8_0 {
     const DTYPE: GgmlDType = GgmlDType::Q8_0;
     const BLCK_NUMEL: usize = QK8_0;
 }
 
+impl GGType for BlockQ8_1 {
+    const DTYPE: GgmlDType = GgmlDType::Q8_1;
+    const BLCK_NUMEL: usize = QK8_1;
+}
+
+impl GGType for BlockQ8K {
+    const DTYPE: GgmlDType = GgmlDType::Q8K;
+    const BLCK_NUMEL: usize = QK_K;
+}
+
 impl GGType for f32 {
     const DTYPE: GgmlDType = GgmlDType::F32;
     const BLCK_NUMEL: usize = 1;
@@ -162,3 +202,691 @@ impl GGType for f16 {
     const DTYPE: GgmlDType = GgmlDType::F16;
     const BLCK_NUMEL: usize = 1;
 }
+
+impl GGType for bf16 {
+    const DTYPE: GgmlDType = GgmlDType::BF16;
+    const BLCK_NUMEL: usize = 1;
+}
+
+/// Quantized GGUF types, which can be dequantized on load.
+///
+/// This is the reference implementation, used for types without a native Ratchet equivalent &
+/// for tensors loaded onto the CPU.
+pub trait Dequantize: GGType {
+    /// Dequantizes `xs` into `ys`, which holds `BLCK_NUMEL` values for each block.
+    fn to_float(xs: &[Self], ys: &mut [f32]);
+}
+
+impl Dequantize for BlockQ4_0 {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK4_0)) {
+            let d = block.d.to_f32();
+            for (j, q) in block.qs.iter().enumerate() {
+                y[j] = ((q & 0xF) as i16 - 8) as f32 * d;
+                y[j + QK4_0 / 2] = ((q >> 4) as i16 - 8) as f32 * d;
+            }
+        }
+    }
+}
+
+impl Dequantize for BlockQ4_1 {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK4_1)) {
+            let (d, m) = (block.d.to_f32(), block.m.to_f32());
+            for (j, q) in block.qs.iter().enumerate() {
+                y[j] = (q & 0xF) as f32 * d + m;
+                y[j + QK4_1 / 2] = (q >> 4) as f32 * d + m;
+            }
+        }
+    }
+}
+
+/// Rebuilds the 5 bit values of a Q5 block, from the low nibbles in `qs` & the high bits in
+/// `qh`.
+fn q5_values(qs: &[u8; QK5_0 / 2], qh: &[u8; 4]) -> [u8; QK5_0] {
+    let qh = u32::from_le_bytes(*qh);
+    let mut values = [0u8; QK5_0];
+    for (j, q) in qs.iter().enumerate() {
+        let xh_0 = (((qh >> j) << 4) & 0x10) as u8;
+        let xh_1 = ((qh >> (j + 12)) & 0x10) as u8;
+        values[j] = (q & 0xF) | xh_0;
+        values[j + QK5_0 / 2] = (q >> 4) | xh_1;
+    }
+    values
+}
+
+impl Dequantize for BlockQ5_0 {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK5_0)) {
+            let d = block.d.to_f32();
+            for (y, q) in y.iter_mut().zip(q5_values(&block.qs, &block.qh)) {
+                *y = (q as i16 - 16) as f32 * d;
+            }
+        }
+    }
+}
+
+impl Dequantize for BlockQ5_1 {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK5_1)) {
+            let (d, m) = (block.d.to_f32(), block.m.to_f32());
+            for (y, q) in y.iter_mut().zip(q5_values(&block.qs, &block.qh)) {
+                *y = q as f32 * d + m;
+            }
+        }
+    }
+}
+
+impl Dequantize for BlockQ8_0 {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK8_0)) {
+            let d = block.d.to_f32();
+            for (y, &q) in y.iter_mut().zip(block.qs.iter()) {
+                *y = q as f32 * d;
+            }
+        }
+    }
+}
+
+impl Dequantize for BlockQ8_1 {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK8_1)) {
+            let d = block.d.to_f32();
+            for (y, &q) in y.iter_mut().zip(block.qs.iter()) {
+                *y = q as f32 * d;
+            }
+        }
+    }
+}
+
+impl Dequantize for BlockQ8K {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            for (y, &q) in y.iter_mut().zip(block.qs.iter()) {
+                *y = q as f32 * block.d;
+            }
+        }
+    }
+}
+
+// https://github.com/ggerganov/llama.cpp/blob/468ea24fb4633a0d681f7ac84089566c1c6190cb/k_quants.c#L354
+impl Dequantize for BlockQ2K {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            let d = block.d.to_f32();
+            let min = block.dmin.to_f32();
+
+            let mut is = 0;
+            let mut ys_index = 0;
+            for q in block.qs.chunks_exact(32) {
+                for shift in (0..8).step_by(2) {
+                    for half in q.chunks_exact(16) {
+                        let sc = block.scales[is];
+                        is += 1;
+                        let dl = d * (sc & 0xF) as f32;
+                        let ml = min * (sc >> 4) as f32;
+                        for q in half {
+                            y[ys_index] = dl * ((q >> shift) & 3) as f32 - ml;
+                            ys_index += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// https://github.com/ggerganov/llama.cpp/blob/468ea24fb4633a0d681f7ac84089566c1c6190cb/k_quants.c#L642
+impl Dequantize for BlockQ3K {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        const KMASK1: u32 = 0x03030303;
+        const KMASK2: u32 = 0x0f0f0f0f;
+
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            let d_all = block.d.to_f32();
+
+            //Unpack the 16 6-bit scales
+            let mut aux = [0u32; 4];
+            for (a, bytes) in aux.iter_mut().zip(block.scales.chunks_exact(4)) {
+                *a = u32::from_le_bytes(bytes.try_into().unwrap());
+            }
+            let tmp = aux[2];
+            aux[2] = ((aux[0] >> 4) & KMASK2) | (((tmp >> 4) & KMASK1) << 4);
+            aux[3] = ((aux[1] >> 4) & KMASK2) | (((tmp >> 6) & KMASK1) << 4);
+            aux[0] = (aux[0] & KMASK2) | ((tmp & KMASK1) << 4);
+            aux[1] = (aux[1] & KMASK2) | (((tmp >> 2) & KMASK1) << 4);
+            let scales = aux
+                .iter()
+                .flat_map(|a| a.to_le_bytes())
+                .map(|s| s as i8)
+                .collect::<Vec<_>>();
+
+            let mut m = 1u8;
+            let mut is = 0;
+            let mut ys_index = 0;
+            for q in block.qs.chunks_exact(32) {
+                for shift in (0..8).step_by(2) {
+                    for (q, hm) in q.chunks_exact(16).zip(block.hmask.chunks_exact(16)) {
+                        let dl = d_all * (scales[is] - 32) as f32;
+                        is += 1;
+                        for (q, hm) in q.iter().zip(hm) {
+                            let high = if hm & m != 0 { 0 } else { 4 };
+                            y[ys_index] = dl * (((q >> shift) & 3) as i8 - high) as f32;
+                            ys_index += 1;
+                        }
+                    }
+                    m <<= 1;
+                }
+            }
+        }
+    }
+}
+
+fn get_scale_min_k4(j: usize, q: &[u8]) -> (u8, u8) {
+    if j < 4 {
+        (q[j] & 63, q[j + 4] & 63)
+    } else {
+        let d = (q[j + 4] & 0xF) | ((q[j - 4] >> 6) << 4);
+        let m = (q[j + 4] >> 4) | ((q[j] >> 6) << 4);
+        (d, m)
+    }
+}
+
+// https://github.com/ggerganov/llama.cpp/blob/468ea24fb4633a0d681f7ac84089566c1c6190cb/k_quants.c#L735
+impl Dequantize for BlockQ4K {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            let d = block.d.to_f32();
+            let min = block.dmin.to_f32();
+
+            let mut is = 0;
+            let mut ys_index = 0;
+            for q in block.qs.chunks_exact(32) {
+                let (sc, m) = get_scale_min_k4(is, &block.scales);
+                let (d1, m1) = (d * sc as f32, min * m as f32);
+                let (sc, m) = get_scale_min_k4(is + 1, &block.scales);
+                let (d2, m2) = (d * sc as f32, min * m as f32);
+                for q in q {
+                    y[ys_index] = d1 * (q & 0xF) as f32 - m1;
+                    ys_index += 1;
+                }
+                for q in q {
+                    y[ys_index] = d2 * (q >> 4) as f32 - m2;
+                    ys_index += 1;
+                }
+                is += 2;
+            }
+        }
+    }
+}
+
+// https://github.com/ggerganov/llama.cpp/blob/468ea24fb4633a0d681f7ac84089566c1c6190cb/k_quants.c#L928
+impl Dequantize for BlockQ5K {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            let d = block.d.to_f32();
+            let min = block.dmin.to_f32();
+
+            let mut is = 0;
+            let (mut u1, mut u2) = (1u8, 2u8);
+            let mut ys_index = 0;
+            for ql in block.qs.chunks_exact(32) {
+                let (sc, m) = get_scale_min_k4(is, &block.scales);
+                let (d1, m1) = (d * sc as f32, min * m as f32);
+                let (sc, m) = get_scale_min_k4(is + 1, &block.scales);
+                let (d2, m2) = (d * sc as f32, min * m as f32);
+                for (ql, qh) in ql.iter().zip(block.qh.iter()) {
+                    let high = if qh & u1 != 0 { 16 } else { 0 };
+                    y[ys_index] = d1 * ((ql & 0xF) + high) as f32 - m1;
+                    ys_index += 1;
+                }
+                for (ql, qh) in ql.iter().zip(block.qh.iter()) {
+                    let high = if qh & u2 != 0 { 16 } else { 0 };
+                    y[ys_index] = d2 * ((ql >> 4) + high) as f32 - m2;
+                    ys_index += 1;
+                }
+                is += 2;
+                u1 <<= 2;
+                u2 <<= 2;
+            }
+        }
+    }
+}
+
+// https://github.com/ggerganov/llama.cpp/blob/468ea24fb4633a0d681f7ac84089566c1c6190cb/k_quants.c#L1067
+impl Dequantize for BlockQ6K {
+    fn to_float(xs: &[Self], ys: &mut [f32]) {
+        for (block, y) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            let d = block.d.to_f32();
+
+            for (idx, y) in y.chunks_exact_mut(128).enumerate() {
+                let sc = &block.scales[8 * idx..];
+                let ql = &block.ql[64 * idx..];
+                let qh = &block.qh[32 * idx..];
+                for l in 0..32 {
+                    let is = l / 16;
+                    let q1 = ((ql[l] & 0xF) | ((qh[l] & 3) << 4)) as i8 - 32;
+                    let q2 = ((ql[l + 32] & 0xF) | (((qh[l] >> 2) & 3) << 4)) as i8 - 32;
+                    let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i8 - 32;
+                    let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i8 - 32;
+                    y[l] = d * sc[is] as f32 * q1 as f32;
+                    y[l + 32] = d * sc[is + 2] as f32 * q2 as f32;
+                    y[l + 64] = d * sc[is + 4] as f32 * q3 as f32;
+                    y[l + 96] = d * sc[is + 6] as f32 * q4 as f32;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dequantize<T: Dequantize>(block: T) -> Vec<f32> {
+        let mut ys = vec![0f32; T::BLCK_NUMEL];
+        T::to_float(&[block], &mut ys);
+        ys
+    }
+
+    /// Quants spread over `0..levels`, so every bit of the packed layout is exercised.
+    fn quants(levels: u8) -> [u8; QK_K] {
+        std::array::from_fn(|i| ((i * 7 + i / 13) % levels as usize) as u8)
+    }
+
+    /// Packs 6 bit scales & mins as llama.cpp's `quantize_row_q4_K_reference` does.
+    fn pack_scales(sc: &[u8; 8], m: &[u8; 8]) -> [u8; K_SCALE_SIZE] {
+        let mut scales = [0u8; K_SCALE_SIZE];
+        for j in 0..8 {
+            if j < 4 {
+                scales[j] = sc[j];
+                scales[j + 4] = m[j];
+            } else {
+                scales[j + 4] = (sc[j] & 0xF) | ((m[j] & 0xF) << 4);
+                scales[j - 4] |= (sc[j] >> 4) << 6;
+                scales[j] |= (m[j] >> 4) << 6;
+            }
+        }
+        scales
+    }
+
+    const SC: [u8; 8] = [1, 63, 17, 32, 5, 48, 63, 20];
+    const M: [u8; 8] = [0, 7, 63, 33, 16, 2, 50, 63];
+
+    #[test]
+    fn q8_0_known_block() {
+        let block = BlockQ8_0 {
+            d: f16::from_f32(0.5),
+            qs: std::array::from_fn(|i| (i as i8 - 16) * 8),
+        };
+        let ys = dequantize(block);
+        assert_eq!(ys[0], -64.);
+        assert_eq!(ys[16], 0.);
+        assert_eq!(ys[31], 60.);
+    }
+
+    #[test]
+    fn q8_0_round_trip() {
+        let xs = (0..QK8_0)
+            .map(|i| (i as f32 - 10.).sin() * 3.)
+            .collect::<Vec<_>>();
+        let amax = xs.iter().fold(0f32, |acc, x| acc.max(x.abs()));
+        let d = amax / 127.;
+        let block = BlockQ8_0 {
+            d: f16::from_f32(d),
+            qs: std::array::from_fn(|i| (xs[i] / d).round() as i8),
+        };
+        for (x, y) in xs.iter().zip(dequantize(block)) {
+            assert!((x - y).abs() <= d, "{} != {}", x, y);
+        }
+    }
+
+    #[test]
+    fn q4_1_known_block() {
+        let block = BlockQ4_1 {
+            d: f16::from_f32(0.5),
+            m: f16::from_f32(-1.),
+            qs: [0x31; QK4_1 / 2],
+        };
+        let ys = dequantize(block);
+        //Low nibbles are the first half of the block, high nibbles the second
+        assert_eq!(ys[0], 0.5 * 1. - 1.);
+        assert_eq!(ys[15], 0.5 * 1. - 1.);
+        assert_eq!(ys[16], 0.5 * 3. - 1.);
+        assert_eq!(ys[31], 0.5 * 3. - 1.);
+    }
+
+    /// Packs 5 bit quants as llama.cpp's `quantize_row_q5_0_reference` does.
+    fn pack_q5(l: &[u8]) -> ([u8; QK5_0 / 2], [u8; 4]) {
+        let mut qs = [0u8; QK5_0 / 2];
+        let mut qh = 0u32;
+        for j in 0..QK5_0 / 2 {
+            let (x0, x1) = (l[j], l[j + QK5_0 / 2]);
+            qs[j] = (x0 & 0xF) | ((x1 & 0xF) << 4);
+            qh |= ((x0 as u32 & 0x10) >> 4) << j;
+            qh |= ((x1 as u32 & 0x10) >> 4) << (j + QK5_0 / 2);
+        }
+        (qs, qh.to_le_bytes())
+    }
+
+    #[test]
+    fn q5_0_known_block() {
+        //Bits 0, 16 & 31 of `qh` raise elements 0, 16 & 31 by 16
+        let block = BlockQ5_0 {
+            d: f16::from_f32(0.5),
+            qh: (1u32 | 1 << 16 | 1 << 31).to_le_bytes(),
+            qs: [0x00; QK5_0 / 2],
+        };
+        let ys = dequantize(block);
+        assert_eq!(ys[0], 0.);
+        assert_eq!(ys[1], 0.5 * -16.);
+        assert_eq!(ys[15], 0.5 * -16.);
+        assert_eq!(ys[16], 0.);
+        assert_eq!(ys[17], 0.5 * -16.);
+        assert_eq!(ys[31], 0.);
+    }
+
+    #[test]
+    fn q5_0_round_trip() {
+        let d = f16::from_f32(0.25);
+        let l = quants(32);
+        let (qs, qh) = pack_q5(&l[..QK5_0]);
+        let block = BlockQ5_0 { d, qh, qs };
+        for (i, y) in dequantize(block).into_iter().enumerate() {
+            assert_eq!(y, d.to_f32() * (l[i] as f32 - 16.), "index {}", i);
+        }
+    }
+
+    #[test]
+    fn q5_1_round_trip() {
+        let (d, m) = (f16::from_f32(0.25), f16::from_f32(-2.));
+        let l = quants(32);
+        let (qs, qh) = pack_q5(&l[3..3 + QK5_1]);
+        let block = BlockQ5_1 { d, m, qh, qs };
+        for (i, y) in dequantize(block).into_iter().enumerate() {
+            let expected = d.to_f32() * l[i + 3] as f32 + m.to_f32();
+            assert_eq!(y, expected, "index {}", i);
+        }
+    }
+
+    #[test]
+    fn q8_1_known_block() {
+        //The sum `s` is only used by dot products, it doesn't affect the values
+        let block = BlockQ8_1 {
+            d: f16::from_f32(0.25),
+            s: f16::from_f32(100.),
+            qs: std::array::from_fn(|i| i as i8 * 4 - 64),
+        };
+        let ys = dequantize(block);
+        assert_eq!(ys[0], -16.);
+        assert_eq!(ys[16], 0.);
+        assert_eq!(ys[31], 15.);
+    }
+
+    #[test]
+    fn q8k_known_block() {
+        let block = BlockQ8K {
+            d: 0.1,
+            qs: std::array::from_fn(|i| (i as i16 - 128) as i8),
+            bsums: [0; QK_K / 16],
+        };
+        let ys = dequantize(block);
+        assert_eq!(ys[0], -128. * 0.1);
+        assert_eq!(ys[128], 0.);
+        assert_eq!(ys[255], 127. * 0.1);
+    }
+
+    /// Packs 2 bit quants as llama.cpp's `quantize_row_q2_K_reference` & `q3_K` do.
+    fn pack_q2(l: &[u8; QK_K]) -> [u8; QK_K / 4] {
+        let mut qs = [0u8; QK_K / 4];
+        for j in (0..QK_K).step_by(128) {
+            for i in 0..32 {
+                qs[j / 4 + i] =
+                    l[j + i] | (l[j + i + 32] << 2) | (l[j + i + 64] << 4) | (l[j + i + 96] << 6);
+            }
+        }
+        qs
+    }
+
+    #[test]
+    fn q2k_known_block() {
+        //Sub-blocks 0..8 have scale 1 & min 2, the rest scale 2 & min 1
+        let mut scales = [0x21; QK_K / 16];
+        scales[8..].fill(0x12);
+        let block = BlockQ2K {
+            scales,
+            //Quants 0, 1, 2 & 3 at shifts 0, 2, 4 & 6
+            qs: [0xE4; QK_K / 4],
+            d: f16::from_f32(1.),
+            dmin: f16::from_f32(0.5),
+        };
+        let ys = dequantize(block);
+        assert_eq!(ys[0], -1.);
+        assert_eq!(ys[32], 0.);
+        assert_eq!(ys[64], 1.);
+        assert_eq!(ys[127], 2.);
+        assert_eq!(ys[128], -0.5);
+        assert_eq!(ys[255], 5.5);
+    }
+
+    #[test]
+    fn q2k_round_trip() {
+        let (d, dmin) = (f16::from_f32(0.5), f16::from_f32(0.25));
+        let l = quants(4);
+        let scales: [u8; QK_K / 16] =
+            std::array::from_fn(|j| ((j * 5 + 3) % 16 | (j % 16) << 4) as u8);
+        let block = BlockQ2K {
+            scales,
+            qs: pack_q2(&l),
+            d,
+            dmin,
+        };
+        for (i, y) in dequantize(block).into_iter().enumerate() {
+            let (sc, m) = (scales[i / 16] & 0xF, scales[i / 16] >> 4);
+            let expected = d.to_f32() * sc as f32 * l[i] as f32 - dmin.to_f32() * m as f32;
+            assert_eq!(y, expected, "index {}", i);
+        }
+    }
+
+    /// Packs 16 6 bit scales as llama.cpp's `quantize_row_q3_K_reference` does.
+    fn pack_q3k_scales(ls: &[u8; QK_K / 16]) -> [u8; 12] {
+        let mut scales = [0u8; 12];
+        for (j, &l) in ls.iter().enumerate() {
+            if j < 8 {
+                scales[j] = l & 0xF;
+            } else {
+                scales[j - 8] |= (l & 0xF) << 4;
+            }
+            scales[j % 4 + 8] |= (l >> 4) << (2 * (j / 4));
+        }
+        scales
+    }
+
+    /// Splits 3 bit quants into `hmask` & 2 bit quants, as `quantize_row_q3_K_reference` does.
+    fn pack_q3k(l: &[u8; QK_K]) -> ([u8; QK_K / 8], [u8; QK_K / 4]) {
+        let mut hmask = [0u8; QK_K / 8];
+        let mut low = *l;
+        for (j, q) in low.iter_mut().enumerate() {
+            if *q > 3 {
+                hmask[j % (QK_K / 8)] |= 1 << (j / (QK_K / 8));
+                *q -= 4;
+            }
+        }
+        (hmask, pack_q2(&low))
+    }
+
+    #[test]
+    fn q3k_known_block() {
+        //Every scale is 33, i.e 1 once centered
+        let block = BlockQ3K {
+            //The high bit is set for the first 128 values only
+            hmask: [0x0F; QK_K / 8],
+            qs: [0xE4; QK_K / 4],
+            scales: [
+                0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0xAA, 0xAA, 0xAA, 0xAA,
+            ],
+            d: f16::from_f32(0.5),
+        };
+        let ys = dequantize(block);
+        assert_eq!(ys[0], 0.);
+        assert_eq!(ys[32], 0.5);
+        assert_eq!(ys[64], 1.);
+        assert_eq!(ys[127], 1.5);
+        assert_eq!(ys[128], -2.);
+        assert_eq!(ys[255], -0.5);
+    }
+
+    #[test]
+    fn q3k_round_trip() {
+        let d = f16::from_f32(0.125);
+        let l = quants(8);
+        let ls: [u8; QK_K / 16] = std::array::from_fn(|j| ((j * 23 + 5) % 64) as u8);
+        let (hmask, qs) = pack_q3k(&l);
+        let block = BlockQ3K {
+            hmask,
+            qs,
+            scales: pack_q3k_scales(&ls),
+            d,
+        };
+        for (i, y) in dequantize(block).into_iter().enumerate() {
+            let scale = ls[i / 16] as f32 - 32.;
+            let expected = d.to_f32() * scale * (l[i] as f32 - 4.);
+            assert_eq!(y, expected, "index {}", i);
+        }
+    }
+
+    #[test]
+    fn q4k_known_block() {
+        //Sub-blocks 0..4 have scales 1..4 & mins 5..8, the rest are zero
+        let block = BlockQ4K {
+            d: f16::from_f32(0.5),
+            dmin: f16::from_f32(0.25),
+            scales: [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0],
+            qs: [0x31; QK_K / 2],
+        };
+        let ys = dequantize(block);
+        //Low nibbles in even sub-blocks, high nibbles in odd ones
+        assert_eq!(ys[0], 0.5 * 1. * 1. - 0.25 * 5.);
+        assert_eq!(ys[32], 0.5 * 2. * 3. - 0.25 * 6.);
+        assert_eq!(ys[64], 0.5 * 3. * 1. - 0.25 * 7.);
+        assert_eq!(ys[127], 0.5 * 4. * 3. - 0.25 * 8.);
+        assert!(ys[128..].iter().all(|&y| y == 0.));
+    }
+
+    #[test]
+    fn q4k_round_trip() {
+        let (d, dmin) = (f16::from_f32(0.125), f16::from_f32(0.0625));
+        let l = quants(16);
+        let mut qs = [0u8; QK_K / 2];
+        for j in (0..QK_K).step_by(64) {
+            for i in 0..32 {
+                qs[j / 2 + i] = l[j + i] | (l[j + i + 32] << 4);
+            }
+        }
+        let block = BlockQ4K {
+            d,
+            dmin,
+            scales: pack_scales(&SC, &M),
+            qs,
+        };
+        for (i, y) in dequantize(block).into_iter().enumerate() {
+            let expected =
+                d.to_f32() * SC[i / 32] as f32 * l[i] as f32 - dmin.to_f32() * M[i / 32] as f32;
+            assert_eq!(y, expected, "index {}", i);
+        }
+    }
+
+    #[test]
+    fn q5k_known_block() {
+        //Only the first byte of `qh` is set, raising the first element of each sub-block by 16
+        let mut qh = [0u8; QK_K / 8];
+        qh[0] = 0xFF;
+        let block = BlockQ5K {
+            d: f16::from_f32(1.),
+            dmin: f16::from_f32(1.),
+            scales: [1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+            qh,
+            qs: [0x52; QK_K / 2],
+        };
+        let ys = dequantize(block);
+        assert_eq!(ys[0], 18.);
+        assert_eq!(ys[1], 2.);
+        assert_eq!(ys[32], 21.);
+        assert_eq!(ys[33], 5.);
+        assert_eq!(ys[96], 21.);
+        assert_eq!(ys[128], 0.);
+    }
+
+    #[test]
+    fn q5k_round_trip() {
+        let (d, dmin) = (f16::from_f32(0.25), f16::from_f32(0.5));
+        let l = quants(32);
+        let (mut qs, mut qh) = ([0u8; QK_K / 2], [0u8; QK_K / 8]);
+        let (mut m1, mut m2) = (1u8, 2u8);
+        for n in (0..QK_K).step_by(64) {
+            for j in 0..32 {
+                let (mut l1, mut l2) = (l[n + j], l[n + j + 32]);
+                if l1 > 15 {
+                    l1 -= 16;
+                    qh[j] |= m1;
+                }
+                if l2 > 15 {
+                    l2 -= 16;
+                    qh[j] |= m2;
+                }
+                qs[n / 2 + j] = l1 | (l2 << 4);
+            }
+            m1 <<= 2;
+            m2 <<= 2;
+        }
+        let block = BlockQ5K {
+            d,
+            dmin,
+            scales: pack_scales(&SC, &M),
+            qh,
+            qs,
+        };
+        for (i, y) in dequantize(block).into_iter().enumerate() {
+            let expected =
+                d.to_f32() * SC[i / 32] as f32 * l[i] as f32 - dmin.to_f32() * M[i / 32] as f32;
+            assert_eq!(y, expected, "index {}", i);
+        }
+    }
+
+    #[test]
+    fn q6k_known_block() {
+        //Every quant is 1 | (1 << 4) = 17, i.e -15 once centered
+        let block = BlockQ6K {
+            ql: [0x11; QK_K / 2],
+            qh: [0x55; QK_K / 4],
+            scales: std::array::from_fn(|i| i as i8 - 8),
+            d: f16::from_f32(0.5),
+        };
+        let ys = dequantize(block);
+        assert_eq!(ys[0], 0.5 * -8. * -15.);
+        assert_eq!(ys[16], 0.5 * -7. * -15.);
+        assert_eq!(ys[255], 0.5 * 7. * -15.);
+    }
+
+    #[test]
+    fn q6k_round_trip() {
+        let d = f16::from_f32(0.03125);
+        let l = quants(64);
+        let scales: [i8; QK_K / 16] = std::array::from_fn(|i| ((i * 9) % 64) as i8 - 32);
+        let (mut ql, mut qh) = ([0u8; QK_K / 2], [0u8; QK_K / 4]);
+        for j in (0..QK_K).step_by(128) {
+            let (ql, qh) = (&mut ql[j / 2..], &mut qh[j / 4..]);
+            for i in 0..32 {
+                let q = |k: usize| l[j + i + 32 * k];
+                ql[i] = (q(0) & 0xF) | ((q(2) & 0xF) << 4);
+                ql[i + 32] = (q(1) & 0xF) | ((q(3) & 0xF) << 4);
+                qh[i] = (q(0) >> 4) | ((q(1) >> 4) << 2) | ((q(2) >> 4) << 4) | ((q(3) >> 4) << 6);
+            }
+        }
+        let block = BlockQ6K { ql, qh, scales, d };
+        for (i, y) in dequantize(block).into_iter().enumerate() {
+            let expected = d.to_f32() * scales[i / 16] as f32 * (l[i] as i8 - 32) as f32;
+            assert_eq!(y, expected, "index {}", i);
+        }
+    }
+}
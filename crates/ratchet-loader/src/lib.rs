@@ -1,6 +1,7 @@
 mod error;
 pub mod gguf;
 mod k_quants;
+pub mod safetensors;
 
 pub const STORAGE_BUFFER_ALIGN: usize = 256;
 
@@ -29,6 +30,7 @@ pub enum LoadError {
 pub enum GgmlDType {
     F32,
     F16,
+    BF16,
     Q4_0,
     Q4_1,
     Q5_0,
@@ -43,13 +45,15 @@ pub enum GgmlDType {
     Q8K,
 }
 
+/// The dtype of a tensor of this type, once loaded onto the CPU.
+///
+/// Quantized types are dequantized to F32 on the CPU, see [gguf::dtype::Dequantized].
 impl From<GgmlDType> for ratchet::DType {
     fn from(val: GgmlDType) -> Self {
         match val {
-            GgmlDType::F32 => ratchet::DType::F32,
             GgmlDType::F16 => ratchet::DType::F16,
-            //TODO: disambiguate F and H variants
-            _ => unimplemented!(),
+            GgmlDType::BF16 => ratchet::DType::BF16,
+            _ => ratchet::DType::F32,
         }
     }
 }
@@ -73,6 +77,7 @@ impl TryFrom<u32> for GgmlDType {
             13 => Self::Q5K,
             14 => Self::Q6K,
             15 => Self::Q8K,
+            30 => Self::BF16,
             _ => return Err(LoadError::InvalidDType(u)),
         };
         Ok(dtype)
@@ -96,6 +101,7 @@ impl GgmlDType {
             Self::Q5K => 13,
             Self::Q6K => 14,
             Self::Q8K => 15,
+            Self::BF16 => 30,
         }
     }
 
@@ -105,6 +111,7 @@ impl GgmlDType {
         match self {
             Self::F32 => 4,
             Self::F16 => 2,
+            Self::BF16 => 2,
             Self::Q4_0 => std::mem::size_of::<BlockQ4_0>(),
             Self::Q4_1 => std::mem::size_of::<BlockQ4_1>(),
             Self::Q5_0 => std::mem::size_of::<BlockQ5_0>(),
@@ -126,6 +133,7 @@ impl GgmlDType {
         match self {
             Self::F32 => 1,
             Self::F16 => 1,
+            Self::BF16 => 1,
             Self::Q4_0 => k_quants::QK4_0,
             Self::Q4_1 => k_quants::QK4_1,
             Self::Q5_0 => k_quants::QK5_0,
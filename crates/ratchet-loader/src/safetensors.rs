@@ -0,0 +1,225 @@
+//! Support for the safetensors file format, used by most HF checkpoints.
+//! Spec: https://github.com/huggingface/safetensors#format
+
+use crate::{error::Result, gguf::gguf::ratchet_from_gguf, GgmlDType};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use ratchet::{DType, Device, Shape, Tensor};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Headers larger than this are rejected before allocation, matching the reference implementation.
+const MAX_HEADER_SIZE: u64 = 100_000_000;
+const METADATA_KEY: &str = "__metadata__";
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum Dtype {
+    BOOL,
+    U8,
+    I8,
+    F8_E5M2,
+    F8_E4M3,
+    I16,
+    U16,
+    F16,
+    BF16,
+    I32,
+    U32,
+    F32,
+    F64,
+    I64,
+    U64,
+}
+
+impl Dtype {
+    pub fn size_of(&self) -> usize {
+        match self {
+            Dtype::BOOL | Dtype::U8 | Dtype::I8 | Dtype::F8_E5M2 | Dtype::F8_E4M3 => 1,
+            Dtype::I16 | Dtype::U16 | Dtype::F16 | Dtype::BF16 => 2,
+            Dtype::I32 | Dtype::U32 | Dtype::F32 => 4,
+            Dtype::F64 | Dtype::I64 | Dtype::U64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RawTensorInfo {
+    dtype: Dtype,
+    shape: Vec<usize>,
+    data_offsets: (u64, u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct TensorInfo {
+    pub dtype: Dtype,
+    pub shape: Shape,
+    /// Byte range of the tensor, relative to the start of the data section.
+    pub data_offsets: Range<u64>,
+}
+
+impl TensorInfo {
+    pub fn read<R: std::io::Seek + std::io::Read>(
+        &self,
+        reader: &mut R,
+        tensor_data_offset: u64,
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        let mut raw_data = vec![0u8; self.size_in_bytes()];
+        reader.seek(std::io::SeekFrom::Start(
+            tensor_data_offset + self.data_offsets.start,
+        ))?;
+        reader.read_exact(&mut raw_data)?;
+
+        let shape = self.shape.clone();
+        match self.dtype {
+            Dtype::F32 => ratchet_from_gguf(GgmlDType::F32, &raw_data, shape, device),
+            Dtype::F16 => ratchet_from_gguf(GgmlDType::F16, &raw_data, shape, device),
+            Dtype::BF16 => ratchet_from_gguf(GgmlDType::BF16, &raw_data, shape, device),
+            Dtype::I32 => Tensor::from_bytes(&raw_data, DType::I32, shape, device.clone()),
+            Dtype::U32 => Tensor::from_bytes(&raw_data, DType::U32, shape, device.clone()),
+            dt => anyhow::bail!("Unsupported safetensors dtype {dt:?}"),
+        }
+    }
+
+    pub fn byte_range(&self, tensor_data_offset: u64) -> Range<u64> {
+        tensor_data_offset + self.data_offsets.start..tensor_data_offset + self.data_offsets.end
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.shape.numel() * self.dtype.size_of()
+    }
+}
+
+/// # Header
+///
+/// The parsed JSON header of a safetensors file. Tensors are read lazily with [Header::tensor].
+#[derive(Debug)]
+pub struct Header {
+    pub metadata: HashMap<String, String>,
+    pub tensor_infos: HashMap<String, TensorInfo>,
+    pub tensor_data_offset: u64,
+}
+
+impl Header {
+    pub fn read<R: std::io::Seek + std::io::Read>(reader: &mut R) -> Result<Self> {
+        let header_size = reader.read_u64::<LittleEndian>()?;
+        if header_size > MAX_HEADER_SIZE {
+            crate::bail!("safetensors header too large: {header_size} bytes")
+        }
+        let mut raw_header = vec![0u8; header_size as usize];
+        reader.read_exact(&mut raw_header)?;
+
+        let entries: HashMap<String, serde_json::Value> =
+            serde_json::from_slice(&raw_header).map_err(crate::error::Error::wrap)?;
+
+        let mut metadata = HashMap::new();
+        let mut tensor_infos = HashMap::new();
+        for (name, entry) in entries {
+            if name == METADATA_KEY {
+                metadata = serde_json::from_value(entry).map_err(crate::error::Error::wrap)?;
+                continue;
+            }
+            let RawTensorInfo {
+                dtype,
+                shape,
+                data_offsets: (start, end),
+            } = serde_json::from_value(entry).map_err(crate::error::Error::wrap)?;
+
+            let shape = Shape::from(shape);
+            let expected = (shape.numel() * dtype.size_of()) as u64;
+            if end < start || end - start != expected {
+                crate::bail!(
+                    "tensor {name} has data offsets {start}..{end}, expected {expected} bytes"
+                )
+            }
+            tensor_infos.insert(
+                name,
+                TensorInfo {
+                    dtype,
+                    shape,
+                    data_offsets: start..end,
+                },
+            );
+        }
+
+        Ok(Self {
+            metadata,
+            tensor_infos,
+            tensor_data_offset: 8 + header_size,
+        })
+    }
+
+    /// # Tensor
+    /// Load the safetensors tensor from the reader into memory.
+    ///
+    /// Takes the same arguments as [crate::gguf::gguf::Header::tensor], so a `load_inner`
+    /// closure only needs to map the HF tensor names.
+    pub fn tensor<R: std::io::Seek + std::io::Read>(
+        &self,
+        reader: &mut R,
+        name: &str,
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        let tensor_info = match self.tensor_infos.get(name) {
+            Some(tensor_info) => tensor_info,
+            None => anyhow::bail!("cannot find tensor info for {name}"),
+        };
+        log::info!("Loading tensor {tensor_info:#?}");
+        tensor_info.read(reader, self.tensor_data_offset, device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use half::bf16;
+    use ratchet::{shape, Device, Tensor};
+
+    use super::{Dtype, Header};
+
+    fn safetensors_file(header: &str, data: &[u8]) -> Cursor<Vec<u8>> {
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(data);
+        Cursor::new(bytes)
+    }
+
+    #[test]
+    fn reads_tensors_lazily() -> anyhow::Result<()> {
+        let weight = [1f32, 2., 3., 4., 5., 6.];
+        let bias = [bf16::from_f32(0.5), bf16::from_f32(-2.)];
+        let mut data = bytemuck::cast_slice::<f32, u8>(&weight).to_vec();
+        data.extend_from_slice(bytemuck::cast_slice::<bf16, u8>(&bias));
+
+        let header = r#"{
+            "__metadata__": {"format": "pt"},
+            "linear.weight": {"dtype": "F32", "shape": [2, 3], "data_offsets": [0, 24]},
+            "linear.bias": {"dtype": "BF16", "shape": [2], "data_offsets": [24, 28]}
+        }"#;
+        let mut reader = safetensors_file(header, &data);
+        let header = Header::read(&mut reader)?;
+        assert_eq!(header.metadata["format"], "pt");
+        assert_eq!(header.tensor_infos["linear.bias"].dtype, Dtype::BF16);
+
+        let mut lt = |name: &str| {
+            let key = format!("linear.{}", name);
+            header.tensor(&mut reader, &key, &Device::CPU)
+        };
+        let read_bias = lt("bias")?;
+        let read_weight = lt("weight")?;
+
+        let expected = Tensor::from_data(weight, shape![2, 3], Device::CPU);
+        read_weight.all_close(&expected, 1e-6, 1e-6)?;
+        assert_eq!(read_bias.to_vec::<bf16>()?, bias.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_mismatched_offsets() {
+        let header = r#"{"w": {"dtype": "F16", "shape": [4], "data_offsets": [0, 4]}}"#;
+        let mut reader = safetensors_file(header, &[0u8; 4]);
+        assert!(Header::read(&mut reader).is_err());
+    }
+}
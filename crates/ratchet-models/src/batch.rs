@@ -0,0 +1,127 @@
+use ratchet::{shape, Device, Tensor};
+
+/// A batch of sequences generated together, each with its own prompt length and stop condition.
+///
+/// Prompts are left-padded to a common length, such that every row writes the KV cache at the
+/// same offset. The padding is masked, and shifts the RoPE positions of each row, once passed to
+/// [ratchet_nn::KVCache::set_padding].
+#[derive(Debug)]
+pub struct Batch {
+    prompts: Vec<Vec<i32>>,
+    generated: Vec<Vec<i32>>,
+    finished: Vec<bool>,
+    stop_tokens: Vec<i32>,
+    max_len: usize,
+}
+
+impl Batch {
+    /// Rows finish on any of `stop_tokens`, or once the batch spans `max_len` positions.
+    pub fn new(prompts: Vec<Vec<i32>>, stop_tokens: Vec<i32>, max_len: usize) -> Self {
+        assert!(prompts.iter().all(|p| !p.is_empty()));
+        let size = prompts.len();
+        Self {
+            prompts,
+            generated: vec![vec![]; size],
+            finished: vec![false; size],
+            stop_tokens,
+            max_len,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.prompts.len()
+    }
+
+    fn prompt_len(&self) -> usize {
+        self.prompts.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    /// The number of padding positions at the start of each row.
+    pub fn padding(&self) -> Vec<usize> {
+        let len = self.prompt_len();
+        self.prompts.iter().map(|p| len - p.len()).collect()
+    }
+
+    /// The left-padded prompts, as `[B, L]` token ids.
+    pub fn prompt_tokens(&self, pad_token: i32, device: &Device) -> Tensor {
+        let tokens = self
+            .prompts
+            .iter()
+            .zip(self.padding())
+            .flat_map(|(p, pad)| std::iter::repeat(pad_token).take(pad).chain(p.clone()))
+            .collect::<Vec<_>>();
+        Tensor::from_data(
+            tokens,
+            shape![self.size(), self.prompt_len()],
+            device.clone(),
+        )
+    }
+
+    /// The `[B, 1]` token ids of the next step, the last token of each row.
+    /// Finished rows keep decoding their last token, and their outputs are ignored.
+    pub fn next_tokens(&self, device: &Device) -> Tensor {
+        let tokens = self
+            .prompts
+            .iter()
+            .zip(&self.generated)
+            .map(|(p, g)| *g.last().or(p.last()).unwrap())
+            .collect::<Vec<_>>();
+        Tensor::from_data(tokens, shape![self.size(), 1], device.clone())
+    }
+
+    /// Records the next token of each row, returning the rows that were still generating.
+    pub fn push(&mut self, next: &[i32]) -> Vec<usize> {
+        assert_eq!(next.len(), self.size());
+        let out_of_space = self.prompt_len() + self.generated[0].len() + 1 >= self.max_len;
+        let mut rows = vec![];
+        for (row, &token) in next.iter().enumerate() {
+            self.generated[row].push(token);
+            if self.finished[row] {
+                continue;
+            }
+            self.finished[row] = out_of_space || self.stop_tokens.contains(&token);
+            rows.push(row);
+        }
+        rows
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.iter().all(|&f| f)
+    }
+
+    /// The tokens generated by each row, up to and including its stop token.
+    pub fn into_generated(self) -> Vec<Vec<i32>> {
+        let stop_tokens = self.stop_tokens;
+        self.generated
+            .into_iter()
+            .map(|mut g| {
+                if let Some(end) = g.iter().position(|t| stop_tokens.contains(t)) {
+                    g.truncate(end + 1);
+                }
+                g
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Batch;
+    use ratchet::Device;
+
+    #[test]
+    fn batch_rows_stop_independently() -> anyhow::Result<()> {
+        let mut batch = Batch::new(vec![vec![1, 2, 3], vec![4]], vec![0], 16);
+        assert_eq!(batch.padding(), vec![0, 2]);
+        let prompt = batch.prompt_tokens(9, &Device::CPU);
+        assert_eq!(prompt.to_vec::<i32>()?, vec![1, 2, 3, 9, 9, 4]);
+
+        assert_eq!(batch.push(&[5, 0]), vec![0, 1]);
+        assert!(!batch.is_finished());
+        assert_eq!(batch.next_tokens(&Device::CPU).to_vec::<i32>()?, vec![5, 0]);
+        assert_eq!(batch.push(&[0, 7]), vec![0]);
+        assert!(batch.is_finished());
+        assert_eq!(batch.into_generated(), vec![vec![5, 0], vec![0]]);
+        Ok(())
+    }
+}
@@ -1,10 +1,12 @@
 #![allow(clippy::upper_case_acronyms)]
+mod batch;
 pub mod moondream;
 pub mod phi2;
 pub mod phi3;
 pub mod registry;
 mod token_stream;
 pub mod whisper;
+pub use batch::Batch;
 pub use token_stream::TokenOutputStream;
 
 #[cfg(target_arch = "wasm32")]
@@ -1,30 +1,15 @@
 use super::model::Moondream;
 use crate::TokenOutputStream;
-use ndarray::Axis;
-use ndarray_stats::QuantileExt;
 use ratchet::shape;
 use ratchet::Device;
+use ratchet::SampleStrategy;
 use ratchet::Tensor;
 use ratchet_nn::Module;
 use tokenizers::Tokenizer;
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn generate(
-    model: &mut Moondream,
-    image_bytes: &[u8],
-    question: String,
-    tokenizer: Tokenizer,
-    callback: impl Fn(String),
-) -> anyhow::Result<()> {
-    use ratchet::rvec;
-    use web_time::Instant;
+fn embed_image(model: &Moondream, image_bytes: &[u8]) -> anyhow::Result<Tensor> {
     let device = model.text_model.device.clone();
-
-    let prompt = format!("\n\nQuestion: {}\n\nAnswer:", question);
-    log::warn!("Prompt: {}", prompt);
-
-    let mut tos = TokenOutputStream::new(tokenizer);
-
     let img = image::io::Reader::new(std::io::Cursor::new(image_bytes))
         .with_guessed_format()?
         .decode()
@@ -43,7 +28,27 @@ pub fn generate(
         .view(shape![1, 3, 378, 378])?
         .cast(device.compute_precision())?;
 
-    let img_embed = model.vision_encoder.schedule(img_tensor)?.resolve()?;
+    model.vision_encoder.schedule(img_tensor)?.resolve()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn generate(
+    model: &mut Moondream,
+    image_bytes: &[u8],
+    question: String,
+    tokenizer: Tokenizer,
+    callback: impl Fn(String),
+) -> anyhow::Result<()> {
+    use ratchet::rvec;
+    use web_time::Instant;
+    let device = model.text_model.device.clone();
+
+    let prompt = format!("\n\nQuestion: {}\n\nAnswer:", question);
+    log::warn!("Prompt: {}", prompt);
+
+    let mut tos = TokenOutputStream::new(tokenizer);
+
+    let img_embed = embed_image(model, image_bytes)?;
 
     let bos_token = model
         .text_model
@@ -79,18 +84,12 @@ pub fn generate(
         let result = model
             .text_model
             .schedule(embeds.clone())?
-            .full()?
+            .sample(SampleStrategy::Greedy, 0)?
             .resolve()?;
 
         model.text_model.cache_mut().update(embeds.shape()[1]);
 
-        let logits = result.to(&Device::CPU).unwrap();
-        let next_tokens = logits
-            .to_ndarray_view::<f32>()
-            .map_axis(Axis(2), |row| row.argmax_skipnan().unwrap())
-            .iter()
-            .map(|&x| x as i32)
-            .collect::<Vec<_>>();
+        let next_tokens = result.to(&Device::CPU)?.to_vec::<i32>()?;
         tokens = next_tokens.clone();
         generated_tokens.extend(next_tokens.clone());
         all_tokens.extend(next_tokens.clone());
@@ -107,6 +106,96 @@ pub fn generate(
     Ok(())
 }
 
+/// Answers each of `questions` about the same image as one batch, calling `callback` with the
+/// index of the question and its next piece of text. Each answer stops independently.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn generate_batch(
+    model: &mut Moondream,
+    image_bytes: &[u8],
+    questions: Vec<String>,
+    tokenizer: Tokenizer,
+    callback: impl Fn(usize, String),
+) -> anyhow::Result<()> {
+    use crate::Batch;
+    use ratchet::rvec;
+    let device = model.text_model.device.clone();
+
+    let img_embed = embed_image(model, image_bytes)?;
+    let bos_token = model
+        .text_model
+        .embedding
+        .schedule(Tensor::from_data([50256], shape![1], device.clone()))?
+        .view(shape![1, 1, 2048])?;
+
+    let prompts = questions
+        .into_iter()
+        .map(|question| {
+            let prompt = format!("\n\nQuestion: {}\n\nAnswer:", question);
+            let encoding = tokenizer.encode(prompt, false).unwrap();
+            encoding
+                .get_ids()
+                .iter()
+                .map(|&x| x as i32)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut streams = prompts
+        .iter()
+        .map(|_| TokenOutputStream::new(tokenizer.clone()))
+        .collect::<Vec<_>>();
+
+    //The batch only counts text tokens, which follow the BOS token & the image in the cache
+    let max_len = 2048 - 1 - img_embed.shape()[1];
+    let mut batch = Batch::new(prompts.clone(), vec![50256], max_len);
+    let padding = batch.padding();
+    model
+        .text_model
+        .cache_mut()
+        .set_padding(&padding, &device)?;
+
+    //The image precedes every question, so the padding must precede the image
+    let embed = |tokens: Vec<i32>| {
+        let len = tokens.len();
+        let input = Tensor::from_data(tokens, shape![1, len], device.clone());
+        model.text_model.embedding.schedule(input)
+    };
+    let rows = prompts
+        .into_iter()
+        .zip(padding)
+        .map(|(tokens, pad)| {
+            let mut row = rvec![bos_token.clone(), img_embed.clone(), embed(tokens)?];
+            if pad > 0 {
+                row.insert(0, embed(vec![50256; pad])?);
+            }
+            Tensor::cat(row, 1)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut embeds = Tensor::cat(rows.into(), 0)?;
+    while !batch.is_finished() {
+        let seq_len = embeds.shape()[1];
+        let result = model
+            .text_model
+            .schedule(embeds)?
+            .sample(SampleStrategy::Greedy, 0)?
+            .resolve()?;
+        model.text_model.cache_mut().update(seq_len);
+
+        let tokens = result.to(&Device::CPU)?.to_vec::<i32>()?;
+        for row in batch.push(&tokens) {
+            if let Some(t) = streams[row].next_token(tokens[row] as u32)? {
+                callback(row, t);
+            }
+        }
+        embeds = model
+            .text_model
+            .embedding
+            .schedule(batch.next_tokens(&device))?;
+    }
+    model.text_model.cache_mut().set_padding(&[0], &device)?;
+    Ok(())
+}
+
 #[cfg(target_arch = "wasm32")]
 pub async fn generate(
     model: &mut Moondream,
@@ -177,18 +266,12 @@ pub async fn generate(
         let result = model
             .text_model
             .schedule(embeds.clone())?
-            .full()?
+            .sample(SampleStrategy::Greedy, 0)?
             .resolve()?;
 
         model.text_model.cache_mut().update(embeds.shape()[1]);
 
-        let logits = result.to(&Device::CPU).await.unwrap();
-        let next_tokens = logits
-            .to_ndarray_view::<f32>()
-            .map_axis(Axis(2), |row| row.argmax_skipnan().unwrap())
-            .iter()
-            .map(|&x| x as i32)
-            .collect::<Vec<_>>();
+        let next_tokens = result.to(&Device::CPU).await?.to_vec::<i32>()?;
         tokens = next_tokens.clone();
         generated_tokens.extend(next_tokens.clone());
         all_tokens.extend(next_tokens.clone());
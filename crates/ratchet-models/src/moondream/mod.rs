@@ -5,4 +5,6 @@ mod text_model;
 mod vision_encoder;
 
 pub use generate::generate;
+#[cfg(not(target_arch = "wasm32"))]
+pub use generate::generate_batch;
 pub use model::Moondream;
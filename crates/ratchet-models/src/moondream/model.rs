@@ -51,14 +51,11 @@ impl Moondream {
         F: FnMut(&str) -> Tensor,
     {
         let n_layers = 24_i32;
-        let dim = 2048_f32;
         let n_heads = 32_u32;
         let n_kv_heads = 32_u32;
         let rope_base = 10000.0f32;
         let rope_dim = 32_u32;
         let ln_eps = 1e-05;
-        let hdim = dim / n_heads as f32;
-        let softmax_scale = Tensor::from_data([1.0 / hdim.sqrt()], shape![1], device.clone());
         let cache_shape = shape![1, 32, 4096, 64];
 
         let kv_cache = match device.compute_precision() {
@@ -97,7 +94,6 @@ impl Moondream {
                             ),
                             RotaryEmbedding::new(rope_dim as usize, false, rope_base, 1.0),
                             n_heads,
-                            softmax_scale.clone(),
                             n_kv_heads,
                         ),
                         MLP::new(
@@ -149,9 +145,6 @@ impl Moondream {
 
                     let n_heads = 16;
                     let dim = 1152;
-                    let h_dim = dim / n_heads;
-                    let scale_factor =
-                        Tensor::from_data([1.0 / (h_dim as f32).sqrt()], shape![1], device.clone());
 
                     VitBlock::new(
                         1152,
@@ -163,7 +156,6 @@ impl Moondream {
                                 lt(&format!("vision_encoder.encoder.model.visual.blocks.{}.attn.proj.weight", layer)),
                                 Some(lt(&format!("vision_encoder.encoder.model.visual.blocks.{}.attn.proj.bias", layer))),
                             ),
-                            scale_factor,
                         ),
                         MLP::new(
                             Linear::new(
@@ -1,4 +1,4 @@
-use ratchet::{rvec, shape, Device, Tensor};
+use ratchet::{rvec, shape, Device, RollingWindow, Tensor};
 use ratchet_nn::{
     Embedding, KVCache, KVEntry, LayerNorm, Linear, Module, RotaryEmbedding, RotaryInput,
 };
@@ -11,13 +11,13 @@ pub struct SelfAttention {
     o: Linear,
     rope: RotaryEmbedding,
     n_heads: u32,
-    softmax_scale: Tensor,
     n_kv_heads: u32,
 }
 
 pub struct AttnInput {
     pub input: Tensor,
     pub mask: Option<Tensor>,
+    pub causal: bool,
     pub kv_cache: Option<KVEntry>,
 }
 
@@ -28,6 +28,7 @@ impl Module for SelfAttention {
         let AttnInput {
             input,
             mask,
+            causal,
             kv_cache,
         } = input;
         let [batch_size, q_len, n_state]: [usize; 3] = input.shape().try_into()?;
@@ -58,12 +59,14 @@ impl Module for SelfAttention {
         let value_states = value_states.view(kv_shape)?.permute(&[0, 2, 1, 3])?;
 
         let offset = kv_cache.as_ref().map(|kv| kv.entries).unwrap_or(0);
+        let row_offsets = kv_cache.as_ref().and_then(|kv| kv.row_offsets());
         let q_dt = query_states.dt();
         let query_states = self
             .rope
             .schedule(RotaryInput {
                 input: query_states.full()?,
                 offset,
+                row_offsets: row_offsets.clone(),
             })?
             .cast(q_dt)?;
         let key_states = self
@@ -71,31 +74,18 @@ impl Module for SelfAttention {
             .schedule(RotaryInput {
                 input: key_states.full()?,
                 offset,
+                row_offsets,
             })?
             .cast(q_dt)?;
 
         let (key_states, value_states) = if let Some(kv) = kv_cache {
-            let k_cache = kv.k_cache.cache(key_states, 2, offset)?;
-            let v_cache = kv.v_cache.cache(value_states, 2, offset)?;
-            (k_cache, v_cache)
+            kv.cache(key_states, value_states, 2)?
         } else {
             (key_states, value_states)
         };
 
-        let mut attn_weights = query_states
-            .full()?
-            .matmul(key_states.full()?, false, true)?
-            .mul(self.softmax_scale.clone())?
-            .cast(q_dt)?;
-
-        if let Some(m) = mask {
-            let attn_dt = attn_weights.dt();
-            attn_weights = attn_weights.add(m.cast(attn_dt)?)?;
-        }
-
-        let w = attn_weights.full()?.softmax(3)?.cast(value_states.dt())?;
-        let wv = w
-            .matmul(value_states, false, false)?
+        let wv = query_states
+            .sdpa(key_states, value_states, mask, causal, None)?
             .permute(&[0, 2, 1, 3])?;
         let wv = wv.view(shape![batch_size as _, q_len, n_state])?;
         self.o.schedule(wv)
@@ -113,6 +103,7 @@ pub struct DecoderLayer {
 pub struct DecoderLayerInput {
     pub x: Tensor,
     pub mask: Option<Tensor>,
+    pub causal: bool,
     pub kv_cache: Option<KVEntry>,
 }
 
@@ -120,12 +111,18 @@ impl Module for DecoderLayer {
     type Input = DecoderLayerInput;
 
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
-        let DecoderLayerInput { x, mask, kv_cache } = input;
+        let DecoderLayerInput {
+            x,
+            mask,
+            causal,
+            kv_cache,
+        } = input;
         let residual = x.clone();
         let xs = self.ln.schedule(x)?;
         let attn_output = self.self_attn.schedule(AttnInput {
             input: xs.clone(),
             mask,
+            causal,
             kv_cache,
         })?;
         let ff_hs = self.mlp.schedule(xs)?;
@@ -148,45 +145,40 @@ impl Module for TextModel {
 
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
         let mut x = input.clone();
-        let [_, seq_len, n_state]: [usize; 3] = x.shape().try_into()?;
-        let mask = if seq_len <= 1 {
-            None
-        } else {
-            Some(Self::generate_mask(seq_len, x.device())?)
-        };
+        let [batch_size, seq_len, n_state]: [usize; 3] = x.shape().try_into()?;
+        let (mask, causal) = self.kv_cache.sdpa_mask(seq_len, x.dt(), x.device())?;
 
         for (i, layer) in self.layers.iter().enumerate() {
             let input = DecoderLayerInput {
                 x,
                 mask: mask.clone(),
+                causal,
                 kv_cache: Some(self.kv_cache[i].clone()),
             };
             x = layer.schedule(input)?;
         }
         x = self.ln_post.schedule(x)?;
-        x = x.slice(&[0..1, seq_len - 1..seq_len, 0..n_state])?;
+        x = x.slice(&[0..batch_size, seq_len - 1..seq_len, 0..n_state])?;
         let logits = self.lm_head.schedule(x)?;
         Ok(logits)
     }
 }
 
 impl TextModel {
-    pub fn generate_mask(seq_len: usize, device: &Device) -> anyhow::Result<Tensor> {
-        let mask: Vec<_> = (0..seq_len)
-            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
-            .collect();
-
-        Ok(Tensor::from_data(
-            mask,
-            shape![seq_len, seq_len],
-            device.clone(),
-        ))
-    }
-
     pub fn cache_mut(&mut self) -> &mut KVCache {
         &mut self.kv_cache
     }
     pub fn reset(&mut self) {
         self.kv_cache.reset();
     }
+
+    /// Makes the KV cache a rolling window, see [KVCache::set_window].
+    /// With a window, generation is no longer bounded by the size of the cache.
+    pub fn set_window(&mut self, window: Option<RollingWindow>) {
+        self.kv_cache.set_window(window);
+    }
+
+    pub fn window(&self) -> Option<RollingWindow> {
+        self.kv_cache.window()
+    }
 }
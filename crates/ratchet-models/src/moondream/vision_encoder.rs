@@ -9,7 +9,6 @@ pub struct Attention {
     dim: usize,
     qkv: Linear,
     proj: Linear,
-    scale_factor: Tensor,
 }
 
 impl Module for Attention {
@@ -52,14 +51,10 @@ impl Module for Attention {
             .slice(&[2..3, 0..(b * self.n_heads * n * h_dim)])?
             .view(shape![b, self.n_heads, n, h_dim])?;
 
-        // scaled dot-product attention
-        let mut attn_weights = q
-            .full()?
-            .matmul(k.permute(&[0, 1, 3, 2])?.full()?, false, false)?
-            .mul(self.scale_factor.clone())?;
-        attn_weights = attn_weights.softmax(3)?.cast(v.dt())?;
-        let mut x = attn_weights.matmul(v, false, false)?;
-        x = x.permute(&[0, 2, 1, 3])?.view(shape![b, n, c])?;
+        let x = q
+            .sdpa(k, v, None, false, None)?
+            .permute(&[0, 2, 1, 3])?
+            .view(shape![b, n, c])?;
         self.proj.schedule(x)
     }
 }
@@ -93,29 +88,21 @@ impl Module for LinearPatchEmbedding {
     type Input = Tensor;
 
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
-        let [b, c, hp1, wp2]: [usize; 4] = input.shape().try_into()?;
+        let [b, c, _, _]: [usize; 4] = input.shape().try_into()?;
         let (p1, p2) = (14, 14);
-        let (h, w) = (hp1 / p1, wp2 / p2);
-        // step 1 - 0, 1, 2, 3, 4, 5
-        // step 2 - 0, 2, 1, 3, 4, 5
-        // step 3 - 0, 2, 1, 4, 3, 5
-        // step 4 - 0, 2, 4, 1, 3, 5
-
-        // b, c, h, p1, w, p2
-        let mut x = input
-            .view(shape![b, c, h, p1 * w * p2])?
-            .permute(&[0, 2, 1, 3])?;
-        // b, h, c, p1, w, p2
-        x = x
-            .view(shape![b * h * c, p1, w, p2])?
-            .permute(&[0, 2, 1, 3])?;
-        // b, h, c, w, p1, p2
-        x = x
-            .view(shape![b * h, c, w, p1 * p2])?
-            .permute(&[0, 2, 1, 3])?;
-        // b, h, w, c, p1, p2
-        x = x.view(shape![b, h * w, c * p1 * p2])?;
-        self.linear.schedule(x)
+        // The linear layer over each flattened (c, p1, p2) patch is a convolution with a stride
+        // of the patch size
+        let d_model = self.linear.w.shape()[0];
+        let weight = self.linear.w.clone().view(shape![d_model, c, p1, p2])?;
+        let bias = self
+            .linear
+            .b
+            .clone()
+            .map(|b| b.cast(input.dt()))
+            .transpose()?;
+        let x = input.conv2d(weight, bias, [p1, p2], [0, 0], [1, 1], 1)?;
+        let [_, _, h, w]: [usize; 4] = x.shape().try_into()?;
+        x.view(shape![b, d_model, h * w])?.permute(&[0, 2, 1])
     }
 }
 
@@ -16,7 +16,6 @@ pub struct PhiSelfAttention {
     o: Linear,
     rope: RotaryEmbedding,
     n_heads: u32,
-    softmax_scale: Tensor,
     n_kv_heads: u32,
 }
 
@@ -31,7 +30,7 @@ impl PhiSelfAttention {
             let key = format!("blk.{}.{}", layer_index, name);
             disk_model.tensor(reader, &key, device)
         };
-        Self::load_inner(disk_model, lt, device)
+        Self::load_inner(disk_model, lt)
     }
 
     #[cfg(target_arch = "wasm32")]
@@ -48,10 +47,10 @@ impl PhiSelfAttention {
                 .ok_or_else(|| anyhow::anyhow!("missing tensor"))?;
             ratchet_from_gguf_web(tensor, device)
         };
-        Self::load_inner(header, lt, device)
+        Self::load_inner(header, lt)
     }
 
-    fn load_inner<F>(header: &Header, mut lt: F, device: &Device) -> anyhow::Result<Self>
+    fn load_inner<F>(header: &Header, mut lt: F) -> anyhow::Result<Self>
     where
         F: FnMut(&str) -> anyhow::Result<Tensor>,
     {
@@ -71,8 +70,6 @@ impl PhiSelfAttention {
             .unwrap()
             .to_u32()?;
 
-        let scale_val = 1.0 / 80_f32.sqrt();
-        let softmax_scale = Tensor::from_data([scale_val], shape![1], device.clone());
         //TODO: hardcoded for Phi2, should read from meta
         let base = 10000.0;
         let dim = (0.4 * (2560f64 / 32f64)) as usize;
@@ -84,7 +81,6 @@ impl PhiSelfAttention {
             o,
             rope,
             n_heads,
-            softmax_scale,
             n_kv_heads,
         })
     }
@@ -93,6 +89,7 @@ impl PhiSelfAttention {
 pub struct PhiAttnInput {
     pub input: Tensor,
     pub mask: Option<Tensor>,
+    pub causal: bool,
     pub cache: Option<KVEntry>,
 }
 
@@ -100,7 +97,12 @@ impl Module for PhiSelfAttention {
     type Input = PhiAttnInput;
 
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
-        let PhiAttnInput { input, mask, cache } = input;
+        let PhiAttnInput {
+            input,
+            mask,
+            causal,
+            cache,
+        } = input;
         let [batch_size, seq_len, n_state]: [usize; 3] = input.shape().try_into()?;
         let q = self.q.schedule(input.clone())?;
         let k = self.k.schedule(input.clone())?;
@@ -118,36 +120,26 @@ impl Module for PhiSelfAttention {
         let value_states = v.view(kv_shape)?.permute(&[0, 2, 1, 3])?;
 
         let offset = cache.as_ref().map(|kv| kv.entries).unwrap_or(0);
+        let row_offsets = cache.as_ref().and_then(|kv| kv.row_offsets());
         let query_states = self.rope.schedule(RotaryInput {
             input: query_states,
             offset,
+            row_offsets: row_offsets.clone(),
         })?;
         let key_states = self.rope.schedule(RotaryInput {
             input: key_states,
             offset,
+            row_offsets,
         })?;
 
         let (key_states, value_states) = if let Some(kv) = cache {
-            let k_cache = kv.k_cache.cache(key_states, 2, offset)?;
-            let v_cache = kv.v_cache.cache(value_states, 2, offset)?;
-            (k_cache, v_cache)
+            kv.cache(key_states, value_states, 2)?
         } else {
             (key_states, value_states)
         };
 
-        //TODO: can we just use the built in transposed matmul?
-        let mut attn_weights = query_states
-            .full()?
-            .matmul(key_states.permute(&[0, 1, 3, 2])?.full()?, false, false)?
-            .mul(self.softmax_scale.clone())?;
-
-        if let Some(m) = mask {
-            attn_weights = attn_weights.add(m)?;
-        }
-
-        let w = attn_weights.softmax(3)?.cast(value_states.dt())?;
-        let wv = w
-            .matmul(value_states, false, false)?
+        let wv = query_states
+            .sdpa(key_states, value_states, mask, causal, None)?
             .permute(&[0, 2, 1, 3])?;
         let wv = wv.view(shape![batch_size as _, seq_len, n_state])?;
         self.o.schedule(wv)
@@ -1,18 +1,18 @@
-#![cfg(target_arch = "wasm32")]
 use crate::phi2::Phi2;
 use crate::TokenOutputStream;
-use ndarray::Axis;
-use ndarray_stats::QuantileExt;
-use ratchet::{shape, Device, Tensor};
+use ratchet::{Device, SampleStrategy};
 use ratchet_nn::Module;
 use tokenizers::Tokenizer;
 
+#[cfg(target_arch = "wasm32")]
 pub async fn generate(
     model: &mut Phi2,
     tokenizer: Tokenizer,
     prompt: String,
+    max_tokens: usize,
     callback: impl Fn(String),
 ) -> anyhow::Result<()> {
+    use ratchet::{shape, Tensor};
     use web_time::Instant;
     log::warn!("Prompt: {}", prompt);
 
@@ -26,22 +26,24 @@ pub async fn generate(
     let mut all_tokens = tokens.clone();
     let mut loop_cnt = 0;
     let start = Instant::now();
-    while tokens[tokens.len() - 1] != 50256 && loop_cnt < 256 {
+    let mut tokens = model.prefill(&tokens)?.to_vec();
+    while tokens[tokens.len() - 1] != 50256
+        && loop_cnt < max_tokens
+        && (model.window().is_some() || all_tokens.len() < 1024)
+    {
         let input = Tensor::from_data(
             tokens.clone(),
             shape![1, tokens.len()],
             model.device.clone(),
         );
-        let result = model.schedule(input)?.resolve()?;
-        let logits = result.to(&Device::CPU).await?;
+        let result = model
+            .schedule(input)?
+            .sample(SampleStrategy::Greedy, 0)?
+            .resolve()?;
+        let next_tokens = result.to(&Device::CPU).await?;
         model.cache_mut().update(tokens.len());
 
-        tokens = logits
-            .to_ndarray_view::<f32>()
-            .map_axis(Axis(2), |row| row.argmax_skipnan().unwrap())
-            .iter()
-            .map(|&x| x as i32)
-            .collect::<Vec<_>>();
+        tokens = next_tokens.to_vec::<i32>()?;
 
         if let Some(t) = tos.next_token(tokens[0] as u32)? {
             callback(t);
@@ -55,3 +57,56 @@ pub async fn generate(
     model.reset();
     Ok(())
 }
+
+/// Generates a completion for each of `prompts` as one batch, calling `callback` with the index
+/// of the prompt and its next piece of text. Each prompt stops independently.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn generate_batch(
+    model: &mut Phi2,
+    tokenizer: Tokenizer,
+    prompts: Vec<String>,
+    callback: impl Fn(usize, String),
+) -> anyhow::Result<()> {
+    use crate::Batch;
+
+    let prompts = prompts
+        .into_iter()
+        .map(|prompt| {
+            let encoding = tokenizer.encode(prompt, true).unwrap();
+            encoding
+                .get_ids()
+                .iter()
+                .map(|&x| x as i32)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut streams = prompts
+        .iter()
+        .map(|_| TokenOutputStream::new(tokenizer.clone()))
+        .collect::<Vec<_>>();
+
+    let device = model.device.clone();
+    let max_len = prompts.iter().map(Vec::len).max().unwrap_or(0) + 256;
+    let mut batch = Batch::new(prompts, vec![50256], max_len);
+    model.cache_mut().set_padding(&batch.padding(), &device)?;
+
+    let mut input = batch.prompt_tokens(50256, &device);
+    while !batch.is_finished() {
+        let seq_len = input.shape()[1];
+        let result = model
+            .schedule(input)?
+            .sample(SampleStrategy::Greedy, 0)?
+            .resolve()?;
+        let tokens = result.to(&Device::CPU)?.to_vec::<i32>()?;
+        model.cache_mut().update(seq_len);
+
+        for row in batch.push(&tokens) {
+            if let Some(t) = streams[row].next_token(tokens[row] as u32)? {
+                callback(row, t);
+            }
+        }
+        input = batch.next_tokens(&device);
+    }
+    model.cache_mut().set_padding(&[0], &device)?;
+    Ok(())
+}
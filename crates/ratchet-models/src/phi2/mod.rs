@@ -7,3 +7,5 @@ pub use model::Phi2;
 
 #[cfg(target_arch = "wasm32")]
 pub use generate::generate;
+#[cfg(not(target_arch = "wasm32"))]
+pub use generate::generate_batch;
@@ -3,7 +3,7 @@ use super::{
     mlp::MLP,
 };
 use half::f16;
-use ratchet::{shape, DType, Device, Tensor};
+use ratchet::{shape, DType, Device, RollingWindow, Tensor};
 use ratchet_loader::gguf::gguf::Header;
 use ratchet_nn::{Embedding, KVCache, KVEntry, LayerNorm, Linear, Module};
 use std::io::{BufRead, Seek};
@@ -69,6 +69,7 @@ impl DecoderLayer {
 pub struct DecoderLayerInput {
     pub x: Tensor,
     pub mask: Option<Tensor>,
+    pub causal: bool,
     pub cache: Option<KVEntry>,
 }
 
@@ -76,12 +77,18 @@ impl Module for DecoderLayer {
     type Input = DecoderLayerInput;
 
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
-        let DecoderLayerInput { x, mask, cache } = input;
+        let DecoderLayerInput {
+            x,
+            mask,
+            causal,
+            cache,
+        } = input;
         let residual = x.clone();
         let xs = self.ln.schedule(x)?;
         let attn_output = self.self_attn.schedule(PhiAttnInput {
             input: xs.clone(),
             mask,
+            causal,
             cache,
         })?;
         let ff_hs = self.mlp.schedule(xs)?;
@@ -105,23 +112,20 @@ impl Module for Phi2 {
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
         let mut x = self.embedding.schedule(input)?;
 
-        let [_, seq_len, n_state]: [usize; 3] = x.shape().try_into()?;
-        let mask = if seq_len <= 1 {
-            None
-        } else {
-            Some(Self::generate_mask(seq_len, x.device())?)
-        };
+        let [batch_size, seq_len, n_state]: [usize; 3] = x.shape().try_into()?;
+        let (mask, causal) = self.kv_cache.sdpa_mask(seq_len, x.dt(), x.device())?;
 
         for (layer_idx, layer) in self.layers.iter().enumerate() {
             let input = DecoderLayerInput {
                 x,
                 mask: mask.clone(),
+                causal,
                 cache: Some(self.kv_cache[layer_idx].clone()),
             };
             x = layer.schedule(input)?;
         }
         x = self.ln_post.schedule(x)?;
-        x = x.slice(&[0..1, seq_len - 1..seq_len, 0..n_state])?;
+        x = x.slice(&[0..batch_size, seq_len - 1..seq_len, 0..n_state])?;
         let logits = self.lm_head.schedule(x)?;
         Ok(logits)
     }
@@ -220,18 +224,6 @@ impl Phi2 {
         })
     }
 
-    pub fn generate_mask(seq_len: usize, device: &Device) -> anyhow::Result<Tensor> {
-        let mask: Vec<_> = (0..seq_len)
-            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
-            .collect();
-
-        Ok(Tensor::from_data(
-            mask,
-            shape![seq_len, seq_len],
-            device.clone(),
-        ))
-    }
-
     pub fn reset(&mut self) {
         self.kv_cache.reset();
     }
@@ -239,6 +231,33 @@ impl Phi2 {
     pub fn cache_mut(&mut self) -> &mut KVCache {
         &mut self.kv_cache
     }
+
+    /// Makes the KV cache a rolling window, see [KVCache::set_window].
+    /// With a window, generation is no longer bounded by the size of the cache.
+    pub fn set_window(&mut self, window: Option<RollingWindow>) {
+        self.kv_cache.set_window(window);
+    }
+
+    pub fn window(&self) -> Option<RollingWindow> {
+        self.kv_cache.window()
+    }
+
+    /// Writes all but the last `window` tokens of the prompt into the cache, one window-sized
+    /// chunk at a time, returning the tokens left to schedule. Without a window, returns
+    /// `tokens` unchanged. See [KVCache::max_write].
+    pub fn prefill<'a>(&mut self, mut tokens: &'a [i32]) -> anyhow::Result<&'a [i32]> {
+        let Some(max_write) = self.kv_cache.max_write() else {
+            return Ok(tokens);
+        };
+        while tokens.len() > max_write {
+            let (chunk, rest) = tokens.split_at(max_write);
+            let input = Tensor::from_data(chunk, shape![1, chunk.len()], self.device.clone());
+            self.schedule(input)?.resolve()?;
+            self.kv_cache.update(chunk.len());
+            tokens = rest;
+        }
+        Ok(tokens)
+    }
 }
 
 #[cfg(all(test, not(target_arch = "wasm32"), feature = "pyo3"))]
@@ -254,6 +273,16 @@ mod tests {
     use tokenizers::Tokenizer;
 
     use super::Phi2;
+    use crate::Batch;
+
+    fn greedy(logits: &Tensor) -> Vec<i32> {
+        logits
+            .to_ndarray_view::<f32>()
+            .map_axis(Axis(2), |row| row.argmax_skipnan().unwrap())
+            .iter()
+            .map(|&x| x as i32)
+            .collect()
+    }
 
     fn ground_truth() -> anyhow::Result<Vec<Tensor>> {
         let prg = r#"
@@ -335,4 +364,75 @@ def ground():
         assert!(all_equal);
         Ok(())
     }
+
+    #[test]
+    #[cfg_attr(feature = "ci", ignore)]
+    fn batch_matches_single() -> anyhow::Result<()> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let api = Api::new().unwrap();
+        let model_repo = api.model("FL33TW00D-HF/phi2".to_string());
+        let model_path = model_repo.get("phi2-f16.gguf").unwrap();
+
+        let mut reader = std::io::BufReader::new(std::fs::File::open(model_path)?);
+        let device = Device::request_device(DeviceRequest::GPU)?;
+        let content = gguf::gguf::Header::read(&mut reader)?;
+        let mut model = Phi2::load(content, &mut reader, &device)?;
+
+        let tokenizer_repo = api.model("microsoft/phi-2".to_string());
+        let tokenizer_path = tokenizer_repo.get("tokenizer.json").unwrap();
+        let tokenizer = Tokenizer::from_file(tokenizer_path).unwrap();
+
+        //The short prompt is left-padded to the length of the long one
+        let prompts = [
+            "def print_prime(n):",
+            "The quick brown fox jumps over the lazy dog, and then the dog",
+        ]
+        .map(|prompt| {
+            let encoding = tokenizer.encode(prompt, true).unwrap();
+            encoding
+                .get_ids()
+                .iter()
+                .map(|&x| x as i32)
+                .collect::<Vec<_>>()
+        });
+        let steps = 4;
+
+        let mut single = vec![];
+        for prompt in &prompts {
+            model.cache_mut().set_padding(&[0], &device)?;
+            let mut logits = vec![];
+            let mut input = Tensor::from_data(prompt, shape![1, prompt.len()], device.clone());
+            for _ in 0..steps {
+                let seq_len = input.shape()[1];
+                let result = model.schedule(input)?.resolve()?.to(&Device::CPU)?;
+                model.cache_mut().update(seq_len);
+                input = Tensor::from_data(greedy(&result), shape![1, 1], device.clone());
+                logits.push(result);
+            }
+            single.push(logits);
+        }
+
+        let mut batch = Batch::new(prompts.to_vec(), vec![50256], 1024);
+        model.cache_mut().set_padding(&batch.padding(), &device)?;
+        let mut input = batch.prompt_tokens(50256, &device);
+        for step in 0..steps {
+            let seq_len = input.shape()[1];
+            let result = model.schedule(input)?.resolve()?.to(&Device::CPU)?;
+            model.cache_mut().update(seq_len);
+
+            let vocab = result.shape()[2];
+            for (row, expected) in single.iter().enumerate() {
+                let ours = result
+                    .clone()
+                    .slice(&[row..row + 1, 0..1, 0..vocab])?
+                    .resolve()?;
+                //Padding changes the reduction order of attention
+                expected[step].all_close(&ours, 1e-2, 1e-2)?;
+            }
+            batch.push(&greedy(&result));
+            input = batch.next_tokens(&device);
+        }
+        model.cache_mut().set_padding(&[0], &device)?;
+        Ok(())
+    }
 }
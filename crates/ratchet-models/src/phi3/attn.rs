@@ -6,6 +6,8 @@ use ratchet_nn::{KVEntry, Linear, Module, RotaryEmbedding, RotaryInput};
 
 #[cfg(target_arch = "wasm32")]
 use crate::{ratchet_from_gguf_web, TensorMap};
+#[cfg(not(target_arch = "wasm32"))]
+use ratchet_loader::gguf::MmapGGUF;
 
 #[derive(Debug)]
 pub struct PhiSelfAttention {
@@ -13,7 +15,6 @@ pub struct PhiSelfAttention {
     o: Linear,
     rope: RotaryEmbedding,
     n_heads: u32,
-    softmax_scale: Tensor,
     n_kv_heads: u32,
 }
 
@@ -28,7 +29,16 @@ impl PhiSelfAttention {
             let key = format!("blk.{}.{}", layer_index, name);
             disk_model.tensor(reader, &key, device)
         };
-        Self::load_inner(disk_model, lt, device)
+        Self::load_inner(disk_model, lt)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_mmap(gguf: &MmapGGUF, layer_index: usize, device: &Device) -> anyhow::Result<Self> {
+        let lt = |name: &str| {
+            let key = format!("blk.{}.{}", layer_index, name);
+            gguf.tensor(&key, device)
+        };
+        Self::load_inner(gguf.header(), lt)
     }
 
     #[cfg(target_arch = "wasm32")]
@@ -45,10 +55,10 @@ impl PhiSelfAttention {
                 .ok_or_else(|| anyhow::anyhow!("missing tensor"))?;
             ratchet_from_gguf_web(tensor, device)
         };
-        Self::load_inner(header, lt, device)
+        Self::load_inner(header, lt)
     }
 
-    fn load_inner<F>(header: &Header, mut lt: F, device: &Device) -> anyhow::Result<Self>
+    fn load_inner<F>(header: &Header, mut lt: F) -> anyhow::Result<Self>
     where
         F: FnMut(&str) -> anyhow::Result<Tensor>,
     {
@@ -58,19 +68,15 @@ impl PhiSelfAttention {
         let metadata = &header.metadata;
         let n_heads = metadata.get("phi3.attention.head_count")?.to_u32()?;
         let n_kv_heads = metadata.get("phi3.attention.head_count_kv")?.to_u32()?;
-        let d_model = metadata.get("phi3.embedding_length")?.to_u32()?;
         let rope_base = 10000.0f32;
         let rope_dim = metadata.get("phi3.rope.dimension_count")?.to_u32()?;
 
-        let hdim = d_model as f32 / n_heads as f32;
-        let softmax_scale = Tensor::from_data([1.0 / hdim.sqrt()], shape![1], device.clone());
         let rope = RotaryEmbedding::new(rope_dim as _, false, rope_base, 1.0);
         Ok(Self {
             qkv,
             o,
             rope,
             n_heads,
-            softmax_scale,
             n_kv_heads,
         })
     }
@@ -79,6 +85,7 @@ impl PhiSelfAttention {
 pub struct PhiAttnInput {
     pub input: Tensor,
     pub mask: Option<Tensor>,
+    pub causal: bool,
     pub cache: Option<KVEntry>,
 }
 
@@ -86,7 +93,12 @@ impl Module for PhiSelfAttention {
     type Input = PhiAttnInput;
 
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
-        let PhiAttnInput { input, mask, cache } = input;
+        let PhiAttnInput {
+            input,
+            mask,
+            causal,
+            cache,
+        } = input;
         let [batch_size, q_len, n_state]: [usize; 3] = input.shape().try_into()?;
 
         let hdim = n_state / self.n_heads as usize;
@@ -115,12 +127,14 @@ impl Module for PhiSelfAttention {
         let value_states = value_states.view(kv_shape)?.permute(&[0, 2, 1, 3])?;
 
         let offset = cache.as_ref().map(|kv| kv.entries).unwrap_or(0);
+        let row_offsets = cache.as_ref().and_then(|kv| kv.row_offsets());
         let q_dt = query_states.dt();
         let query_states = self
             .rope
             .schedule(RotaryInput {
                 input: query_states.full()?,
                 offset,
+                row_offsets: row_offsets.clone(),
             })?
             .cast(q_dt)?;
         let key_states = self
@@ -128,31 +142,18 @@ impl Module for PhiSelfAttention {
             .schedule(RotaryInput {
                 input: key_states.full()?,
                 offset,
+                row_offsets,
             })?
             .cast(q_dt)?;
 
         let (key_states, value_states) = if let Some(kv) = cache {
-            let k_cache = kv.k_cache.cache(key_states, 2, offset)?;
-            let v_cache = kv.v_cache.cache(value_states, 2, offset)?;
-            (k_cache, v_cache)
+            kv.cache(key_states, value_states, 2)?
         } else {
             (key_states, value_states)
         };
 
-        let mut attn_weights = query_states
-            .full()?
-            .matmul(key_states.full()?, false, true)?
-            .mul(self.softmax_scale.clone())?
-            .cast(q_dt)?;
-
-        if let Some(m) = mask {
-            let attn_dt = attn_weights.dt();
-            attn_weights = attn_weights.add(m.cast(attn_dt)?)?;
-        }
-
-        let w = attn_weights.full()?.softmax(3)?.cast(value_states.dt())?;
-        let wv = w
-            .matmul(value_states, false, false)?
+        let wv = query_states
+            .sdpa(key_states, value_states, mask, causal, None)?
             .permute(&[0, 2, 1, 3])?;
         let wv = wv.view(shape![batch_size as _, q_len, n_state])?;
         self.o.schedule(wv)
@@ -11,6 +11,7 @@ pub async fn generate(
     model: &mut Phi3,
     tokenizer: Tokenizer,
     prompt: String,
+    max_tokens: usize,
     callback: impl Fn(String),
 ) -> anyhow::Result<()> {
     use web_time::Instant;
@@ -34,7 +35,12 @@ pub async fn generate(
     tokens.insert(0, 1);
     let mut all_tokens = tokens.clone();
     let start = Instant::now();
-    while tokens[tokens.len() - 1] != 32007 && all_tokens.len() < 2048 {
+    let mut tokens = model.prefill(&tokens)?.to_vec();
+    let mut generated = 0;
+    while tokens[tokens.len() - 1] != 32007
+        && generated < max_tokens
+        && (model.window().is_some() || all_tokens.len() < 2048)
+    {
         let input = Tensor::from_data(
             tokens.clone(),
             shape![1, tokens.len()],
@@ -51,6 +57,7 @@ pub async fn generate(
             .map(|&x| x as i32)
             .collect::<Vec<_>>();
         all_tokens.extend(tokens.clone());
+        generated += 1;
         if let Some(t) = tos.next_token(tokens[0] as u32)? {
             callback(t);
         }
@@ -67,6 +74,7 @@ pub fn generate(
     model: &mut Phi3,
     tokenizer: Tokenizer,
     prompt: String,
+    max_tokens: usize,
     callback: impl Fn(String),
 ) -> anyhow::Result<()> {
     use web_time::Instant;
@@ -90,7 +98,12 @@ pub fn generate(
     tokens.insert(0, 1);
     let mut all_tokens = tokens.clone();
     let start = Instant::now();
-    while tokens[tokens.len() - 1] != 32007 && all_tokens.len() < 2048 {
+    let mut tokens = model.prefill(&tokens)?.to_vec();
+    let mut generated = 0;
+    while tokens[tokens.len() - 1] != 32007
+        && generated < max_tokens
+        && (model.window().is_some() || all_tokens.len() < 2048)
+    {
         let input = Tensor::from_data(
             tokens.clone(),
             shape![1, tokens.len()],
@@ -107,6 +120,7 @@ pub fn generate(
             .map(|&x| x as i32)
             .collect::<Vec<_>>();
         all_tokens.extend(tokens.clone());
+        generated += 1;
         if let Some(t) = tos.next_token(tokens[0] as u32)? {
             callback(t);
         }
@@ -117,3 +131,66 @@ pub fn generate(
     model.reset();
     Ok(())
 }
+
+/// Generates a completion for each of `prompts` as one batch, calling `callback` with the index
+/// of the prompt and its next piece of text. Each prompt stops independently.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn generate_batch(
+    model: &mut Phi3,
+    tokenizer: Tokenizer,
+    prompts: Vec<String>,
+    callback: impl Fn(usize, String),
+) -> anyhow::Result<()> {
+    use crate::Batch;
+
+    let prompts = prompts
+        .into_iter()
+        .map(|prompt| {
+            let prompt = format!(
+                r#"<|user|>
+{}<|end|>
+<|assistant|>"#,
+                prompt
+            );
+            let encoding = tokenizer.encode(prompt, true).unwrap();
+            let mut tokens = encoding
+                .get_ids()
+                .iter()
+                .map(|&x| x as i32)
+                .collect::<Vec<_>>();
+            tokens.insert(0, 1);
+            tokens
+        })
+        .collect::<Vec<_>>();
+    let mut streams = prompts
+        .iter()
+        .map(|_| TokenOutputStream::new(tokenizer.clone()))
+        .collect::<Vec<_>>();
+
+    let device = model.device.clone();
+    let mut batch = Batch::new(prompts, vec![32007], 2048);
+    model.cache_mut().set_padding(&batch.padding(), &device)?;
+
+    let mut input = batch.prompt_tokens(32007, &device);
+    while !batch.is_finished() {
+        let seq_len = input.shape()[1];
+        let result = model.schedule(input)?.resolve()?;
+        let logits = result.to(&Device::CPU)?;
+        model.cache_mut().update(seq_len);
+
+        let tokens = logits
+            .to_ndarray_view::<f32>()
+            .map_axis(Axis(2), |row| row.argmax_skipnan().unwrap())
+            .iter()
+            .map(|&x| x as i32)
+            .collect::<Vec<_>>();
+        for row in batch.push(&tokens) {
+            if let Some(t) = streams[row].next_token(tokens[row] as u32)? {
+                callback(row, t);
+            }
+        }
+        input = batch.next_tokens(&device);
+    }
+    model.cache_mut().set_padding(&[0], &device)?;
+    Ok(())
+}
@@ -4,4 +4,6 @@ mod mlp;
 mod model;
 
 pub use generate::generate;
+#[cfg(not(target_arch = "wasm32"))]
+pub use generate::generate_batch;
 pub use model::Phi3;
@@ -1,7 +1,7 @@
 use std::io::{BufRead, Seek};
 
 use half::f16;
-use ratchet::{shape, DType, Device, Tensor};
+use ratchet::{shape, DType, Device, RollingWindow, Tensor, Q8_0F, Q8_0H};
 use ratchet_loader::gguf::gguf::Header;
 use ratchet_nn::{Embedding, KVCache, KVEntry, Linear, Module, RMSNorm};
 
@@ -10,6 +10,8 @@ use super::{
     mlp::MLP,
 };
 
+#[cfg(not(target_arch = "wasm32"))]
+use ratchet_loader::gguf::MmapGGUF;
 #[cfg(target_arch = "wasm32")]
 use {crate::ratchet_from_gguf_web, crate::TensorMap};
 
@@ -29,11 +31,31 @@ impl DecoderLayer {
         device: &Device,
     ) -> anyhow::Result<Self> {
         let self_attn = PhiSelfAttention::load(header, reader, layer_index, device)?;
-        let mut lt = |name: &str| {
+        let lt = |name: &str| {
             let key = format!("blk.{}.{}", layer_index, name);
             header.tensor(reader, &key, device)
         };
+        Self::load_inner(header, self_attn, lt)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_mmap(gguf: &MmapGGUF, layer_index: usize, device: &Device) -> anyhow::Result<Self> {
+        let self_attn = PhiSelfAttention::from_mmap(gguf, layer_index, device)?;
+        let lt = |name: &str| {
+            let key = format!("blk.{}.{}", layer_index, name);
+            gguf.tensor(&key, device)
+        };
+        Self::load_inner(gguf.header(), self_attn, lt)
+    }
 
+    fn load_inner<F>(
+        header: &Header,
+        self_attn: PhiSelfAttention,
+        mut lt: F,
+    ) -> anyhow::Result<Self>
+    where
+        F: FnMut(&str) -> anyhow::Result<Tensor>,
+    {
         let norm_eps = header
             .metadata
             .get("phi3.attention.layer_norm_rms_epsilon")?
@@ -94,6 +116,7 @@ impl DecoderLayer {
 pub struct DecoderLayerInput {
     pub x: Tensor,
     pub mask: Option<Tensor>,
+    pub causal: bool,
     pub cache: Option<KVEntry>,
 }
 
@@ -101,12 +124,18 @@ impl Module for DecoderLayer {
     type Input = DecoderLayerInput;
 
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
-        let DecoderLayerInput { x, mask, cache } = input;
+        let DecoderLayerInput {
+            x,
+            mask,
+            causal,
+            cache,
+        } = input;
         let residual = x.clone();
         let xs = self.input_norm.schedule(x)?;
         let attn_output = self.self_attn.schedule(PhiAttnInput {
             input: xs.clone(),
             mask,
+            causal,
             cache,
         })?;
         let xs = residual.add(attn_output)?;
@@ -134,23 +163,20 @@ impl Module for Phi3 {
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
         let mut x = self.embedding.schedule(input)?;
 
-        let [_, seq_len, n_state]: [usize; 3] = x.shape().try_into()?;
-        let mask = if seq_len <= 1 {
-            None
-        } else {
-            Some(Self::generate_mask(seq_len, x.device())?)
-        };
+        let [batch_size, seq_len, n_state]: [usize; 3] = x.shape().try_into()?;
+        let (mask, causal) = self.kv_cache.sdpa_mask(seq_len, x.dt(), x.device())?;
 
         for (layer_idx, layer) in self.layers.iter().enumerate() {
             let input = DecoderLayerInput {
                 x,
                 mask: mask.clone(),
+                causal,
                 cache: Some(self.kv_cache[layer_idx].clone()),
             };
             x = layer.schedule(input)?;
         }
         x = self.ln_post.schedule(x)?;
-        x = x.slice(&[0..1, seq_len - 1..seq_len, 0..n_state])?;
+        x = x.slice(&[0..batch_size, seq_len - 1..seq_len, 0..n_state])?;
         let logits = self.lm_head.schedule(x)?;
         Ok(logits)
     }
@@ -164,30 +190,43 @@ impl Phi3 {
         reader: &mut R,
         device: &Device,
     ) -> anyhow::Result<Self> {
-        let embedding = Embedding::new(header.tensor(reader, "token_embd.weight", device)?);
-
-        let n_layers = header.metadata.get("phi3.block_count")?.to_u32()? as i32;
+        let n_layers = header.metadata.get("phi3.block_count")?.to_u32()? as usize;
+        let layers = (0..n_layers)
+            .map(|i| DecoderLayer::load(&header, reader, i, device))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let lt = |name: &str| header.tensor(reader, name, device);
+        Self::load_inner(&header, layers, lt, device)
+    }
 
+    /// Loads the model from a memory-mapped GGUF file, see [MmapGGUF].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_mmap(gguf: &MmapGGUF, device: &Device) -> anyhow::Result<Self> {
+        let n_layers = gguf.header().metadata.get("phi3.block_count")?.to_u32()? as usize;
         let layers = (0..n_layers)
-            .fold(Vec::with_capacity(n_layers as _), |mut blocks, i| {
-                blocks.push(DecoderLayer::load(&header, reader, i as _, device));
-                blocks
-            })
-            .into_iter()
+            .map(|i| DecoderLayer::from_mmap(gguf, i, device))
             .collect::<anyhow::Result<Vec<_>>>()?;
+        let lt = |name: &str| gguf.tensor(name, device);
+        Self::load_inner(gguf.header(), layers, lt, device)
+    }
 
-        let mut lt = |name: &str| {
-            let key = format!("output{}", name);
-            header.tensor(reader, &key, device)
-        };
+    fn load_inner<F>(
+        header: &Header,
+        layers: Vec<DecoderLayer>,
+        mut lt: F,
+        device: &Device,
+    ) -> anyhow::Result<Self>
+    where
+        F: FnMut(&str) -> anyhow::Result<Tensor>,
+    {
+        let embedding = Embedding::new(lt("token_embd.weight")?);
 
         let metadata = &header.metadata;
 
         let norm_eps = metadata
             .get("phi3.attention.layer_norm_rms_epsilon")?
             .to_f32()?;
-        let ln_post = RMSNorm::new(lt("_norm.weight")?, norm_eps);
-        let lm_head = Linear::new(lt(".weight")?, None);
+        let ln_post = RMSNorm::new(lt("output_norm.weight")?, norm_eps);
+        let lm_head = Linear::new(lt("output.weight")?, None);
 
         let n_layers = metadata.get("phi3.block_count")?.to_u32()?;
         let d_model = metadata.get("phi3.embedding_length")?.to_u32()?;
@@ -269,18 +308,6 @@ impl Phi3 {
         })
     }
 
-    pub fn generate_mask(seq_len: usize, device: &Device) -> anyhow::Result<Tensor> {
-        let mask: Vec<_> = (0..seq_len)
-            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
-            .collect();
-
-        Ok(Tensor::from_data(
-            mask,
-            shape![seq_len, seq_len],
-            device.clone(),
-        ))
-    }
-
     pub fn reset(&mut self) {
         self.kv_cache.reset();
     }
@@ -288,6 +315,48 @@ impl Phi3 {
     pub fn cache_mut(&mut self) -> &mut KVCache {
         &mut self.kv_cache
     }
+
+    /// Makes the KV cache a rolling window, see [KVCache::set_window].
+    /// With a window, generation is no longer bounded by the size of the cache.
+    pub fn set_window(&mut self, window: Option<RollingWindow>) {
+        self.kv_cache.set_window(window);
+    }
+
+    pub fn window(&self) -> Option<RollingWindow> {
+        self.kv_cache.window()
+    }
+
+    /// Writes all but the last `window` tokens of the prompt into the cache, one window-sized
+    /// chunk at a time, returning the tokens left to schedule. Without a window, returns
+    /// `tokens` unchanged. See [KVCache::max_write].
+    pub fn prefill<'a>(&mut self, mut tokens: &'a [i32]) -> anyhow::Result<&'a [i32]> {
+        let Some(max_write) = self.kv_cache.max_write() else {
+            return Ok(tokens);
+        };
+        while tokens.len() > max_write {
+            let (chunk, rest) = tokens.split_at(max_write);
+            let input = Tensor::from_data(chunk, shape![1, chunk.len()], self.device.clone());
+            self.schedule(input)?.resolve()?;
+            self.kv_cache.update(chunk.len());
+            tokens = rest;
+        }
+        Ok(tokens)
+    }
+
+    /// Replaces the KV cache with one stored as Q8_0, about 2x smaller than an F16 cache and 4x
+    /// smaller than an F32 one. Keys & values are quantized as they are written, discarding any
+    /// cached entries.
+    pub fn quantize_kv_cache(&mut self) {
+        let k_cache = &self.kv_cache[0].k_cache;
+        let dt = match k_cache.dt() {
+            DType::F16 => DType::Q8_0H(Q8_0H::default()),
+            _ => DType::Q8_0F(Q8_0F::default()),
+        };
+        let shape = k_cache.shape().clone();
+        let window = self.kv_cache.window();
+        self.kv_cache = KVCache::quantized(self.layers.len() as _, shape, dt, &self.device);
+        self.kv_cache.set_window(window);
+    }
 }
 
 #[cfg(all(test, not(target_arch = "wasm32"), feature = "pyo3"))]
@@ -298,7 +367,7 @@ mod tests {
     use numpy::PyArrayDyn;
     use pyo3::{types::PyModule, Python};
     use ratchet::{prelude::shape, Device, DeviceRequest, Tensor};
-    use ratchet_loader::gguf;
+    use ratchet_loader::gguf::MmapGGUF;
     use ratchet_nn::Module;
     use tokenizers::Tokenizer;
 
@@ -343,10 +412,9 @@ def ground():
         let model_path = model_repo.get("phi3-mini-4k-f16.gguf").unwrap();
         println!("MODEL PATH: {}", model_path.display());
 
-        let mut reader = std::io::BufReader::new(std::fs::File::open(model_path)?);
         let device = Device::request_device(DeviceRequest::GPU)?;
-        let content = gguf::gguf::Header::read(&mut reader)?;
-        let mut model = Phi3::load(content, &mut reader, &device)?;
+        let gguf = MmapGGUF::open(model_path)?;
+        let mut model = Phi3::from_mmap(&gguf, &device)?;
 
         let tokenizer_repo = api.model("microsoft/Phi-3-mini-4k-instruct".to_string());
         let tokenizer_path = tokenizer_repo.get("tokenizer.json").unwrap();
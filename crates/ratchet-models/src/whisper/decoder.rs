@@ -1,8 +1,7 @@
 use super::config::Config;
 use crate::whisper::residual_block::*;
 use half::f16;
-use num::Zero;
-use ratchet::{prelude::*, DType, TensorDType};
+use ratchet::{prelude::*, DType};
 use ratchet_loader::gguf::gguf::Header;
 use ratchet_nn::{Embedding, KVCache, LayerNorm, Module};
 use std::io::{BufRead, Seek};
@@ -82,7 +81,6 @@ impl Module for DecoderStem {
 pub struct WhisperDecoder {
     stem: DecoderStem,
     blocks: Vec<ResidualAttentionBlock>,
-    mask: Tensor,
     ln_post: LayerNorm,
     cache: KVCache,
     #[allow(dead_code)] //Should maintain a handle to the device
@@ -103,7 +101,7 @@ impl Module for WhisperDecoder {
             let block_input = ResidualAttentionBlockInputs {
                 x,
                 xa: Some(audio_ctx.clone()),
-                mask: Some(self.mask.clone()),
+                causal: true,
                 cache: Some(self.cache[block_idx].clone()),
             };
             x = block.schedule(block_input)?;
@@ -131,15 +129,6 @@ impl WhisperDecoder {
         self.cache.reset();
     }
 
-    fn load_mask<T: TensorDType + num::Float>(n_ctx: usize, device: &Device) -> Tensor {
-        let mask: Vec<_> = (0..n_ctx)
-            .flat_map(|i| {
-                (0..n_ctx).map(move |j| if j > i { T::neg_infinity() } else { T::zero() })
-            })
-            .collect();
-        Tensor::from_data(mask, shape![n_ctx, n_ctx], device.clone())
-    }
-
     #[cfg(target_arch = "wasm32")]
     pub fn from_web(
         header: &Header,
@@ -174,12 +163,6 @@ impl WhisperDecoder {
         let n_state = config.n_audio_state as _;
 
         let dt = blocks[0].mlp.activation_dt();
-        let mask = match dt {
-            DType::F16 => Self::load_mask::<f16>(config.n_text_ctx as _, device),
-            DType::F32 => Self::load_mask::<f32>(config.n_text_ctx as _, device),
-            _ => unimplemented!(),
-        };
-
         let cache_shape = shape![1, Self::MAX_CACHE, n_state];
         let cache = match dt {
             DType::F16 => KVCache::new::<f16>(n_layers as _, cache_shape, device),
@@ -190,7 +173,6 @@ impl WhisperDecoder {
         Ok(Self {
             stem,
             blocks,
-            mask,
             ln_post: LayerNorm::new(lt("weight")?, Some(lt("bias")?), 1e-5),
             cache,
             device: device.clone(),
@@ -229,12 +211,6 @@ impl WhisperDecoder {
         let n_state = config.n_audio_state as _;
 
         let dt = blocks[0].mlp.activation_dt();
-        let mask = match dt {
-            DType::F16 => Self::load_mask::<f16>(config.n_text_ctx as _, device),
-            DType::F32 => Self::load_mask::<f32>(config.n_text_ctx as _, device),
-            _ => unimplemented!(),
-        };
-
         let cache_shape = shape![1, Self::MAX_CACHE, n_state];
         let cache = match dt {
             DType::F16 => KVCache::new::<f16>(n_layers as _, cache_shape, device),
@@ -245,7 +221,6 @@ impl WhisperDecoder {
         Ok(Self {
             stem,
             blocks,
-            mask,
             ln_post: LayerNorm::new(lt("weight")?, Some(lt("bias")?), 1e-5),
             cache,
             device: device.clone(),
@@ -325,7 +300,7 @@ def ground(options):
         let header = gguf::Header::read(&mut reader).unwrap();
 
         let device = Device::request_device(DeviceRequest::GPU).unwrap();
-        let audio_ctx = Tensor::read_npy::<f32, _>(hs_npy, &device)?;
+        let audio_ctx = Tensor::read_npy(hs_npy, &device)?;
         let mut decoder = WhisperDecoder::load(&header, &config, &mut reader, &device)?;
 
         let mut tokens = vec![50258, 50259, 50359];
@@ -31,6 +31,8 @@ impl Module for ConvBlock {
                 Some(self.b.clone().cast(input_dt)?),
                 self.stride,
                 self.padding,
+                1,
+                1,
             )?
             .gelu()
     }
@@ -112,7 +114,7 @@ impl Module for WhisperEncoder {
             let input = ResidualAttentionBlockInputs {
                 x: x.clone(),
                 xa: None,
-                mask: None,
+                causal: false,
                 cache: None,
             };
             x = block.schedule(input)?;
@@ -236,11 +238,11 @@ mod tests {
         let device = Device::request_device(DeviceRequest::GPU).unwrap();
 
         let encoder = WhisperEncoder::load(&header, &config, &mut reader, &device)?;
-        let input = Tensor::read_npy::<f32, _>(input_npy, &device)?;
+        let input = Tensor::read_npy(input_npy, &device)?;
 
         let result = encoder.schedule(input)?.full()?.resolve()?;
         let ours = result.to(&Device::CPU)?;
-        let ground = Tensor::read_npy::<f32, _>(ground_npy, &Device::CPU)?;
+        let ground = Tensor::read_npy(ground_npy, &Device::CPU)?;
         println!("OURS: {:#?}", ours);
         println!("Ground: {:#?}", ground);
         ground.all_close(&ours, 1e-3, 1e-3)?;
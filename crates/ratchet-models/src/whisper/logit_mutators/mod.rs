@@ -5,10 +5,12 @@ use crate::whisper::tokenizer::WhisperTokenizer;
 use ratchet::Tensor;
 
 pub trait LogitMutator {
+    /// Modifies the `[1, vocab_size]` logits of the next token, given the tokens so far.
+    /// The logits stay on their device, so mutators build on the graph rather than resolving it.
     fn apply(
         &self,
         logits: Tensor,
         tokenizer: &WhisperTokenizer,
-        tokens: Option<&Tensor>,
+        tokens: &[i32],
     ) -> anyhow::Result<Tensor>;
 }
@@ -1,97 +1,86 @@
-use ndarray::s;
-use ndarray_stats::QuantileExt;
-use ratchet::{NDArrayExt, Tensor};
+use ratchet::{shape, Tensor};
 
 use super::LogitMutator;
 use crate::whisper::tokenizer::WhisperTokenizer;
 
+/// Suppresses the tokens that would break the timestamp structure of the transcript.
+///
+/// These rules only depend on the tokens sampled so far, so they are applied as an additive mask.
+/// The final rule, sampling a timestamp whenever the timestamps are more likely than any text
+/// token, depends on the logits themselves and is applied by the `GreedySampler`.
 #[derive(Debug, derive_new::new)]
 pub struct ApplyTimestampRules {
     pub sample_begin: usize,
     pub max_initial_timestamp_index: Option<usize>,
 }
 
-impl LogitMutator for ApplyTimestampRules {
-    fn apply(
-        &self,
-        logits: Tensor,
-        tokenizer: &WhisperTokenizer,
-        tokens: Option<&Tensor>,
-    ) -> anyhow::Result<Tensor> {
-        let nd_tokens = tokens.unwrap().clone().into_ndarray::<i32>();
-        let mut nd_logits = logits.into_ndarray::<f32>();
-
-        nd_logits
-            .slice_mut(s![.., tokenizer.notimestamps() as usize])
-            .map_inplace(move |el| *el = f32::NEG_INFINITY);
+impl ApplyTimestampRules {
+    fn mask(&self, tokenizer: &WhisperTokenizer, tokens: &[i32], vocab_size: usize) -> Vec<f32> {
+        let mut mask = vec![0f32; vocab_size];
+        let mut suppress = |range: std::ops::Range<usize>| {
+            let end = range.end.min(vocab_size);
+            if range.start < end {
+                mask[range.start..end].fill(f32::NEG_INFINITY);
+            }
+        };
+        let timestamp_begin = tokenizer.timestamp_begin();
+        let notimestamps = tokenizer.notimestamps() as usize;
+        suppress(notimestamps..notimestamps + 1);
 
-        for k in 0..nd_tokens.shape()[0] {
-            let sampled_tokens = nd_tokens.slice(s![k, self.sample_begin..]);
-            let sample_len = sampled_tokens.len();
+        let sampled_tokens = &tokens[self.sample_begin..];
+        let sample_len = sampled_tokens.len();
 
-            let last_was_timestamp = !sampled_tokens.is_empty()
-                && sampled_tokens[sample_len - 1] >= tokenizer.timestamp_begin();
-            let penultimate_was_timestamp = sampled_tokens.len() < 2
-                || sampled_tokens[sample_len - 2] >= tokenizer.timestamp_begin();
+        let last_was_timestamp =
+            !sampled_tokens.is_empty() && sampled_tokens[sample_len - 1] >= timestamp_begin;
+        let penultimate_was_timestamp =
+            sampled_tokens.len() < 2 || sampled_tokens[sample_len - 2] >= timestamp_begin;
 
-            if last_was_timestamp {
-                if penultimate_was_timestamp {
-                    nd_logits
-                        .slice_mut(s![k, tokenizer.timestamp_begin()..])
-                        .map_inplace(move |el| *el = f32::NEG_INFINITY);
-                } else {
-                    nd_logits
-                        .slice_mut(s![k, ..WhisperTokenizer::EOT])
-                        .map_inplace(move |el| *el = f32::NEG_INFINITY);
-                }
+        if last_was_timestamp {
+            if penultimate_was_timestamp {
+                suppress(timestamp_begin as usize..vocab_size);
+            } else {
+                suppress(0..WhisperTokenizer::EOT as usize);
             }
+        }
 
-            let timestamps = sampled_tokens
-                .iter()
-                .filter(|x| **x >= tokenizer.timestamp_begin())
-                .collect::<Vec<_>>();
+        let timestamps = sampled_tokens
+            .iter()
+            .filter(|x| **x >= timestamp_begin)
+            .collect::<Vec<_>>();
 
-            if !timestamps.is_empty() {
-                // timestamps shouldn't decrease; forbid timestamp tokens smaller than the last
-                // also force each segment to have a nonzero length, to prevent infinite looping
-                let timestamp_last = if last_was_timestamp && !penultimate_was_timestamp {
-                    *timestamps[timestamps.len() - 1]
-                } else {
-                    timestamps[timestamps.len() - 1] + 1
-                };
-                nd_logits
-                    .slice_mut(s![k, tokenizer.timestamp_begin()..timestamp_last])
-                    .map_inplace(move |el| *el = f32::NEG_INFINITY);
-            }
+        if !timestamps.is_empty() {
+            // timestamps shouldn't decrease; forbid timestamp tokens smaller than the last
+            // also force each segment to have a nonzero length, to prevent infinite looping
+            let timestamp_last = if last_was_timestamp && !penultimate_was_timestamp {
+                *timestamps[timestamps.len() - 1]
+            } else {
+                timestamps[timestamps.len() - 1] + 1
+            };
+            suppress(timestamp_begin as usize..timestamp_last as usize);
         }
-        if nd_tokens.shape()[1] == self.sample_begin {
+        if tokens.len() == self.sample_begin {
             // suppress generating non-timestamp tokens at the beginning
-            nd_logits
-                .slice_mut(s![.., ..tokenizer.timestamp_begin()])
-                .map_inplace(move |el| *el = f32::NEG_INFINITY);
+            suppress(0..timestamp_begin as usize);
 
-            if self.max_initial_timestamp_index.is_some() {
-                let last_allowed = (tokenizer.timestamp_begin() as usize)
-                    + self.max_initial_timestamp_index.unwrap();
-                nd_logits
-                    .slice_mut(s![.., last_allowed + 1..])
-                    .map_inplace(move |el| *el = f32::NEG_INFINITY);
+            if let Some(index) = self.max_initial_timestamp_index {
+                let last_allowed = timestamp_begin as usize + index;
+                suppress(last_allowed + 1..vocab_size);
             }
         }
+        mask
+    }
+}
 
-        let logprobs = nd_logits.log_softmax(1);
-        for _k in 0..nd_tokens.shape()[0] {
-            let timestamp_logprob = logprobs
-                .slice(s![.., tokenizer.timestamp_begin()..])
-                .logsumexp(1);
-            let text_logprobs = logprobs.slice(s![.., ..tokenizer.timestamp_begin()]);
-            let max_text_token_logprob = text_logprobs.max()?;
-            if timestamp_logprob > *max_text_token_logprob {
-                nd_logits
-                    .slice_mut(s![.., ..tokenizer.timestamp_begin()])
-                    .map_inplace(move |el| *el = f32::NEG_INFINITY);
-            }
-        }
-        Ok(Tensor::from(nd_logits))
+impl LogitMutator for ApplyTimestampRules {
+    fn apply(
+        &self,
+        logits: Tensor,
+        tokenizer: &WhisperTokenizer,
+        tokens: &[i32],
+    ) -> anyhow::Result<Tensor> {
+        let vocab_size = logits.shape()[1];
+        let mask = self.mask(tokenizer, tokens, vocab_size);
+        let mask = Tensor::from_data(mask, shape![1, vocab_size], logits.device().clone());
+        logits.add(mask)
     }
 }
@@ -1,6 +1,4 @@
-use half::f16;
-use num::traits::real::Real;
-use ratchet::{rvec, shape, Tensor};
+use ratchet::{shape, Tensor};
 use ratchet_nn::{KVEntry, Linear, Module};
 
 #[derive(Debug)]
@@ -10,34 +8,16 @@ pub struct MultiHeadAttention {
     v: Linear,
     o: Linear,
     n_heads: usize,
-    dk: Tensor,
 }
 
 impl MultiHeadAttention {
     pub fn new(q: Linear, k: Linear, v: Linear, o: Linear, n_heads: usize) -> MultiHeadAttention {
-        let n_state = q.w.shape()[1];
-        let dk = match q.w.dt().activation_dt() {
-            ratchet::DType::F16 => {
-                let dk = f16::from_f32((n_state / n_heads) as f32);
-                Tensor::from_data(
-                    [dk.powf(f16::from_f32(-0.25))],
-                    shape![1],
-                    q.w.device().clone(),
-                )
-            }
-            ratchet::DType::F32 => {
-                let dk = (n_state / n_heads) as f32;
-                Tensor::from_data([dk.powf(-0.25)], shape![1], q.w.device().clone())
-            }
-            _ => unimplemented!(),
-        };
         MultiHeadAttention {
             q,
             k,
             v,
             o,
             n_heads,
-            dk,
         }
     }
 }
@@ -94,7 +74,6 @@ impl MultiHeadAttention {
         let [bs, n_ctx, n_state]: [usize; 3] = q.shape().try_into()?;
         let [k0, k1, _]: [usize; 3] = k.shape().try_into()?;
         let [v0, v1, _]: [usize; 3] = v.shape().try_into()?;
-        let q_dt = q.dt();
 
         let hdim = n_state / self.n_heads;
 
@@ -102,26 +81,15 @@ impl MultiHeadAttention {
         let ks = shape![k0, k1, self.n_heads, hdim];
         let vs = shape![v0, v1, self.n_heads, hdim];
 
-        let q = q.view(qs)?.permute(&[0, 2, 1, 3])?.mul(self.dk.clone())?;
-        let k = k.view(ks)?.permute(&[0, 2, 3, 1])?.mul(self.dk.clone())?;
+        let q = q.view(qs)?.permute(&[0, 2, 1, 3])?;
+        let k = k.view(ks)?.permute(&[0, 2, 1, 3])?;
         let v = v.view(vs)?.permute(&[0, 2, 1, 3])?;
 
-        let mut qk = q.matmul(k, false, false)?;
-
-        if let Some(m) = mask {
-            let prepared_mask = if is_causal {
-                m.slice(&[0..n_ctx, 0..n_ctx])?
-            } else {
-                m.clone()
-            };
-            qk = qk.add(prepared_mask)?;
-        }
-        qk = qk.full()?;
-
-        let w = qk.softmax(3)?.cast(q_dt)?;
-
         let s = shape![bs, n_ctx, n_state];
-        let wv = w.matmul(v, false, false)?.permute(&[0, 2, 1, 3])?.view(s)?;
+        let wv = q
+            .sdpa(k, v, mask, is_causal, None)?
+            .permute(&[0, 2, 1, 3])?
+            .view(s)?;
 
         self.o.schedule(wv)
     }
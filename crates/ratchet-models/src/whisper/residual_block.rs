@@ -21,19 +21,24 @@ pub struct ResidualAttentionBlock {
 pub struct ResidualAttentionBlockInputs {
     pub x: Tensor,
     pub xa: Option<Tensor>,
-    pub mask: Option<Tensor>,
+    pub causal: bool,
     pub cache: Option<KVEntry>,
 }
 
 impl Module for ResidualAttentionBlock {
     type Input = ResidualAttentionBlockInputs;
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
-        let ResidualAttentionBlockInputs { x, xa, mask, cache } = input;
+        let ResidualAttentionBlockInputs {
+            x,
+            xa,
+            causal,
+            cache,
+        } = input;
 
         let attn_ln = self.attn_ln.schedule(x.clone())?;
-        let self_attn =
-            self.attn
-                .schedule(MHAInputs::new(attn_ln, None, mask.clone(), cache, true))?;
+        let self_attn = self
+            .attn
+            .schedule(MHAInputs::new(attn_ln, None, None, cache, causal))?;
 
         let mut attn = x.add(self_attn)?;
 
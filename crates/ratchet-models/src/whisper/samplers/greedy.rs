@@ -1,30 +1,134 @@
 use crate::whisper::task::DecodeError;
 use crate::whisper::tokenizer::WhisperTokenizer;
 
-use ndarray::Axis;
-use ndarray_stats::QuantileExt;
-use ratchet::Tensor;
+use ratchet::{rvec, shape, DType, Device, SampleStrategy, Tensor};
 
 pub struct GreedySampler;
 
 impl GreedySampler {
+    /// Samples the next token from the `[1, vocab_size]` logits, which stay on their device.
+    /// Only a handful of values are read back, see [GreedySampler::candidates].
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn sample(
-        mut tokens: Vec<i32>,
+        tokens: Vec<i32>,
         logits: Tensor,
-    ) -> Result<(Tensor, Vec<i32>, bool), DecodeError> {
-        let nd_logits = logits.to_ndarray_view::<f32>();
-        let next_tokens = nd_logits
-            .map_axis(Axis(1), |row| row.argmax_skipnan())
-            .iter()
-            .map(|r| {
-                r.as_ref()
-                    .map_err(|_| DecodeError::InvalidLogits)
-                    .map(|v| *v as i32)
-            })
-            .collect::<Result<Vec<i32>, DecodeError>>()?;
-
-        tokens.extend_from_slice(&next_tokens);
-        let completed = tokens[tokens.len() - 1] == WhisperTokenizer::EOT;
-        Ok((logits, tokens, completed))
+        tokenizer: &WhisperTokenizer,
+    ) -> Result<(Vec<i32>, bool), DecodeError> {
+        let timestamp_begin = tokenizer.timestamp_begin() as usize;
+        let candidates = Self::candidates(logits, timestamp_begin)?
+            .resolve()?
+            .to(&Device::CPU)?
+            .to_vec::<f32>()?;
+        Self::select(tokens, &candidates, timestamp_begin)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub async fn sample(
+        tokens: Vec<i32>,
+        logits: Tensor,
+        tokenizer: &WhisperTokenizer,
+    ) -> Result<(Vec<i32>, bool), DecodeError> {
+        let timestamp_begin = tokenizer.timestamp_begin() as usize;
+        let candidates = Self::candidates(logits, timestamp_begin)?
+            .resolve()?
+            .to(&Device::CPU)
+            .await?
+            .to_vec::<f32>()?;
+        Self::select(tokens, &candidates, timestamp_begin)
+    }
+
+    /// Computes `[greedy, greedy timestamp, timestamp logprob - max text logprob, norm]`.
+    ///
+    /// Whisper samples a timestamp whenever the total probability of the timestamps exceeds that
+    /// of any text token. Both are compared relative to the max logit, which cancels out.
+    /// The norm (i.e the softmax denominator) is NaN if any logit is, or if every logit is masked.
+    fn candidates(logits: Tensor, timestamp_begin: usize) -> anyhow::Result<Tensor> {
+        let vocab_size = logits.shape()[1];
+        let max = logits.clone().max(1, true)?;
+        let shifted = logits.clone().sub(max)?;
+        let exp = shifted.clone().exp()?;
+
+        let norm = exp.clone().sum(1, true)?;
+        let timestamp_logprob = exp
+            .slice(&[0..1, timestamp_begin..vocab_size])?
+            .sum(1, true)?
+            .log()?;
+        let text_logprob = shifted.slice(&[0..1, 0..timestamp_begin])?.max(1, true)?;
+        let timestamp_over_text = timestamp_logprob.sub(text_logprob)?;
+
+        let greedy = logits
+            .clone()
+            .sample(SampleStrategy::Greedy, 0)?
+            .view(shape![1, 1])?
+            .cast(DType::F32)?;
+        let greedy_timestamp = logits
+            .slice(&[0..1, timestamp_begin..vocab_size])?
+            .sample(SampleStrategy::Greedy, 0)?
+            .view(shape![1, 1])?
+            .cast(DType::F32)?;
+        Tensor::cat(
+            rvec![greedy, greedy_timestamp, timestamp_over_text, norm],
+            1,
+        )
+    }
+
+    fn select(
+        mut tokens: Vec<i32>,
+        candidates: &[f32],
+        timestamp_begin: usize,
+    ) -> Result<(Vec<i32>, bool), DecodeError> {
+        let &[greedy, greedy_timestamp, timestamp_over_text, norm] = candidates else {
+            return Err(DecodeError::InvalidLogits);
+        };
+        if norm.is_nan() || timestamp_over_text.is_nan() {
+            return Err(DecodeError::InvalidLogits);
+        }
+        let next = if timestamp_over_text > 0. {
+            greedy_timestamp as i32 + timestamp_begin as i32
+        } else {
+            greedy as i32
+        };
+
+        tokens.push(next);
+        let completed = next == WhisperTokenizer::EOT;
+        Ok((tokens, completed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GreedySampler;
+    use crate::whisper::task::DecodeError;
+    use ratchet::{shape, Device, Tensor};
+
+    fn sample(logits: Vec<f32>, timestamp_begin: usize) -> Result<i32, DecodeError> {
+        let logits = Tensor::from_data(logits.clone(), shape![1, logits.len()], Device::CPU);
+        let candidates = GreedySampler::candidates(logits, timestamp_begin)?
+            .resolve()?
+            .to_vec::<f32>()?;
+        let (tokens, _) = GreedySampler::select(vec![], &candidates, timestamp_begin)?;
+        Ok(tokens[0])
+    }
+
+    #[test]
+    fn greedy_respects_timestamp_mass() -> anyhow::Result<()> {
+        //The most likely token is text, but the timestamps are more likely together
+        assert_eq!(sample(vec![0., 2., 1.5, 1.5, 1.5], 2)?, 2);
+        assert_eq!(sample(vec![0., 3., 1.5, 1.5, 1.5], 2)?, 1);
+        let masked = f32::NEG_INFINITY;
+        assert_eq!(sample(vec![0., 3., masked, masked, masked], 2)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn greedy_rejects_invalid_logits() {
+        assert!(matches!(
+            sample(vec![0., f32::NAN, 1., 2.], 2),
+            Err(DecodeError::InvalidLogits)
+        ));
+        assert!(matches!(
+            sample(vec![f32::NEG_INFINITY; 4], 2),
+            Err(DecodeError::InvalidLogits)
+        ));
     }
 }
@@ -4,7 +4,7 @@ use super::{
 };
 use crate::whisper::options::{DecodingOptions, Prompt};
 use ndarray::{s, Axis};
-use ratchet::{shape, Device, Tensor};
+use ratchet::{shape, Tensor};
 use ratchet_nn::Module;
 
 #[derive(Debug, thiserror::Error)]
@@ -102,18 +102,15 @@ impl DecodingTask {
 
             let logits = decoder
                 .schedule([audio_ctx.clone(), input_t])?
-                .cast(DType::F32)?
-                .resolve()?;
+                .cast(DType::F32)?;
             decoder.cache_mut().update(input.len());
 
-            let mut logits = Self::slice_logits(logits.to(&Device::CPU)?, sliced_vocab_size);
-            let token_t = Tensor::from_data(tokens.clone(), shape![1, tokens.len()], Device::CPU);
+            let mut logits = Self::next_token_logits(logits, sliced_vocab_size)?;
             for m in &self.logit_mutators {
-                logits = m.apply(logits, &self.tokenizer, Some(&token_t))?;
+                logits = m.apply(logits, &self.tokenizer, &tokens)?;
             }
 
-            let (_, new_tokens, completed) = GreedySampler::sample(tokens, logits)?;
-            //println!("NEW TOKENS: {:?}", new_tokens);
+            let (new_tokens, completed) = GreedySampler::sample(tokens, logits, &self.tokenizer)?;
 
             if let Some(ref cb) = callback {
                 self.handle_callback(&self.tokenizer, &new_tokens, &mut timestamps_seen, cb);
@@ -147,16 +144,16 @@ impl DecodingTask {
             };
             let input_t = Tensor::from_data(input, shape![1, input.len()], device.clone());
 
-            let logits = decoder.schedule([audio_ctx.clone(), input_t])?.resolve()?;
+            let logits = decoder.schedule([audio_ctx.clone(), input_t])?;
             decoder.cache_mut().update(input.len());
 
-            let mut logits = Self::slice_logits(logits.to(&Device::CPU).await?, sliced_vocab_size);
-            let token_t = Tensor::from_data(tokens.clone(), shape![1, tokens.len()], Device::CPU);
+            let mut logits = Self::next_token_logits(logits, sliced_vocab_size)?;
             for m in &self.logit_mutators {
-                logits = m.apply(logits, &self.tokenizer, Some(&token_t))?;
+                logits = m.apply(logits, &self.tokenizer, &tokens)?;
             }
 
-            let (_, new_tokens, completed) = GreedySampler::sample(tokens, logits)?;
+            let (new_tokens, completed) =
+                GreedySampler::sample(tokens, logits, &self.tokenizer).await?;
 
             if let Some(ref cb) = callback {
                 self.handle_callback(&self.tokenizer, &new_tokens, &mut timestamps_seen, cb);
@@ -195,6 +192,14 @@ impl DecodingTask {
         }
     }
 
+    /// Slice the logits of the last token on their device, [1xnum_tokensx51872] -> [1x51865]
+    fn next_token_logits(logits: Tensor, vocab_size: usize) -> anyhow::Result<Tensor> {
+        let num_tokens = logits.shape()[1];
+        logits
+            .slice(&[0..1, num_tokens - 1..num_tokens, 0..vocab_size])?
+            .view(shape![1, vocab_size])
+    }
+
     /// Slice logits from [1xnum_tokensx51872] -> [1x1x51865]
     pub(crate) fn slice_logits(logits: Tensor, vocab_size: usize) -> Tensor {
         let nd_logits = logits.into_ndarray::<f32>();
@@ -36,8 +36,8 @@ async fn tiny_encoder() -> Result<(), JsValue> {
     let device = Device::request_device(DeviceRequest::GPU).await.unwrap();
 
     let input_data = &input_npy.to_vec();
-    let input = Tensor::from_npy_bytes::<f32>(input_data, &device).unwrap();
-    let ground = Tensor::from_npy_bytes::<f32>(&ground_npy.to_vec(), &Device::CPU).unwrap();
+    let input = Tensor::from_npy_bytes(input_data, &device).unwrap();
+    let ground = Tensor::from_npy_bytes(&ground_npy.to_vec(), &Device::CPU).unwrap();
 
     let encoder = WhisperEncoder::load(&header, &config, &mut reader, &device).unwrap();
     let result = encoder.schedule(input).unwrap().resolve().unwrap();
@@ -60,7 +60,7 @@ async fn tiny_decoder() -> Result<(), JsValue> {
     let config: Config = serde_json::from_slice(&config_data.to_vec()).unwrap();
 
     let device = Device::request_device(DeviceRequest::GPU).await.unwrap();
-    let audio_ctx = Tensor::from_npy_bytes::<f32>(&hs_data.to_vec(), &device).unwrap();
+    let audio_ctx = Tensor::from_npy_bytes(&hs_data.to_vec(), &device).unwrap();
     let mut decoder = WhisperDecoder::load(&header, &config, &mut reader, &device).unwrap();
 
     let mut tokens = vec![50258, 50259, 50359];
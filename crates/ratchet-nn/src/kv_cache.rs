@@ -1,10 +1,35 @@
-use ratchet::{Device, Shape, Tensor, TensorDType};
+use half::f16;
+use ratchet::{shape, DType, Device, PaddingSide, RollingWindow, Shape, Tensor, TensorDType};
 
 #[derive(Clone, Debug)]
 pub struct KVEntry {
     pub k_cache: Tensor,
     pub v_cache: Tensor,
+    /// Number of positions written, which is also the RoPE offset of the next position.
     pub entries: usize,
+    pub window: Option<RollingWindow>,
+    pub padding: Option<LeftPadding>,
+}
+
+/// The left padding of each row of a batch, such that the prompts end at the same position and
+/// every row writes the cache at the same offset.
+#[derive(Clone, Debug)]
+pub struct LeftPadding {
+    /// Number of padding positions at the start of each row.
+    pub lengths: Vec<usize>,
+    /// `-lengths` as an I32 tensor, shifting the RoPE positions of each row to start at 0.
+    pub row_offsets: Tensor,
+}
+
+impl LeftPadding {
+    pub fn new(lengths: Vec<usize>, device: &Device) -> Self {
+        let offsets = lengths.iter().map(|&l| -(l as i32)).collect::<Vec<_>>();
+        let row_offsets = Tensor::from_data(offsets, shape![lengths.len()], device.clone());
+        Self {
+            lengths,
+            row_offsets,
+        }
+    }
 }
 
 impl KVEntry {
@@ -13,8 +38,85 @@ impl KVEntry {
             k_cache: Tensor::zeros::<T>(shape, device),
             v_cache: Tensor::zeros::<T>(shape, device),
             entries: 0,
+            window: None,
+            padding: None,
+        }
+    }
+
+    /// Allocates a cache stored as `dt`, e.g Q8_0, which is quantized as it is written.
+    /// The last dimension of `shape` must be a multiple of the block size.
+    pub fn allocate_quantized(shape: &Shape, dt: DType, device: &Device) -> Self {
+        assert!(dt.is_quantized());
+        let zeros = || {
+            let n_bytes = dt
+                .segments(shape.numel())
+                .iter()
+                .map(|s| s.size.get())
+                .sum::<u64>();
+            let data = vec![0u32; n_bytes as usize / 4];
+            unsafe { Tensor::from_quantized(data, dt, shape.clone(), device.clone()) }
+        };
+        KVEntry {
+            k_cache: zeros(),
+            v_cache: zeros(),
+            entries: 0,
+            window: None,
+            padding: None,
         }
     }
+
+    /// Reallocates the cache with `batch_size` rows, keeping its shape and dtype otherwise.
+    pub fn reallocate(&mut self, batch_size: usize) -> anyhow::Result<()> {
+        let mut shape = self.k_cache.shape().clone();
+        shape[0] = batch_size;
+        let device = self.k_cache.device().clone();
+        let fresh = match self.k_cache.dt() {
+            DType::F32 => KVEntry::allocate::<f32>(&shape, &device),
+            DType::F16 => KVEntry::allocate::<f16>(&shape, &device),
+            dt if dt.is_quantized() => KVEntry::allocate_quantized(&shape, dt, &device),
+            dt => anyhow::bail!("Cannot allocate a KV cache of {:?}", dt),
+        };
+        self.k_cache = fresh.k_cache;
+        self.v_cache = fresh.v_cache;
+        Ok(())
+    }
+
+    /// The RoPE offset of each row of a left-padded batch, see [LeftPadding].
+    pub fn row_offsets(&self) -> Option<Tensor> {
+        self.padding.as_ref().map(|p| p.row_offsets.clone())
+    }
+
+    /// Writes the new keys & values along `dim`, returning the keys & values of every cached
+    /// position. With a window, these are the occupied slots of the cache, in slot order, and a
+    /// single write cannot exceed `window.window` positions.
+    pub fn cache(&self, k: Tensor, v: Tensor, dim: usize) -> anyhow::Result<(Tensor, Tensor)> {
+        let (k_cache, v_cache) = (self.k_cache.clone(), self.v_cache.clone());
+        match self.window {
+            Some(window) => {
+                check_write(k.shape()[dim], window)?;
+                Ok((
+                    k_cache.rolling_cache(k, dim, self.entries, window)?,
+                    v_cache.rolling_cache(v, dim, self.entries, window)?,
+                ))
+            }
+            None => Ok((
+                k_cache.cache(k, dim, self.entries)?,
+                v_cache.cache(v, dim, self.entries)?,
+            )),
+        }
+    }
+}
+
+/// A single write must fit in the window, so a longer prompt has to be written in chunks, see
+/// [KVCache::max_write].
+fn check_write(len: usize, window: RollingWindow) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        len <= window.window,
+        "Cannot write {} positions at once into a window of {}, write the prompt in chunks",
+        len,
+        window.window
+    );
+    Ok(())
 }
 
 #[derive(Clone, Debug)]
@@ -37,6 +139,132 @@ impl KVCache {
         KVCache(entries)
     }
 
+    /// A cache holding K & V as `dt`, see [KVEntry::allocate_quantized].
+    pub fn quantized(n_layers: i32, shape: Shape, dt: DType, device: &Device) -> Self {
+        let entries = (0..n_layers)
+            .map(|_| KVEntry::allocate_quantized(&shape, dt, device))
+            .collect();
+        KVCache(entries)
+    }
+
+    /// Makes the cache a rolling buffer, holding the `sinks` first positions and the `window`
+    /// most recent, so that generation can continue beyond the size of the cache.
+    /// `window.capacity()` must not exceed the size of the sequence dimension.
+    /// Resets the cache.
+    pub fn set_window(&mut self, window: Option<RollingWindow>) {
+        for entry in &mut self.0 {
+            assert!(
+                window.map_or(true, |w| w.capacity() <= entry.k_cache.shape()[2]),
+                "Window exceeds the size of the cache"
+            );
+            entry.window = window;
+        }
+        self.reset();
+    }
+
+    pub fn window(&self) -> Option<RollingWindow> {
+        self.0.first().and_then(|entry| entry.window)
+    }
+
+    /// The most positions a single write may hold, `window.window` with a window.
+    pub fn max_write(&self) -> Option<usize> {
+        self.window().map(|w| w.window)
+    }
+
+    /// Prepares the cache for a batch of left-padded prompts, `padding[b]` being the number of
+    /// padding tokens at the start of row `b`. The cache is reallocated if the batch size
+    /// changes, and reset.
+    pub fn set_padding(&mut self, padding: &[usize], device: &Device) -> anyhow::Result<()> {
+        let padded = padding.iter().any(|&p| p > 0);
+        anyhow::ensure!(
+            !(padded && self.window().is_some()),
+            "Left padding cannot be combined with a rolling window"
+        );
+        let left_padding = padded.then(|| LeftPadding::new(padding.to_vec(), device));
+        for entry in &mut self.0 {
+            if entry.k_cache.shape()[0] != padding.len() {
+                entry.reallocate(padding.len())?;
+            }
+            entry.padding = left_padding.clone();
+        }
+        self.reset();
+        Ok(())
+    }
+
+    pub fn padding(&self) -> Option<&LeftPadding> {
+        self.0.first().and_then(|entry| entry.padding.as_ref())
+    }
+
+    /// The causal mask of the next `q_len` positions, matching the keys returned by
+    /// [KVEntry::cache].
+    pub fn causal_mask(&self, q_len: usize, dt: DType, device: &Device) -> anyhow::Result<Tensor> {
+        let offset = self.entries(0);
+        match self.window() {
+            Some(window) => {
+                check_write(q_len, window)?;
+                Tensor::rolling_causal_mask(q_len, offset, window, dt, device)
+            }
+            None => Tensor::causal_mask(q_len, offset + q_len, offset, dt, device),
+        }
+    }
+
+    /// The mask of the next `q_len` positions: causal when `q_len > 1`, combined with the
+    /// padding of each row when the batch is left-padded, see [KVCache::set_padding].
+    pub fn attention_mask(
+        &self,
+        q_len: usize,
+        dt: DType,
+        device: &Device,
+    ) -> anyhow::Result<Option<Tensor>> {
+        let causal = if q_len > 1 {
+            Some(self.causal_mask(q_len, dt, device)?)
+        } else {
+            None
+        };
+        let Some(mask) = self.padding_mask(q_len, dt, device)? else {
+            return Ok(causal);
+        };
+        match causal {
+            Some(causal) => Ok(Some(mask.add(causal)?)),
+            None => Ok(Some(mask)),
+        }
+    }
+
+    /// The mask & `causal` flag to pass to [Tensor::sdpa] for the next `q_len` positions.
+    /// Without a window the keys are in position order, so the attention kernel applies
+    /// causality itself and only the padding, if any, is masked.
+    pub fn sdpa_mask(
+        &self,
+        q_len: usize,
+        dt: DType,
+        device: &Device,
+    ) -> anyhow::Result<(Option<Tensor>, bool)> {
+        if self.window().is_some() {
+            return Ok((self.attention_mask(q_len, dt, device)?, false));
+        }
+        Ok((self.padding_mask(q_len, dt, device)?, q_len > 1))
+    }
+
+    fn padding_mask(
+        &self,
+        q_len: usize,
+        dt: DType,
+        device: &Device,
+    ) -> anyhow::Result<Option<Tensor>> {
+        let Some(padding) = self.padding() else {
+            return Ok(None);
+        };
+        let kv_len = self.entries(0) + q_len;
+        let lengths = padding
+            .lengths
+            .iter()
+            .map(|&p| (kv_len - p) as u32)
+            .collect::<Vec<_>>();
+        let lengths = Tensor::from_data(lengths, shape![padding.lengths.len()], device.clone());
+        let mask = Tensor::padding_mask(lengths, q_len, kv_len, PaddingSide::Left, dt)?;
+        Ok(Some(mask))
+    }
+
     pub fn update(&mut self, offset: usize) {
         for entry in &mut self.0 {
             entry.entries += offset;
@@ -53,3 +281,97 @@ impl KVCache {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::KVCache;
+    use ratchet::{shape, DType, Device, RollingWindow, Tensor};
+
+    #[test]
+    fn rolling_cache_outlives_its_size() -> anyhow::Result<()> {
+        let device = Device::CPU;
+        let mut cache = KVCache::new::<f32>(1, shape![1, 2, 6, 4], &device);
+        cache.set_window(Some(RollingWindow::new(2, 4)));
+
+        let prompt = Tensor::randn::<f32>(shape![1, 2, 3, 4], Device::CPU);
+        let (k, _) = cache[0].cache(prompt.clone(), prompt, 2)?;
+        assert_eq!(k.resolve()?.shape(), &shape![1, 2, 3, 4]);
+        cache.update(3);
+
+        for step in 0..8 {
+            let token = Tensor::randn::<f32>(shape![1, 2, 1, 4], Device::CPU);
+            let (k, _) = cache[0].cache(token.clone(), token, 2)?;
+            let occupied = (4 + step).min(6);
+            assert_eq!(k.resolve()?.shape(), &shape![1, 2, occupied, 4]);
+            cache.update(1);
+        }
+        //The RoPE offset keeps counting every position
+        assert_eq!(cache.entries(0), 11);
+        let mask = cache.causal_mask(2, DType::F32, &device)?;
+        assert_eq!(mask.shape(), &shape![2, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn rolling_cache_rejects_long_writes() -> anyhow::Result<()> {
+        let device = Device::CPU;
+        let mut cache = KVCache::new::<f32>(1, shape![1, 2, 6, 4], &device);
+        cache.set_window(Some(RollingWindow::new(2, 4)));
+        assert_eq!(cache.max_write(), Some(4));
+
+        let prompt = Tensor::randn::<f32>(shape![1, 2, 5, 4], Device::CPU);
+        assert!(cache[0].cache(prompt.clone(), prompt, 2).is_err());
+        assert!(cache.causal_mask(5, DType::F32, &device).is_err());
+
+        let chunk = Tensor::randn::<f32>(shape![1, 2, 4, 4], Device::CPU);
+        cache[0].cache(chunk.clone(), chunk, 2)?;
+        cache.causal_mask(4, DType::F32, &device)?;
+        Ok(())
+    }
+
+    #[test]
+    fn sdpa_mask_leaves_causality_to_the_kernel() -> anyhow::Result<()> {
+        let device = Device::CPU;
+        let mut cache = KVCache::new::<f32>(1, shape![1, 2, 8, 4], &device);
+        let (mask, causal) = cache.sdpa_mask(3, DType::F32, &device)?;
+        assert!(mask.is_none() && causal);
+
+        cache.set_padding(&[2, 0], &device)?;
+        let (mask, causal) = cache.sdpa_mask(3, DType::F32, &device)?;
+        assert_eq!(mask.unwrap().shape(), &shape![2, 1, 3, 3]);
+        assert!(causal);
+
+        //Slots are not in position order once the window wraps around
+        cache.set_padding(&[0], &device)?;
+        cache.set_window(Some(RollingWindow::new(2, 4)));
+        let (mask, causal) = cache.sdpa_mask(3, DType::F32, &device)?;
+        assert_eq!(mask.unwrap().shape(), &shape![3, 3]);
+        assert!(!causal);
+        Ok(())
+    }
+
+    #[test]
+    fn left_padded_batch() -> anyhow::Result<()> {
+        let device = Device::CPU;
+        let mut cache = KVCache::new::<f32>(1, shape![1, 2, 8, 4], &device);
+        cache.set_padding(&[2, 0], &device)?;
+        assert_eq!(cache[0].k_cache.shape(), &shape![2, 2, 8, 4]);
+        let row_offsets = cache[0].row_offsets().unwrap().resolve()?;
+        assert_eq!(row_offsets.to_vec::<i32>()?, vec![-2, 0]);
+
+        let prompt = cache.attention_mask(3, DType::F32, &device)?.unwrap();
+        assert_eq!(prompt.shape(), &shape![2, 1, 3, 3]);
+        cache.update(3);
+
+        let step = cache.attention_mask(1, DType::F32, &device)?.unwrap();
+        let step = step.resolve()?.to_vec::<f32>()?;
+        let visible = step.iter().map(|&m| m == 0.).collect::<Vec<_>>();
+        assert_eq!(visible[..4], [false, false, true, true]);
+        assert_eq!(visible[4..], [true; 4]);
+
+        cache.set_padding(&[0], &device)?;
+        assert!(cache.padding().is_none());
+        assert_eq!(cache[0].k_cache.shape(), &shape![1, 2, 8, 4]);
+        Ok(())
+    }
+}
@@ -3,6 +3,7 @@ mod groupnorm;
 mod kv_cache;
 mod linear;
 mod norm;
+mod paged_kv_cache;
 mod rope;
 
 pub use embedding::*;
@@ -10,6 +11,7 @@ pub use groupnorm::*;
 pub use kv_cache::*;
 pub use linear::*;
 pub use norm::*;
+pub use paged_kv_cache::*;
 pub use rope::*;
 
 use ratchet::Tensor;
@@ -9,7 +9,7 @@ use crate::Module;
 #[derive(derive_new::new, Debug)]
 pub struct Linear {
     pub w: Tensor,
-    b: Option<Tensor>,
+    pub b: Option<Tensor>,
 }
 
 impl Module for Linear {
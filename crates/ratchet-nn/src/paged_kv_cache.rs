@@ -0,0 +1,324 @@
+use ratchet::{rvec, shape, Device, Shape, Tensor, TensorDType};
+
+/// Identifies a sequence within a [PagedKVCache].
+pub type SequenceId = usize;
+
+/// The blocks holding a sequence, in order, and the number of positions written to them.
+#[derive(Clone, Debug, Default)]
+pub struct BlockTable {
+    pub blocks: Vec<u32>,
+    pub entries: usize,
+}
+
+/// The K & V pools of a single layer.
+#[derive(Clone, Debug)]
+pub struct KVPool {
+    pub k_pool: Tensor,
+    pub v_pool: Tensor,
+}
+
+/// The view of a [PagedKVCache] used by a single layer, for a batch of sequences.
+///
+/// An entry holds the pools as they were when it was built. [PagedKVCache::reserve] may grow the
+/// pools, replacing them, so entries must not be kept across calls to it.
+#[derive(Clone, Debug)]
+pub struct PagedKVEntry {
+    pub k_pool: Tensor,
+    pub v_pool: Tensor,
+    pub block_table: Tensor,
+    pub dim: usize,
+    pub entries: usize,
+}
+
+impl PagedKVEntry {
+    /// Writes the new keys & values into their pages, returning the keys & values of every
+    /// cached position.
+    pub fn cache(&self, k: Tensor, v: Tensor) -> anyhow::Result<(Tensor, Tensor)> {
+        let (table, dim, offset) = (self.block_table.clone(), self.dim, self.entries);
+        let k = self
+            .k_pool
+            .clone()
+            .paged_cache(k, table.clone(), dim, offset)?;
+        let v = self.v_pool.clone().paged_cache(v, table, dim, offset)?;
+        Ok((k, v))
+    }
+}
+
+/// # Paged KV cache
+///
+/// Where [crate::KVCache] preallocates the maximum context for a single sequence, a paged cache
+/// stores keys & values in fixed-size blocks, taken from a pool shared by every sequence. Each
+/// sequence holds a [BlockTable], listing its blocks in order.
+///
+/// Blocks are taken as sequences grow, and returned to the pool when a sequence is removed.
+/// When the pool runs out of blocks, it doubles in size.
+///
+/// Each layer has a K & V pool of shape `[n_blocks, ..]`, where the remaining dimensions match
+/// the keys & values of a single sequence, with `dim` (the sequence dimension) of `block_size`.
+///
+/// A forward pass writes every sequence of its batch at the same offset, so the sequences of a
+/// batch must hold the same number of entries, e.g prompts left-padded to a common length.
+/// Sequences of different lengths share the pool, but are run in separate batches.
+#[derive(Clone, Debug)]
+pub struct PagedKVCache {
+    layers: Vec<KVPool>,
+    block_shape: Shape,
+    dim: usize,
+    n_blocks: usize,
+    free: Vec<u32>,
+    sequences: Vec<Option<BlockTable>>,
+    zeros: fn(&Shape, &Device) -> Tensor,
+    device: Device,
+}
+
+impl std::ops::Index<usize> for PagedKVCache {
+    type Output = KVPool;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.layers[index]
+    }
+}
+
+impl PagedKVCache {
+    /// `shape` is the shape of the keys & values of a single sequence, e.g `[1, 32, S, 80]`,
+    /// where `dim` is the sequence dimension. `S` is ignored.
+    pub fn new<T: TensorDType>(
+        n_layers: usize,
+        shape: Shape,
+        dim: usize,
+        block_size: usize,
+        n_blocks: usize,
+        device: &Device,
+    ) -> Self {
+        assert!(dim > 0, "The first dimension indexes blocks");
+        assert!(block_size > 0 && n_blocks > 0);
+        let mut block_shape = shape;
+        block_shape[0] = 1;
+        block_shape[dim] = block_size;
+
+        let zeros = Tensor::zeros::<T>;
+        let pool_shape = Self::pool_shape(&block_shape, n_blocks);
+        let layers = (0..n_layers)
+            .map(|_| KVPool {
+                k_pool: zeros(&pool_shape, device),
+                v_pool: zeros(&pool_shape, device),
+            })
+            .collect();
+        PagedKVCache {
+            layers,
+            block_shape,
+            dim,
+            n_blocks,
+            free: (0..n_blocks as u32).rev().collect(),
+            sequences: vec![],
+            zeros,
+            device: device.clone(),
+        }
+    }
+
+    fn pool_shape(block_shape: &Shape, n_blocks: usize) -> Shape {
+        let mut pool_shape = block_shape.clone();
+        pool_shape[0] = n_blocks;
+        pool_shape
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_shape[self.dim]
+    }
+
+    /// Number of blocks in the pool, in use or free.
+    pub fn n_blocks(&self) -> usize {
+        self.n_blocks
+    }
+
+    pub fn n_free_blocks(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Adds an empty sequence, which holds no blocks until it is reserved.
+    pub fn add_sequence(&mut self) -> SequenceId {
+        let table = Some(BlockTable::default());
+        match self.sequences.iter().position(Option::is_none) {
+            Some(id) => {
+                self.sequences[id] = table;
+                id
+            }
+            None => {
+                self.sequences.push(table);
+                self.sequences.len() - 1
+            }
+        }
+    }
+
+    /// Removes a sequence, returning its blocks to the pool.
+    pub fn remove_sequence(&mut self, seq: SequenceId) {
+        if let Some(table) = self.sequences.get_mut(seq).and_then(Option::take) {
+            self.free.extend(table.blocks.into_iter().rev());
+        }
+    }
+
+    pub fn block_table(&self, seq: SequenceId) -> &BlockTable {
+        self.sequences[seq].as_ref().expect("Sequence was removed")
+    }
+
+    pub fn entries(&self, seq: SequenceId) -> usize {
+        self.block_table(seq).entries
+    }
+
+    /// Takes enough blocks for each of `seqs` to hold `n_tokens` more positions, growing the
+    /// pool if required. Must be called before each forward pass, and before building its
+    /// [PagedKVEntry]s and block table.
+    pub fn reserve(&mut self, seqs: &[SequenceId], n_tokens: usize) -> anyhow::Result<()> {
+        let block_size = self.block_size();
+        let required = seqs
+            .iter()
+            .map(|&seq| {
+                let table = self.block_table(seq);
+                (table.entries + n_tokens)
+                    .div_ceil(block_size)
+                    .saturating_sub(table.blocks.len())
+            })
+            .collect::<Vec<_>>();
+        let total = required.iter().sum::<usize>();
+        if total > self.free.len() {
+            self.grow(total - self.free.len())?;
+        }
+
+        for (&seq, n) in seqs.iter().zip(required) {
+            let blocks = self.free.split_off(self.free.len() - n);
+            let table = self.sequences[seq].as_mut().expect("Sequence was removed");
+            table.blocks.extend(blocks.into_iter().rev());
+        }
+        Ok(())
+    }
+
+    /// Grows the pool by at least `n_required` blocks, doubling it where possible.
+    fn grow(&mut self, n_required: usize) -> anyhow::Result<()> {
+        let n_new = n_required.max(self.n_blocks);
+        let new_shape = Self::pool_shape(&self.block_shape, n_new);
+        for layer in self.layers.iter_mut() {
+            for pool in [&mut layer.k_pool, &mut layer.v_pool] {
+                let added = (self.zeros)(&new_shape, &self.device);
+                //Copied into a constant, so the grown pool doesn't hold onto the previous one
+                let grown = Tensor::cat(rvec![pool.clone(), added], 0)?.resolve()?;
+                *pool = grown.deep_clone();
+            }
+        }
+        let n_blocks = self.n_blocks + n_new;
+        self.free
+            .splice(0..0, (self.n_blocks as u32..n_blocks as u32).rev());
+        self.n_blocks = n_blocks;
+        Ok(())
+    }
+
+    /// Builds the `[B, max_blocks]` block table of a batch of sequences, shared by every layer.
+    /// All sequences in the batch must hold the same number of entries, and the batch must hold
+    /// at least one block, see [PagedKVCache::reserve].
+    pub fn batch_table(&self, seqs: &[SequenceId]) -> anyhow::Result<Tensor> {
+        let tables = seqs
+            .iter()
+            .map(|&seq| self.block_table(seq))
+            .collect::<Vec<_>>();
+        let max_blocks = tables.iter().map(|t| t.blocks.len()).max().unwrap_or(0);
+        anyhow::ensure!(
+            max_blocks > 0,
+            "A batch table requires at least one reserved block"
+        );
+        let mut data = vec![0u32; seqs.len() * max_blocks];
+        for (row, table) in data.chunks_exact_mut(max_blocks).zip(&tables) {
+            row[..table.blocks.len()].copy_from_slice(&table.blocks);
+        }
+        let table = Tensor::from_data(data, shape![seqs.len(), max_blocks], self.device.clone());
+        Ok(table)
+    }
+
+    /// The view of `layer` for a batch of sequences, see [PagedKVCache::batch_table]. Every
+    /// sequence is written at the same offset, their shared number of entries.
+    ///
+    /// Must be built after [PagedKVCache::reserve], from a block table built after it too.
+    pub fn entry(&self, layer: usize, seqs: &[SequenceId], block_table: Tensor) -> PagedKVEntry {
+        let entries = self.entries(seqs[0]);
+        assert!(
+            seqs.iter().all(|&seq| self.entries(seq) == entries),
+            "Sequences in a batch must hold the same number of entries"
+        );
+        let max_blocks = seqs
+            .iter()
+            .map(|&seq| self.block_table(seq).blocks.len())
+            .max()
+            .unwrap_or(0);
+        assert_eq!(
+            block_table.shape().to_vec(),
+            vec![seqs.len(), max_blocks],
+            "The block table predates the last call to reserve"
+        );
+        let KVPool { k_pool, v_pool } = self.layers[layer].clone();
+        PagedKVEntry {
+            k_pool,
+            v_pool,
+            block_table,
+            dim: self.dim,
+            entries,
+        }
+    }
+
+    /// Advances each of `seqs` by `n_tokens`, after they have been written.
+    pub fn update(&mut self, seqs: &[SequenceId], n_tokens: usize) {
+        let block_size = self.block_size();
+        for &seq in seqs {
+            let table = self.sequences[seq].as_mut().expect("Sequence was removed");
+            table.entries += n_tokens;
+            assert!(table.entries <= table.blocks.len() * block_size);
+        }
+    }
+
+    /// Removes every sequence, returning all blocks to the pool.
+    pub fn reset(&mut self) {
+        self.sequences.clear();
+        self.free = (0..self.n_blocks as u32).rev().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PagedKVCache;
+    use ratchet::{shape, Device};
+
+    #[test]
+    fn blocks_grow_with_context() -> anyhow::Result<()> {
+        let mut cache = PagedKVCache::new::<f32>(2, shape![1, 4, 16, 8], 2, 4, 2, &Device::CPU);
+        let (a, b) = (cache.add_sequence(), cache.add_sequence());
+
+        cache.reserve(&[a, b], 3)?;
+        assert_eq!(cache.n_free_blocks(), 0);
+        cache.update(&[a, b], 3);
+
+        //Each sequence needs a second block, so the pool doubles
+        cache.reserve(&[a, b], 2)?;
+        assert_eq!(cache.n_blocks(), 4);
+        assert_eq!(cache[0].k_pool.shape(), &shape![4, 4, 4, 8]);
+        assert_eq!(cache.block_table(a).blocks.len(), 2);
+        cache.update(&[a, b], 2);
+
+        cache.remove_sequence(a);
+        assert_eq!(cache.n_free_blocks(), 2);
+        let c = cache.add_sequence();
+        assert_eq!(c, a);
+        cache.reserve(&[c], 8)?;
+        assert_eq!(cache.n_blocks(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn batch_table_requires_blocks() -> anyhow::Result<()> {
+        let mut cache = PagedKVCache::new::<f32>(1, shape![1, 4, 16, 8], 2, 4, 2, &Device::CPU);
+        let (a, b) = (cache.add_sequence(), cache.add_sequence());
+        assert!(cache.batch_table(&[a, b]).is_err());
+        assert!(cache.batch_table(&[]).is_err());
+
+        cache.reserve(&[a, b], 5)?;
+        let table = cache.batch_table(&[a, b])?;
+        assert_eq!(table.shape(), &shape![2, 2]);
+        Ok(())
+    }
+}
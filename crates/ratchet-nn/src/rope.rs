@@ -31,13 +31,22 @@ pub struct RotaryEmbedding {
 pub struct RotaryInput {
     pub input: Tensor,
     pub offset: usize,
+    /// Per-row shift of `offset` (I32 `[B]`), for left-padded batches.
+    pub row_offsets: Option<Tensor>,
 }
 
 impl Module for RotaryEmbedding {
     type Input = RotaryInput;
 
     fn schedule(&self, input: Self::Input) -> anyhow::Result<Tensor> {
-        let RotaryInput { input, offset } = input;
-        input.rope(self.dim, self.base, offset)
+        let RotaryInput {
+            input,
+            offset,
+            row_offsets,
+        } = input;
+        match row_offsets {
+            Some(row_offsets) => input.rope_with_offsets(self.dim, self.base, offset, row_offsets),
+            None => input.rope(self.dim, self.base, offset),
+        }
     }
 }
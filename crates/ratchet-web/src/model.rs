@@ -57,7 +57,8 @@ impl WebModel {
                 let model_repo = ApiBuilder::from_hf("microsoft/phi-2", RepoType::Model).build();
                 let model_bytes = model_repo.get("tokenizer.json").await?;
                 let tokenizer = Tokenizer::from_bytes(model_bytes.to_vec()).unwrap();
-                phi2::generate(model, tokenizer, prompt, rs_callback)
+                let max_tokens = input.max_tokens.unwrap_or(256);
+                phi2::generate(model, tokenizer, prompt, max_tokens, rs_callback)
                     .await
                     .unwrap();
                 Ok(JsValue::NULL)
@@ -74,7 +75,8 @@ impl WebModel {
                         .build();
                 let model_bytes = model_repo.get("tokenizer.json").await?;
                 let tokenizer = Tokenizer::from_bytes(model_bytes.to_vec()).unwrap();
-                phi3::generate(model, tokenizer, prompt, rs_callback)
+                let max_tokens = input.max_tokens.unwrap_or(2048);
+                phi3::generate(model, tokenizer, prompt, max_tokens, rs_callback)
                     .await
                     .unwrap();
                 Ok(JsValue::NULL)
@@ -143,6 +145,8 @@ pub struct WhisperInputs {
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct PhiInputs {
     pub prompt: String,
+    /// The most tokens to generate, defaulting to 256 for Phi2 & 2048 for Phi3.
+    pub max_tokens: Option<usize>,
     #[serde(with = "serde_wasm_bindgen::preserve")]
     pub callback: js_sys::Function,
 }